@@ -1,19 +1,17 @@
 use image::io::Reader as ImageReader;
 use image::Rgb;
 use image::RgbImage;
-use std::time::SystemTime;
-use xdof::image_impl::{greyscale_gaussian_blur, rgb_to_grayscale};
+use xdof::image_impl::greyscale_gaussian_blur;
 
 use image::GrayImage;
 use image::ImageBuffer;
 //use image::Luma;
 
 use xdof::common::*;
+use xdof::config::SlamConfig;
 use xdof::descriptors;
-use xdof::essential;
 use xdof::fast_detect;
 use xdof::image_impl;
-use xdof::matcher;
 use xdof::rand::Rand;
 use xdof::Slam;
 
@@ -21,7 +19,7 @@ fn main() {
     let args = std::env::args().collect::<Vec<String>>();
 
     if args.len() < 3 {
-        println!("\nUsage: slamiam <image_file_1> <image_file_2> \n");
+        println!("\nUsage: slamiam <image_file_1> <image_file_2> [config.toml]\n");
         return;
     }
 
@@ -29,19 +27,36 @@ fn main() {
     let img_b = read_gray_image(&args[2]);
 
     let mut slam = Slam::new(img_a, img_b);
+    if let Some(path) = args.get(3) {
+        let config = match SlamConfig::load(path) {
+            Ok(config) => config,
+            Err(e) => {
+                println!("{}: {}", path, e);
+                return;
+            }
+        };
+        slam = slam.with_config(config).unwrap();
+    }
 
-    let now = std::time::Instant::now();
-    let rslt = slam.calculate_pose();
-    let t = now.elapsed();
-
-    println!("matches: {:?}", rslt.1.len());
+    let estimate = match slam.calculate_pose() {
+        Ok(estimate) => estimate,
+        Err(e) => {
+            println!("no pose: {}", e);
+            return;
+        }
+    };
 
-    if let Some(mtx) = rslt.0 {
-        println!("rotation   : {:?}", mtx.0);
-        println!("translation: {:?}", mtx.1);
-    }
+    println!(
+        "inliers: {} of {} matches",
+        estimate.quality.inliers, estimate.quality.matches
+    );
+    println!("rotation   : {:?}", estimate.pose().rotation_matrix());
+    println!("translation: {:?}", estimate.translation);
 
-    println!("Time to calculate post: {:?}", t.as_secs_f64());
+    println!(
+        "Time to calculate post: {:?}",
+        estimate.timing.total().as_secs_f64()
+    );
 
     // let (kpswo_a, descriptors_a) = compute_kepoint_descriptors(&img_a);
     // let (kpswo_b, descriptors_b) = compute_kepoint_descriptors(&img_b);
@@ -64,13 +79,11 @@ fn main() {
     // let essential_matrix =
     //     essential::estimate_essential_ransac(&matched_keypoints, 1000, 10.0, &mut random);
 
-    // // let (rotation, tranlation) = essential::decompose_essential_matrix(essential_matrix);
-
-    // let decomposed_essential = if let Some(essential) = essential_matrix {
-    //     Some(essential::decompose_essential_matrix(essential))
-    // } else {
-    //     None
-    // };
+    // // the motion that puts the matches in front of both cameras
+    // let decomposed_essential = essential_matrix.and_then(|result| {
+    //     essential::recover_pose(&result.model, &camera.normalize_matches(&matched_keypoints))
+    //         .map(|recovered| (recovered.pose.translation, recovered.pose.rotation))
+    // });
 
     // // Once we have the essential matrix we don't need to compute this every time
     // //   Check out  https://github.com/PoseLib/PoseLib
@@ -116,6 +129,7 @@ fn main() {
 // }
 //}
 
+#[allow(dead_code)]
 fn compute_kepoint_descriptors(
     img: &ImageBuffer<Rgb<u8>, Vec<u8>>,
 ) -> (Vec<KeyPoint>, Vec<Descriptor>) {
@@ -129,9 +143,10 @@ fn compute_kepoint_descriptors(
         img.width() as usize,
         img.height() as usize,
         3.0,
-    );
+    )
+    .unwrap();
 
-    let kps_a = fast_detect::fast_keypoints(&blurred_img, width, height, 6);
+    let kps_a = fast_detect::fast_keypoints(&blurred_img, width, height, 6).unwrap();
 
     let key_points_with_orientation =
         fast_detect::compute_orientations(&blurred_img, width, &kps_a);
@@ -148,17 +163,15 @@ fn compute_kepoint_descriptors(
         height as u32,
         &key_points_with_orientation,
         &sampling_pattern,
-    );
+    )
+    .unwrap();
     (key_points_with_orientation, descriptors_a)
 }
 
 pub fn read_rgb_image(filename: &str) -> RgbImage {
     let img = ImageReader::open(filename).unwrap().decode().unwrap();
 
-    let gimg =
-        image::RgbImage::from_raw(img.width(), img.height(), img.into_bytes().to_vec()).unwrap();
-
-    gimg
+    image::RgbImage::from_raw(img.width(), img.height(), img.into_bytes().to_vec()).unwrap()
 }
 
 pub fn read_gray_image(filename: &str) -> Image {
@@ -178,12 +191,12 @@ pub fn read_gray_image(filename: &str) -> Image {
 
 pub fn blur_image(image: &GrayImage, blur_radius: f32) -> GrayImage {
     let bytes = image_impl::greyscale_gaussian_blur(
-        &image.as_raw(),
+        image.as_raw(),
         image.width() as usize,
         image.height() as usize,
         blur_radius,
-    );
+    )
+    .unwrap();
 
-    let blurred = image::GrayImage::from_raw(image.width(), image.height(), bytes).unwrap();
-    blurred
+    image::GrayImage::from_raw(image.width(), image.height(), bytes).unwrap()
 }
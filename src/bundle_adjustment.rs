@@ -0,0 +1,716 @@
+//! Sparse bundle adjustment, the joint refinement of camera poses and 3D points that minimizes
+//! the (robustified) reprojection error of every observation.
+//!
+//! The normal equations have the block structure
+//!
+//! ```text
+//! [ U    W ] [ d_poses  ]   [ -g_poses  ]
+//! [ W^T  V ] [ d_points ] = [ -g_points ]
+//! ```
+//!
+//! where V is block diagonal (one 3x3 block per point) because every residual touches a single
+//! point. We eliminate the points with the Schur complement, solve the much smaller reduced
+//! camera system `(U - W V^-1 W^T) d_poses = ...`, and back substitute for the points. Only the
+//! blocks of W for actual observations are ever stored, and the reduced system only has blocks
+//! for pairs of poses sharing a point. It is factored with a block sparse Cholesky, whose cost
+//! follows the covisibility of the poses rather than growing with the cube of their number.
+//!
+//! https://en.wikipedia.org/wiki/Bundle_adjustment
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use nalgebra::{
+    Matrix2x3, Matrix2x6, Matrix3, Matrix6, Matrix6x3, Point2, Point3, Vector2, Vector3, Vector6,
+};
+
+use crate::camera::Camera;
+use crate::lie::{SE3, SO3};
+use crate::robust::RobustKernel;
+
+// The reprojection error charged to an observation behind its camera, in pixels. Large, so that
+// moving a point out of sight never pays, but finite so that costs stay comparable.
+const BEHIND_CAMERA_RESIDUAL: f64 = 1e6;
+
+#[derive(PartialEq, Debug, Clone)]
+struct Pose {
+    camera: Camera,
+    /// `X_camera = pose * X_world`.
+    pose: SE3,
+    fixed: bool,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+struct Point {
+    position: Point3<f64>,
+    fixed: bool,
+}
+
+/// A pixel measurement of `point` in the image taken from `pose`.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct Observation {
+    pub pose: usize,
+    pub point: usize,
+    pub pixel: Point2<f64>,
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum BundleAdjustmentError {
+    UnknownPose(usize),
+    UnknownPoint(usize),
+}
+
+impl fmt::Display for BundleAdjustmentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BundleAdjustmentError::UnknownPose(index) => write!(f, "unknown pose {}", index),
+            BundleAdjustmentError::UnknownPoint(index) => write!(f, "unknown point {}", index),
+        }
+    }
+}
+
+impl std::error::Error for BundleAdjustmentError {}
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct BundleAdjustmentOptions {
+    pub max_iterations: usize,
+    /// Applied to the reprojection error of each observation, in pixels.
+    pub kernel: RobustKernel,
+    /// Stop once an iteration reduces the cost by less than this fraction.
+    pub function_tolerance: f64,
+    /// The starting Levenberg-Marquardt damping.
+    pub initial_lambda: f64,
+}
+
+impl Default for BundleAdjustmentOptions {
+    fn default() -> Self {
+        Self {
+            max_iterations: 20,
+            kernel: RobustKernel::Huber(2.0),
+            function_tolerance: 1e-8,
+            initial_lambda: 1e-4,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct BundleAdjustmentSummary {
+    /// The summed kernel cost before and after optimization. An observation behind its camera
+    /// costs as much as a reprojection error of a million pixels.
+    pub initial_cost: f64,
+    pub final_cost: f64,
+    pub iterations: usize,
+    /// False when we ran out of iterations before the cost settled.
+    pub converged: bool,
+    /// The final reprojection error of each observation in pixels, in the order they were added.
+    /// Points behind their camera are `f64::INFINITY`.
+    pub residuals: Vec<f64>,
+}
+
+impl BundleAdjustmentSummary {
+    /// The indices of the observations whose reprojection error exceeds `threshold` pixels, the
+    /// candidates for culling.
+    pub fn outliers(&self, threshold: f64) -> Vec<usize> {
+        (0..self.residuals.len())
+            .filter(|&i| self.residuals[i] > threshold)
+            .collect()
+    }
+}
+
+/// A bundle adjustment problem. Add poses, points and observations, then `optimize`.
+///
+/// Monocular reconstructions are only defined up to a similarity transform, so fix at least one
+/// pose (and, to pin the scale, a point or a second pose) or the damping alone will have to
+/// keep the solution from drifting.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct BundleAdjustment {
+    poses: Vec<Pose>,
+    points: Vec<Point>,
+    observations: Vec<Observation>,
+}
+
+// the linearization of one observation
+struct Linearization {
+    residual: Vector2<f64>,
+    weight: f64,
+    // with respect to the pose tangent (rotation, translation) and the point
+    jacobian_pose: Matrix2x6<f64>,
+    jacobian_point: Matrix2x3<f64>,
+}
+
+impl BundleAdjustment {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a camera pose, `X_camera = pose * X_world`, and returns its index.
+    pub fn add_pose(&mut self, camera: Camera, pose: SE3, fixed: bool) -> usize {
+        self.poses.push(Pose {
+            camera,
+            pose,
+            fixed,
+        });
+        self.poses.len() - 1
+    }
+
+    /// Adds a 3D point in world coordinates and returns its index.
+    pub fn add_point(&mut self, position: Point3<f64>, fixed: bool) -> usize {
+        self.points.push(Point { position, fixed });
+        self.points.len() - 1
+    }
+
+    /// Adds a pixel measurement of `point` from `pose` and returns its index.
+    pub fn add_observation(
+        &mut self,
+        pose: usize,
+        point: usize,
+        pixel: Point2<f64>,
+    ) -> Result<usize, BundleAdjustmentError> {
+        if pose >= self.poses.len() {
+            return Err(BundleAdjustmentError::UnknownPose(pose));
+        }
+        if point >= self.points.len() {
+            return Err(BundleAdjustmentError::UnknownPoint(point));
+        }
+        self.observations.push(Observation { pose, point, pixel });
+        Ok(self.observations.len() - 1)
+    }
+
+    pub fn pose(&self, index: usize) -> SE3 {
+        self.poses[index].pose
+    }
+
+    pub fn point(&self, index: usize) -> Point3<f64> {
+        self.points[index].position
+    }
+
+    pub fn observations(&self) -> &[Observation] {
+        &self.observations
+    }
+
+    fn project(&self, observation: &Observation) -> Option<(Vector3<f64>, Point2<f64>)> {
+        let pose = &self.poses[observation.pose];
+        let point = &self.points[observation.point];
+        let in_camera = pose.pose * point.position;
+        let pixel = pose.camera.project(&in_camera)?;
+        Some((in_camera.coords, pixel))
+    }
+
+    fn residual(&self, observation: &Observation) -> Option<Vector2<f64>> {
+        self.project(observation)
+            .map(|(_, pixel)| pixel - observation.pixel)
+    }
+
+    /// The reprojection error of every observation in pixels.
+    pub fn residuals(&self) -> Vec<f64> {
+        self.observations
+            .iter()
+            .map(|o| self.residual(o).map_or(f64::INFINITY, |r| r.norm()))
+            .collect()
+    }
+
+    fn cost(&self, kernel: &RobustKernel) -> f64 {
+        self.observations
+            .iter()
+            .map(|o| {
+                let residual = self
+                    .residual(o)
+                    .map_or(BEHIND_CAMERA_RESIDUAL, |r| r.norm());
+                kernel.cost(residual)
+            })
+            .sum()
+    }
+
+    // whether each observation is in front of its camera
+    fn in_front(&self) -> Vec<bool> {
+        self.observations
+            .iter()
+            .map(|o| self.project(o).is_some())
+            .collect()
+    }
+
+    fn linearize(&self, observation: &Observation, kernel: &RobustKernel) -> Option<Linearization> {
+        let (in_camera, pixel) = self.project(observation)?;
+        let pose = &self.poses[observation.pose];
+        let camera = &pose.camera;
+        let residual = pixel - observation.pixel;
+
+        // derivative of the pixel with respect to the point in camera coordinates
+        let (x, y, z) = (in_camera.x, in_camera.y, in_camera.z);
+        let d_pixel = Matrix2x3::new(
+            camera.fx / z,
+            0.0,
+            -camera.fx * x / (z * z),
+            0.0,
+            camera.fy / z,
+            -camera.fy * y / (z * z),
+        );
+
+        // X_c = exp(w) * R * X + t + dt, so dX_c/dw = -[R * X]x and dX_c/dt = I
+        let rotation = pose.pose.rotation_matrix();
+        let rotated = rotation * self.points[observation.point].position.coords;
+        let mut jacobian_pose = Matrix2x6::zeros();
+        jacobian_pose
+            .fixed_view_mut::<2, 3>(0, 0)
+            .copy_from(&(d_pixel * -rotated.cross_matrix()));
+        jacobian_pose
+            .fixed_view_mut::<2, 3>(0, 3)
+            .copy_from(&d_pixel);
+
+        Some(Linearization {
+            residual,
+            weight: kernel.weight(residual.norm()),
+            jacobian_pose,
+            jacobian_point: d_pixel * rotation,
+        })
+    }
+
+    /// Runs Levenberg-Marquardt until the cost settles or `options.max_iterations` is reached.
+    pub fn optimize(&mut self, options: &BundleAdjustmentOptions) -> BundleAdjustmentSummary {
+        // parameter blocks of the free poses and points
+        let pose_index = free_indices(self.poses.iter().map(|p| p.fixed));
+        let point_index = free_indices(self.points.iter().map(|p| p.fixed));
+        let num_free_poses = pose_index.iter().flatten().count();
+
+        let mut cost = self.cost(&options.kernel);
+        let initial_cost = cost;
+        let mut lambda = options.initial_lambda;
+        let mut converged = false;
+        let mut iterations = 0;
+
+        while iterations < options.max_iterations && !converged {
+            iterations += 1;
+
+            // accumulate the blocks of the normal equations
+            let mut u = vec![Matrix6::<f64>::zeros(); num_free_poses];
+            let mut g_pose = vec![Vector6::<f64>::zeros(); num_free_poses];
+            let mut v = vec![Matrix3::<f64>::zeros(); self.points.len()];
+            let mut g_point = vec![Vector3::<f64>::zeros(); self.points.len()];
+            let mut w: HashMap<(usize, usize), Matrix6x3<f64>> = HashMap::new();
+            // the free poses that observe each point
+            let mut observers: Vec<Vec<usize>> = vec![Vec::new(); self.points.len()];
+
+            for observation in &self.observations {
+                let l = match self.linearize(observation, &options.kernel) {
+                    Some(l) => l,
+                    None => continue,
+                };
+                let pose = pose_index[observation.pose];
+                let point = point_index[observation.point];
+
+                if let Some(c) = pose {
+                    u[c] += l.jacobian_pose.transpose() * l.jacobian_pose * l.weight;
+                    g_pose[c] += l.jacobian_pose.transpose() * l.residual * l.weight;
+                }
+                if point.is_some() {
+                    let p = observation.point;
+                    v[p] += l.jacobian_point.transpose() * l.jacobian_point * l.weight;
+                    g_point[p] += l.jacobian_point.transpose() * l.residual * l.weight;
+                    if let Some(c) = pose {
+                        *w.entry((c, p)).or_insert_with(Matrix6x3::zeros) +=
+                            l.jacobian_pose.transpose() * l.jacobian_point * l.weight;
+                        if !observers[p].contains(&c) {
+                            observers[p].push(c);
+                        }
+                    }
+                }
+            }
+
+            // try increasingly damped steps until one lowers the cost without moving a point
+            // behind a camera that saw it, which bounded kernels would not always penalize
+            let in_front = self.in_front();
+            let mut improved = false;
+            while lambda < 1e12 {
+                let step = match solve_schur(
+                    &u,
+                    &g_pose,
+                    &v,
+                    &g_point,
+                    &w,
+                    &observers,
+                    &point_index,
+                    lambda,
+                ) {
+                    Some(step) => step,
+                    None => {
+                        lambda *= 10.0;
+                        continue;
+                    }
+                };
+
+                let previous = (self.poses.clone(), self.points.clone());
+                self.apply(&step, &pose_index, &point_index);
+                let new_cost = self.cost(&options.kernel);
+                let still_in_front = self
+                    .in_front()
+                    .iter()
+                    .zip(&in_front)
+                    .all(|(&now, &before)| now || !before);
+
+                if new_cost < cost && still_in_front {
+                    converged = (cost - new_cost) <= options.function_tolerance * cost;
+                    cost = new_cost;
+                    lambda = (lambda / 10.0).max(1e-12);
+                    improved = true;
+                    break;
+                }
+
+                (self.poses, self.points) = previous;
+                lambda *= 10.0;
+            }
+
+            if !improved {
+                // no step helps, we are at a minimum
+                converged = true;
+            }
+        }
+
+        BundleAdjustmentSummary {
+            initial_cost,
+            final_cost: cost,
+            iterations,
+            converged,
+            residuals: self.residuals(),
+        }
+    }
+
+    fn apply(&mut self, step: &Step, pose_index: &[Option<usize>], point_index: &[Option<usize>]) {
+        for (pose, index) in self.poses.iter_mut().zip(pose_index) {
+            if let Some(c) = index {
+                let d = &step.poses[*c];
+                let omega = Vector3::new(d[0], d[1], d[2]);
+                pose.pose.rotation = SO3::exp(&omega) * pose.pose.rotation;
+                pose.pose.translation += Vector3::new(d[3], d[4], d[5]);
+            }
+        }
+        for ((point, index), d) in self.points.iter_mut().zip(point_index).zip(&step.points) {
+            if index.is_some() {
+                point.position += d;
+            }
+        }
+    }
+}
+
+// Maps every block to its position among the free blocks, `None` for fixed ones.
+fn free_indices(fixed: impl Iterator<Item = bool>) -> Vec<Option<usize>> {
+    let mut next = 0;
+    fixed
+        .map(|fixed| {
+            if fixed {
+                None
+            } else {
+                next += 1;
+                Some(next - 1)
+            }
+        })
+        .collect()
+}
+
+// Solves S * x = b for a symmetric positive definite S given as the upper triangle of its 6x6
+// blocks by block row. S = R^T R is factored in place, the fill in of R only reaching the pairs
+// of poses linked through common neighbours earlier in the order.
+fn solve_block_cholesky(
+    mut rows: Vec<BTreeMap<usize, Matrix6<f64>>>,
+    mut b: Vec<Vector6<f64>>,
+) -> Option<Vec<Vector6<f64>>> {
+    let n = rows.len();
+    for k in 0..n {
+        let diagonal = rows[k].get(&k)?.cholesky()?.l();
+        for (_, block) in rows[k].range_mut(k + 1..) {
+            // R_kj = R_kk^-T * S_kj
+            *block = diagonal.solve_lower_triangular(block)?;
+        }
+        rows[k].insert(k, diagonal.transpose());
+        let off_diagonal = rows[k]
+            .range(k + 1..)
+            .map(|(&j, block)| (j, *block))
+            .collect::<Vec<_>>();
+        for (a, (i, r_ki)) in off_diagonal.iter().enumerate() {
+            for (j, r_kj) in &off_diagonal[a..] {
+                *rows[*i].entry(*j).or_insert_with(Matrix6::zeros) -= r_ki.transpose() * r_kj;
+            }
+        }
+    }
+
+    // R^T y = b, then R x = y
+    for k in 0..n {
+        b[k] = rows[k][&k].transpose().solve_lower_triangular(&b[k])?;
+        let y_k = b[k];
+        for (&j, r_kj) in rows[k].range(k + 1..) {
+            b[j] -= r_kj.transpose() * y_k;
+        }
+    }
+    for k in (0..n).rev() {
+        let mut rhs = b[k];
+        for (&j, r_kj) in rows[k].range(k + 1..) {
+            rhs -= r_kj * b[j];
+        }
+        b[k] = rows[k][&k].solve_upper_triangular(&rhs)?;
+    }
+    Some(b)
+}
+
+struct Step {
+    poses: Vec<Vector6<f64>>,
+    // indexed like the points, zero for fixed ones
+    points: Vec<Vector3<f64>>,
+}
+
+// Solves the damped normal equations by eliminating the points.
+#[allow(clippy::too_many_arguments)]
+fn solve_schur(
+    u: &[Matrix6<f64>],
+    g_pose: &[Vector6<f64>],
+    v: &[Matrix3<f64>],
+    g_point: &[Vector3<f64>],
+    w: &HashMap<(usize, usize), Matrix6x3<f64>>,
+    observers: &[Vec<usize>],
+    point_index: &[Option<usize>],
+    lambda: f64,
+) -> Option<Step> {
+    let num_poses = u.len();
+
+    // Marquardt damping, scaled by the diagonal with a floor for unconstrained directions
+    let damp6 = |m: &Matrix6<f64>| {
+        let mut m = *m;
+        for k in 0..6 {
+            m[(k, k)] += lambda * m[(k, k)].max(1e-6);
+        }
+        m
+    };
+    let damp3 = |m: &Matrix3<f64>| {
+        let mut m = *m;
+        for k in 0..3 {
+            m[(k, k)] += lambda * m[(k, k)].max(1e-6);
+        }
+        m
+    };
+
+    // V^-1 for every free point
+    let mut v_inv = vec![Matrix3::zeros(); v.len()];
+    for (p, index) in point_index.iter().enumerate() {
+        if index.is_some() {
+            v_inv[p] = damp3(&v[p]).try_inverse()?;
+        }
+    }
+
+    // the reduced camera system S * d_poses = b, the upper triangle of S by block row
+    let mut s = (0..num_poses)
+        .map(|c| BTreeMap::from([(c, damp6(&u[c]))]))
+        .collect::<Vec<_>>();
+    let mut b = g_pose.iter().map(|g| -g).collect::<Vec<_>>();
+    for (p, cameras) in observers.iter().enumerate() {
+        for &i in cameras {
+            let w_i = w[&(i, p)] * v_inv[p];
+            b[i] += w_i * g_point[p];
+            for &j in cameras.iter().filter(|&&j| j >= i) {
+                *s[i].entry(j).or_insert_with(Matrix6::zeros) -= w_i * w[&(j, p)].transpose();
+            }
+        }
+    }
+    let poses = solve_block_cholesky(s, b)?;
+
+    // back substitute, d_point = V^-1 * (-g_point - W^T * d_poses)
+    let mut points = vec![Vector3::zeros(); v.len()];
+    for (p, index) in point_index.iter().enumerate() {
+        if index.is_none() {
+            continue;
+        }
+        let mut rhs = -g_point[p];
+        for &c in &observers[p] {
+            rhs -= w[&(c, p)].transpose() * poses[c];
+        }
+        points[p] = v_inv[p] * rhs;
+    }
+
+    let finite = poses.iter().all(|d| d.iter().all(|x| x.is_finite()))
+        && points.iter().all(|d| d.iter().all(|x| x.is_finite()));
+    finite.then_some(Step { poses, points })
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_scene;
+
+    // three cameras looking at the test scene, the first at the origin
+    fn poses() -> Vec<SE3> {
+        let motion = test_scene::motion();
+        vec![SE3::identity(), motion, motion * motion]
+    }
+
+    fn problem(
+        points: &[Point3<f64>],
+        pose_noise: f64,
+        point_noise: f64,
+    ) -> (BundleAdjustment, Vec<SE3>) {
+        let camera = test_scene::camera();
+        let truth = poses();
+        let mut ba = BundleAdjustment::new();
+        for (i, pose) in truth.iter().enumerate() {
+            // fix the first two poses to pin down the gauge and the scale
+            let noise = if i < 2 { 0.0 } else { pose_noise };
+            let noisy = SE3::exp(&Vector6::new(-noise, noise, noise, noise, -noise, noise)) * *pose;
+            ba.add_pose(camera, noisy, i < 2);
+        }
+        for (i, p) in points.iter().enumerate() {
+            let offset = Vector3::new(1.0, -1.0, 0.5) * point_noise * ((i % 3) as f64 - 1.0);
+            let index = ba.add_point(p + offset, false);
+            for (c, pose) in truth.iter().enumerate() {
+                let pixel = camera.project(&(*pose * *p)).unwrap();
+                ba.add_observation(c, index, pixel).unwrap();
+            }
+        }
+        (ba, truth)
+    }
+
+    #[test]
+    fn test_bundle_adjustment_converges() {
+        let points = test_scene::points(30);
+        let (mut ba, truth) = problem(&points, 0.02, 0.05);
+
+        let summary = ba.optimize(&BundleAdjustmentOptions {
+            max_iterations: 50,
+            ..Default::default()
+        });
+
+        assert!(summary.converged);
+        assert!(summary.final_cost < summary.initial_cost * 1e-6);
+        assert!(summary.residuals.iter().all(|&r| r < 1e-3));
+        assert!((ba.pose(2).inverse() * truth[2]).log().norm() < 1e-5);
+        for (i, p) in points.iter().enumerate() {
+            assert!((ba.point(i) - p).norm() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_fixed_blocks_do_not_move() {
+        let points = test_scene::points(20);
+        let (mut ba, truth) = problem(&points, 0.02, 0.0);
+        let fixed_point = ba.add_point(Point3::new(0.5, 0.5, 7.0), true);
+        let camera = test_scene::camera();
+        for (c, pose) in truth.iter().enumerate() {
+            let pixel = camera
+                .project(&(*pose * Point3::new(0.5, 0.5, 7.0)))
+                .unwrap();
+            ba.add_observation(c, fixed_point, pixel).unwrap();
+        }
+
+        ba.optimize(&BundleAdjustmentOptions::default());
+        assert_eq!(ba.pose(0), truth[0]);
+        assert_eq!(ba.pose(1), truth[1]);
+        assert_eq!(ba.point(fixed_point), Point3::new(0.5, 0.5, 7.0));
+        assert!((ba.pose(2).translation - truth[2].translation).norm() < 1e-4);
+    }
+
+    #[test]
+    fn test_outliers_are_reported() {
+        let points = test_scene::points(30);
+        let (mut ba, _) = problem(&points, 0.0, 0.0);
+        let bad = ba.observations().len();
+        let index = ba.add_point(Point3::new(0.0, 0.0, 6.0), false);
+        ba.add_observation(0, index, Point2::new(320.0, 240.0))
+            .unwrap();
+        ba.add_observation(1, index, Point2::new(100.0, 100.0))
+            .unwrap();
+        ba.add_observation(2, index, Point2::new(500.0, 50.0))
+            .unwrap();
+
+        let summary = ba.optimize(&BundleAdjustmentOptions::default());
+        let outliers = summary.outliers(5.0);
+        assert!(!outliers.is_empty());
+        assert!(outliers.iter().all(|&o| o >= bad), "{:?}", outliers);
+    }
+
+    #[test]
+    fn test_points_stay_in_front_of_their_cameras() {
+        // both observations are explained exactly by a point at (1, 0, -2), behind the first
+        // camera, and by no point in front of both cameras
+        let camera = test_scene::camera();
+        let second = SE3::new(SO3::identity(), Vector3::new(0.0, 0.0, 5.0));
+        let behind = Point3::new(1.0, 0.0, -2.0);
+        for kernel in [RobustKernel::Quadratic, RobustKernel::Huber(2.0)] {
+            let mut ba = BundleAdjustment::new();
+            ba.add_pose(camera, SE3::identity(), true);
+            ba.add_pose(camera, second, true);
+            let point = ba.add_point(Point3::new(0.0, 0.0, 1.0), false);
+            ba.add_observation(
+                0,
+                point,
+                Point2::new(camera.cx - 0.5 * camera.fx, camera.cy),
+            )
+            .unwrap();
+            ba.add_observation(1, point, camera.project(&(second * behind)).unwrap())
+                .unwrap();
+
+            let summary = ba.optimize(&BundleAdjustmentOptions {
+                kernel,
+                max_iterations: 50,
+                ..Default::default()
+            });
+            assert!(ba.point(point).z > 0.0, "{:?}", ba.point(point));
+            assert!(summary.residuals.iter().all(|r| r.is_finite()));
+            assert!(summary.final_cost > 0.0 && summary.final_cost <= summary.initial_cost);
+        }
+    }
+
+    #[test]
+    fn test_add_observation_checks_indices() {
+        let mut ba = BundleAdjustment::new();
+        let pose = ba.add_pose(test_scene::camera(), SE3::identity(), true);
+        let point = ba.add_point(Point3::new(0.0, 0.0, 5.0), false);
+        let pixel = Point2::new(320.0, 240.0);
+        assert_eq!(ba.add_observation(pose, point, pixel), Ok(0));
+        assert_eq!(
+            ba.add_observation(1, point, pixel),
+            Err(BundleAdjustmentError::UnknownPose(1))
+        );
+        assert_eq!(
+            ba.add_observation(pose, 3, pixel),
+            Err(BundleAdjustmentError::UnknownPoint(3))
+        );
+        assert_eq!(ba.observations().len(), 1);
+    }
+
+    #[test]
+    fn test_block_cholesky() {
+        // a chain of five poses, each linked to the next, and the first to the last
+        let n = 5;
+        let block = |i: usize, j: usize| {
+            Matrix6::from_fn(|r, c| ((r + 2 * c + 3 * i + 5 * j) % 7) as f64 * 0.1)
+        };
+        let mut dense = nalgebra::DMatrix::<f64>::zeros(6 * n, 6 * n);
+        let mut rows = vec![BTreeMap::new(); n];
+        for (i, row) in rows.iter_mut().enumerate() {
+            let diagonal = Matrix6::identity() * 20.0 + block(i, i) + block(i, i).transpose();
+            row.insert(i, diagonal);
+            dense
+                .fixed_view_mut::<6, 6>(6 * i, 6 * i)
+                .copy_from(&diagonal);
+        }
+        for k in 0..n {
+            let (i, j) = (k.min((k + 1) % n), k.max((k + 1) % n));
+            rows[i].insert(j, block(i, j));
+            dense
+                .fixed_view_mut::<6, 6>(6 * i, 6 * j)
+                .copy_from(&block(i, j));
+            dense
+                .fixed_view_mut::<6, 6>(6 * j, 6 * i)
+                .copy_from(&block(i, j).transpose());
+        }
+        let b = (0..n)
+            .map(|i| Vector6::from_fn(|r, _| (r + i) as f64 - 4.0))
+            .collect::<Vec<_>>();
+        let dense_b = nalgebra::DVector::from_iterator(6 * n, b.iter().flatten().copied());
+        let expected = dense.cholesky().unwrap().solve(&dense_b);
+
+        let solution = solve_block_cholesky(rows, b).unwrap();
+        for (i, x) in solution.iter().enumerate() {
+            assert!((x - expected.fixed_rows::<6>(6 * i)).norm() < 1e-10);
+        }
+    }
+}
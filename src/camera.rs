@@ -0,0 +1,114 @@
+use nalgebra::{Matrix3, Point2, Point3, Vector3};
+
+use crate::common::{KeyPoint, PointMatch};
+
+/// A pinhole camera, the intrinsics are in pixels.
+///
+/// https://en.wikipedia.org/wiki/Pinhole_camera_model
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct Camera {
+    pub fx: f64,
+    pub fy: f64,
+    pub cx: f64,
+    pub cy: f64,
+}
+
+impl Camera {
+    pub fn new(fx: f64, fy: f64, cx: f64, cy: f64) -> Self {
+        Self { fx, fy, cx, cy }
+    }
+
+    /// A guess for an uncalibrated camera, the principal point at the image centre and a focal
+    /// length equal to the larger image dimension (roughly a 53 degree field of view).
+    pub fn from_image_size(width: usize, height: usize) -> Self {
+        let focal = width.max(height) as f64;
+        Self::new(focal, focal, width as f64 / 2.0, height as f64 / 2.0)
+    }
+
+    /// The calibration matrix K.
+    pub fn k(&self) -> Matrix3<f64> {
+        Matrix3::new(self.fx, 0.0, self.cx, 0.0, self.fy, self.cy, 0.0, 0.0, 1.0)
+    }
+
+    pub fn k_inv(&self) -> Matrix3<f64> {
+        Matrix3::new(
+            1.0 / self.fx,
+            0.0,
+            -self.cx / self.fx,
+            0.0,
+            1.0 / self.fy,
+            -self.cy / self.fy,
+            0.0,
+            0.0,
+            1.0,
+        )
+    }
+
+    /// Maps a pixel onto the normalized image plane (z = 1), removing the intrinsics.
+    pub fn normalize(&self, pixel: &Point2<f64>) -> Point2<f64> {
+        Point2::new((pixel.x - self.cx) / self.fx, (pixel.y - self.cy) / self.fy)
+    }
+
+    /// The inverse of `normalize`.
+    pub fn denormalize(&self, point: &Point2<f64>) -> Point2<f64> {
+        Point2::new(point.x * self.fx + self.cx, point.y * self.fy + self.cy)
+    }
+
+    /// Normalizes both keypoints of every match.
+    pub fn normalize_matches(&self, matches: &[(KeyPoint, KeyPoint)]) -> Vec<PointMatch> {
+        matches
+            .iter()
+            .map(|(p1, p2)| (self.normalize(&p1.point()), self.normalize(&p2.point())))
+            .collect()
+    }
+
+    /// Projects a point in camera coordinates to a pixel, `None` if it is behind the camera.
+    pub fn project(&self, point: &Point3<f64>) -> Option<Point2<f64>> {
+        if point.z <= 0.0 {
+            return None;
+        }
+        Some(self.denormalize(&Point2::new(point.x / point.z, point.y / point.z)))
+    }
+
+    /// The unit length viewing ray through a pixel.
+    pub fn unproject(&self, pixel: &Point2<f64>) -> Vector3<f64> {
+        self.normalize(pixel).to_homogeneous().normalize()
+    }
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_round_trip() {
+        let camera = Camera::new(500.0, 480.0, 320.0, 240.0);
+        let pixel = Point2::new(100.0, 400.0);
+        let normalized = camera.normalize(&pixel);
+        assert!((normalized.x - -0.44).abs() < 1e-12);
+        assert!((normalized.y - 1.0 / 3.0).abs() < 1e-12);
+        assert!((camera.denormalize(&normalized) - pixel).norm() < 1e-12);
+        assert!((camera.k() * camera.k_inv() - Matrix3::identity()).norm() < 1e-12);
+    }
+
+    #[test]
+    fn test_from_image_size() {
+        let camera = Camera::from_image_size(640, 480);
+        assert_eq!(camera, Camera::new(640.0, 640.0, 320.0, 240.0));
+    }
+
+    #[test]
+    fn test_project() {
+        let camera = Camera::new(500.0, 500.0, 320.0, 240.0);
+        let pixel = camera.project(&Point3::new(1.0, -1.0, 5.0)).unwrap();
+        assert_eq!(pixel, Point2::new(420.0, 140.0));
+        assert!(camera.project(&Point3::new(1.0, -1.0, -5.0)).is_none());
+
+        let ray = camera.unproject(&pixel);
+        assert!((ray - Vector3::new(1.0, -1.0, 5.0).normalize()).norm() < 1e-12);
+    }
+}
@@ -1,3 +1,5 @@
+use nalgebra::Point2;
+
 #[derive(PartialEq, Debug, Copy, Clone)]
 pub struct KeyPoint {
     pub x: f32,
@@ -9,11 +11,26 @@ impl KeyPoint {
     pub fn new(x: f32, y: f32, orientation: f32) -> Self {
         Self { x, y, orientation }
     }
+
+    /// The pixel position as a double precision point.
+    pub fn point(&self) -> Point2<f64> {
+        Point2::new(self.x as f64, self.y as f64)
+    }
 }
 
+/// A correspondence between two images in double precision, e.g. normalized image coordinates.
+pub type PointMatch = (Point2<f64>, Point2<f64>);
+
 #[derive(PartialEq, Debug, Clone)]
 pub struct Descriptor(pub Vec<u8>);
 
+/// The keypoints of an image and their descriptors, in the same order.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Features {
+    pub key_points: Vec<KeyPoint>,
+    pub descriptors: Vec<Descriptor>,
+}
+
 //#[derive(PartialEq, Debug, Clone, Copy)]
 // pub struct Image<'a> {
 //     pub width: usize,
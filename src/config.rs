@@ -0,0 +1,711 @@
+//! The tuning parameters of `Slam`, set in code with `SlamConfigBuilder` or read from a file.
+//!
+//! Files are TOML, or YAML with the `yaml` feature, with the tables below. Missing keys keep
+//! their defaults, unknown keys are an error so a typo does not go unnoticed. The options of the
+//! `SlamSequence` modules (tracking, initialization, relocalization, local mapping and loop
+//! closing) are only set in code. `SlamConfig::to_toml` writes every other key:
+//!
+//! ```toml
+//! seed = 2523523                # of the random generator, also fixes the descriptor pattern
+//!
+//! [camera]                      # optional, guessed from the image size when missing
+//! fx = 500.0                    # focal lengths and principal point, pixels
+//! fy = 500.0
+//! cx = 320.0
+//! cy = 240.0
+//!
+//! [features]
+//! blur_radius = 3.0             # standard deviation of the Gaussian blur, pixels
+//! fast_threshold = 30           # intensity difference of a FAST corner, grey levels
+//! patch_size = 100              # side of the BRIEF sampling patch, pixels
+//! num_pairs = 500               # intensity comparisons, bits per descriptor
+//! max_hamming_distance = 300    # largest distance of a match, bits, at most num_pairs
+//!
+//! [essential]
+//! max_iterations = 1000         # RANSAC hypotheses
+//! inlier_threshold = 5.0        # Sampson distance, pixels
+//! confidence = 0.99             # probability of drawing an all inlier sample, in (0, 1)
+//! scoring = "msac"              # "ransac", "msac" or "magsac"
+//! sigma_max = 0.0               # largest noise level of "magsac", pixels
+//! sampling = "prosac"           # "uniform" or "prosac"
+//! local_optimization_iterations = 10
+//!
+//! [refinement]
+//! max_iterations = 20           # Levenberg-Marquardt iterations, 0 skips the refinement
+//! kernel = "huber"              # "quadratic", "huber", "cauchy" or "tukey"
+//! kernel_threshold = 1.0        # where the kernel stops being quadratic, pixels
+//! ```
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::camera::Camera;
+use crate::extractor::ExtractorConfig;
+use crate::initializer::InitializerOptions;
+use crate::local_mapping::LocalMappingOptions;
+use crate::loop_closing::LoopClosingOptions;
+use crate::ransac::{RansacOptions, Sampling, Scoring};
+use crate::relocalization::RelocalizationOptions;
+use crate::robust::RobustKernel;
+use crate::tracking::TrackingOptions;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    /// Not TOML, an unknown key or a value of the wrong type.
+    Toml(toml::de::Error),
+    /// Not YAML, an unknown key or a value of the wrong type.
+    #[cfg(feature = "yaml")]
+    Yaml(serde_norway::Error),
+    /// A parameter out of its range, named as in the file.
+    Invalid {
+        key: &'static str,
+        reason: &'static str,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "{}", e),
+            ConfigError::Toml(e) => write!(f, "{}", e),
+            #[cfg(feature = "yaml")]
+            ConfigError::Yaml(e) => write!(f, "{}", e),
+            ConfigError::Invalid { key, reason } => write!(f, "{} {}", key, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<io::Error> for ConfigError {
+    fn from(error: io::Error) -> Self {
+        ConfigError::Io(error)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(error: toml::de::Error) -> Self {
+        ConfigError::Toml(error)
+    }
+}
+
+#[cfg(feature = "yaml")]
+impl From<serde_norway::Error> for ConfigError {
+    fn from(error: serde_norway::Error) -> Self {
+        ConfigError::Yaml(error)
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct SlamConfig {
+    /// Seeds the random generator. The BRIEF sampling pattern is drawn from it, so maps are only
+    /// reusable with the same seed, patch size and number of pairs, see `SlamSequence::with_map`.
+    pub seed: u64,
+    /// Calibrated intrinsics, in pixels. Guessed from the image size when `None`.
+    pub camera: Option<Camera>,
+    /// The standard deviation of the Gaussian blur before detection, pixels.
+    pub blur_radius: f32,
+    /// How much brighter or darker than the center the FAST circle must be, grey levels.
+    pub fast_threshold: u8,
+    /// The side of the square BRIEF samples in, pixels.
+    pub patch_size: usize,
+    /// The number of BRIEF intensity comparisons, bits per descriptor.
+    pub num_pairs: usize,
+    /// The largest Hamming distance of a match, bits. At most `num_pairs`.
+    pub max_hamming_distance: usize,
+    /// RANSAC for the essential matrix, `inlier_threshold` is a Sampson distance in pixels and
+    /// `confidence` a probability strictly between 0 and 1.
+    pub essential: RansacOptions,
+    /// Levenberg-Marquardt iterations polishing the relative pose, 0 skips the refinement.
+    pub refinement_iterations: usize,
+    /// The robust kernel of the refinement, its threshold in pixels.
+    pub refinement_kernel: RobustKernel,
+    pub tracking: TrackingOptions,
+    pub initializer: InitializerOptions,
+    pub relocalization: RelocalizationOptions,
+    pub local_mapping: LocalMappingOptions,
+    pub loop_closing: LoopClosingOptions,
+    /// `FrameResult::confidence` is scaled down for poses with fewer inliers than this.
+    pub confident_inliers: usize,
+}
+
+impl Default for SlamConfig {
+    fn default() -> Self {
+        Self {
+            seed: 2523523,
+            camera: None,
+            blur_radius: 3.0,
+            fast_threshold: 30,
+            patch_size: 100,
+            num_pairs: 500,
+            max_hamming_distance: 300,
+            essential: RansacOptions {
+                max_iterations: 1000,
+                inlier_threshold: 5.0,
+                confidence: 0.99,
+                scoring: Scoring::Msac,
+                sampling: Sampling::Prosac,
+                local_optimization_iterations: 10,
+            },
+            refinement_iterations: 20,
+            refinement_kernel: RobustKernel::Huber(1.0),
+            tracking: TrackingOptions::default(),
+            initializer: InitializerOptions::default(),
+            relocalization: RelocalizationOptions::default(),
+            local_mapping: LocalMappingOptions::default(),
+            loop_closing: LoopClosingOptions::default(),
+            confident_inliers: 50,
+        }
+    }
+}
+
+fn invalid(key: &'static str, reason: &'static str) -> Result<(), ConfigError> {
+    Err(ConfigError::Invalid { key, reason })
+}
+
+// the name and threshold of a kernel as written in files
+fn kernel_parts(kernel: &RobustKernel) -> (KernelName, f64) {
+    match *kernel {
+        RobustKernel::Quadratic => (KernelName::Quadratic, 0.0),
+        RobustKernel::Huber(t) => (KernelName::Huber, t),
+        RobustKernel::Cauchy(t) => (KernelName::Cauchy, t),
+        RobustKernel::Tukey(t) => (KernelName::Tukey, t),
+    }
+}
+
+// the name of a scoring and its maximum noise level, 0 when it has none
+fn scoring_parts(scoring: &Scoring) -> (ScoringName, f64) {
+    match *scoring {
+        Scoring::Ransac => (ScoringName::Ransac, 0.0),
+        Scoring::Msac => (ScoringName::Msac, 0.0),
+        Scoring::Magsac { sigma_max } => (ScoringName::Magsac, sigma_max),
+    }
+}
+
+impl SlamConfig {
+    pub fn builder() -> SlamConfigBuilder {
+        SlamConfigBuilder::default()
+    }
+
+    /// The feature extraction parameters.
+    pub fn extractor(&self) -> ExtractorConfig {
+        ExtractorConfig {
+            blur_radius: self.blur_radius,
+            fast_threshold: self.fast_threshold,
+            patch_size: self.patch_size,
+            num_pairs: self.num_pairs,
+            seed: self.seed,
+        }
+    }
+
+    /// Checks every parameter is in its range.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(camera) = &self.camera {
+            if !(camera.fx > 0.0 && camera.fy > 0.0) {
+                invalid("camera.fx", "and camera.fy must be positive")?;
+            }
+            if !(camera.cx.is_finite() && camera.cy.is_finite()) {
+                invalid("camera.cx", "and camera.cy must be finite")?;
+            }
+        }
+        if !(self.blur_radius > 0.0 && self.blur_radius.is_finite()) {
+            invalid("features.blur_radius", "must be positive")?;
+        }
+        if self.fast_threshold == 0 {
+            invalid("features.fast_threshold", "must be at least 1")?;
+        }
+        if self.patch_size == 0 {
+            invalid("features.patch_size", "must be at least 1")?;
+        }
+        if self.num_pairs == 0 {
+            invalid("features.num_pairs", "must be at least 1")?;
+        }
+        if self.max_hamming_distance > self.num_pairs {
+            invalid(
+                "features.max_hamming_distance",
+                "must not exceed features.num_pairs",
+            )?;
+        }
+        if self.essential.max_iterations == 0 {
+            invalid("essential.max_iterations", "must be at least 1")?;
+        }
+        if !(self.essential.inlier_threshold > 0.0 && self.essential.inlier_threshold.is_finite()) {
+            invalid("essential.inlier_threshold", "must be positive")?;
+        }
+        if !(self.essential.confidence > 0.0 && self.essential.confidence < 1.0) {
+            invalid("essential.confidence", "must be between 0 and 1")?;
+        }
+        if let Scoring::Magsac { sigma_max } = self.essential.scoring {
+            if !(sigma_max > 0.0 && sigma_max.is_finite()) {
+                invalid("essential.sigma_max", "must be positive")?;
+            }
+        }
+        let (_, threshold) = kernel_parts(&self.refinement_kernel);
+        if self.refinement_kernel != RobustKernel::Quadratic
+            && !(threshold > 0.0 && threshold.is_finite())
+        {
+            invalid("refinement.kernel_threshold", "must be positive")?;
+        }
+        if self.confident_inliers == 0 {
+            invalid("confident_inliers", "must be at least 1")?;
+        }
+        Ok(())
+    }
+
+    /// Reads a configuration file, see the module documentation for the format. With the `yaml`
+    /// feature, files ending in `.yaml` or `.yml` are read as YAML.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        #[cfg(feature = "yaml")]
+        if matches!(
+            path.extension().and_then(|e| e.to_str()),
+            Some("yaml" | "yml")
+        ) {
+            return Self::from_yaml(&text);
+        }
+        Self::from_toml(&text)
+    }
+
+    /// Parses a TOML configuration, keys that are not given keep their defaults.
+    pub fn from_toml(text: &str) -> Result<Self, ConfigError> {
+        let file: ConfigFile = toml::from_str(text)?;
+        file.into_config()
+    }
+
+    /// Writes every parameter in the format `from_toml` reads.
+    pub fn to_toml(&self) -> String {
+        toml::to_string(&ConfigFile::from(self)).expect("plain tables always serialize")
+    }
+
+    /// Parses a YAML configuration with the tables and keys of the TOML one.
+    #[cfg(feature = "yaml")]
+    pub fn from_yaml(text: &str) -> Result<Self, ConfigError> {
+        let file: ConfigFile = serde_norway::from_str(text)?;
+        file.into_config()
+    }
+
+    /// Writes every parameter in the format `from_yaml` reads.
+    #[cfg(feature = "yaml")]
+    pub fn to_yaml(&self) -> String {
+        serde_norway::to_string(&ConfigFile::from(self)).expect("plain tables always serialize")
+    }
+}
+
+// the layout of configuration files, see the module documentation
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    seed: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    camera: Option<CameraTable>,
+    features: FeaturesTable,
+    essential: EssentialTable,
+    refinement: RefinementTable,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraTable {
+    fx: f64,
+    fy: f64,
+    cx: f64,
+    cy: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FeaturesTable {
+    blur_radius: f32,
+    fast_threshold: u8,
+    patch_size: usize,
+    num_pairs: usize,
+    max_hamming_distance: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct EssentialTable {
+    max_iterations: usize,
+    inlier_threshold: f64,
+    confidence: f64,
+    scoring: ScoringName,
+    sigma_max: f64,
+    sampling: SamplingName,
+    local_optimization_iterations: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RefinementTable {
+    max_iterations: usize,
+    kernel: KernelName,
+    kernel_threshold: f64,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+enum ScoringName {
+    Ransac,
+    Msac,
+    Magsac,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+enum SamplingName {
+    Uniform,
+    Prosac,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+enum KernelName {
+    Quadratic,
+    Huber,
+    Cauchy,
+    Tukey,
+}
+
+impl From<&SlamConfig> for ConfigFile {
+    fn from(config: &SlamConfig) -> Self {
+        let (scoring, sigma_max) = scoring_parts(&config.essential.scoring);
+        let (kernel, kernel_threshold) = kernel_parts(&config.refinement_kernel);
+        Self {
+            seed: config.seed,
+            camera: config.camera.map(|camera| CameraTable {
+                fx: camera.fx,
+                fy: camera.fy,
+                cx: camera.cx,
+                cy: camera.cy,
+            }),
+            features: FeaturesTable {
+                blur_radius: config.blur_radius,
+                fast_threshold: config.fast_threshold,
+                patch_size: config.patch_size,
+                num_pairs: config.num_pairs,
+                max_hamming_distance: config.max_hamming_distance,
+            },
+            essential: EssentialTable {
+                max_iterations: config.essential.max_iterations,
+                inlier_threshold: config.essential.inlier_threshold,
+                confidence: config.essential.confidence,
+                scoring,
+                sigma_max,
+                sampling: match config.essential.sampling {
+                    Sampling::Uniform => SamplingName::Uniform,
+                    Sampling::Prosac => SamplingName::Prosac,
+                },
+                local_optimization_iterations: config.essential.local_optimization_iterations,
+            },
+            refinement: RefinementTable {
+                max_iterations: config.refinement_iterations,
+                kernel,
+                kernel_threshold,
+            },
+        }
+    }
+}
+
+impl Default for ConfigFile {
+    fn default() -> Self {
+        Self::from(&SlamConfig::default())
+    }
+}
+
+// the tables default to their parts of the default configuration, so missing keys do too
+
+impl Default for FeaturesTable {
+    fn default() -> Self {
+        ConfigFile::default().features
+    }
+}
+
+impl Default for EssentialTable {
+    fn default() -> Self {
+        ConfigFile::default().essential
+    }
+}
+
+impl Default for RefinementTable {
+    fn default() -> Self {
+        ConfigFile::default().refinement
+    }
+}
+
+impl ConfigFile {
+    // the configuration with the defaults of what the file does not set, validated
+    fn into_config(self) -> Result<SlamConfig, ConfigError> {
+        let ConfigFile {
+            seed,
+            camera,
+            features,
+            essential,
+            refinement,
+        } = self;
+        let config = SlamConfig {
+            seed,
+            camera: camera.map(|c| Camera::new(c.fx, c.fy, c.cx, c.cy)),
+            blur_radius: features.blur_radius,
+            fast_threshold: features.fast_threshold,
+            patch_size: features.patch_size,
+            num_pairs: features.num_pairs,
+            max_hamming_distance: features.max_hamming_distance,
+            essential: RansacOptions {
+                max_iterations: essential.max_iterations,
+                inlier_threshold: essential.inlier_threshold,
+                confidence: essential.confidence,
+                scoring: match essential.scoring {
+                    ScoringName::Ransac => Scoring::Ransac,
+                    ScoringName::Msac => Scoring::Msac,
+                    ScoringName::Magsac => Scoring::Magsac {
+                        sigma_max: essential.sigma_max,
+                    },
+                },
+                sampling: match essential.sampling {
+                    SamplingName::Uniform => Sampling::Uniform,
+                    SamplingName::Prosac => Sampling::Prosac,
+                },
+                local_optimization_iterations: essential.local_optimization_iterations,
+            },
+            refinement_iterations: refinement.max_iterations,
+            refinement_kernel: match refinement.kernel {
+                KernelName::Quadratic => RobustKernel::Quadratic,
+                KernelName::Huber => RobustKernel::Huber(refinement.kernel_threshold),
+                KernelName::Cauchy => RobustKernel::Cauchy(refinement.kernel_threshold),
+                KernelName::Tukey => RobustKernel::Tukey(refinement.kernel_threshold),
+            },
+            ..SlamConfig::default()
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// Builds a validated `SlamConfig`, starting from the defaults.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct SlamConfigBuilder {
+    config: SlamConfig,
+}
+
+impl SlamConfigBuilder {
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.config.seed = seed;
+        self
+    }
+
+    pub fn camera(mut self, camera: Camera) -> Self {
+        self.config.camera = Some(camera);
+        self
+    }
+
+    /// Pixels.
+    pub fn blur_radius(mut self, blur_radius: f32) -> Self {
+        self.config.blur_radius = blur_radius;
+        self
+    }
+
+    /// Grey levels.
+    pub fn fast_threshold(mut self, fast_threshold: u8) -> Self {
+        self.config.fast_threshold = fast_threshold;
+        self
+    }
+
+    /// Pixels.
+    pub fn patch_size(mut self, patch_size: usize) -> Self {
+        self.config.patch_size = patch_size;
+        self
+    }
+
+    /// Bits per descriptor.
+    pub fn num_pairs(mut self, num_pairs: usize) -> Self {
+        self.config.num_pairs = num_pairs;
+        self
+    }
+
+    /// Bits.
+    pub fn max_hamming_distance(mut self, max_hamming_distance: usize) -> Self {
+        self.config.max_hamming_distance = max_hamming_distance;
+        self
+    }
+
+    /// `inlier_threshold` in pixels.
+    pub fn essential(mut self, essential: RansacOptions) -> Self {
+        self.config.essential = essential;
+        self
+    }
+
+    pub fn refinement_iterations(mut self, refinement_iterations: usize) -> Self {
+        self.config.refinement_iterations = refinement_iterations;
+        self
+    }
+
+    /// The kernel threshold in pixels.
+    pub fn refinement_kernel(mut self, refinement_kernel: RobustKernel) -> Self {
+        self.config.refinement_kernel = refinement_kernel;
+        self
+    }
+
+    pub fn tracking(mut self, tracking: TrackingOptions) -> Self {
+        self.config.tracking = tracking;
+        self
+    }
+
+    pub fn initializer(mut self, initializer: InitializerOptions) -> Self {
+        self.config.initializer = initializer;
+        self
+    }
+
+    pub fn relocalization(mut self, relocalization: RelocalizationOptions) -> Self {
+        self.config.relocalization = relocalization;
+        self
+    }
+
+    pub fn local_mapping(mut self, local_mapping: LocalMappingOptions) -> Self {
+        self.config.local_mapping = local_mapping;
+        self
+    }
+
+    pub fn loop_closing(mut self, loop_closing: LoopClosingOptions) -> Self {
+        self.config.loop_closing = loop_closing;
+        self
+    }
+
+    pub fn confident_inliers(mut self, confident_inliers: usize) -> Self {
+        self.config.confident_inliers = confident_inliers;
+        self
+    }
+
+    pub fn build(self) -> Result<SlamConfig, ConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_toml() {
+        let text = r#"
+            seed = 7   # a comment
+
+            [camera]
+            fx = 400.0
+            fy = 410
+            cx = 320.5
+            cy = 240.5
+
+            [features]
+            fast_threshold = 20
+            num_pairs = 256
+            max_hamming_distance = 100
+
+            [essential]
+            scoring = "magsac"
+            sigma_max = 1.5
+            inlier_threshold = 2.5
+
+            [refinement]
+            kernel = "cauchy"
+            kernel_threshold = 2.0
+        "#;
+        let config = SlamConfig::from_toml(text).unwrap();
+        let expected = SlamConfig::builder()
+            .seed(7)
+            .camera(Camera::new(400.0, 410.0, 320.5, 240.5))
+            .fast_threshold(20)
+            .num_pairs(256)
+            .max_hamming_distance(100)
+            .essential(RansacOptions {
+                scoring: Scoring::Magsac { sigma_max: 1.5 },
+                inlier_threshold: 2.5,
+                ..SlamConfig::default().essential
+            })
+            .refinement_kernel(RobustKernel::Cauchy(2.0))
+            .build()
+            .unwrap();
+        assert_eq!(config, expected);
+
+        // every key written is read back
+        assert_eq!(SlamConfig::from_toml(&config.to_toml()).unwrap(), config);
+        let default = SlamConfig::default();
+        assert_eq!(SlamConfig::from_toml(&default.to_toml()).unwrap(), default);
+        assert_eq!(SlamConfig::from_toml("").unwrap(), default);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |text: &str| SlamConfig::from_toml(text).unwrap_err();
+        let syntax = |text: &str| matches!(error(text), ConfigError::Toml(_));
+        assert!(syntax("seed = 1\nblur = 2"));
+        assert!(syntax("[features]\nfast_threshold = 300"));
+        assert!(syntax("[essential]\nscoring = \"lmeds\""));
+        assert!(syntax("[essential]\nscoring = msac"));
+        assert!(syntax("seed"));
+        assert!(syntax("[camera"));
+        assert!(syntax("[camera]\nfx = 500.0"));
+        assert!(error("seed = 1\nblur = 2").to_string().contains("blur"));
+        assert_eq!(
+            error("[essential]\nconfidence = 1.0").to_string(),
+            "essential.confidence must be between 0 and 1"
+        );
+        assert_eq!(
+            error("[features]\nnum_pairs = 256").to_string(),
+            "features.max_hamming_distance must not exceed features.num_pairs"
+        );
+
+        let built = SlamConfig::builder().blur_radius(0.0).build();
+        assert!(matches!(
+            built,
+            Err(ConfigError::Invalid {
+                key: "features.blur_radius",
+                ..
+            })
+        ));
+        assert!(SlamConfig::builder()
+            .refinement_kernel(RobustKernel::Huber(-1.0))
+            .build()
+            .is_err());
+        assert!(SlamConfig::builder().confident_inliers(0).build().is_err());
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn test_from_yaml() {
+        let text = "
+seed: 7
+features:
+  num_pairs: 256
+  max_hamming_distance: 100
+refinement:
+  kernel: tukey
+  kernel_threshold: 3.0
+";
+        let config = SlamConfig::from_yaml(text).unwrap();
+        let expected = SlamConfig::builder()
+            .seed(7)
+            .num_pairs(256)
+            .max_hamming_distance(100)
+            .refinement_kernel(RobustKernel::Tukey(3.0))
+            .build()
+            .unwrap();
+        assert_eq!(config, expected);
+        assert_eq!(SlamConfig::from_yaml(&config.to_yaml()).unwrap(), config);
+        assert!(matches!(
+            SlamConfig::from_yaml("blur: 2"),
+            Err(ConfigError::Yaml(_))
+        ));
+    }
+}
@@ -1,21 +1,26 @@
 use crate::{
     common::{Descriptor, KeyPoint},
+    error::XdofError,
+    image_view::{ImageView, Pixel},
     rand::Rand,
 };
 use std::iter;
 
+/// A pair of pixel offsets, relative to a keypoint, whose intensities are compared.
+pub type SamplePair = ((f32, f32), (f32, f32));
+
 pub fn compute_brief_descriptors_img(
     grey_blurred_img: &image::GrayImage,
     keypoints: &[KeyPoint],
-    sampling_pattern: &[((f32, f32), (f32, f32))],
+    sampling_pattern: &[SamplePair],
 ) -> Vec<Descriptor> {
-    compute_brief_descriptors(
+    let image = ImageView::new(
         grey_blurred_img.as_raw(),
-        grey_blurred_img.width(),
-        grey_blurred_img.height(),
-        keypoints,
-        sampling_pattern,
+        grey_blurred_img.width() as usize,
+        grey_blurred_img.height() as usize,
     )
+    .expect("a GrayImage holds its pixels");
+    compute_brief_descriptors_view(image, keypoints, sampling_pattern)
 }
 
 pub fn compute_brief_descriptors(
@@ -23,11 +28,26 @@ pub fn compute_brief_descriptors(
     width: u32,
     height: u32,
     keypoints: &[KeyPoint],
-    sampling_pattern: &[((f32, f32), (f32, f32))],
+    sampling_pattern: &[SamplePair],
+) -> Result<Vec<Descriptor>, XdofError> {
+    let image = ImageView::new(image, width as usize, height as usize)?;
+    Ok(compute_brief_descriptors_view(
+        image,
+        keypoints,
+        sampling_pattern,
+    ))
+}
+
+/// `compute_brief_descriptors` of any pixel type. The keypoints are relative to the corner of
+/// the view, and samples outside it are clamped to its border.
+pub fn compute_brief_descriptors_view<T: Pixel>(
+    image: ImageView<T>,
+    keypoints: &[KeyPoint],
+    sampling_pattern: &[SamplePair],
 ) -> Vec<Descriptor> {
     keypoints
         .iter()
-        .map(|kp| compute_descriptor(image, width, height, kp, sampling_pattern))
+        .map(|kp| compute_descriptor(image, kp, sampling_pattern))
         .collect()
 }
 
@@ -35,7 +55,7 @@ pub fn generate_sampling_pattern(
     rng: &mut Rand,
     patch_size: usize,
     num_pairs: usize,
-) -> Vec<((f32, f32), (f32, f32))> {
+) -> Vec<SamplePair> {
     iter::repeat_with(|| {
         let x1 = rng.gen_range(-(patch_size as f32 / 2.0)..=(patch_size as f32 / 2.0));
         let y1 = rng.gen_range(-(patch_size as f32 / 2.0)..=(patch_size as f32 / 2.0));
@@ -47,13 +67,12 @@ pub fn generate_sampling_pattern(
     .collect()
 }
 
-fn compute_descriptor(
-    image: &[u8],
-    width: u32,
-    height: u32,
+fn compute_descriptor<T: Pixel>(
+    image: ImageView<T>,
     keypoint: &KeyPoint,
-    sampling_pattern: &[((f32, f32), (f32, f32))],
+    sampling_pattern: &[SamplePair],
 ) -> Descriptor {
+    let (width, height) = (image.width() as u32, image.height() as u32);
     let mut descriptor = Vec::new();
     let mut bit_index = 0;
     let mut current_byte = 0u8;
@@ -63,24 +82,16 @@ fn compute_descriptor(
         let (x2_rotated, y2_rotated) = rotate_point(x2, y2, keypoint.orientation);
 
         let (x1_final, y1_final) = (
-            (keypoint.x as f32 + x1_rotated)
-                .min(width as f32 - 1.0)
-                .max(0.0) as u32,
-            (keypoint.y as f32 + y1_rotated)
-                .min(height as f32 - 1.0)
-                .max(0.0) as u32,
+            (keypoint.x + x1_rotated).min(width as f32 - 1.0).max(0.0) as u32,
+            (keypoint.y + y1_rotated).min(height as f32 - 1.0).max(0.0) as u32,
         );
         let (x2_final, y2_final) = (
-            (keypoint.x as f32 + x2_rotated)
-                .min(width as f32 - 1.0)
-                .max(0.0) as u32,
-            (keypoint.y as f32 + y2_rotated)
-                .min(height as f32 - 1.0)
-                .max(0.0) as u32,
+            (keypoint.x + x2_rotated).min(width as f32 - 1.0).max(0.0) as u32,
+            (keypoint.y + y2_rotated).min(height as f32 - 1.0).max(0.0) as u32,
         );
 
-        let intensity1 = image[(y1_final * width + x1_final) as usize];
-        let intensity2 = image[(y2_final * width + x2_final) as usize];
+        let intensity1 = image.get(x1_final as usize, y1_final as usize);
+        let intensity2 = image.get(x2_final as usize, y2_final as usize);
 
         if intensity1 > intensity2 {
             current_byte |= 1 << bit_index;
@@ -119,7 +130,7 @@ mod tests {
     #[test]
     fn test_compute_descriptor() {
         let image = vec![
-            1, 2, 3, //
+            1u8, 2, 3, //
             4, 5, 6, //
             7, 8, 9, //
         ];
@@ -143,8 +154,8 @@ mod tests {
         // and next three samples are 1 because they are greater than 5
         let expected_descriptor = Descriptor(vec![0b0011_1000]);
 
-        let actual_descriptor =
-            compute_descriptor(&image, width, height, &keypoint, &sampling_pattern);
+        let image = ImageView::new(&image, width, height).unwrap();
+        let actual_descriptor = compute_descriptor(image, &keypoint, &sampling_pattern);
 
         assert_eq!(expected_descriptor, actual_descriptor);
     }
@@ -208,9 +219,13 @@ mod tests {
                 },
             ],
             &sampling_pattern,
-        );
+        )
+        .unwrap();
 
         assert_eq!(expected_descriptors, actual_descriptors);
+        assert!(
+            compute_brief_descriptors(&image, width, height + 1, &[], &sampling_pattern).is_err()
+        );
 
         // now lets double the sampling to make sure descriptors can go over 8 bits
 
@@ -277,7 +292,8 @@ mod tests {
                 },
             ],
             &sampling_pattern,
-        );
+        )
+        .unwrap();
 
         assert_eq!(expected_descriptors, actual_descriptors);
     }
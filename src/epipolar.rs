@@ -0,0 +1,254 @@
+//! Error functions for a 3x3 epipolar matrix M (essential or fundamental) and a
+//! correspondence `x1 <-> x2`, where `x2^T * M * x1 = 0` for a perfect match.
+//!
+//! All of them return a distance in the units of the points, pixels for a fundamental matrix
+//! and pixel coordinates, or normalized image units for an essential matrix and points that
+//! went through `Camera::normalize`.
+//!
+//! See Hartley & Zisserman, Multiple View Geometry, section 11.4.3.
+
+use nalgebra::{DMatrix, Matrix3, Point2};
+
+use crate::common::{KeyPoint, PointMatch};
+use crate::ransac::ErrorMetric;
+
+/// Builds the linear system `A * m = 0` where `m` is M in row major order and each row of A
+/// encodes `x2^T * M * x1 = 0` for one correspondence. A is padded with zero rows to at least
+/// 9x9 so that the thin SVD always yields the full nullspace.
+pub(crate) fn epipolar_constraint_matrix(points: &[PointMatch]) -> DMatrix<f64> {
+    let mut a = DMatrix::<f64>::zeros(points.len().max(9), 9);
+    for (i, (p1, p2)) in points.iter().enumerate() {
+        a[(i, 0)] = p1.x * p2.x;
+        a[(i, 1)] = p1.y * p2.x;
+        a[(i, 2)] = p2.x;
+        a[(i, 3)] = p1.x * p2.y;
+        a[(i, 4)] = p1.y * p2.y;
+        a[(i, 5)] = p2.y;
+        a[(i, 6)] = p1.x;
+        a[(i, 7)] = p1.y;
+        a[(i, 8)] = 1.0;
+    }
+    a
+}
+
+/// `epipolar_constraint_matrix` with each row scaled by the square root of its weight, so that
+/// the least squares solution minimizes the weighted sum of squared algebraic errors.
+pub(crate) fn weighted_epipolar_constraint_matrix(
+    points: &[PointMatch],
+    weights: &[f64],
+) -> DMatrix<f64> {
+    let mut a = epipolar_constraint_matrix(points);
+    for (i, weight) in weights.iter().enumerate().take(points.len()) {
+        a.row_mut(i).scale_mut(weight.max(0.0).sqrt());
+    }
+    a
+}
+
+/// Replaces the smallest singular value of M by zero, the closest rank 2 matrix in the
+/// Frobenius norm.
+pub fn enforce_rank_two(m: &Matrix3<f64>) -> Matrix3<f64> {
+    let svd = m.svd(true, true);
+    let mut singular_values = svd.singular_values;
+    singular_values[2] = 0.0;
+    svd.u.unwrap() * Matrix3::from_diagonal(&singular_values) * svd.v_t.unwrap()
+}
+
+/// The algebraic residual `|x2^T * M * x1|`. It is cheap but has no geometric meaning and
+/// depends on the scale of M.
+pub fn algebraic_error(m: &Matrix3<f64>, x1: &Point2<f64>, x2: &Point2<f64>) -> f64 {
+    (x2.to_homogeneous().transpose() * m * x1.to_homogeneous())[0].abs()
+}
+
+/// The Sampson distance, a first order approximation of the reprojection error (the distance
+/// the two points have to move to satisfy the epipolar constraint). Infinite for a degenerate
+/// match, both points at the epipoles, so RANSAC never counts it as an inlier.
+pub fn sampson_distance(m: &Matrix3<f64>, x1: &Point2<f64>, x2: &Point2<f64>) -> f64 {
+    match sampson_terms(m, x1, x2) {
+        Some((numerator, denominator)) => numerator.abs() / denominator.sqrt(),
+        None => f64::INFINITY,
+    }
+}
+
+/// The signed Sampson distance, for least squares where the sign of the residual matters.
+/// Zero for a degenerate match, which then has no say in the fit.
+pub fn sampson_residual(m: &Matrix3<f64>, x1: &Point2<f64>, x2: &Point2<f64>) -> f64 {
+    match sampson_terms(m, x1, x2) {
+        Some((numerator, denominator)) => numerator / denominator.sqrt(),
+        None => 0.0,
+    }
+}
+
+// the epipolar constraint and the squared norm of its gradient, or `None` when the gradient
+// vanishes
+fn sampson_terms(m: &Matrix3<f64>, x1: &Point2<f64>, x2: &Point2<f64>) -> Option<(f64, f64)> {
+    let x1 = x1.to_homogeneous();
+    let x2 = x2.to_homogeneous();
+    let l2 = m * x1;
+    let l1 = m.transpose() * x2;
+    let numerator = x2.dot(&l2);
+    let denominator = l2[0].powi(2) + l2[1].powi(2) + l1[0].powi(2) + l1[1].powi(2);
+    (denominator > f64::EPSILON).then_some((numerator, denominator))
+}
+
+/// The symmetric epipolar distance, the root of the summed squared distances from each point
+/// to the epipolar line of the other.
+pub fn symmetric_epipolar_distance(m: &Matrix3<f64>, x1: &Point2<f64>, x2: &Point2<f64>) -> f64 {
+    let x1 = x1.to_homogeneous();
+    let x2 = x2.to_homogeneous();
+    let l2 = m * x1;
+    let l1 = m.transpose() * x2;
+    let numerator_sq = x2.dot(&l2).powi(2);
+    let norm1 = l1[0].powi(2) + l1[1].powi(2);
+    let norm2 = l2[0].powi(2) + l2[1].powi(2);
+    if norm1 <= f64::EPSILON || norm2 <= f64::EPSILON {
+        return f64::INFINITY;
+    }
+    (numerator_sq / norm1 + numerator_sq / norm2).sqrt()
+}
+
+/// `sampson_distance` for every keypoint match.
+pub fn sampson_distances(m: &Matrix3<f64>, matches: &[(KeyPoint, KeyPoint)]) -> Vec<f64> {
+    matches
+        .iter()
+        .map(|(p1, p2)| sampson_distance(m, &p1.point(), &p2.point()))
+        .collect()
+}
+
+/// `symmetric_epipolar_distance` for every keypoint match.
+pub fn symmetric_epipolar_distances(
+    m: &Matrix3<f64>,
+    matches: &[(KeyPoint, KeyPoint)],
+) -> Vec<f64> {
+    matches
+        .iter()
+        .map(|(p1, p2)| symmetric_epipolar_distance(m, &p1.point(), &p2.point()))
+        .collect()
+}
+
+/// `algebraic_error` for every keypoint match.
+pub fn algebraic_errors(m: &Matrix3<f64>, matches: &[(KeyPoint, KeyPoint)]) -> Vec<f64> {
+    matches
+        .iter()
+        .map(|(p1, p2)| algebraic_error(m, &p1.point(), &p2.point()))
+        .collect()
+}
+
+/// Scores matches with `sampson_distance`.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub struct SampsonDistance;
+
+impl ErrorMetric<Matrix3<f64>, (KeyPoint, KeyPoint)> for SampsonDistance {
+    fn error(&self, m: &Matrix3<f64>, (p1, p2): &(KeyPoint, KeyPoint)) -> f64 {
+        sampson_distance(m, &p1.point(), &p2.point())
+    }
+}
+
+impl ErrorMetric<Matrix3<f64>, PointMatch> for SampsonDistance {
+    fn error(&self, m: &Matrix3<f64>, (x1, x2): &PointMatch) -> f64 {
+        sampson_distance(m, x1, x2)
+    }
+}
+
+/// Scores matches with `symmetric_epipolar_distance`.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub struct SymmetricEpipolarDistance;
+
+impl ErrorMetric<Matrix3<f64>, (KeyPoint, KeyPoint)> for SymmetricEpipolarDistance {
+    fn error(&self, m: &Matrix3<f64>, (p1, p2): &(KeyPoint, KeyPoint)) -> f64 {
+        symmetric_epipolar_distance(m, &p1.point(), &p2.point())
+    }
+}
+
+impl ErrorMetric<Matrix3<f64>, PointMatch> for SymmetricEpipolarDistance {
+    fn error(&self, m: &Matrix3<f64>, (x1, x2): &PointMatch) -> f64 {
+        symmetric_epipolar_distance(m, x1, x2)
+    }
+}
+
+/// Scores matches with `algebraic_error`.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub struct AlgebraicError;
+
+impl ErrorMetric<Matrix3<f64>, (KeyPoint, KeyPoint)> for AlgebraicError {
+    fn error(&self, m: &Matrix3<f64>, (p1, p2): &(KeyPoint, KeyPoint)) -> f64 {
+        algebraic_error(m, &p1.point(), &p2.point())
+    }
+}
+
+impl ErrorMetric<Matrix3<f64>, PointMatch> for AlgebraicError {
+    fn error(&self, m: &Matrix3<f64>, (x1, x2): &PointMatch) -> f64 {
+        algebraic_error(m, x1, x2)
+    }
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a camera translating along x, the epipolar lines are the rows y = const
+    fn sideways() -> Matrix3<f64> {
+        Matrix3::new(0.0, 0.0, 0.0, 0.0, 0.0, -1.0, 0.0, 1.0, 0.0)
+    }
+
+    #[test]
+    fn test_perfect_match_has_no_error() {
+        let x1 = Point2::new(10.0, 20.0);
+        let x2 = Point2::new(3.0, 20.0);
+        assert_eq!(algebraic_error(&sideways(), &x1, &x2), 0.0);
+        assert_eq!(sampson_distance(&sideways(), &x1, &x2), 0.0);
+        assert_eq!(symmetric_epipolar_distance(&sideways(), &x1, &x2), 0.0);
+    }
+
+    #[test]
+    fn test_errors_are_geometric() {
+        // x2 is 2 pixels off its epipolar line, and so is x1 from its own
+        let x1 = Point2::new(10.0, 20.0);
+        let x2 = Point2::new(3.0, 22.0);
+        let m = sideways();
+        assert_eq!(algebraic_error(&m, &x1, &x2), 2.0);
+        // moving each point 1 pixel towards the other fixes the match
+        assert!((sampson_distance(&m, &x1, &x2) - 2.0_f64.sqrt()).abs() < 1e-12);
+        assert!((symmetric_epipolar_distance(&m, &x1, &x2) - 8.0_f64.sqrt()).abs() < 1e-12);
+
+        // the algebraic error scales with M, the geometric ones do not
+        let scaled = m * 10.0;
+        assert_eq!(algebraic_error(&scaled, &x1, &x2), 20.0);
+        assert!((sampson_distance(&scaled, &x1, &x2) - 2.0_f64.sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_degenerate_match() {
+        let x = Point2::new(10.0, 20.0);
+        let m = Matrix3::zeros();
+        assert_eq!(sampson_distance(&m, &x, &x), f64::INFINITY);
+        assert_eq!(sampson_residual(&m, &x, &x), 0.0);
+    }
+
+    #[test]
+    fn test_batch_errors_and_metrics() {
+        let matches = [
+            (
+                KeyPoint::new(10.0, 20.0, 0.0),
+                KeyPoint::new(3.0, 20.0, 0.0),
+            ),
+            (
+                KeyPoint::new(10.0, 20.0, 0.0),
+                KeyPoint::new(3.0, 22.0, 0.0),
+            ),
+        ];
+        let m = sideways();
+        let sampson = sampson_distances(&m, &matches);
+        assert_eq!(sampson.len(), 2);
+        assert_eq!(sampson[0], 0.0);
+        assert_eq!(SampsonDistance.error(&m, &matches[1]), sampson[1]);
+        assert_eq!(
+            symmetric_epipolar_distances(&m, &matches)[1],
+            SymmetricEpipolarDistance.error(&m, &matches[1])
+        );
+        assert_eq!(algebraic_errors(&m, &matches), vec![0.0, 2.0]);
+    }
+}
@@ -0,0 +1,107 @@
+//! The errors of feature extraction, the two view pose pipeline, `Slam::calculate_pose`, and
+//! `SlamSequence::process_frame`.
+
+use std::fmt;
+
+use crate::extractor::BriefSettings;
+use crate::map::MapError;
+
+/// Why no pose could be estimated between two images.
+#[derive(PartialEq, Debug, Clone)]
+pub enum XdofError {
+    /// The two images differ in size, (width, height) of each.
+    ImageSizeMismatch {
+        a: (usize, usize),
+        b: (usize, usize),
+    },
+    /// The pixel buffer of an image does not hold width x height bytes.
+    InvalidImage {
+        expected: usize,
+        len: usize,
+    },
+    /// An image (0 for A, 1 for B) has fewer keypoints than a pose needs.
+    TooFewKeyPoints {
+        image: usize,
+        found: usize,
+        required: usize,
+    },
+    TooFewMatches {
+        found: usize,
+        required: usize,
+    },
+    /// No essential matrix explains the matches, or none puts the points in front of both
+    /// cameras, e.g. without translation or with all the points on a line.
+    DegenerateGeometry,
+    /// A map given to `SlamSequence::with_map` has descriptors made with other BRIEF settings
+    /// than the configured extractor, so they cannot be matched.
+    BriefMismatch {
+        map: BriefSettings,
+        extractor: BriefSettings,
+    },
+    /// A frame from a custom extractor has a different number of keypoints and descriptors.
+    DescriptorCountMismatch {
+        key_points: usize,
+        descriptors: usize,
+    },
+    /// The map of a sequence is inconsistent, e.g. tracking refers to a keyframe it lacks.
+    Map(MapError),
+}
+
+impl fmt::Display for XdofError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = |image: &usize| if *image == 0 { "A" } else { "B" };
+        match self {
+            XdofError::ImageSizeMismatch { a, b } => write!(
+                f,
+                "image A is {}x{} but image B is {}x{}",
+                a.0, a.1, b.0, b.1
+            ),
+            XdofError::InvalidImage { expected, len } => {
+                write!(f, "the image holds {} bytes instead of {}", len, expected)
+            }
+            XdofError::TooFewKeyPoints {
+                image,
+                found,
+                required,
+            } => write!(
+                f,
+                "image {} has {} keypoints, at least {} are needed",
+                name(image),
+                found,
+                required
+            ),
+            XdofError::TooFewMatches { found, required } => {
+                write!(f, "{} matches, at least {} are needed", found, required)
+            }
+            XdofError::DegenerateGeometry => write!(f, "the matches fit no camera motion"),
+            XdofError::BriefMismatch { map, extractor } => write!(
+                f,
+                "the map has BRIEF seed {}, patch size {} and {} pairs but the extractor has \
+                 seed {}, patch size {} and {} pairs",
+                map.seed,
+                map.patch_size,
+                map.num_pairs,
+                extractor.seed,
+                extractor.patch_size,
+                extractor.num_pairs
+            ),
+            XdofError::DescriptorCountMismatch {
+                key_points,
+                descriptors,
+            } => write!(
+                f,
+                "the frame has {} keypoints but {} descriptors",
+                key_points, descriptors
+            ),
+            XdofError::Map(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for XdofError {}
+
+impl From<MapError> for XdofError {
+    fn from(error: MapError) -> Self {
+        XdofError::Map(error)
+    }
+}
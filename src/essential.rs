@@ -1,115 +1,302 @@
-use nalgebra::{DMatrix, Matrix3, Vector3}; //, SVD};
+//! Essential matrix estimation for calibrated cameras.
+//!
+//! The essential matrix relates normalized image coordinates (pixels with the intrinsics
+//! removed, see `Camera::normalize`) of matching points, `x2^T * E * x1 = 0`. Unlike the
+//! fundamental matrix it encodes only the relative pose, `E = [t]x * R`, so its two non zero
+//! singular values are equal. For uncalibrated imagery see the `fundamental` module.
 
+use nalgebra::{Matrix3, Point3, Vector3};
+
+use crate::camera::Camera;
 use crate::common::*;
+use crate::epipolar::{weighted_epipolar_constraint_matrix, SampsonDistance};
+use crate::lie::SE3;
 use crate::rand::*;
+use crate::ransac::*;
+use crate::triangulation::{depth, projection_matrix, triangulate};
 
-fn keypoints_to_essential(keypoints: &[(KeyPoint, KeyPoint)]) -> Matrix3<f64> {
-    // Construct a matrix A from the keypoints
-    let mut a = DMatrix::<f64>::zeros(keypoints.len(), 9);
-    for (i, &(ref p1, ref p2)) in keypoints.iter().enumerate() {
-        a[(i, 0)] = p1.x as f64 * p2.x as f64;
-        a[(i, 1)] = p1.y as f64 * p2.x as f64;
-        a[(i, 2)] = p2.x as f64;
-        a[(i, 3)] = p1.x as f64 * p2.y as f64;
-        a[(i, 4)] = p1.y as f64 * p2.y as f64;
-        a[(i, 5)] = p2.y as f64;
-        a[(i, 6)] = p1.x as f64;
-        a[(i, 7)] = p1.y as f64;
-        a[(i, 8)] = 1.0;
-    }
+fn points_to_essential(points: &[PointMatch], weights: &[f64]) -> Matrix3<f64> {
+    // Construct a matrix A from the points so that each row encodes x2^T * E * x1 = 0
+    let a = weighted_epipolar_constraint_matrix(points, weights);
 
     // Compute the singular value decomposition of A
-    let svd = a.svd(true, true);
+    let svd = a.svd(false, true);
 
     // Extract the singular values and vectors
-    let v = svd.v_t.unwrap();
+    let v_t = svd.v_t.unwrap();
 
-    // Extract the nullspace of A (i.e., the last column of V)
-    let e_vec = v.column(8);
+    // Extract the nullspace of A (i.e., the right singular vector of the smallest singular
+    // value, which nalgebra sorts last)
+    let e_vec = v_t.row(8);
 
     // Reshape the nullspace vector into a 3x3 matrix
     let essential_matrix = Matrix3::from_row_slice(&[
         e_vec[0], e_vec[1], e_vec[2], e_vec[3], e_vec[4], e_vec[5], e_vec[6], e_vec[7], e_vec[8],
     ]);
 
-    essential_matrix
+    project_to_essential(&essential_matrix)
+}
+
+/// The closest essential matrix to `m`, i.e. with singular values (1, 1, 0).
+pub fn project_to_essential(m: &Matrix3<f64>) -> Matrix3<f64> {
+    let svd = m.svd(true, true);
+    svd.u.unwrap() * Matrix3::from_diagonal(&Vector3::new(1.0, 1.0, 0.0)) * svd.v_t.unwrap()
 }
 
-fn choose_multiple_keypoints(
-    key_points: &Vec<(KeyPoint, KeyPoint)>,
-    num_keypoints: usize,
-    random: &mut Rand,
-) -> Vec<(KeyPoint, KeyPoint)> {
-    key_points.choose_multiple(random, num_keypoints)
+/// `E = [t]x * R` for the motion `X2 = pose * X1 = R * X1 + t`.
+pub fn essential_from_pose(pose: &SE3) -> Matrix3<f64> {
+    pose.translation.cross_matrix() * pose.rotation_matrix()
+}
+
+/// The 8 point algorithm on normalized image coordinates as a RANSAC minimal solver.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub struct EightPointSolver;
+
+impl MinimalSolver<PointMatch> for EightPointSolver {
+    type Model = Matrix3<f64>;
+
+    fn sample_size(&self) -> usize {
+        8
+    }
+
+    fn solve(&self, sample: &[PointMatch]) -> Vec<Matrix3<f64>> {
+        vec![points_to_essential(sample, &vec![1.0; sample.len()])]
+    }
+
+    fn solve_nonminimal(&self, data: &[PointMatch]) -> Vec<Matrix3<f64>> {
+        vec![points_to_essential(data, &vec![1.0; data.len()])]
+    }
+
+    fn solve_weighted(&self, data: &[PointMatch], weights: &[f64]) -> Vec<Matrix3<f64>> {
+        vec![points_to_essential(data, weights)]
+    }
 }
 
+/// Estimates E from pixel matches of two images taken with `camera`, `None` with fewer than 8
+/// matches. `options.inlier_threshold` is a Sampson distance in pixels.
 pub fn estimate_essential_ransac(
-    key_points: &Vec<(KeyPoint, KeyPoint)>,
-    num_iterations: usize,
-    inlier_threshold: f64,
+    key_points: &[(KeyPoint, KeyPoint)],
+    camera: &Camera,
+    options: &RansacOptions,
     rnd: &mut Rand,
-) -> Option<Matrix3<f64>> {
+) -> Option<RansacResult<Matrix3<f64>>> {
     if key_points.len() < 8 {
-        println!(
-            "not enough keypoints '{}' to estimate essential.",
-            key_points.len()
-        );
         return None;
     }
-    let mut best_essential_matrix = None;
-    let mut best_num_inliers = 0;
-
-    for _ in 0..num_iterations {
-        // Choose a random subset of keypoints
-        let subset = choose_multiple_keypoints(key_points, 9, rnd);
-
-        // Compute the essential matrix using the 8-point algorithm
-        let essential_matrix = keypoints_to_essential(&subset);
-
-        // Compute the number of inliers that are consistent with the essential matrix
-        let mut num_inliers = 0;
-        for &(ref p1, ref p2) in key_points.iter() {
-            // Compute the epipolar lines corresponding to each keypoint
-            let l1 = essential_matrix * Vector3::new(p2.x as f64, p2.y as f64, 1.0);
-            let l2 = essential_matrix.transpose() * Vector3::new(p1.x as f64, p1.y as f64, 1.0);
-
-            // Compute the reprojection error for each key
-            let error1 = (l1.dot(&Vector3::new(p1.x as f64, p1.y as f64, 1.0))).abs()
-                / (l1[0].powi(2) + l1[1].powi(2)).sqrt();
-            let error2 = (l2.dot(&Vector3::new(p2.x as f64, p2.y as f64, 1.0))).abs()
-                / (l2[0].powi(2) + l2[1].powi(2)).sqrt();
-
-            // If the sum of the errors is below the inlier threshold, count this as an inlier
-            if error1 + error2 < inlier_threshold {
-                num_inliers += 1;
-            }
-        }
 
-        // If this solution has more inliers than any previous solution, update the best estimate
-        if num_inliers > best_num_inliers {
-            best_essential_matrix = Some(essential_matrix);
-            best_num_inliers = num_inliers;
-        }
-    }
+    // the errors are measured on the normalized image plane, so scale the pixel thresholds
+    let options = options.scaled(2.0 / (camera.fx + camera.fy));
 
-    best_essential_matrix
+    ransac(
+        &camera.normalize_matches(key_points),
+        &EightPointSolver,
+        &SampsonDistance,
+        &options,
+        rnd,
+    )
 }
 
-pub fn decompose_essential_matrix(essential: Matrix3<f64>) -> (Matrix3<f64>, Vector3<f64>) {
-    // Compute the singular value decomposition of the essential matrix
+/// The four motions consistent with an essential matrix. Only one of them puts the scene in
+/// front of both cameras, see `recover_pose`. The translation is unit length.
+pub fn pose_candidates(essential: &Matrix3<f64>) -> [SE3; 4] {
     let svd = essential.svd(true, true);
+    let mut u = svd.u.unwrap();
+    let mut v_t = svd.v_t.unwrap();
 
-    // Extract the singular values and vectors
-    let u = svd.u.unwrap();
-    let v = svd.v_t.unwrap();
-    let _s = svd.singular_values;
+    // make sure we build proper rotations (det = +1), E is only defined up to sign anyway
+    if u.determinant() < 0.0 {
+        u = -u;
+    }
+    if v_t.determinant() < 0.0 {
+        v_t = -v_t;
+    }
 
-    // Compute the rotation matrix
     let w = Matrix3::new(0.0, -1.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0);
-    let rotation = u * w * v.transpose();
+    let r1 = u * w * v_t;
+    let r2 = u * w.transpose() * v_t;
+    let t: Vector3<f64> = u.column(2).into();
+
+    [
+        SE3::from_parts(&r1, &t),
+        SE3::from_parts(&r1, &-t),
+        SE3::from_parts(&r2, &t),
+        SE3::from_parts(&r2, &-t),
+    ]
+}
 
-    // Compute the translation vector
-    let translation: Vector3<f64> = u.column(2).into();
+/// The motion recovered from an essential matrix and the points it triangulates.
+#[derive(PartialEq, Debug, Clone)]
+pub struct RecoveredPose {
+    /// `X2 = pose * X1`, with a unit translation.
+    pub pose: SE3,
+    /// The triangulated point of each match in camera 1 coordinates, `None` where it is at
+    /// infinity or behind either camera.
+    pub points: Vec<Option<Point3<f64>>>,
+}
+
+impl RecoveredPose {
+    pub fn num_points(&self) -> usize {
+        self.points.iter().filter(|p| p.is_some()).count()
+    }
+}
 
-    (rotation, translation)
+/// Picks the candidate motion of `essential` that puts the most of the (normalized) matches in
+/// front of both cameras, the cheirality check. Returns `None` if no match ends up in front.
+pub fn recover_pose(essential: &Matrix3<f64>, points: &[PointMatch]) -> Option<RecoveredPose> {
+    let p1 = projection_matrix(&SE3::identity());
+
+    pose_candidates(essential)
+        .iter()
+        .map(|pose| {
+            let p2 = projection_matrix(pose);
+            let triangulated = points
+                .iter()
+                .map(|(x1, x2)| {
+                    triangulate(&p1, x1, &p2, x2)
+                        .filter(|point| depth(&p1, point) > 0.0 && depth(&p2, point) > 0.0)
+                })
+                .collect();
+            RecoveredPose {
+                pose: *pose,
+                points: triangulated,
+            }
+        })
+        .max_by_key(|pose| pose.num_points())
+        .filter(|pose| pose.num_points() > 0)
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_scene;
+    use nalgebra::Point2;
+
+    #[test]
+    fn test_eight_point_solver() {
+        let camera = test_scene::camera();
+        let pose = test_scene::motion();
+        let matches = test_scene::matches(&camera, &test_scene::points(30), &pose);
+        let points = camera.normalize_matches(&matches);
+
+        let essential = EightPointSolver.solve(&points[..8])[0];
+        for p in &points {
+            assert!(SampsonDistance.error(&essential, p) < 1e-5);
+        }
+
+        // equal up to scale and sign with the true essential matrix
+        let expected = essential_from_pose(&pose).normalize();
+        let estimated = essential.normalize();
+        let difference = (estimated - expected)
+            .norm()
+            .min((estimated + expected).norm());
+        assert!(difference < 1e-4, "{}", difference);
+    }
+
+    #[test]
+    fn test_estimate_essential_ransac_inliers() {
+        let camera = test_scene::camera();
+        let pose = test_scene::motion();
+        let mut matches = test_scene::matches(&camera, &test_scene::points(30), &pose);
+        matches.push((
+            KeyPoint::new(10.0, 10.0, 0.0),
+            KeyPoint::new(60.0, 90.0, 0.0),
+        ));
+        matches.push((
+            KeyPoint::new(150.0, 20.0, 0.0),
+            KeyPoint::new(20.0, 140.0, 0.0),
+        ));
+
+        let options = RansacOptions {
+            inlier_threshold: 1.0,
+            ..Default::default()
+        };
+        let mut rnd = Rand::new_with_seed(2523523);
+        let result = estimate_essential_ransac(&matches, &camera, &options, &mut rnd).unwrap();
+
+        assert_eq!(result.num_inliers(), 30);
+        assert!(!result.inliers[30]);
+        assert!(!result.inliers[31]);
+
+        let options = RansacOptions {
+            inlier_threshold: 1.0,
+            scoring: Scoring::Msac,
+            local_optimization_iterations: 10,
+            ..Default::default()
+        };
+        let result = estimate_essential_ransac(&matches, &camera, &options, &mut rnd).unwrap();
+        assert_eq!(result.num_inliers(), 30);
+
+        // MAGSAC ignores the threshold, sigma_max is in pixels like it
+        let options = RansacOptions {
+            inlier_threshold: 1e-9,
+            scoring: Scoring::Magsac { sigma_max: 1.0 },
+            ..Default::default()
+        };
+        let result = estimate_essential_ransac(&matches, &camera, &options, &mut rnd).unwrap();
+        assert_eq!(result.num_inliers(), 30);
+        let expected = essential_from_pose(&pose).normalize();
+        let estimated = result.model.normalize();
+        let difference = (estimated - expected)
+            .norm()
+            .min((estimated + expected).norm());
+        assert!(difference < 1e-4, "{}", difference);
+    }
+
+    #[test]
+    fn test_eight_point_solver_weighted() {
+        let camera = test_scene::camera();
+        let pose = test_scene::motion();
+        let mut points = camera.normalize_matches(&test_scene::matches(
+            &camera,
+            &test_scene::points(12),
+            &pose,
+        ));
+        // a wild match with no weight does not disturb the fit
+        points.push((Point2::new(0.3, -0.2), Point2::new(-0.4, 0.1)));
+        let mut weights = vec![1.0; 12];
+        weights.push(0.0);
+
+        let expected = essential_from_pose(&pose).normalize();
+        let estimated = EightPointSolver.solve_weighted(&points, &weights)[0].normalize();
+        let difference = (estimated - expected)
+            .norm()
+            .min((estimated + expected).norm());
+        assert!(difference < 1e-4, "{}", difference);
+    }
+
+    #[test]
+    fn test_recover_pose() {
+        let camera = test_scene::camera();
+        let pose = test_scene::motion();
+        let points = test_scene::points(20);
+        let matches = camera.normalize_matches(&test_scene::matches(&camera, &points, &pose));
+
+        // E is only known up to sign
+        for essential in [essential_from_pose(&pose), -essential_from_pose(&pose)] {
+            let recovered = recover_pose(&essential, &matches).unwrap();
+            let rotation_error = (recovered.pose.rotation.inverse() * pose.rotation).angle();
+            assert!(rotation_error < 1e-9);
+            assert!((recovered.pose.translation - pose.translation.normalize()).norm() < 1e-9);
+            assert_eq!(recovered.num_points(), 20);
+
+            // the points come back scaled by the unknown baseline
+            let scale = 1.0 / pose.translation.norm();
+            for (recovered, point) in recovered.points.iter().zip(&points) {
+                assert!((recovered.unwrap() - point * scale).norm() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn test_estimate_essential_ransac_not_enough_keypoints() {
+        let camera = test_scene::camera();
+        let matches = test_scene::matches(&camera, &test_scene::points(7), &test_scene::motion());
+        let mut rnd = Rand::new_with_seed(2523523);
+        let options = RansacOptions::default();
+        assert!(estimate_essential_ransac(&matches, &camera, &options, &mut rnd).is_none());
+    }
 }
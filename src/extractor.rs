@@ -0,0 +1,277 @@
+//! Feature extraction split into its steps, so each can be swapped on its own:
+//!
+//! * a [`Detector`] finds corners in a greyscale image,
+//! * an [`OrientationEstimator`] gives each corner an orientation, making it a [`KeyPoint`],
+//! * a [`DescriptorExtractor`] describes the patch around each keypoint.
+//!
+//! An [`ExtractionPipeline`] blurs the image and chains the three into a [`FeatureExtractor`],
+//! by default FAST, intensity centroid and rotated BRIEF.
+
+use crate::common::{Descriptor, Features, KeyPoint};
+use crate::descriptors::{self, SamplePair};
+use crate::fast_detect;
+use crate::image_impl;
+use crate::image_view::ImageView;
+use crate::rand::Rand;
+
+/// Finds the corners of a greyscale image, as (x, y) pixels from the corner of the view.
+pub trait Detector: Send {
+    fn detect(&self, image: ImageView<u8>) -> Vec<(usize, usize)>;
+}
+
+/// Turns corners into keypoints by estimating their orientations.
+pub trait OrientationEstimator: Send {
+    fn orient(&self, image: ImageView<u8>, corners: &[(usize, usize)]) -> Vec<KeyPoint>;
+}
+
+/// Describes keypoints by the patches around them.
+pub trait DescriptorExtractor: Send {
+    /// One descriptor per keypoint, in the same order.
+    fn describe(&self, image: ImageView<u8>, key_points: &[KeyPoint]) -> Vec<Descriptor>;
+}
+
+/// Extracts the keypoints and descriptors of a greyscale image in one go.
+pub trait FeatureExtractor: Send {
+    fn extract(&self, image: ImageView<u8>) -> Features;
+}
+
+/// Any `Fn(ImageView<u8>) -> Features` works as an extractor.
+impl<F> FeatureExtractor for F
+where
+    F: Fn(ImageView<u8>) -> Features + Send,
+{
+    fn extract(&self, image: ImageView<u8>) -> Features {
+        self(image)
+    }
+}
+
+/// How features are extracted: Gaussian blur, FAST corners, intensity centroid orientations and
+/// rotated BRIEF descriptors.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct ExtractorConfig {
+    /// The standard deviation of the Gaussian blur before detection, pixels.
+    pub blur_radius: f32,
+    /// How much brighter or darker than the center the FAST circle must be, grey levels.
+    pub fast_threshold: u8,
+    /// The side of the square BRIEF samples in, pixels.
+    pub patch_size: usize,
+    /// The number of BRIEF intensity comparisons, bits per descriptor.
+    pub num_pairs: usize,
+    /// Seeds the BRIEF sampling pattern. Descriptors only match those made with the same seed,
+    /// patch size and number of pairs.
+    pub seed: u64,
+}
+
+impl Default for ExtractorConfig {
+    fn default() -> Self {
+        Self {
+            blur_radius: 3.0,
+            fast_threshold: 30,
+            patch_size: 100,
+            num_pairs: 500,
+            seed: 2523523,
+        }
+    }
+}
+
+/// What decides the BRIEF sampling pattern, so whether two descriptors can be compared. Stored
+/// with saved maps.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct BriefSettings {
+    pub seed: u64,
+    pub patch_size: usize,
+    pub num_pairs: usize,
+}
+
+impl ExtractorConfig {
+    pub fn brief(&self) -> BriefSettings {
+        BriefSettings {
+            seed: self.seed,
+            patch_size: self.patch_size,
+            num_pairs: self.num_pairs,
+        }
+    }
+
+    pub fn sampling_pattern(&self) -> Vec<SamplePair> {
+        descriptors::generate_sampling_pattern(
+            &mut Rand::new_with_seed(self.seed),
+            self.patch_size,
+            self.num_pairs,
+        )
+    }
+}
+
+/// The FAST-9 segment test on a 16 pixel circle.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct FastDetector {
+    pub threshold: u8,
+}
+
+impl Detector for FastDetector {
+    fn detect(&self, image: ImageView<u8>) -> Vec<(usize, usize)> {
+        fast_detect::fast_keypoints_view(image, self.threshold)
+    }
+}
+
+/// Orients a corner from its center towards the intensity centroid of the circle around it.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub struct IntensityCentroid;
+
+impl OrientationEstimator for IntensityCentroid {
+    fn orient(&self, image: ImageView<u8>, corners: &[(usize, usize)]) -> Vec<KeyPoint> {
+        fast_detect::compute_orientations_view(image, corners)
+    }
+}
+
+/// Binary descriptors of intensity comparisons, rotated with the keypoint.
+#[derive(PartialEq, Debug, Clone)]
+pub struct BriefExtractor {
+    pattern: Vec<SamplePair>,
+}
+
+impl BriefExtractor {
+    pub fn new(pattern: Vec<SamplePair>) -> Self {
+        Self { pattern }
+    }
+
+    pub fn pattern(&self) -> &[SamplePair] {
+        &self.pattern
+    }
+}
+
+impl DescriptorExtractor for BriefExtractor {
+    fn describe(&self, image: ImageView<u8>, key_points: &[KeyPoint]) -> Vec<Descriptor> {
+        descriptors::compute_brief_descriptors_view(image, key_points, &self.pattern)
+    }
+}
+
+/// Blurs the image, then detects, orients and describes.
+pub struct ExtractionPipeline {
+    /// The standard deviation of the Gaussian blur, pixels. Zero skips the blur.
+    pub blur_radius: f32,
+    detector: Box<dyn Detector>,
+    orientation: Box<dyn OrientationEstimator>,
+    descriptor: Box<dyn DescriptorExtractor>,
+}
+
+impl ExtractionPipeline {
+    pub fn new(
+        blur_radius: f32,
+        detector: impl Detector + 'static,
+        orientation: impl OrientationEstimator + 'static,
+        descriptor: impl DescriptorExtractor + 'static,
+    ) -> Self {
+        Self {
+            blur_radius,
+            detector: Box::new(detector),
+            orientation: Box::new(orientation),
+            descriptor: Box::new(descriptor),
+        }
+    }
+
+    pub fn with_detector(mut self, detector: impl Detector + 'static) -> Self {
+        self.detector = Box::new(detector);
+        self
+    }
+
+    pub fn with_orientation(mut self, orientation: impl OrientationEstimator + 'static) -> Self {
+        self.orientation = Box::new(orientation);
+        self
+    }
+
+    pub fn with_descriptor(mut self, descriptor: impl DescriptorExtractor + 'static) -> Self {
+        self.descriptor = Box::new(descriptor);
+        self
+    }
+}
+
+impl From<&ExtractorConfig> for ExtractionPipeline {
+    fn from(config: &ExtractorConfig) -> Self {
+        Self::new(
+            config.blur_radius,
+            FastDetector {
+                threshold: config.fast_threshold,
+            },
+            IntensityCentroid,
+            BriefExtractor::new(config.sampling_pattern()),
+        )
+    }
+}
+
+impl Default for ExtractionPipeline {
+    fn default() -> Self {
+        Self::from(&ExtractorConfig::default())
+    }
+}
+
+impl FeatureExtractor for ExtractionPipeline {
+    fn extract(&self, image: ImageView<u8>) -> Features {
+        let blurred;
+        let image = if self.blur_radius > 0.0 {
+            blurred = image_impl::gaussian_blur_view(image, self.blur_radius);
+            ImageView::new(&blurred, image.width(), image.height()).unwrap()
+        } else {
+            image
+        };
+        let corners = self.detector.detect(image);
+        let key_points = self.orientation.orient(image, &corners);
+        let descriptors = self.descriptor.describe(image, &key_points);
+        Features {
+            key_points,
+            descriptors,
+        }
+    }
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // three corners of a centered square, whatever the image
+    struct SquareCorners;
+
+    impl Detector for SquareCorners {
+        fn detect(&self, image: ImageView<u8>) -> Vec<(usize, usize)> {
+            let (w, h) = (image.width(), image.height());
+            vec![(w / 4, h / 4), (3 * w / 4, h / 4), (w / 4, 3 * h / 4)]
+        }
+    }
+
+    #[test]
+    fn test_pipeline_steps() {
+        let data = (0..80 * 60).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let image = ImageView::new(&data, 80, 60).unwrap();
+        let pipeline = ExtractionPipeline::default().with_detector(SquareCorners);
+        let features = pipeline.extract(image);
+        let corners = features
+            .key_points
+            .iter()
+            .map(|kp| (kp.x as usize, kp.y as usize))
+            .collect::<Vec<_>>();
+        assert_eq!(corners, [(20, 15), (60, 15), (20, 45)]);
+        assert_eq!(features.descriptors.len(), 3);
+
+        // without blur the steps see the image itself
+        let unblurred = ExtractionPipeline {
+            blur_radius: 0.0,
+            ..pipeline
+        };
+        let key_points = IntensityCentroid.orient(image, &SquareCorners.detect(image));
+        assert_eq!(unblurred.extract(image).key_points, key_points);
+
+        // a crop is extracted in place, in its own coordinates
+        let crop = image.sub_view(8, 4, 40, 40).unwrap();
+        let cropped = unblurred.extract(crop);
+        assert_eq!(
+            cropped.key_points[0].point(),
+            nalgebra::Point2::new(10.0, 10.0)
+        );
+        let copy = crop.to_vec();
+        let copied = unblurred.extract(ImageView::new(&copy, 40, 40).unwrap());
+        assert_eq!(cropped, copied);
+    }
+}
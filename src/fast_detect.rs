@@ -1,14 +1,16 @@
 //use image::imageops::grayscale;
 
 use crate::common::KeyPoint;
+use crate::error::XdofError;
+use crate::image_view::{ImageView, Pixel};
 
 type SpiralPatternPositions = [(isize, isize); 12];
-type SpiralIntensity = [u8; 12];
+type SpiralIntensity<T = u8> = [T; 12];
 
-fn is_corner_in_spiral(
-    center_point_intensity: u8,
-    circle: &SpiralIntensity,
-    threshold_for_intensity_difference: u8,
+fn is_corner_in_spiral<T: Pixel>(
+    center_point_intensity: T,
+    circle: &SpiralIntensity<T>,
+    threshold_for_intensity_difference: T,
     needed_consecutive_intensity_differences: u8,
 ) -> bool {
     // In the context of image processing and computer vision, a "corner" refers to a point in the
@@ -18,9 +20,9 @@ fn is_corner_in_spiral(
     let mut consecutive = 0;
 
     for &pixel in circle.iter() {
-        let is_signifigant_intensity_difference = (pixel as i32 - center_point_intensity as i32)
-            .abs()
-            > threshold_for_intensity_difference as i32;
+        let is_signifigant_intensity_difference =
+            (pixel.to_f32() - center_point_intensity.to_f32()).abs()
+                > threshold_for_intensity_difference.to_f32();
         if is_signifigant_intensity_difference {
             consecutive += 1;
             if consecutive == needed_consecutive_intensity_differences {
@@ -56,10 +58,13 @@ const SPIRAL_PATTERN: SpiralPatternPositions = [
 /// https://en.wikipedia.org/wiki/Features_from_accelerated_segment_test
 ///
 pub fn fast_keypoints_img(grey_img: &image::GrayImage, threshold: u8) -> Vec<(usize, usize)> {
-    fast_keypoints(
-        &grey_img.as_raw(),
-        grey_img.width() as usize,
-        grey_img.height() as usize,
+    fast_keypoints_view(
+        ImageView::new(
+            grey_img.as_raw(),
+            grey_img.width() as usize,
+            grey_img.height() as usize,
+        )
+        .expect("a GrayImage holds its pixels"),
         threshold,
     )
 }
@@ -68,45 +73,32 @@ pub fn fast_keypoints_img(grey_img: &image::GrayImage, threshold: u8) -> Vec<(us
 // keypoints (corners) in the image. It iterates over the image and for each pixel, it checks if
 // the pixel is a corner by comparing it to the pixels in a circle around it. If the pixel is a
 // corner, it is added to the list of keypoints.
+//
+// Fails with `XdofError::InvalidImage` when `img` does not hold `width` x `height` pixels.
 pub fn fast_keypoints(
     img: &[u8],
     width: usize,
     height: usize,
     threshold: u8,
-) -> Vec<(usize, usize)> {
+) -> Result<Vec<(usize, usize)>, XdofError> {
+    Ok(fast_keypoints_view(
+        ImageView::new(img, width, height)?,
+        threshold,
+    ))
+}
+
+/// `fast_keypoints` of any pixel type, `threshold` in the units of the pixels. The corners are
+/// relative to the corner of the view.
+pub fn fast_keypoints_view<T: Pixel>(img: ImageView<T>, threshold: T) -> Vec<(usize, usize)> {
     let mut keypoints = Vec::new();
-    for y in 3..(height as isize - 3) {
-        for x in 3..(width as isize - 3) {
+    for y in 3..(img.height() as isize - 3) {
+        for x in 3..(img.width() as isize - 3) {
             // get the intensity at x y
-            let intensity = img[(y * width as isize + x) as usize];
+            let intensity = img.get(x as usize, y as usize);
 
             // get the surrounding spiral intensities
-            let spiral_intensities: SpiralIntensity = [
-                img[(y + SPIRAL_PATTERN[0].1) as usize * width
-                    + (x + SPIRAL_PATTERN[0].0) as usize],
-                img[(y + SPIRAL_PATTERN[1].1) as usize * width
-                    + (x + SPIRAL_PATTERN[1].0) as usize],
-                img[(y + SPIRAL_PATTERN[2].1) as usize * width
-                    + (x + SPIRAL_PATTERN[2].0) as usize],
-                img[(y + SPIRAL_PATTERN[3].1) as usize * width
-                    + (x + SPIRAL_PATTERN[3].0) as usize],
-                img[(y + SPIRAL_PATTERN[4].1) as usize * width
-                    + (x + SPIRAL_PATTERN[4].0) as usize],
-                img[(y + SPIRAL_PATTERN[5].1) as usize * width
-                    + (x + SPIRAL_PATTERN[5].0) as usize],
-                img[(y + SPIRAL_PATTERN[6].1) as usize * width
-                    + (x + SPIRAL_PATTERN[6].0) as usize],
-                img[(y + SPIRAL_PATTERN[7].1) as usize * width
-                    + (x + SPIRAL_PATTERN[7].0) as usize],
-                img[(y + SPIRAL_PATTERN[8].1) as usize * width
-                    + (x + SPIRAL_PATTERN[8].0) as usize],
-                img[(y + SPIRAL_PATTERN[9].1) as usize * width
-                    + (x + SPIRAL_PATTERN[9].0) as usize],
-                img[(y + SPIRAL_PATTERN[10].1) as usize * width
-                    + (x + SPIRAL_PATTERN[10].0) as usize],
-                img[(y + SPIRAL_PATTERN[11].1) as usize * width
-                    + (x + SPIRAL_PATTERN[11].0) as usize],
-            ];
+            let spiral_intensities =
+                SPIRAL_PATTERN.map(|(dx, dy)| img.get((x + dx) as usize, (y + dy) as usize));
 
             if is_corner_in_spiral(intensity, &spiral_intensities, threshold, 9) {
                 keypoints.push((x as usize, y as usize));
@@ -134,6 +126,16 @@ pub fn compute_orientations(
     img: &[u8],
     width: usize,
     keypoints: &[(usize, usize)],
+) -> Vec<KeyPoint> {
+    let height = img.len().checked_div(width).unwrap_or(0);
+    let image = ImageView::new(img, width, height).expect("whole rows always fit");
+    compute_orientations_view(image, keypoints)
+}
+
+/// `compute_orientations` of any pixel type.
+pub fn compute_orientations_view<T: Pixel>(
+    img: ImageView<T>,
+    keypoints: &[(usize, usize)],
 ) -> Vec<KeyPoint> {
     let circle_offsets = [
         (-1, -3),
@@ -163,7 +165,7 @@ pub fn compute_orientations(
             for &(dx, dy) in circle_offsets.iter() {
                 let x_offset = (x as i32 + dx) as usize;
                 let y_offset = (y as i32 + dy) as usize;
-                let w = img[y_offset * width + x_offset] as f32;
+                let w = img.get(x_offset, y_offset).to_f32();
 
                 m_x += w * dx as f32;
                 m_y += w * dy as f32;
@@ -200,7 +202,7 @@ mod tests {
             threshold_for_intensity_difference,
             needed_consecutive_intensity_differences,
         );
-        assert_eq!(is_corner, true);
+        assert!(is_corner);
 
         // Test with a simple spiral pattern where the center pixel is not a corner
         let circle: SpiralIntensity = [10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10];
@@ -213,7 +215,7 @@ mod tests {
             threshold_for_intensity_difference,
             needed_consecutive_intensity_differences,
         );
-        assert_eq!(is_corner, false);
+        assert!(!is_corner);
 
         // Test with a more complex spiral pattern where the center pixel is a corner
         let circle: SpiralIntensity = [10, 10, 100, 10, 10, 10, 200, 10, 10, 10, 10, 10];
@@ -226,7 +228,7 @@ mod tests {
             threshold_for_intensity_difference,
             needed_consecutive_intensity_differences,
         );
-        assert_eq!(is_corner, true);
+        assert!(is_corner);
 
         // Test with a more complex spiral pattern where the center pixel is a corner
         // notice we have multiple consecutive intensity differences that are greater than the threshold
@@ -240,7 +242,7 @@ mod tests {
             threshold_for_intensity_difference,
             needed_consecutive_intensity_differences,
         );
-        assert_eq!(is_corner, true);
+        assert!(is_corner);
     }
 
     #[test]
@@ -257,8 +259,9 @@ mod tests {
         let width = 5;
         let height = 5;
         let threshold = 50;
-        let keypoints = fast_keypoints(&img, width, height, threshold);
+        let keypoints = fast_keypoints(&img, width, height, threshold).unwrap();
         assert_eq!(keypoints, vec![]);
+        assert!(fast_keypoints(&img, width, height + 1, threshold).is_err());
 
         // Test with a simple 9x9 image where there are no corners
         let img = [
@@ -273,7 +276,7 @@ mod tests {
             10, 10, 10, 10, 10, 10, 10, 10, 10, //
         ];
         let threshold = 50;
-        let keypoints = fast_keypoints(&img, 9, 9, threshold);
+        let keypoints = fast_keypoints(&img, 9, 9, threshold).unwrap();
         assert_eq!(keypoints, vec![(4, 4)]);
         let keypoints_with_orientation = compute_orientations(&img, 9, &keypoints);
         assert_eq!(keypoints_with_orientation.len(), 1);
@@ -292,12 +295,15 @@ mod tests {
             10, 10, 10, 10, 10, 10, 10, 10, 10, //
         ];
         let threshold = 50;
-        let keypoints = fast_keypoints(&img, 9, 9, threshold);
+        let keypoints = fast_keypoints(&img, 9, 9, threshold).unwrap();
         assert_eq!(keypoints, vec![(3, 3), (5, 5)]);
 
         let keypoints_with_orientation = compute_orientations(&img, 9, &keypoints);
         assert_eq!(keypoints_with_orientation.len(), 2);
-        assert_eq!(keypoints_with_orientation[0].orientation, 0.7853982);
+        assert_eq!(
+            keypoints_with_orientation[0].orientation,
+            std::f32::consts::FRAC_PI_4
+        );
         // 0.7853982 is the angle of the vector (1, 1)
         assert_eq!(keypoints_with_orientation[1].orientation, -2.3561945);
         // -2.3561945 is the angle of the vector (-1, 1)
@@ -316,7 +322,7 @@ mod tests {
             10, 10, 10, 10, 10, 10, 10, 10, 10, //
         ];
         let threshold = 50;
-        let keypoints = fast_keypoints(&img, 9, 9, threshold);
+        let keypoints = fast_keypoints(&img, 9, 9, threshold).unwrap();
         assert_eq!(keypoints, vec![(3, 3), (3, 5)]);
 
         let keypoints_with_orientation = compute_orientations(&img, 9, &keypoints);
@@ -339,7 +345,7 @@ mod tests {
             10, 10, 10, 10, 10, 10, 10, 10, 10, //
         ];
         let threshold = 50;
-        let keypoints = fast_keypoints(&img, 9, 9, threshold);
+        let keypoints = fast_keypoints(&img, 9, 9, threshold).unwrap();
         assert_eq!(keypoints, vec![(3, 3), (5, 3)]);
 
         let keypoints_with_orientation = compute_orientations(&img, 9, &keypoints);
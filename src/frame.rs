@@ -0,0 +1,135 @@
+//! An image with its features, extracted once and reused by every pose it takes part in.
+
+use std::sync::Arc;
+
+use crate::common::{Descriptor, Features, Image, KeyPoint};
+use crate::error::XdofError;
+use crate::extractor::FeatureExtractor;
+use crate::image_view::ImageView;
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Frame {
+    width: usize,
+    height: usize,
+    // shared with the pose estimates made from the frame
+    features: Arc<Features>,
+}
+
+impl Frame {
+    /// Extracts the features of a greyscale image.
+    pub fn new(image: &Image, extractor: &dyn FeatureExtractor) -> Result<Self, XdofError> {
+        let view = ImageView::new(&image.data, image.width, image.height)?;
+        Ok(Self::from_view(view, extractor))
+    }
+
+    /// Extracts the features of a borrowed image, e.g. a crop or a buffer owned by a camera
+    /// driver, without copying it.
+    pub fn from_view(image: ImageView<u8>, extractor: &dyn FeatureExtractor) -> Self {
+        Self {
+            width: image.width(),
+            height: image.height(),
+            features: Arc::new(extractor.extract(image)),
+        }
+    }
+
+    /// A frame of a `width` x `height` image whose features were extracted elsewhere.
+    pub fn from_features(width: usize, height: usize, features: Features) -> Self {
+        Self {
+            width,
+            height,
+            features: Arc::new(features),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn features(&self) -> &Features {
+        &self.features
+    }
+
+    /// The features without copying them, e.g. to keep them after the frame is dropped.
+    pub fn shared_features(&self) -> Arc<Features> {
+        Arc::clone(&self.features)
+    }
+
+    pub fn key_points(&self) -> &[KeyPoint] {
+        &self.features.key_points
+    }
+
+    pub fn descriptors(&self) -> &[Descriptor] {
+        &self.features.descriptors
+    }
+
+    /// The features, copied only when they are still shared.
+    pub fn into_features(self) -> Features {
+        Arc::try_unwrap(self.features).unwrap_or_else(|shared| (*shared).clone())
+    }
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extractor::{ExtractionPipeline, ExtractorConfig};
+
+    // bright squares on a dark background, every corner a FAST corner
+    fn checkers(width: usize, height: usize) -> Image {
+        let data = (0..width * height)
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+                if (x / 20 + y / 20) % 2 == 0 {
+                    220
+                } else {
+                    30
+                }
+            })
+            .collect();
+        Image {
+            width,
+            height,
+            data,
+        }
+    }
+
+    #[test]
+    fn test_extract() {
+        let image = checkers(160, 120);
+        let config = ExtractorConfig {
+            blur_radius: 1.0,
+            ..Default::default()
+        };
+        let extractor = ExtractionPipeline::from(&config);
+        let frame = Frame::new(&image, &extractor).unwrap();
+        assert!(!frame.key_points().is_empty());
+        assert_eq!(frame.key_points().len(), frame.descriptors().len());
+        assert!(frame
+            .descriptors()
+            .iter()
+            .all(|d| d.0.len() == config.num_pairs.div_ceil(8)));
+        assert_eq!((frame.width(), frame.height()), (160, 120));
+
+        // the same image and config always give the same features
+        assert_eq!(Frame::new(&image, &extractor).unwrap(), frame);
+
+        let truncated = Image {
+            data: image.data[1..].to_vec(),
+            ..image
+        };
+        assert_eq!(
+            Frame::new(&truncated, &extractor),
+            Err(XdofError::InvalidImage {
+                expected: 160 * 120,
+                len: 160 * 120 - 1
+            })
+        );
+    }
+}
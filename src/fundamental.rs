@@ -0,0 +1,406 @@
+//! Fundamental matrix estimation for uncalibrated cameras.
+//!
+//! The fundamental matrix F relates pixel coordinates of matching points, `x2^T * F * x1 = 0`.
+//! When the intrinsics K1 and K2 are known it is related to the essential matrix by
+//! `E = K2^T * F * K1`.
+//!
+//! https://en.wikipedia.org/wiki/Fundamental_matrix_(computer_vision)
+
+use nalgebra::{Matrix3, Point2, Vector3};
+
+use crate::camera::Camera;
+use crate::common::*;
+use crate::epipolar::{
+    enforce_rank_two, epipolar_constraint_matrix, weighted_epipolar_constraint_matrix,
+    SampsonDistance,
+};
+use crate::rand::*;
+use crate::ransac::*;
+
+/// Hartley normalization, translates the points so their centroid is at the origin and scales
+/// them so the mean distance to it is sqrt(2). Returns the points and the transform applied.
+pub fn normalize_points(points: &[Point2<f64>]) -> (Vec<Point2<f64>>, Matrix3<f64>) {
+    let n = points.len().max(1) as f64;
+    let centroid = points
+        .iter()
+        .fold(Vector3::zeros(), |sum, p| sum + Vector3::new(p.x, p.y, 0.0))
+        / n;
+    let mean_distance = points
+        .iter()
+        .map(|p| ((p.x - centroid.x).powi(2) + (p.y - centroid.y).powi(2)).sqrt())
+        .sum::<f64>()
+        / n;
+    let scale = if mean_distance > f64::EPSILON {
+        2.0_f64.sqrt() / mean_distance
+    } else {
+        1.0
+    };
+
+    let transform = Matrix3::new(
+        scale,
+        0.0,
+        -scale * centroid.x,
+        0.0,
+        scale,
+        -scale * centroid.y,
+        0.0,
+        0.0,
+        1.0,
+    );
+    let normalized = points
+        .iter()
+        .map(|p| Point2::new(scale * (p.x - centroid.x), scale * (p.y - centroid.y)))
+        .collect();
+    (normalized, transform)
+}
+
+// Normalizes both sides of the matches independently.
+fn normalize_matches(
+    matches: &[(KeyPoint, KeyPoint)],
+) -> (Vec<PointMatch>, Matrix3<f64>, Matrix3<f64>) {
+    let points1 = matches.iter().map(|(p1, _)| p1.point()).collect::<Vec<_>>();
+    let points2 = matches.iter().map(|(_, p2)| p2.point()).collect::<Vec<_>>();
+    let (points1, t1) = normalize_points(&points1);
+    let (points2, t2) = normalize_points(&points2);
+    (points1.into_iter().zip(points2).collect(), t1, t2)
+}
+
+fn matrix_from_row_major(m: &[f64]) -> Matrix3<f64> {
+    Matrix3::from_row_slice(&m[..9])
+}
+
+/// The normalized 8 point algorithm, needs at least 8 matches. The result has rank 2.
+pub fn eight_point(matches: &[(KeyPoint, KeyPoint)]) -> Option<Matrix3<f64>> {
+    eight_point_weighted(matches, &vec![1.0; matches.len()])
+}
+
+/// The normalized 8 point algorithm minimizing the weighted algebraic error, needs at least 8
+/// matches with a non zero weight. Matches with a zero weight are ignored.
+pub fn eight_point_weighted(
+    matches: &[(KeyPoint, KeyPoint)],
+    weights: &[f64],
+) -> Option<Matrix3<f64>> {
+    let (matches, weights): (Vec<_>, Vec<_>) = matches
+        .iter()
+        .zip(weights)
+        .filter(|(_, &weight)| weight > 0.0)
+        .map(|(&m, &weight)| (m, weight))
+        .unzip();
+    if matches.len() < 8 {
+        return None;
+    }
+    let (points, t1, t2) = normalize_matches(&matches);
+
+    let svd = weighted_epipolar_constraint_matrix(&points, &weights).svd(false, true);
+    let v_t = svd.v_t?;
+    let row = v_t.row(8).iter().copied().collect::<Vec<_>>();
+    let f = enforce_rank_two(&matrix_from_row_major(&row));
+
+    // undo the normalization, x2^T * T2^T * F * T1 * x1 = 0
+    let f = t2.transpose() * f * t1;
+    let norm = f.norm();
+    if norm <= f64::EPSILON {
+        return None;
+    }
+    Some(f / norm)
+}
+
+/// Real roots of `a*x^3 + b*x^2 + c*x + d = 0`.
+fn solve_cubic(a: f64, b: f64, c: f64, d: f64) -> Vec<f64> {
+    if a.abs() < 1e-12 {
+        // degenerate, solve the quadratic
+        if b.abs() < 1e-12 {
+            if c.abs() < 1e-12 {
+                return vec![];
+            }
+            return vec![-d / c];
+        }
+        let discriminant = c * c - 4.0 * b * d;
+        if discriminant < 0.0 {
+            return vec![];
+        }
+        let sqrt = discriminant.sqrt();
+        return vec![(-c + sqrt) / (2.0 * b), (-c - sqrt) / (2.0 * b)];
+    }
+
+    // depressed cubic t^3 + p*t + q = 0 with x = t - b / 3a
+    let (b, c, d) = (b / a, c / a, d / a);
+    let p = c - b * b / 3.0;
+    let q = 2.0 * b * b * b / 27.0 - b * c / 3.0 + d;
+    let shift = -b / 3.0;
+    let discriminant = q * q / 4.0 + p * p * p / 27.0;
+
+    if discriminant > 0.0 {
+        let sqrt = discriminant.sqrt();
+        vec![(-q / 2.0 + sqrt).cbrt() + (-q / 2.0 - sqrt).cbrt() + shift]
+    } else if p.abs() < 1e-12 {
+        vec![shift]
+    } else {
+        // three real roots, use the trigonometric form
+        let r = (-p / 3.0).sqrt();
+        let phi = (-q / (2.0 * r * r * r)).clamp(-1.0, 1.0).acos();
+        (0..3)
+            .map(|k| 2.0 * r * ((phi + 2.0 * std::f64::consts::PI * k as f64) / 3.0).cos() + shift)
+            .collect()
+    }
+}
+
+/// The 7 point algorithm, uses exactly 7 matches and returns one or three solutions.
+pub fn seven_point(matches: &[(KeyPoint, KeyPoint)]) -> Vec<Matrix3<f64>> {
+    if matches.len() < 7 {
+        return vec![];
+    }
+    let (points, t1, t2) = normalize_matches(&matches[..7]);
+
+    // the nullspace of the 7x9 system is two dimensional, F = alpha * F1 + (1 - alpha) * F2
+    let svd = epipolar_constraint_matrix(&points).svd(false, true);
+    let v_t = match svd.v_t {
+        Some(v_t) => v_t,
+        None => return vec![],
+    };
+    let f1 = matrix_from_row_major(&v_t.row(7).iter().copied().collect::<Vec<_>>());
+    let f2 = matrix_from_row_major(&v_t.row(8).iter().copied().collect::<Vec<_>>());
+
+    // det(F) = 0 is a cubic in alpha, recover its coefficients from 4 samples
+    let det = |alpha: f64| (f1 * alpha + f2 * (1.0 - alpha)).determinant();
+    let d0 = det(0.0);
+    let d1 = det(1.0);
+    let d_minus1 = det(-1.0);
+    let d2 = det(2.0);
+    let c0 = d0;
+    let c2 = (d1 + d_minus1) / 2.0 - c0;
+    let odd = (d1 - d_minus1) / 2.0; // c3 + c1
+    let c3 = (d2 - c0 - 4.0 * c2 - 2.0 * odd) / 6.0;
+    let c1 = odd - c3;
+
+    solve_cubic(c3, c2, c1, c0)
+        .into_iter()
+        .filter_map(|alpha| {
+            let f = t2.transpose() * (f1 * alpha + f2 * (1.0 - alpha)) * t1;
+            let norm = f.norm();
+            (norm > f64::EPSILON).then(|| f / norm)
+        })
+        .collect()
+}
+
+/// The 7 point algorithm as a RANSAC minimal solver, local optimization uses the 8 point
+/// algorithm.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub struct SevenPointSolver;
+
+impl MinimalSolver<(KeyPoint, KeyPoint)> for SevenPointSolver {
+    type Model = Matrix3<f64>;
+
+    fn sample_size(&self) -> usize {
+        7
+    }
+
+    fn solve(&self, sample: &[(KeyPoint, KeyPoint)]) -> Vec<Matrix3<f64>> {
+        seven_point(sample)
+    }
+
+    fn solve_nonminimal(&self, data: &[(KeyPoint, KeyPoint)]) -> Vec<Matrix3<f64>> {
+        eight_point(data).into_iter().collect()
+    }
+
+    fn solve_weighted(&self, data: &[(KeyPoint, KeyPoint)], weights: &[f64]) -> Vec<Matrix3<f64>> {
+        eight_point_weighted(data, weights).into_iter().collect()
+    }
+}
+
+/// The normalized 8 point algorithm as a RANSAC minimal solver.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub struct EightPointSolver;
+
+impl MinimalSolver<(KeyPoint, KeyPoint)> for EightPointSolver {
+    type Model = Matrix3<f64>;
+
+    fn sample_size(&self) -> usize {
+        8
+    }
+
+    fn solve(&self, sample: &[(KeyPoint, KeyPoint)]) -> Vec<Matrix3<f64>> {
+        eight_point(sample).into_iter().collect()
+    }
+
+    fn solve_nonminimal(&self, data: &[(KeyPoint, KeyPoint)]) -> Vec<Matrix3<f64>> {
+        eight_point(data).into_iter().collect()
+    }
+
+    fn solve_weighted(&self, data: &[(KeyPoint, KeyPoint)], weights: &[f64]) -> Vec<Matrix3<f64>> {
+        eight_point_weighted(data, weights).into_iter().collect()
+    }
+}
+
+/// Which minimal solver `estimate_fundamental_ransac` draws hypotheses with.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub enum FundamentalSolver {
+    /// Smaller samples, so fewer iterations for the same outlier ratio.
+    #[default]
+    SevenPoint,
+    EightPoint,
+}
+
+/// Estimates F from pixel matches, `options.inlier_threshold` is a Sampson distance in pixels.
+pub fn estimate_fundamental_ransac(
+    matches: &[(KeyPoint, KeyPoint)],
+    solver: FundamentalSolver,
+    options: &RansacOptions,
+    rnd: &mut Rand,
+) -> Option<RansacResult<Matrix3<f64>>> {
+    match solver {
+        FundamentalSolver::SevenPoint => {
+            ransac(matches, &SevenPointSolver, &SampsonDistance, options, rnd)
+        }
+        FundamentalSolver::EightPoint => {
+            ransac(matches, &EightPointSolver, &SampsonDistance, options, rnd)
+        }
+    }
+}
+
+/// `E = K2^T * F * K1`, the essential matrix of a calibrated pair.
+pub fn essential_from_fundamental(
+    fundamental: &Matrix3<f64>,
+    camera1: &Camera,
+    camera2: &Camera,
+) -> Matrix3<f64> {
+    camera2.k().transpose() * fundamental * camera1.k()
+}
+
+/// `F = K2^-T * E * K1^-1`, the inverse of `essential_from_fundamental`.
+pub fn fundamental_from_essential(
+    essential: &Matrix3<f64>,
+    camera1: &Camera,
+    camera2: &Camera,
+) -> Matrix3<f64> {
+    camera2.k_inv().transpose() * essential * camera1.k_inv()
+}
+
+/// The epipoles `(e1, e2)` in homogeneous pixel coordinates, where `F * e1 = 0` and
+/// `F^T * e2 = 0`. e1 is the image of camera 2's centre in image 1 and vice versa. The last
+/// coordinate is (close to) zero when an epipole is at infinity, e.g. for sideways motion.
+pub fn epipoles(fundamental: &Matrix3<f64>) -> (Vector3<f64>, Vector3<f64>) {
+    let svd = fundamental.svd(true, true);
+    let e1 = svd.v_t.unwrap().row(2).transpose();
+    let e2 = svd.u.unwrap().column(2).into_owned();
+    (normalize_homogeneous(e1), normalize_homogeneous(e2))
+}
+
+// Scales a homogeneous point so its last coordinate is 1, when that is possible.
+fn normalize_homogeneous(v: Vector3<f64>) -> Vector3<f64> {
+    if v.z.abs() > 1e-12 {
+        v / v.z
+    } else {
+        v.normalize()
+    }
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epipolar::sampson_distances;
+    use crate::essential::essential_from_pose;
+    use crate::test_scene;
+
+    fn max_error(f: &Matrix3<f64>, matches: &[(KeyPoint, KeyPoint)]) -> f64 {
+        sampson_distances(f, matches)
+            .into_iter()
+            .fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn test_eight_point() {
+        let camera = test_scene::camera();
+        let pose = test_scene::motion();
+        let matches = test_scene::matches(&camera, &test_scene::points(20), &pose);
+        let f = eight_point(&matches).unwrap();
+        assert!(max_error(&f, &matches) < 1e-3);
+        assert!(f.determinant().abs() < 1e-9);
+        assert!(eight_point(&matches[..7]).is_none());
+    }
+
+    #[test]
+    fn test_seven_point() {
+        let camera = test_scene::camera();
+        let pose = test_scene::motion();
+        let matches = test_scene::matches(&camera, &test_scene::points(20), &pose);
+        let solutions = seven_point(&matches[..7]);
+        assert!(!solutions.is_empty());
+        // exactly one of the solutions is the true F, which agrees with all the matches
+        let best = solutions
+            .iter()
+            .map(|f| max_error(f, &matches))
+            .fold(f64::INFINITY, f64::min);
+        assert!(best < 1e-2, "{}", best);
+    }
+
+    #[test]
+    fn test_solve_cubic() {
+        // (x - 1)(x - 2)(x + 3) = x^3 - 7x + 6
+        let mut roots = solve_cubic(1.0, 0.0, -7.0, 6.0);
+        roots.sort_by(f64::total_cmp);
+        assert_eq!(roots.len(), 3);
+        for (root, expected) in roots.iter().zip([-3.0, 1.0, 2.0]) {
+            assert!((root - expected).abs() < 1e-9);
+        }
+        // x^3 + x + 1 has a single real root
+        let roots = solve_cubic(2.0, 0.0, 2.0, 2.0);
+        assert_eq!(roots.len(), 1);
+        assert!((roots[0].powi(3) + roots[0] + 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_estimate_fundamental_ransac() {
+        let camera = test_scene::camera();
+        let pose = test_scene::motion();
+        let mut matches = test_scene::matches(&camera, &test_scene::points(40), &pose);
+        for i in 0..8 {
+            let (p1, p2) = matches[i * 5];
+            matches[i * 5] = (p1, KeyPoint::new(p2.y, p2.x + 40.0, 0.0));
+        }
+
+        for solver in [FundamentalSolver::SevenPoint, FundamentalSolver::EightPoint] {
+            let options = RansacOptions {
+                inlier_threshold: 1.0,
+                local_optimization_iterations: 5,
+                ..Default::default()
+            };
+            let mut rnd = Rand::new_with_seed(11);
+            let result = estimate_fundamental_ransac(&matches, solver, &options, &mut rnd).unwrap();
+            assert_eq!(result.num_inliers(), 32, "{:?}", solver);
+            assert!((0..8).all(|i| !result.inliers[i * 5]));
+        }
+    }
+
+    #[test]
+    fn test_essential_fundamental_round_trip() {
+        let camera = test_scene::camera();
+        let pose = test_scene::motion();
+        let matches = test_scene::matches(&camera, &test_scene::points(20), &pose);
+
+        let essential = essential_from_pose(&pose);
+        let f = fundamental_from_essential(&essential, &camera, &camera);
+        assert!(max_error(&f, &matches) < 1e-3);
+
+        let back = essential_from_fundamental(&f, &camera, &camera);
+        assert!((back - essential).norm() < 1e-9);
+    }
+
+    #[test]
+    fn test_epipoles() {
+        let camera = test_scene::camera();
+        let pose = test_scene::motion();
+        let f = fundamental_from_essential(&essential_from_pose(&pose), &camera, &camera);
+        let (e1, e2) = epipoles(&f);
+        assert!((f * e1).norm() < 1e-9);
+        assert!((f.transpose() * e2).norm() < 1e-9);
+
+        // e2 is camera 1's centre seen from camera 2, which sits at t in camera 2 coordinates
+        let expected = camera.project(&pose.translation.into()).unwrap();
+        assert!((e2.x - expected.x).abs() < 1e-6 && (e2.y - expected.y).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,436 @@
+//! Homography estimation, decomposition, and choosing between a homography and the epipolar
+//! geometry for two view initialization.
+//!
+//! A homography H maps pixels of image 1 to image 2, `x2 ~ H * x1`. It explains the matches when
+//! the scene is planar (e.g. aerial imagery of flat ground) or the camera only rotates, both
+//! cases where the 8 point essential matrix is degenerate.
+//!
+//! https://en.wikipedia.org/wiki/Homography_(computer_vision)
+
+use nalgebra::{DMatrix, Matrix3, Point2, Vector3};
+
+use crate::camera::Camera;
+use crate::common::*;
+use crate::fundamental::normalize_points;
+use crate::lie::SE3;
+use crate::rand::*;
+use crate::ransac::*;
+
+/// The normalized direct linear transform, needs at least 4 matches.
+pub fn dlt(matches: &[(KeyPoint, KeyPoint)]) -> Option<Matrix3<f64>> {
+    if matches.len() < 4 {
+        return None;
+    }
+    let points1 = matches.iter().map(|(p1, _)| p1.point()).collect::<Vec<_>>();
+    let points2 = matches.iter().map(|(_, p2)| p2.point()).collect::<Vec<_>>();
+    let (points1, t1) = normalize_points(&points1);
+    let (points2, t2) = normalize_points(&points2);
+
+    // two rows per match from x2 cross (H * x1) = 0, padded to at least 9 rows
+    let mut a = DMatrix::<f64>::zeros((2 * matches.len()).max(9), 9);
+    for (i, (p1, p2)) in points1.iter().zip(&points2).enumerate() {
+        let (x1, y1) = (p1.x, p1.y);
+        let (x2, y2) = (p2.x, p2.y);
+        a.row_mut(2 * i)
+            .copy_from_slice(&[0.0, 0.0, 0.0, -x1, -y1, -1.0, y2 * x1, y2 * y1, y2]);
+        a.row_mut(2 * i + 1).copy_from_slice(&[
+            x1,
+            y1,
+            1.0,
+            0.0,
+            0.0,
+            0.0,
+            -x2 * x1,
+            -x2 * y1,
+            -x2,
+        ]);
+    }
+
+    let v_t = a.svd(false, true).v_t?;
+    let h = v_t.row(8);
+    let h = Matrix3::from_row_slice(&[h[0], h[1], h[2], h[3], h[4], h[5], h[6], h[7], h[8]]);
+
+    // undo the normalization, T2 * x2 ~ H * T1 * x1
+    let h = t2.try_inverse()? * h * t1;
+    if h[(2, 2)].abs() <= f64::EPSILON {
+        return None;
+    }
+    Some(h / h[(2, 2)])
+}
+
+/// Where H maps `x1` in image 2.
+pub fn transfer(h: &Matrix3<f64>, x1: &Point2<f64>) -> Option<Point2<f64>> {
+    Point2::from_homogeneous(h * x1.to_homogeneous())
+}
+
+/// The distance between `x2` and the transfer of `x1`.
+pub fn transfer_error(h: &Matrix3<f64>, x1: &Point2<f64>, x2: &Point2<f64>) -> f64 {
+    transfer(h, x1).map_or(f64::INFINITY, |p| (p - x2).norm())
+}
+
+/// The root of the summed squared transfer errors, forwards with H and backwards with H^-1.
+pub fn symmetric_transfer_error(h: &Matrix3<f64>, x1: &Point2<f64>, x2: &Point2<f64>) -> f64 {
+    let h_inv = match h.try_inverse() {
+        Some(h_inv) => h_inv,
+        None => return f64::INFINITY,
+    };
+    let forward = transfer_error(h, x1, x2);
+    let backward = transfer_error(&h_inv, x2, x1);
+    (forward * forward + backward * backward).sqrt()
+}
+
+/// The 4 point DLT as a RANSAC minimal solver.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub struct HomographySolver;
+
+impl MinimalSolver<(KeyPoint, KeyPoint)> for HomographySolver {
+    type Model = Matrix3<f64>;
+
+    fn sample_size(&self) -> usize {
+        4
+    }
+
+    fn solve(&self, sample: &[(KeyPoint, KeyPoint)]) -> Vec<Matrix3<f64>> {
+        dlt(sample).into_iter().collect()
+    }
+
+    fn solve_nonminimal(&self, data: &[(KeyPoint, KeyPoint)]) -> Vec<Matrix3<f64>> {
+        dlt(data).into_iter().collect()
+    }
+}
+
+/// Scores matches with `symmetric_transfer_error`.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub struct SymmetricTransferError;
+
+impl ErrorMetric<Matrix3<f64>, (KeyPoint, KeyPoint)> for SymmetricTransferError {
+    fn error(&self, h: &Matrix3<f64>, (p1, p2): &(KeyPoint, KeyPoint)) -> f64 {
+        symmetric_transfer_error(h, &p1.point(), &p2.point())
+    }
+}
+
+/// Estimates H from pixel matches, `options.inlier_threshold` is a symmetric transfer error in
+/// pixels.
+pub fn estimate_homography_ransac(
+    matches: &[(KeyPoint, KeyPoint)],
+    options: &RansacOptions,
+    rnd: &mut Rand,
+) -> Option<RansacResult<Matrix3<f64>>> {
+    ransac(
+        matches,
+        &HomographySolver,
+        &SymmetricTransferError,
+        options,
+        rnd,
+    )
+}
+
+/// One motion and plane explaining a homography, `H ~ K * (R + t * n^T) * K^-1` where
+/// `X2 = R * X1 + t` and n is the plane normal in camera 1 scaled by the inverse distance to
+/// the plane. The translation is only known up to scale, so it and the normal are unit length.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct HomographyDecomposition {
+    /// `X2 = pose * X1`, with a unit translation.
+    pub pose: SE3,
+    pub normal: Vector3<f64>,
+}
+
+/// Decomposes a pixel homography between two images of `camera` into the (up to 8) motions
+/// that explain it, using the method of Faugeras & Lustman (1988). The right one has to be
+/// picked by checking which puts the most triangulated points in front of both cameras. Returns
+/// nothing when the singular values are (nearly) repeated, e.g. for a pure rotation.
+pub fn decompose_homography(h: &Matrix3<f64>, camera: &Camera) -> Vec<HomographyDecomposition> {
+    let a = camera.k_inv() * h * camera.k();
+    let svd = a.svd(true, true);
+    let (u, v_t) = (svd.u.unwrap(), svd.v_t.unwrap());
+    let v = v_t.transpose();
+    let s = u.determinant() * v_t.determinant();
+
+    let (d1, d2, d3) = (
+        svd.singular_values[0],
+        svd.singular_values[1],
+        svd.singular_values[2],
+    );
+    if d1 / d2 < 1.00001 || d2 / d3 < 1.00001 {
+        return vec![];
+    }
+
+    let aux1 = ((d1 * d1 - d2 * d2) / (d1 * d1 - d3 * d3)).sqrt();
+    let aux3 = ((d2 * d2 - d3 * d3) / (d1 * d1 - d3 * d3)).sqrt();
+    let x1 = [aux1, aux1, -aux1, -aux1];
+    let x3 = [aux3, -aux3, aux3, -aux3];
+    let root = ((d1 * d1 - d2 * d2) * (d2 * d2 - d3 * d3)).sqrt();
+
+    let mut solutions = Vec::with_capacity(8);
+
+    // d' = d2
+    let sin_theta = root / ((d1 + d3) * d2);
+    let cos_theta = (d2 * d2 + d1 * d3) / ((d1 + d3) * d2);
+    for i in 0..4 {
+        let sin_theta = if i == 0 || i == 3 {
+            sin_theta
+        } else {
+            -sin_theta
+        };
+        let rp = Matrix3::new(
+            cos_theta, 0.0, -sin_theta, 0.0, 1.0, 0.0, sin_theta, 0.0, cos_theta,
+        );
+        let tp = Vector3::new(x1[i], 0.0, -x3[i]) * (d1 - d3);
+        let np = Vector3::new(x1[i], 0.0, x3[i]);
+        solutions.push(decomposition(s * u * rp * v_t, u * tp, v * np));
+    }
+
+    // d' = -d2
+    let sin_phi = root / ((d1 - d3) * d2);
+    let cos_phi = (d1 * d3 - d2 * d2) / ((d1 - d3) * d2);
+    for i in 0..4 {
+        let sin_phi = if i == 0 || i == 3 { sin_phi } else { -sin_phi };
+        let rp = Matrix3::new(
+            cos_phi, 0.0, sin_phi, 0.0, -1.0, 0.0, sin_phi, 0.0, -cos_phi,
+        );
+        let tp = Vector3::new(x1[i], 0.0, x3[i]) * (d1 + d3);
+        let np = Vector3::new(x1[i], 0.0, x3[i]);
+        solutions.push(decomposition(s * u * rp * v_t, u * tp, v * np));
+    }
+
+    solutions
+}
+
+fn decomposition(
+    rotation: Matrix3<f64>,
+    translation: Vector3<f64>,
+    normal: Vector3<f64>,
+) -> HomographyDecomposition {
+    // the plane is in front of camera 1, so its normal points away from it
+    let normal = if normal.z < 0.0 { -normal } else { normal };
+    HomographyDecomposition {
+        pose: SE3::from_parts(&rotation, &translation.normalize()),
+        normal: normal.normalize(),
+    }
+}
+
+/// The model chosen to initialize from two views.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum TwoViewModel {
+    Homography,
+    Fundamental,
+}
+
+/// Above this `homography_ratio` the scene is treated as planar (or the motion as a pure
+/// rotation) and the homography is used.
+pub const HOMOGRAPHY_RATIO_THRESHOLD: f64 = 0.45;
+
+// chi-square 95% quantiles for 1 and 2 degrees of freedom
+const CHI_SQUARE_1DOF: f64 = 3.841;
+const CHI_SQUARE_2DOF: f64 = 5.991;
+
+/// How well H explains the matches, as in ORB-SLAM. Each match scores in both directions
+/// `5.991 - e^2 / sigma^2` when its squared transfer error is within the 95% chi-square bound for
+/// pixel noise of standard deviation `sigma`, and zero otherwise.
+pub fn homography_score(h: &Matrix3<f64>, matches: &[(KeyPoint, KeyPoint)], sigma: f64) -> f64 {
+    let h_inv = match h.try_inverse() {
+        Some(h_inv) => h_inv,
+        None => return 0.0,
+    };
+    let inv_sigma_sq = 1.0 / (sigma * sigma);
+    matches
+        .iter()
+        .map(|(p1, p2)| {
+            let (x1, x2) = (p1.point(), p2.point());
+            [
+                transfer_error(h, &x1, &x2),
+                transfer_error(&h_inv, &x2, &x1),
+            ]
+            .iter()
+            .map(|e| {
+                let chi_square = e * e * inv_sigma_sq;
+                if chi_square < CHI_SQUARE_2DOF {
+                    CHI_SQUARE_2DOF - chi_square
+                } else {
+                    0.0
+                }
+            })
+            .sum::<f64>()
+        })
+        .sum()
+}
+
+/// How well F explains the matches, on the same scale as `homography_score`. The distance to
+/// each epipolar line is a 1 degree of freedom error, so it is gated by the 1 dof bound but
+/// scored against the 2 dof one to be comparable.
+pub fn fundamental_score(f: &Matrix3<f64>, matches: &[(KeyPoint, KeyPoint)], sigma: f64) -> f64 {
+    let inv_sigma_sq = 1.0 / (sigma * sigma);
+    matches
+        .iter()
+        .map(|(p1, p2)| {
+            // the symmetric distance is the root of the two squared point-to-line distances
+            let x1 = p1.point();
+            let x2 = p2.point();
+            let l2 = f * x1.to_homogeneous();
+            let l1 = f.transpose() * x2.to_homogeneous();
+            let algebraic = x2.to_homogeneous().dot(&l2);
+            [
+                algebraic * algebraic / (l2[0] * l2[0] + l2[1] * l2[1]),
+                algebraic * algebraic / (l1[0] * l1[0] + l1[1] * l1[1]),
+            ]
+            .iter()
+            .map(|e_sq| {
+                let chi_square = e_sq * inv_sigma_sq;
+                if chi_square < CHI_SQUARE_1DOF {
+                    CHI_SQUARE_2DOF - chi_square
+                } else {
+                    0.0
+                }
+            })
+            .sum::<f64>()
+        })
+        .sum()
+}
+
+/// `S_H / (S_H + S_F)`, the share of the evidence that favours the homography.
+pub fn homography_ratio(homography_score: f64, fundamental_score: f64) -> f64 {
+    let total = homography_score + fundamental_score;
+    if total <= 0.0 {
+        return 0.0;
+    }
+    homography_score / total
+}
+
+/// Picks the homography or the fundamental matrix to initialize from, returning the choice and
+/// the `homography_ratio` it was based on. `sigma` is the expected pixel noise.
+pub fn select_model(
+    h: &Matrix3<f64>,
+    f: &Matrix3<f64>,
+    matches: &[(KeyPoint, KeyPoint)],
+    sigma: f64,
+) -> (TwoViewModel, f64) {
+    let ratio = homography_ratio(
+        homography_score(h, matches, sigma),
+        fundamental_score(f, matches, sigma),
+    );
+    if ratio > HOMOGRAPHY_RATIO_THRESHOLD {
+        (TwoViewModel::Homography, ratio)
+    } else {
+        (TwoViewModel::Fundamental, ratio)
+    }
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fundamental::{estimate_fundamental_ransac, FundamentalSolver};
+    use crate::test_scene;
+
+    // the pixel homography induced by the plane z = 5 of `test_scene::planar_points`
+    fn plane_homography(camera: &Camera) -> Matrix3<f64> {
+        let pose = test_scene::motion();
+        let n = Vector3::new(0.0, 0.0, 1.0 / 5.0);
+        camera.k() * (pose.rotation_matrix() + pose.translation * n.transpose()) * camera.k_inv()
+    }
+
+    #[test]
+    fn test_dlt() {
+        let camera = test_scene::camera();
+        let pose = test_scene::motion();
+        let matches = test_scene::matches(&camera, &test_scene::planar_points(10), &pose);
+
+        let expected = plane_homography(&camera);
+        let expected = expected / expected[(2, 2)];
+        for h in [dlt(&matches[..4]).unwrap(), dlt(&matches).unwrap()] {
+            // equal to within the f32 precision of the keypoints
+            assert!((h - expected).norm() / expected.norm() < 1e-3);
+            for (p1, p2) in &matches {
+                assert!(symmetric_transfer_error(&h, &p1.point(), &p2.point()) < 0.05);
+            }
+        }
+        assert!(dlt(&matches[..3]).is_none());
+    }
+
+    #[test]
+    fn test_estimate_homography_ransac() {
+        let camera = test_scene::camera();
+        let pose = test_scene::motion();
+        let mut matches = test_scene::matches(&camera, &test_scene::planar_points(30), &pose);
+        matches.push((
+            KeyPoint::new(10.0, 10.0, 0.0),
+            KeyPoint::new(60.0, 90.0, 0.0),
+        ));
+        matches.push((
+            KeyPoint::new(150.0, 20.0, 0.0),
+            KeyPoint::new(20.0, 140.0, 0.0),
+        ));
+
+        let options = RansacOptions {
+            inlier_threshold: 1.0,
+            ..Default::default()
+        };
+        let mut rnd = Rand::new_with_seed(5);
+        let result = estimate_homography_ransac(&matches, &options, &mut rnd).unwrap();
+        assert_eq!(result.num_inliers(), 30);
+        assert!(!result.inliers[30] && !result.inliers[31]);
+    }
+
+    #[test]
+    fn test_decompose_homography() {
+        let camera = test_scene::camera();
+        let pose = test_scene::motion();
+        let solutions = decompose_homography(&plane_homography(&camera), &camera);
+        assert_eq!(solutions.len(), 8);
+
+        let found = solutions.iter().any(|s| {
+            (s.pose.rotation.inverse() * pose.rotation).angle() < 1e-6
+                && (s.pose.translation - pose.translation.normalize()).norm() < 1e-6
+                && (s.normal - Vector3::z()).norm() < 1e-6
+        });
+        assert!(found, "{:?}", solutions);
+    }
+
+    #[test]
+    fn test_select_model() {
+        let camera = test_scene::camera();
+        let pose = test_scene::motion();
+        let options = RansacOptions {
+            inlier_threshold: 1.0,
+            ..Default::default()
+        };
+
+        for (points, expected) in [
+            (test_scene::planar_points(60), TwoViewModel::Homography),
+            (test_scene::points(60), TwoViewModel::Fundamental),
+        ] {
+            let matches = test_scene::matches(&camera, &points, &pose);
+            let mut rnd = Rand::new_with_seed(5);
+            let h = estimate_homography_ransac(&matches, &options, &mut rnd)
+                .unwrap()
+                .model;
+            let f = estimate_fundamental_ransac(
+                &matches,
+                FundamentalSolver::EightPoint,
+                &options,
+                &mut rnd,
+            )
+            .unwrap()
+            .model;
+            let (model, ratio) = select_model(&h, &f, &matches, 1.0);
+            assert_eq!(model, expected, "ratio {}", ratio);
+        }
+    }
+
+    #[test]
+    fn test_fundamental_score_perfect_match() {
+        let camera = test_scene::camera();
+        let pose = test_scene::motion();
+        let matches = test_scene::matches(&camera, &test_scene::points(1), &pose);
+        let f = crate::fundamental::fundamental_from_essential(
+            &crate::essential::essential_from_pose(&pose),
+            &camera,
+            &camera,
+        );
+        // a perfect match scores the maximum in both directions
+        assert!((fundamental_score(&f, &matches, 1.0) - 2.0 * CHI_SQUARE_2DOF).abs() < 1e-3);
+    }
+}
@@ -18,42 +18,72 @@ pub fn rgb_to_grayscale(img: &[u8], width: usize, height: usize) -> Vec<u8> {
 
 use std::f32::consts::PI;
 
+use crate::error::XdofError;
+use crate::image_view::{ImageView, ImageViewMut, Pixel};
+
+/// Fails with `XdofError::InvalidImage` when `img` does not hold `width` x `height` pixels.
 pub fn greyscale_gaussian_blur(
     img: &[u8],
     width: usize,
     height: usize,
     blur_radius: f32,
-) -> Vec<u8> {
+) -> Result<Vec<u8>, XdofError> {
+    Ok(gaussian_blur_view(
+        ImageView::new(img, width, height)?,
+        blur_radius,
+    ))
+}
+
+/// `greyscale_gaussian_blur` of any pixel type, into a new contiguous buffer.
+pub fn gaussian_blur_view<T: Pixel>(img: ImageView<T>, blur_radius: f32) -> Vec<T> {
+    let (width, height) = (img.width(), img.height());
+    let mut output = vec![T::default(); width * height];
+    let mut view = ImageViewMut::new(&mut output, width, height).unwrap();
+    gaussian_blur_into(img, &mut view, blur_radius);
+    output
+}
+
+/// Blurs `img` into `output`, which has the same width and height.
+pub fn gaussian_blur_into<T: Pixel>(
+    img: ImageView<T>,
+    output: &mut ImageViewMut<T>,
+    blur_radius: f32,
+) {
+    let (width, height) = (img.width(), img.height());
+    assert_eq!(
+        (output.width(), output.height()),
+        (width, height),
+        "the output differs in size"
+    );
     let kernel_size = (blur_radius * 2.0).ceil() as usize | 1;
     let half_kernel = (kernel_size / 2) as i32;
 
     let mut kernel = vec![0f32; kernel_size];
     let mut kernel_sum = 0f32;
 
-    for i in 0..kernel_size {
+    for (i, k) in kernel.iter_mut().enumerate() {
         let x = i as f32 - half_kernel as f32;
         let value =
             (-x * x / (2.0 * blur_radius * blur_radius)).exp() / (blur_radius * (2.0 * PI).sqrt());
-        kernel[i] = value;
+        *k = value;
         kernel_sum += value;
     }
 
     // Normalize the kernel
-    for i in 0..kernel_size {
-        kernel[i] /= kernel_sum;
+    for k in kernel.iter_mut() {
+        *k /= kernel_sum;
     }
 
-    let mut output = vec![0u8; img.len()];
     let mut buffer = vec![0f32; width * height];
 
     // Perform horizontal blur
     for y in 0..height {
+        let row = img.row(y);
         for x in 0..width {
             let mut sum = 0.0;
-            for i in 0..kernel_size {
-                let index = (x as i32 - half_kernel + i as i32).clamp(0, width as i32 - 1) as usize
-                    + y * width;
-                sum += kernel[i] * img[index] as f32;
+            for (i, k) in kernel.iter().enumerate() {
+                let index = (x as i32 - half_kernel + i as i32).clamp(0, width as i32 - 1) as usize;
+                sum += k * row[index].to_f32();
             }
             buffer[x + y * width] = sum;
         }
@@ -61,19 +91,18 @@ pub fn greyscale_gaussian_blur(
 
     // Perform vertical blur
     for y in 0..height {
-        for x in 0..width {
+        let row = output.row_mut(y);
+        for (x, pixel) in row.iter_mut().enumerate() {
             let mut sum = 0.0;
-            for i in 0..kernel_size {
+            for (i, k) in kernel.iter().enumerate() {
                 let index = x
                     + (y as i32 - half_kernel + i as i32).clamp(0, height as i32 - 1) as usize
                         * width;
-                sum += kernel[i] * buffer[index];
+                sum += k * buffer[index];
             }
-            output[x + y * width] = sum.round() as u8;
+            *pixel = T::from_f32(sum);
         }
     }
-
-    output
 }
 
 /****************/
@@ -122,7 +151,7 @@ mod tests {
             153, 153, 153, 153, 153, 119, 119, 119, 119, 119, 86, 86, 86, 86, 86,
         ];
 
-        let output = greyscale_gaussian_blur(&input_image, 5, 3, 2.5);
+        let output = greyscale_gaussian_blur(&input_image, 5, 3, 2.5).unwrap();
         assert_eq!(output, expected_output);
     }
 
@@ -130,8 +159,17 @@ mod tests {
     fn test_greyscale_gaussian_blur_empty_image() {
         let input_image: [u8; 0] = [];
         let expected_output: Vec<u8> = vec![];
-        let output = greyscale_gaussian_blur(&input_image, 0, 0, 2.5);
+        let output = greyscale_gaussian_blur(&input_image, 0, 0, 2.5).unwrap();
         assert_eq!(output, expected_output);
+
+        // a buffer too short for its size is an error
+        assert_eq!(
+            greyscale_gaussian_blur(&input_image, 2, 2, 2.5),
+            Err(XdofError::InvalidImage {
+                expected: 4,
+                len: 0
+            })
+        );
     }
     #[test]
     fn test_rgb_to_grayscale_black_and_white() {
@@ -170,7 +208,7 @@ mod tests {
     fn test_greyscale_gaussian_blur_single_pixel() {
         let input_image: [u8; 1] = [128];
         let expected_output: Vec<u8> = vec![128];
-        let output = greyscale_gaussian_blur(&input_image, 1, 1, 2.5);
+        let output = greyscale_gaussian_blur(&input_image, 1, 1, 2.5).unwrap();
         assert_eq!(output, expected_output);
     }
 
@@ -180,7 +218,29 @@ mod tests {
 
         let expected_output: Vec<u8> = vec![128, 128, 128, 128, 128, 128, 128, 128, 128];
 
-        let output = greyscale_gaussian_blur(&input_image, 3, 3, 2.5);
+        let output = greyscale_gaussian_blur(&input_image, 3, 3, 2.5).unwrap();
         assert_eq!(output, expected_output);
     }
+
+    #[test]
+    fn test_gaussian_blur_view() {
+        // a 4x3 u16 crop of a 6x4 buffer
+        let data = (0..24).map(|i| (i * 1000) as u16).collect::<Vec<_>>();
+        let crop = ImageView::with_stride(&data, 6, 4, 6)
+            .unwrap()
+            .sub_view(1, 1, 4, 3)
+            .unwrap();
+        let copy = crop.to_vec();
+        let expected = gaussian_blur_view(ImageView::new(&copy, 4, 3).unwrap(), 1.5);
+        assert_eq!(gaussian_blur_view(crop, 1.5), expected);
+
+        // into the middle of a larger f32 buffer, leaving the rest alone
+        let input = [2.5f32; 4];
+        let mut output = vec![0.0f32; 16];
+        let mut view = ImageViewMut::new(&mut output, 4, 4).unwrap();
+        let mut middle = view.sub_view_mut(1, 1, 2, 2).unwrap();
+        gaussian_blur_into(ImageView::new(&input, 2, 2).unwrap(), &mut middle, 1.0);
+        assert!((middle.get(1, 1) - 2.5).abs() < 1e-5);
+        assert_eq!(output.iter().filter(|&&v| v == 0.0).count(), 12);
+    }
 }
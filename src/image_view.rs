@@ -0,0 +1,345 @@
+//! Borrowed images with a row stride, so crops and buffers owned elsewhere, e.g. by a camera
+//! driver, are processed without copies.
+
+use crate::common::Image;
+use crate::error::XdofError;
+
+/// A greyscale pixel the image functions work with.
+pub trait Pixel: Copy + PartialOrd + Default + Send + Sync + 'static {
+    fn to_f32(self) -> f32;
+    /// Rounds and saturates for integer pixels.
+    fn from_f32(value: f32) -> Self;
+}
+
+impl Pixel for u8 {
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    fn from_f32(value: f32) -> Self {
+        value.round() as u8
+    }
+}
+
+impl Pixel for u16 {
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    fn from_f32(value: f32) -> Self {
+        value.round() as u16
+    }
+}
+
+impl Pixel for f32 {
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    fn from_f32(value: f32) -> Self {
+        value
+    }
+}
+
+// the buffer length a `width` x `height` image with rows `stride` apart needs, `None` when it
+// overflows
+fn required_len(width: usize, height: usize, stride: usize) -> Option<usize> {
+    if width == 0 || height == 0 {
+        Some(0)
+    } else {
+        (height - 1).checked_mul(stride)?.checked_add(width)
+    }
+}
+
+fn check(len: usize, width: usize, height: usize, stride: usize) -> Result<(), XdofError> {
+    let expected = required_len(width, height, stride).unwrap_or(usize::MAX);
+    if stride < width || len < expected {
+        return Err(XdofError::InvalidImage { expected, len });
+    }
+    Ok(())
+}
+
+// whether `size` pixels from `start` stay within `limit`
+fn fits(start: usize, size: usize, limit: usize) -> bool {
+    start.checked_add(size).is_some_and(|end| end <= limit)
+}
+
+/// A read only `width` x `height` window into a buffer whose rows start `stride` pixels apart.
+/// Views are equal when they have the same size and pixels, whatever their strides.
+#[derive(Debug, Copy, Clone)]
+pub struct ImageView<'a, T> {
+    data: &'a [T],
+    width: usize,
+    height: usize,
+    stride: usize,
+}
+
+impl<'a, T: Pixel> ImageView<'a, T> {
+    /// A view of contiguous rows.
+    pub fn new(data: &'a [T], width: usize, height: usize) -> Result<Self, XdofError> {
+        Self::with_stride(data, width, height, width)
+    }
+
+    pub fn with_stride(
+        data: &'a [T],
+        width: usize,
+        height: usize,
+        stride: usize,
+    ) -> Result<Self, XdofError> {
+        check(data.len(), width, height, stride)?;
+        Ok(Self {
+            data,
+            width,
+            height,
+            stride,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// The distance between the starts of two rows, pixels.
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> T {
+        self.row(y)[x]
+    }
+
+    pub fn row(&self, y: usize) -> &'a [T] {
+        let start = y * self.stride;
+        &self.data[start..start + self.width]
+    }
+
+    /// The `width` x `height` region of interest at (`x`, `y`), or `None` when it does not fit.
+    /// Pixel coordinates in the sub view are relative to its corner.
+    pub fn sub_view(&self, x: usize, y: usize, width: usize, height: usize) -> Option<Self> {
+        if !fits(x, width, self.width) || !fits(y, height, self.height) {
+            return None;
+        }
+        let start = (y * self.stride + x).min(self.data.len());
+        let end = start + required_len(width, height, self.stride)?;
+        Some(Self {
+            data: &self.data[start..end],
+            width,
+            height,
+            stride: self.stride,
+        })
+    }
+
+    /// The pixels, row after row without padding.
+    pub fn to_vec(&self) -> Vec<T> {
+        (0..self.height)
+            .flat_map(|y| self.row(y))
+            .copied()
+            .collect()
+    }
+}
+
+/// A writable `width` x `height` window into a buffer whose rows start `stride` pixels apart.
+/// Compared like `ImageView`.
+#[derive(Debug)]
+pub struct ImageViewMut<'a, T> {
+    data: &'a mut [T],
+    width: usize,
+    height: usize,
+    stride: usize,
+}
+
+impl<'a, T: Pixel> ImageViewMut<'a, T> {
+    pub fn new(data: &'a mut [T], width: usize, height: usize) -> Result<Self, XdofError> {
+        Self::with_stride(data, width, height, width)
+    }
+
+    pub fn with_stride(
+        data: &'a mut [T],
+        width: usize,
+        height: usize,
+        stride: usize,
+    ) -> Result<Self, XdofError> {
+        check(data.len(), width, height, stride)?;
+        Ok(Self {
+            data,
+            width,
+            height,
+            stride,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    pub fn as_view(&self) -> ImageView<'_, T> {
+        ImageView {
+            data: self.data,
+            width: self.width,
+            height: self.height,
+            stride: self.stride,
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> T {
+        self.row(y)[x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, value: T) {
+        self.row_mut(y)[x] = value;
+    }
+
+    pub fn row(&self, y: usize) -> &[T] {
+        let start = y * self.stride;
+        &self.data[start..start + self.width]
+    }
+
+    pub fn row_mut(&mut self, y: usize) -> &mut [T] {
+        let start = y * self.stride;
+        &mut self.data[start..start + self.width]
+    }
+
+    /// The writable region of interest at (`x`, `y`), or `None` when it does not fit.
+    pub fn sub_view_mut(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> Option<ImageViewMut<'_, T>> {
+        if !fits(x, width, self.width) || !fits(y, height, self.height) {
+            return None;
+        }
+        let start = (y * self.stride + x).min(self.data.len());
+        let end = start + required_len(width, height, self.stride)?;
+        Some(ImageViewMut {
+            data: &mut self.data[start..end],
+            width,
+            height,
+            stride: self.stride,
+        })
+    }
+
+    pub fn fill(&mut self, value: T) {
+        for y in 0..self.height {
+            self.row_mut(y).fill(value);
+        }
+    }
+}
+
+impl<T: Pixel> PartialEq for ImageView<'_, T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.width == other.width
+            && self.height == other.height
+            && (0..self.height).all(|y| self.row(y) == other.row(y))
+    }
+}
+
+impl<T: Pixel> PartialEq for ImageViewMut<'_, T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_view() == other.as_view()
+    }
+}
+
+impl Image {
+    /// Borrows the pixels.
+    ///
+    /// # Panics
+    ///
+    /// If `data` does not hold `width` x `height` bytes.
+    pub fn view(&self) -> ImageView<'_, u8> {
+        ImageView::new(&self.data, self.width, self.height).expect("invalid image")
+    }
+
+    /// Borrows the pixels for writing, with the same panics as `view`.
+    pub fn view_mut(&mut self) -> ImageViewMut<'_, u8> {
+        ImageViewMut::new(&mut self.data, self.width, self.height).expect("invalid image")
+    }
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stride_and_sub_view() {
+        // a 3x2 image in rows of 4, the last column is padding
+        let data = [
+            1u16, 2, 3, 0, //
+            4, 5, 6, 0,
+        ];
+        let view = ImageView::with_stride(&data[..7], 3, 2, 4).unwrap();
+        assert_eq!(view.to_vec(), [1, 2, 3, 4, 5, 6]);
+        assert_eq!(view.get(2, 1), 6);
+
+        let roi = view.sub_view(1, 0, 2, 2).unwrap();
+        assert_eq!(roi.to_vec(), [2, 3, 5, 6]);
+        assert_eq!(roi.sub_view(1, 1, 1, 1).unwrap().get(0, 0), 6);
+        assert_eq!(view.sub_view(2, 0, 2, 1), None);
+        assert_eq!(view.sub_view(3, 2, 0, 0).unwrap().to_vec(), []);
+
+        assert_eq!(
+            ImageView::with_stride(&data[..6], 3, 2, 4),
+            Err(XdofError::InvalidImage {
+                expected: 7,
+                len: 6
+            })
+        );
+        assert!(ImageView::with_stride(&data, 3, 2, 2).is_err());
+        assert!(ImageView::with_stride(&data, 3, usize::MAX, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn test_sub_view_overflow() {
+        let mut data = [0u8; 12];
+        let view = ImageView::new(&data, 4, 3).unwrap();
+        assert_eq!(view.sub_view(usize::MAX, 0, 1, 1), None);
+        assert_eq!(view.sub_view(1, 1, 2, usize::MAX), None);
+        let mut view = ImageViewMut::new(&mut data, 4, 3).unwrap();
+        assert_eq!(view.sub_view_mut(1, 0, usize::MAX, 1), None);
+    }
+
+    #[test]
+    fn test_equality_ignores_padding() {
+        let padded = [1u8, 2, 9, 3, 4, 8];
+        let packed = [1u8, 2, 3, 4];
+        let a = ImageView::with_stride(&padded, 2, 2, 3).unwrap();
+        let b = ImageView::new(&packed, 2, 2).unwrap();
+        assert_eq!(a, b);
+        assert_ne!(a, ImageView::new(&packed, 4, 1).unwrap());
+        assert_ne!(a.sub_view(0, 0, 2, 1), b.sub_view(0, 1, 2, 1));
+    }
+
+    #[test]
+    fn test_view_mut() {
+        let mut data = vec![0.0f32; 12];
+        let mut view = ImageViewMut::new(&mut data, 4, 3).unwrap();
+        view.sub_view_mut(1, 1, 2, 2).unwrap().fill(1.0);
+        view.set(0, 0, 2.0);
+        assert_eq!(view.as_view().row(1), [0.0, 1.0, 1.0, 0.0]);
+        assert_eq!(
+            data,
+            [
+                2.0, 0.0, 0.0, 0.0, //
+                0.0, 1.0, 1.0, 0.0, //
+                0.0, 1.0, 1.0, 0.0,
+            ]
+        );
+    }
+}
@@ -0,0 +1,393 @@
+//! Monocular map initialization, bootstrapping the map from two views.
+//!
+//! The motion between the views is estimated both as an essential matrix and as a homography,
+//! since the essential matrix is degenerate for planar scenes (flat ground seen from a drone)
+//! and low parallax. The better explaining model is chosen as in ORB-SLAM, its motion
+//! hypotheses are checked by triangulating the inliers, and the pair is rejected unless one
+//! hypothesis clearly wins with enough well conditioned points. The map is only defined up to
+//! scale, so it is normalized to a median scene depth of 1 in the first view.
+
+use std::fmt;
+
+use nalgebra::Point3;
+
+use crate::camera::Camera;
+use crate::common::*;
+use crate::essential::{estimate_essential_ransac, pose_candidates};
+use crate::fundamental::fundamental_from_essential;
+use crate::homography::TwoViewModel;
+use crate::homography::{decompose_homography, estimate_homography_ransac, select_model};
+use crate::lie::SE3;
+use crate::map::{KeyFrameId, Map, MapError, MapPointId};
+use crate::matcher::match_unique;
+use crate::rand::Rand;
+use crate::ransac::{RansacOptions, Sampling, Scoring};
+use crate::triangulation::{parallax, projection_matrix, triangulate};
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct InitializerOptions {
+    pub max_hamming_distance: usize,
+    /// The inlier threshold is in pixels.
+    pub ransac: RansacOptions,
+    /// The standard deviation of the keypoint positions in pixels, for model selection.
+    pub sigma: f64,
+    /// The largest reprojection error in pixels of a triangulated point in either view.
+    pub max_reprojection_error: f64,
+    /// The least median parallax (radians) of the triangulated points.
+    pub min_parallax: f64,
+    /// The least number of triangulated points.
+    pub min_points: usize,
+    /// Reject the pair when another motion hypothesis triangulates more than this fraction of
+    /// the points of the best one.
+    pub max_ambiguity: f64,
+}
+
+impl Default for InitializerOptions {
+    fn default() -> Self {
+        Self {
+            max_hamming_distance: 300,
+            ransac: RansacOptions {
+                inlier_threshold: 2.0,
+                scoring: Scoring::Msac,
+                sampling: Sampling::Prosac,
+                local_optimization_iterations: 10,
+                ..Default::default()
+            },
+            sigma: 1.0,
+            max_reprojection_error: 4.0,
+            min_parallax: 1f64.to_radians(),
+            min_points: 50,
+            max_ambiguity: 0.7,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum InitializationError {
+    /// Fewer matches than the minimal sample of the estimators.
+    NotEnoughMatches(usize),
+    /// RANSAC found neither a homography nor an essential matrix.
+    NoModel,
+    /// Several motions explain the matches about equally well.
+    Ambiguous,
+    /// The median parallax (radians) of the triangulated points is too low.
+    LowParallax(f64),
+    TooFewPoints(usize),
+    Map(MapError),
+}
+
+impl fmt::Display for InitializationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InitializationError::NotEnoughMatches(n) => write!(f, "only {} matches", n),
+            InitializationError::NoModel => write!(f, "no two view model found"),
+            InitializationError::Ambiguous => write!(f, "ambiguous motion"),
+            InitializationError::LowParallax(p) => {
+                write!(f, "median parallax of {:.2} degrees", p.to_degrees())
+            }
+            InitializationError::TooFewPoints(n) => write!(f, "only {} triangulated points", n),
+            InitializationError::Map(e) => write!(f, "map: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for InitializationError {}
+
+impl From<MapError> for InitializationError {
+    fn from(error: MapError) -> Self {
+        InitializationError::Map(error)
+    }
+}
+
+/// The result of a successful initialization, the two keyframes are in the map.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Initialization {
+    /// At the world origin.
+    pub first: KeyFrameId,
+    pub second: KeyFrameId,
+    /// The pose of the second keyframe, `X_second = pose * X_first`.
+    pub pose: SE3,
+    pub model: TwoViewModel,
+    pub map_points: Vec<MapPointId>,
+    /// The median parallax of the map points in radians.
+    pub parallax: f64,
+}
+
+// the points a motion hypothesis triangulates, by match, and their parallax
+struct Reconstruction {
+    points: Vec<Option<Point3<f64>>>,
+    parallaxes: Vec<f64>,
+}
+
+impl Reconstruction {
+    fn num_points(&self) -> usize {
+        self.parallaxes.len()
+    }
+}
+
+fn reconstruct(
+    pose: &SE3,
+    camera: &Camera,
+    matches: &[(KeyPoint, KeyPoint)],
+    inliers: &[bool],
+    max_reprojection_error: f64,
+) -> Reconstruction {
+    let p1 = projection_matrix(&SE3::identity());
+    let p2 = projection_matrix(pose);
+    let center2 = pose.center();
+    let mut parallaxes = Vec::new();
+
+    let points = matches
+        .iter()
+        .zip(inliers)
+        .map(|((k1, k2), &inlier)| {
+            if !inlier {
+                return None;
+            }
+            let (x1, x2) = (k1.point(), k2.point());
+            let point = triangulate(&p1, &camera.normalize(&x1), &p2, &camera.normalize(&x2))?;
+
+            // in front of both cameras and reprojecting close to both keypoints
+            let reprojected1 = camera.project(&point)?;
+            let reprojected2 = camera.project(&(*pose * point))?;
+            if (reprojected1 - x1).norm() > max_reprojection_error
+                || (reprojected2 - x2).norm() > max_reprojection_error
+            {
+                return None;
+            }
+            parallaxes.push(parallax(&Point3::origin(), &center2, &point));
+            Some(point)
+        })
+        .collect();
+
+    Reconstruction { points, parallaxes }
+}
+
+// NaNs, e.g. the parallax of a point on a camera centre, sort after every number
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(f64::total_cmp);
+    values[values.len() / 2]
+}
+
+/// Initializes an empty `map` from two views of `camera`, given their keypoints and
+/// descriptors.
+pub fn initialize(
+    map: &mut Map,
+    camera: &Camera,
+    first: (&[KeyPoint], &[Descriptor]),
+    second: (&[KeyPoint], &[Descriptor]),
+    options: &InitializerOptions,
+    rnd: &mut Rand,
+) -> Result<Initialization, InitializationError> {
+    // one match per keypoint of the second view, best first for PROSAC
+    let indices = match_unique(first.1, second.1, options.max_hamming_distance);
+    if indices.len() < 8 {
+        return Err(InitializationError::NotEnoughMatches(indices.len()));
+    }
+    let matches = indices
+        .iter()
+        .map(|&(i, j, _)| (first.0[i], second.0[j]))
+        .collect::<Vec<_>>();
+
+    // estimate both models and keep the one that explains the matches better
+    let essential = estimate_essential_ransac(&matches, camera, &options.ransac, rnd);
+    let homography = estimate_homography_ransac(&matches, &options.ransac, rnd);
+    let (model, inliers, candidates) = match (essential, homography) {
+        (None, None) => return Err(InitializationError::NoModel),
+        (Some(e), None) => (
+            TwoViewModel::Fundamental,
+            e.inliers,
+            pose_candidates(&e.model).to_vec(),
+        ),
+        (e, Some(h)) => {
+            let f = e
+                .as_ref()
+                .map(|e| fundamental_from_essential(&e.model, camera, camera));
+            let model = match &f {
+                Some(f) => select_model(&h.model, f, &matches, options.sigma).0,
+                None => TwoViewModel::Homography,
+            };
+            match (model, e) {
+                (TwoViewModel::Fundamental, Some(e)) => {
+                    (model, e.inliers, pose_candidates(&e.model).to_vec())
+                }
+                _ => (
+                    TwoViewModel::Homography,
+                    h.inliers,
+                    decompose_homography(&h.model, camera)
+                        .into_iter()
+                        .map(|d| d.pose)
+                        .collect(),
+                ),
+            }
+        }
+    };
+
+    // the motion hypothesis that triangulates the most points must clearly win
+    let mut reconstructions = candidates
+        .iter()
+        .map(|pose| {
+            let r = reconstruct(
+                pose,
+                camera,
+                &matches,
+                &inliers,
+                options.max_reprojection_error,
+            );
+            (*pose, r)
+        })
+        .collect::<Vec<_>>();
+    reconstructions.sort_by_key(|(_, r)| std::cmp::Reverse(r.num_points()));
+    let (pose, mut best) = match reconstructions.len() {
+        0 => return Err(InitializationError::NoModel),
+        _ => reconstructions.swap_remove(0),
+    };
+    let num_points = best.num_points();
+    let ambiguous = reconstructions
+        .iter()
+        .any(|(_, r)| r.num_points() as f64 > options.max_ambiguity * num_points as f64);
+    if num_points < options.min_points {
+        return Err(InitializationError::TooFewPoints(num_points));
+    }
+    if ambiguous {
+        return Err(InitializationError::Ambiguous);
+    }
+    let parallax = median(&mut best.parallaxes);
+    if parallax < options.min_parallax {
+        return Err(InitializationError::LowParallax(parallax));
+    }
+
+    // scale the map to a median depth of 1
+    let mut depths = best
+        .points
+        .iter()
+        .flatten()
+        .map(|p| p.z)
+        .collect::<Vec<_>>();
+    let scale = 1.0 / median(&mut depths);
+    let pose = SE3::new(pose.rotation, pose.translation * scale);
+
+    let first_id =
+        map.insert_keyframe(SE3::identity(), *camera, first.0.to_vec(), first.1.to_vec())?;
+    let second_id = map.insert_keyframe(pose, *camera, second.0.to_vec(), second.1.to_vec())?;
+    let mut map_points = Vec::with_capacity(num_points);
+    for (point, &(i, j, _)) in best.points.iter().zip(&indices) {
+        if let Some(point) = point {
+            let id = map.insert_map_point(point * scale, first_id, i)?;
+            map.add_observation(id, second_id, j)?;
+            map_points.push(id);
+        }
+    }
+
+    Ok(Initialization {
+        first: first_id,
+        second: second_id,
+        pose,
+        model,
+        map_points,
+        parallax,
+    })
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lie::SO3;
+    use crate::test_scene;
+    use nalgebra::Vector3;
+
+    type View = (Vec<KeyPoint>, Vec<Descriptor>);
+
+    // two views of `points` with shuffled keypoints, so matching has to do some work
+    fn views(points: &[Point3<f64>], pose: &SE3) -> (View, View) {
+        let camera = test_scene::camera();
+        let matches = test_scene::matches(&camera, points, pose);
+        let descriptors = test_scene::descriptors(points.len(), 7);
+        let first = (
+            matches.iter().map(|m| m.0).collect::<Vec<_>>(),
+            descriptors.clone(),
+        );
+        let mut second = (matches.iter().map(|m| m.1).collect::<Vec<_>>(), descriptors);
+        second.0.reverse();
+        second.1.reverse();
+        (first, second)
+    }
+
+    fn run(
+        points: &[Point3<f64>],
+        pose: &SE3,
+    ) -> (Map, Result<Initialization, InitializationError>) {
+        let (first, second) = views(points, pose);
+        let mut map = Map::new();
+        let result = initialize(
+            &mut map,
+            &test_scene::camera(),
+            (&first.0, &first.1),
+            (&second.0, &second.1),
+            &InitializerOptions::default(),
+            &mut Rand::new_with_seed(3),
+        );
+        (map, result)
+    }
+
+    fn check(map: &Map, init: &Initialization, truth: &SE3, num_points: usize) {
+        let rotation_error = (init.pose.rotation.inverse() * truth.rotation).angle();
+        assert!(rotation_error < 1e-3, "{}", rotation_error);
+        let direction = init.pose.translation.normalize();
+        assert!((direction - truth.translation.normalize()).norm() < 1e-2);
+
+        assert_eq!(map.num_keyframes(), 2);
+        assert_eq!(init.map_points.len(), num_points);
+        let mut depths = init
+            .map_points
+            .iter()
+            .map(|&id| map.map_point(id).unwrap().position().z)
+            .collect::<Vec<_>>();
+        assert!((median(&mut depths) - 1.0).abs() < 1e-9);
+        assert_eq!(map.covisibility_weight(init.first, init.second), num_points);
+    }
+
+    #[test]
+    fn test_initialize_general_scene() {
+        let points = test_scene::points(100);
+        let truth = test_scene::motion();
+        let (map, result) = run(&points, &truth);
+        let init = result.unwrap();
+        assert_eq!(init.model, TwoViewModel::Fundamental);
+        check(&map, &init, &truth, 100);
+    }
+
+    #[test]
+    fn test_initialize_planar_scene() {
+        let points = test_scene::planar_points(100);
+        let truth = test_scene::motion();
+        let (map, result) = run(&points, &truth);
+        let init = result.unwrap();
+        assert_eq!(init.model, TwoViewModel::Homography);
+        check(&map, &init, &truth, 100);
+    }
+
+    #[test]
+    fn test_reject_low_parallax() {
+        let points = test_scene::points(100);
+        let rotation = SO3::exp(&Vector3::new(0.0, 0.02, 0.0));
+        let (map, result) = run(&points, &SE3::new(rotation, Vector3::new(-0.02, 0.0, 0.0)));
+        assert!(
+            matches!(result, Err(InitializationError::LowParallax(p)) if p < 1f64.to_radians()),
+            "{:?}",
+            result
+        );
+        assert_eq!(map.num_keyframes(), 0);
+    }
+
+    #[test]
+    fn test_reject_too_few_matches() {
+        let (map, result) = run(&test_scene::points(5), &test_scene::motion());
+        assert_eq!(result, Err(InitializationError::NotEnoughMatches(5)));
+        assert_eq!(map.num_keyframes(), 0);
+    }
+}
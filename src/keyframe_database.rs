@@ -0,0 +1,187 @@
+//! Place recognition, finding the keyframes that look like a set of descriptors.
+//!
+//! Without a trained vocabulary, each descriptor is cut into 16 bit chunks and every
+//! (position, chunk) pair is a visual word. Descriptors of the same scene point share most of
+//! their chunks while unrelated ones rarely share any, so an inverted index from words to
+//! keyframes scores whole images by descriptor similarity without comparing descriptors pairwise.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::common::Descriptor;
+use crate::map::{KeyFrame, KeyFrameId, Map};
+
+// the position of a chunk in the descriptor and its value
+type Word = (u16, u16);
+
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct KeyFrameDatabase {
+    index: HashMap<Word, Vec<KeyFrameId>>,
+    // the distinct words of each keyframe, sorted
+    words: BTreeMap<KeyFrameId, Vec<Word>>,
+}
+
+fn words(descriptors: &[Descriptor]) -> Vec<Word> {
+    let mut words = descriptors
+        .iter()
+        .flat_map(|descriptor| {
+            descriptor
+                .0
+                .chunks_exact(2)
+                .enumerate()
+                .map(|(i, chunk)| (i as u16, u16::from_le_bytes([chunk[0], chunk[1]])))
+        })
+        .collect::<Vec<_>>();
+    words.sort_unstable();
+    words.dedup();
+    words
+}
+
+// the cosine similarity of two sorted word sets
+fn similarity(words1: &[Word], words2: &[Word]) -> f64 {
+    let shared = words1
+        .iter()
+        .filter(|word| words2.binary_search(word).is_ok())
+        .count();
+    if shared == 0 {
+        return 0.0;
+    }
+    shared as f64 / ((words1.len() * words2.len()) as f64).sqrt()
+}
+
+impl KeyFrameDatabase {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A database of every keyframe in `map`.
+    pub fn from_map(map: &Map) -> Self {
+        let mut database = Self::new();
+        for keyframe in map.keyframes() {
+            database.add(keyframe);
+        }
+        database
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    pub fn contains(&self, keyframe: KeyFrameId) -> bool {
+        self.words.contains_key(&keyframe)
+    }
+
+    /// Indexes a keyframe by all its descriptors, replacing any earlier entry.
+    pub fn add(&mut self, keyframe: &KeyFrame) {
+        self.erase(keyframe.id());
+        let words = words(keyframe.descriptors());
+        for word in &words {
+            self.index.entry(*word).or_default().push(keyframe.id());
+        }
+        self.words.insert(keyframe.id(), words);
+    }
+
+    pub fn erase(&mut self, keyframe: KeyFrameId) {
+        for word in self.words.remove(&keyframe).unwrap_or_default() {
+            let keyframes = self.index.get_mut(&word).unwrap();
+            keyframes.retain(|&id| id != keyframe);
+            if keyframes.is_empty() {
+                self.index.remove(&word);
+            }
+        }
+    }
+
+    /// The similarity of two indexed keyframes, as in `query`. Zero if either is missing.
+    pub fn score(&self, a: KeyFrameId, b: KeyFrameId) -> f64 {
+        match (self.words.get(&a), self.words.get(&b)) {
+            (Some(words1), Some(words2)) => similarity(words1, words2),
+            _ => 0.0,
+        }
+    }
+
+    /// The similarity of `descriptors` to an indexed keyframe, as in `query`, e.g. for a
+    /// keyframe not added yet. Zero if the keyframe is missing.
+    pub fn score_descriptors(&self, descriptors: &[Descriptor], keyframe: KeyFrameId) -> f64 {
+        match self.words.get(&keyframe) {
+            Some(words2) => similarity(&words(descriptors), words2),
+            None => 0.0,
+        }
+    }
+
+    /// The keyframes sharing words with `descriptors`, best first, at most `max_results`. The
+    /// score is the cosine similarity of the word sets, between 0 and 1.
+    pub fn query(&self, descriptors: &[Descriptor], max_results: usize) -> Vec<(KeyFrameId, f64)> {
+        let query = words(descriptors);
+        let mut shared = BTreeMap::<KeyFrameId, usize>::new();
+        for word in &query {
+            for &keyframe in self.index.get(word).into_iter().flatten() {
+                *shared.entry(keyframe).or_default() += 1;
+            }
+        }
+
+        let mut scores = shared
+            .into_iter()
+            .map(|(keyframe, count)| {
+                let size = (query.len() * self.words[&keyframe].len()) as f64;
+                (keyframe, count as f64 / size.sqrt())
+            })
+            .collect::<Vec<_>>();
+        scores.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        scores.truncate(max_results);
+        scores
+    }
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lie::SE3;
+    use crate::test_scene;
+
+    #[test]
+    fn test_query() {
+        let camera = test_scene::camera();
+        let key_points = test_scene::points(50)
+            .iter()
+            .map(|p| test_scene::project(&camera, p))
+            .collect::<Vec<_>>();
+        let mut map = Map::new();
+        let places = (0..3)
+            .map(|seed| {
+                let descriptors = test_scene::descriptors(50, seed);
+                map.insert_keyframe(SE3::identity(), camera, key_points.clone(), descriptors)
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let mut database = KeyFrameDatabase::from_map(&map);
+        assert_eq!(database.len(), 3);
+
+        // part of the second place, with a few bits flipped in every descriptor
+        let mut descriptors = test_scene::descriptors(50, 1);
+        descriptors.truncate(30);
+        for (i, descriptor) in descriptors.iter_mut().enumerate() {
+            descriptor.0[i % 32] ^= 0x11;
+        }
+        let results = database.query(&descriptors, 2);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, places[1]);
+        assert!(results[0].1 > 0.5 && results[1].1 < 0.1, "{:?}", results);
+
+        assert_eq!(database.score(places[0], places[0]), 1.0);
+        assert!(database.score(places[0], places[2]) < 0.1);
+
+        database.erase(places[1]);
+        assert!(!database.contains(places[1]));
+        assert!(database
+            .query(&descriptors, 3)
+            .iter()
+            .all(|(_, s)| *s < 0.1));
+    }
+}
@@ -0,0 +1,206 @@
+//! Deciding which tracked frames become keyframes.
+//!
+//! Too few keyframes and tracking loses the map on fast motion, too many and local mapping
+//! cannot keep up and the map fills with redundant points. `DefaultKeyFramePolicy` follows the
+//! ORB-SLAM heuristics, implement `KeyFramePolicy` to tune the trade off for a platform.
+
+use crate::lie::SE3;
+
+/// What a policy knows about the current frame, relative to the last keyframe.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct KeyFrameContext {
+    pub frames_since_keyframe: usize,
+    pub seconds_since_keyframe: f64,
+    /// The map points tracked in the current frame.
+    pub tracked_points: usize,
+    /// The map points the last keyframe observes.
+    pub keyframe_points: usize,
+    /// The pose of the current frame relative to the last keyframe,
+    /// `X_current = motion * X_keyframe`, in map units.
+    pub motion: SE3,
+    /// The median angle (radians) between the rays from the keyframe and the current frame to
+    /// the tracked points.
+    pub median_parallax: f64,
+    /// Whether local mapping has finished with the previous keyframe.
+    pub local_mapping_idle: bool,
+}
+
+impl KeyFrameContext {
+    /// The fraction of the keyframe's points still tracked.
+    pub fn tracked_ratio(&self) -> f64 {
+        if self.keyframe_points == 0 {
+            return 0.0;
+        }
+        self.tracked_points as f64 / self.keyframe_points as f64
+    }
+}
+
+pub trait KeyFramePolicy: Send {
+    fn should_insert(&self, context: &KeyFrameContext) -> bool;
+}
+
+/// Any `Fn(&KeyFrameContext) -> bool` works as a policy.
+impl<F> KeyFramePolicy for F
+where
+    F: Fn(&KeyFrameContext) -> bool + Send,
+{
+    fn should_insert(&self, context: &KeyFrameContext) -> bool {
+        self(context)
+    }
+}
+
+/// Inserts a keyframe when tracking is still good enough to anchor one, and either the view has
+/// changed enough (points lost, moved, turned, parallax gained) or too long has passed.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct DefaultKeyFramePolicy {
+    /// Never insert sooner than this many frames after the last keyframe.
+    pub min_frames: usize,
+    /// Insert after this many frames regardless of motion.
+    pub max_frames: usize,
+    /// Insert after this many seconds regardless of motion.
+    pub max_seconds: f64,
+    /// Insert when fewer than this fraction of the keyframe's points are tracked.
+    pub tracked_ratio: f64,
+    /// Below this many tracked points the frame is too poorly localized to be a keyframe.
+    pub min_tracked_points: usize,
+    /// Insert after moving this far, in map units (the map starts at a median depth of 1).
+    pub max_translation: f64,
+    /// Insert after turning this far, radians.
+    pub max_rotation: f64,
+    /// Insert once the median parallax reaches this, radians.
+    pub max_parallax: f64,
+}
+
+impl Default for DefaultKeyFramePolicy {
+    fn default() -> Self {
+        Self {
+            min_frames: 0,
+            max_frames: 30,
+            max_seconds: 1.0,
+            tracked_ratio: 0.9,
+            min_tracked_points: 15,
+            max_translation: 0.2,
+            max_rotation: 15f64.to_radians(),
+            max_parallax: 3f64.to_radians(),
+        }
+    }
+}
+
+impl KeyFramePolicy for DefaultKeyFramePolicy {
+    fn should_insert(&self, context: &KeyFrameContext) -> bool {
+        if context.tracked_points < self.min_tracked_points
+            || context.frames_since_keyframe < self.min_frames
+        {
+            return false;
+        }
+
+        let timeout = context.frames_since_keyframe >= self.max_frames
+            || context.seconds_since_keyframe >= self.max_seconds;
+        let view_changed = context.tracked_ratio() < self.tracked_ratio
+            || context.motion.translation.norm() >= self.max_translation
+            || context.motion.rotation.angle() >= self.max_rotation
+            || context.median_parallax >= self.max_parallax;
+
+        // a busy local mapper only gets a keyframe when the view changed, not on a timer
+        view_changed || (timeout && context.local_mapping_idle)
+    }
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lie::SO3;
+    use nalgebra::Vector3;
+
+    fn steady() -> KeyFrameContext {
+        KeyFrameContext {
+            frames_since_keyframe: 5,
+            seconds_since_keyframe: 0.1,
+            tracked_points: 190,
+            keyframe_points: 200,
+            motion: SE3::new(SO3::identity(), Vector3::new(0.01, 0.0, 0.0)),
+            median_parallax: 0.2f64.to_radians(),
+            local_mapping_idle: true,
+        }
+    }
+
+    #[test]
+    fn test_default_policy_triggers() {
+        let policy = DefaultKeyFramePolicy::default();
+        assert!(!policy.should_insert(&steady()));
+
+        let triggers = [
+            KeyFrameContext {
+                tracked_points: 150,
+                ..steady()
+            },
+            KeyFrameContext {
+                frames_since_keyframe: 30,
+                ..steady()
+            },
+            KeyFrameContext {
+                seconds_since_keyframe: 2.0,
+                ..steady()
+            },
+            KeyFrameContext {
+                motion: SE3::new(SO3::identity(), Vector3::new(0.0, 0.0, 0.3)),
+                ..steady()
+            },
+            KeyFrameContext {
+                motion: SE3::new(SO3::exp(&Vector3::new(0.0, 0.3, 0.0)), Vector3::zeros()),
+                ..steady()
+            },
+            KeyFrameContext {
+                median_parallax: 5f64.to_radians(),
+                ..steady()
+            },
+        ];
+        for context in &triggers {
+            assert!(policy.should_insert(context), "{:?}", context);
+        }
+    }
+
+    #[test]
+    fn test_default_policy_gates() {
+        let policy = DefaultKeyFramePolicy {
+            min_frames: 3,
+            ..Default::default()
+        };
+        // too few points to anchor a keyframe, however much the view changed
+        let lost = KeyFrameContext {
+            tracked_points: 10,
+            ..steady()
+        };
+        assert!(!policy.should_insert(&lost));
+
+        let early = KeyFrameContext {
+            frames_since_keyframe: 1,
+            tracked_points: 100,
+            ..steady()
+        };
+        assert!(!policy.should_insert(&early));
+
+        // a timeout waits for local mapping
+        let busy = KeyFrameContext {
+            frames_since_keyframe: 40,
+            local_mapping_idle: false,
+            ..steady()
+        };
+        assert!(!policy.should_insert(&busy));
+    }
+
+    #[test]
+    fn test_closure_policy() {
+        let every_tenth: Box<dyn KeyFramePolicy> =
+            Box::new(|context: &KeyFrameContext| context.frames_since_keyframe >= 10);
+        assert!(!every_tenth.should_insert(&steady()));
+        assert!(every_tenth.should_insert(&KeyFrameContext {
+            frames_since_keyframe: 10,
+            ..steady()
+        }));
+    }
+}
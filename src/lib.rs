@@ -1,11 +1,39 @@
+pub mod bundle_adjustment;
+pub mod camera;
 pub mod common;
+pub mod config;
 pub mod descriptors;
+pub mod epipolar;
+pub mod error;
 pub mod essential;
+pub mod extractor;
 pub mod fast_detect; // fast keypoints
+pub mod frame;
+pub mod fundamental;
 pub mod hamming;
+pub mod homography;
 pub mod image_impl; // gray bluring
+pub mod image_view;
+pub mod initializer;
+pub mod keyframe_database;
+pub mod keyframe_policy;
+pub mod lie;
+pub mod local_mapping;
+pub mod loop_closing;
+pub mod map;
+pub mod map_merging;
 pub mod matcher;
+pub mod pnp;
+pub mod pose_graph;
 pub mod rand;
+pub mod ransac;
+pub mod relative_pose;
+pub mod relocalization;
+pub mod robust;
 pub mod slam;
+#[cfg(test)]
+mod test_scene;
+pub mod tracking;
+pub mod triangulation;
 
 pub use slam::*;
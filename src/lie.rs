@@ -0,0 +1,538 @@
+//! The rotation, rigid and similarity transform groups SO(3), SE(3) and Sim(3).
+//!
+//! Poses follow one convention everywhere in the crate: a pose maps world (or first camera)
+//! coordinates into camera coordinates, `X_camera = pose * X_world`, and `a * b` applies `b`
+//! first. So the pose of camera 3 relative to camera 1 is `pose_32 * pose_21`.
+//!
+//! Tangent vectors put the translation part first, like Sophus: `(rho, phi)` for SE(3) and
+//! `(rho, phi, sigma)` for Sim(3), where `phi` is the axis-angle rotation and `sigma` the log of
+//! the scale.
+//!
+//! https://en.wikipedia.org/wiki/Lie_group
+
+use std::ops::Mul;
+
+use nalgebra::{
+    Matrix3, Matrix4, Matrix6, Point3, Rotation3, SMatrix, SVector, UnitQuaternion, Vector3,
+    Vector6,
+};
+
+pub type Vector7 = SVector<f64, 7>;
+pub type Matrix7 = SMatrix<f64, 7, 7>;
+
+const SMALL_ANGLE: f64 = 1e-10;
+
+/// The skew symmetric matrix of `v`, `hat(v) * w = v x w`.
+pub fn hat(v: &Vector3<f64>) -> Matrix3<f64> {
+    v.cross_matrix()
+}
+
+/// The inverse of `hat`, reads `v` back from a skew symmetric matrix.
+pub fn vee(m: &Matrix3<f64>) -> Vector3<f64> {
+    Vector3::new(m[(2, 1)], m[(0, 2)], m[(1, 0)])
+}
+
+/// A 3D rotation.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct SO3 {
+    quaternion: UnitQuaternion<f64>,
+}
+
+impl SO3 {
+    pub fn identity() -> Self {
+        Self {
+            quaternion: UnitQuaternion::identity(),
+        }
+    }
+
+    /// The closest rotation to `m`, which only needs to be approximately orthonormal.
+    pub fn from_matrix(m: &Matrix3<f64>) -> Self {
+        let rotation = Rotation3::from_matrix_eps(m, 1e-12, 100, Rotation3::identity());
+        Self {
+            quaternion: UnitQuaternion::from_rotation_matrix(&rotation),
+        }
+    }
+
+    pub fn from_quaternion(quaternion: UnitQuaternion<f64>) -> Self {
+        Self { quaternion }
+    }
+
+    /// The rotation of `phi.norm()` radians about `phi`.
+    pub fn exp(phi: &Vector3<f64>) -> Self {
+        Self {
+            quaternion: UnitQuaternion::from_scaled_axis(*phi),
+        }
+    }
+
+    /// The axis-angle vector of the rotation, with an angle in `[0, pi]`.
+    pub fn log(&self) -> Vector3<f64> {
+        self.quaternion.scaled_axis()
+    }
+
+    pub fn inverse(&self) -> Self {
+        Self {
+            quaternion: self.quaternion.inverse(),
+        }
+    }
+
+    pub fn matrix(&self) -> Matrix3<f64> {
+        self.quaternion.to_rotation_matrix().into_inner()
+    }
+
+    pub fn quaternion(&self) -> &UnitQuaternion<f64> {
+        &self.quaternion
+    }
+
+    /// The rotation angle in radians.
+    pub fn angle(&self) -> f64 {
+        self.quaternion.angle()
+    }
+
+    /// Maps tangent vectors at the identity through conjugation, `R * exp(phi) * R^T =
+    /// exp(Adj * phi)`. For SO(3) that is the rotation matrix itself.
+    pub fn adjoint(&self) -> Matrix3<f64> {
+        self.matrix()
+    }
+
+    /// The geodesic from `self` (`t = 0`) to `other` (`t = 1`).
+    pub fn interpolate(&self, other: &Self, t: f64) -> Self {
+        *self * Self::exp(&((self.inverse() * *other).log() * t))
+    }
+
+    pub fn transform_vector(&self, v: &Vector3<f64>) -> Vector3<f64> {
+        self.quaternion * v
+    }
+
+    pub fn transform_point(&self, p: &Point3<f64>) -> Point3<f64> {
+        self.quaternion * p
+    }
+}
+
+impl Default for SO3 {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+impl Mul for SO3 {
+    type Output = SO3;
+
+    fn mul(self, rhs: SO3) -> SO3 {
+        SO3 {
+            quaternion: self.quaternion * rhs.quaternion,
+        }
+    }
+}
+
+impl Mul<Point3<f64>> for SO3 {
+    type Output = Point3<f64>;
+
+    fn mul(self, rhs: Point3<f64>) -> Point3<f64> {
+        self.transform_point(&rhs)
+    }
+}
+
+impl Mul<Vector3<f64>> for SO3 {
+    type Output = Vector3<f64>;
+
+    fn mul(self, rhs: Vector3<f64>) -> Vector3<f64> {
+        self.transform_vector(&rhs)
+    }
+}
+
+/// The left jacobian of Sim(3) (and, with `sigma = 0`, of SE(3)), which maps the tangent
+/// translation `rho` to the actual translation, `t = W * rho`.
+fn calc_w(phi: &Vector3<f64>, sigma: f64) -> Matrix3<f64> {
+    let theta = phi.norm();
+    let omega = hat(phi);
+    let omega2 = omega * omega;
+    let scale = sigma.exp();
+
+    let (a, b, c) = if sigma.abs() < SMALL_ANGLE {
+        if theta < SMALL_ANGLE {
+            (0.5, 1.0 / 6.0, 1.0)
+        } else {
+            let theta2 = theta * theta;
+            (
+                (1.0 - theta.cos()) / theta2,
+                (theta - theta.sin()) / (theta2 * theta),
+                1.0,
+            )
+        }
+    } else {
+        let c = (scale - 1.0) / sigma;
+        if theta < SMALL_ANGLE {
+            let sigma2 = sigma * sigma;
+            (
+                ((sigma - 1.0) * scale + 1.0) / sigma2,
+                (scale * 0.5 * sigma2 + scale - 1.0 - sigma * scale) / (sigma2 * sigma),
+                c,
+            )
+        } else {
+            let theta2 = theta * theta;
+            let sa = scale * theta.sin();
+            let sb = scale * theta.cos();
+            let sc = theta2 + sigma * sigma;
+            (
+                (sa * sigma + (1.0 - sb) * theta) / (theta * sc),
+                (c - ((sb - 1.0) * sigma + sa * theta) / sc) / theta2,
+                c,
+            )
+        }
+    };
+
+    omega * a + omega2 * b + Matrix3::identity() * c
+}
+
+/// A rigid body transform, `x -> R * x + t`.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub struct SE3 {
+    pub rotation: SO3,
+    pub translation: Vector3<f64>,
+}
+
+impl SE3 {
+    pub fn new(rotation: SO3, translation: Vector3<f64>) -> Self {
+        Self {
+            rotation,
+            translation,
+        }
+    }
+
+    pub fn identity() -> Self {
+        Self::default()
+    }
+
+    /// From a rotation matrix, which only needs to be approximately orthonormal.
+    pub fn from_parts(rotation: &Matrix3<f64>, translation: &Vector3<f64>) -> Self {
+        Self::new(SO3::from_matrix(rotation), *translation)
+    }
+
+    /// `xi = (rho, phi)`.
+    pub fn exp(xi: &Vector6<f64>) -> Self {
+        let rho = xi.fixed_rows::<3>(0).into_owned();
+        let phi = xi.fixed_rows::<3>(3).into_owned();
+        Self::new(SO3::exp(&phi), calc_w(&phi, 0.0) * rho)
+    }
+
+    pub fn log(&self) -> Vector6<f64> {
+        let phi = self.rotation.log();
+        // W is invertible for rotations below pi
+        let rho = calc_w(&phi, 0.0)
+            .try_inverse()
+            .map_or(self.translation, |w_inv| w_inv * self.translation);
+        let mut xi = Vector6::zeros();
+        xi.fixed_rows_mut::<3>(0).copy_from(&rho);
+        xi.fixed_rows_mut::<3>(3).copy_from(&phi);
+        xi
+    }
+
+    pub fn inverse(&self) -> Self {
+        let rotation = self.rotation.inverse();
+        Self::new(rotation, -(rotation * self.translation))
+    }
+
+    pub fn rotation_matrix(&self) -> Matrix3<f64> {
+        self.rotation.matrix()
+    }
+
+    /// The homogeneous 4x4 matrix.
+    pub fn matrix(&self) -> Matrix4<f64> {
+        let mut m = Matrix4::identity();
+        m.fixed_view_mut::<3, 3>(0, 0)
+            .copy_from(&self.rotation_matrix());
+        m.fixed_view_mut::<3, 1>(0, 3).copy_from(&self.translation);
+        m
+    }
+
+    /// Where the origin of the transformed frame sits in the original one. For a camera pose,
+    /// the camera centre in world coordinates.
+    pub fn center(&self) -> Point3<f64> {
+        Point3::from(self.inverse().translation)
+    }
+
+    /// `T * exp(xi) * T^-1 = exp(Adj * xi)`.
+    pub fn adjoint(&self) -> Matrix6<f64> {
+        let r = self.rotation_matrix();
+        let mut adj = Matrix6::zeros();
+        adj.fixed_view_mut::<3, 3>(0, 0).copy_from(&r);
+        adj.fixed_view_mut::<3, 3>(0, 3)
+            .copy_from(&(hat(&self.translation) * r));
+        adj.fixed_view_mut::<3, 3>(3, 3).copy_from(&r);
+        adj
+    }
+
+    /// The geodesic from `self` (`t = 0`) to `other` (`t = 1`).
+    pub fn interpolate(&self, other: &Self, t: f64) -> Self {
+        *self * Self::exp(&((self.inverse() * *other).log() * t))
+    }
+
+    pub fn transform_point(&self, p: &Point3<f64>) -> Point3<f64> {
+        self.rotation * *p + self.translation
+    }
+
+    /// Rotates a direction, directions are not translated.
+    pub fn transform_vector(&self, v: &Vector3<f64>) -> Vector3<f64> {
+        self.rotation * *v
+    }
+}
+
+impl Mul for SE3 {
+    type Output = SE3;
+
+    fn mul(self, rhs: SE3) -> SE3 {
+        SE3::new(
+            self.rotation * rhs.rotation,
+            self.rotation * rhs.translation + self.translation,
+        )
+    }
+}
+
+impl Mul<Point3<f64>> for SE3 {
+    type Output = Point3<f64>;
+
+    fn mul(self, rhs: Point3<f64>) -> Point3<f64> {
+        self.transform_point(&rhs)
+    }
+}
+
+/// A similarity transform, `x -> s * R * x + t`. Monocular maps drift in scale as well as in
+/// pose, so loop closures are expressed in Sim(3).
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct Sim3 {
+    pub rotation: SO3,
+    pub translation: Vector3<f64>,
+    pub scale: f64,
+}
+
+impl Sim3 {
+    pub fn new(rotation: SO3, translation: Vector3<f64>, scale: f64) -> Self {
+        Self {
+            rotation,
+            translation,
+            scale,
+        }
+    }
+
+    pub fn identity() -> Self {
+        Self::new(SO3::identity(), Vector3::zeros(), 1.0)
+    }
+
+    /// `xi = (rho, phi, sigma)`, the scale is `exp(sigma)`.
+    pub fn exp(xi: &Vector7) -> Self {
+        let rho = xi.fixed_rows::<3>(0).into_owned();
+        let phi = xi.fixed_rows::<3>(3).into_owned();
+        let sigma = xi[6];
+        Self::new(SO3::exp(&phi), calc_w(&phi, sigma) * rho, sigma.exp())
+    }
+
+    pub fn log(&self) -> Vector7 {
+        let phi = self.rotation.log();
+        let sigma = self.scale.ln();
+        let rho = calc_w(&phi, sigma)
+            .try_inverse()
+            .map_or(self.translation, |w_inv| w_inv * self.translation);
+        let mut xi = Vector7::zeros();
+        xi.fixed_rows_mut::<3>(0).copy_from(&rho);
+        xi.fixed_rows_mut::<3>(3).copy_from(&phi);
+        xi[6] = sigma;
+        xi
+    }
+
+    pub fn inverse(&self) -> Self {
+        let rotation = self.rotation.inverse();
+        let scale = 1.0 / self.scale;
+        Self::new(rotation, -(rotation * self.translation) * scale, scale)
+    }
+
+    /// The homogeneous 4x4 matrix.
+    pub fn matrix(&self) -> Matrix4<f64> {
+        let mut m = Matrix4::identity();
+        m.fixed_view_mut::<3, 3>(0, 0)
+            .copy_from(&(self.rotation.matrix() * self.scale));
+        m.fixed_view_mut::<3, 1>(0, 3).copy_from(&self.translation);
+        m
+    }
+
+    /// `S * exp(xi) * S^-1 = exp(Adj * xi)`.
+    pub fn adjoint(&self) -> Matrix7 {
+        let r = self.rotation.matrix();
+        let mut adj = Matrix7::zeros();
+        adj.fixed_view_mut::<3, 3>(0, 0)
+            .copy_from(&(r * self.scale));
+        adj.fixed_view_mut::<3, 3>(0, 3)
+            .copy_from(&(hat(&self.translation) * r));
+        adj.fixed_view_mut::<3, 1>(0, 6)
+            .copy_from(&-self.translation);
+        adj.fixed_view_mut::<3, 3>(3, 3).copy_from(&r);
+        adj[(6, 6)] = 1.0;
+        adj
+    }
+
+    /// The geodesic from `self` (`t = 0`) to `other` (`t = 1`).
+    pub fn interpolate(&self, other: &Self, t: f64) -> Self {
+        *self * Self::exp(&((self.inverse() * *other).log() * t))
+    }
+
+    pub fn transform_point(&self, p: &Point3<f64>) -> Point3<f64> {
+        (self.rotation * *p) * self.scale + self.translation
+    }
+
+    /// The rigid part, dropping the scale. Used to turn a corrected Sim(3) keyframe pose back
+    /// into a camera pose: a camera at `S * X` has the pose `(R, t / s)`.
+    pub fn to_se3(&self) -> SE3 {
+        SE3::new(self.rotation, self.translation / self.scale)
+    }
+}
+
+impl Default for Sim3 {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+impl From<SE3> for Sim3 {
+    fn from(pose: SE3) -> Self {
+        Sim3::new(pose.rotation, pose.translation, 1.0)
+    }
+}
+
+impl Mul for Sim3 {
+    type Output = Sim3;
+
+    fn mul(self, rhs: Sim3) -> Sim3 {
+        Sim3::new(
+            self.rotation * rhs.rotation,
+            (self.rotation * rhs.translation) * self.scale + self.translation,
+            self.scale * rhs.scale,
+        )
+    }
+}
+
+impl Mul<Point3<f64>> for Sim3 {
+    type Output = Point3<f64>;
+
+    fn mul(self, rhs: Point3<f64>) -> Point3<f64> {
+        self.transform_point(&rhs)
+    }
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn se3_tangent() -> Vector6<f64> {
+        Vector6::new(0.3, -0.2, 1.1, 0.4, -0.7, 0.25)
+    }
+
+    fn sim3_tangent() -> Vector7 {
+        Vector7::from_column_slice(&[0.3, -0.2, 1.1, 0.4, -0.7, 0.25, 0.3])
+    }
+
+    #[test]
+    fn test_so3_exp_log() {
+        let phi = Vector3::new(0.4, -0.7, 0.25);
+        let r = SO3::exp(&phi);
+        assert!((r.log() - phi).norm() < 1e-12);
+        assert!((r.angle() - phi.norm()).abs() < 1e-12);
+        let expected = Rotation3::new(phi).into_inner();
+        assert!((r.matrix() - expected).norm() < 1e-12);
+        assert!((SO3::from_matrix(&expected).log() - phi).norm() < 1e-12);
+        assert!((r * r.inverse()).angle() < 1e-12);
+        assert_eq!(vee(&hat(&phi)), phi);
+    }
+
+    #[test]
+    fn test_se3_exp_log() {
+        let xi = se3_tangent();
+        let pose = SE3::exp(&xi);
+        assert!((pose.log() - xi).norm() < 1e-12);
+        // a one parameter subgroup, exp(2 xi) = exp(xi) * exp(xi)
+        let twice = SE3::exp(&(xi * 2.0));
+        assert!(((pose * pose).matrix() - twice.matrix()).norm() < 1e-12);
+        assert!((SE3::exp(&Vector6::zeros()).matrix() - Matrix4::identity()).norm() < 1e-12);
+    }
+
+    #[test]
+    fn test_se3_compose_inverse() {
+        let a = SE3::exp(&se3_tangent());
+        let b = SE3::from_parts(
+            &Rotation3::from_euler_angles(0.1, 0.2, -0.3).into_inner(),
+            &Vector3::new(1.0, 2.0, 3.0),
+        );
+        let p = Point3::new(0.5, -1.0, 2.0);
+
+        assert!(((a * b) * p - a * (b * p)).norm() < 1e-12);
+        assert!(((a * a.inverse()).matrix() - Matrix4::identity()).norm() < 1e-12);
+        assert!(((a * b).matrix() - a.matrix() * b.matrix()).norm() < 1e-12);
+        assert!((a * a.center()).coords.norm() < 1e-12);
+    }
+
+    #[test]
+    fn test_se3_adjoint() {
+        let pose = SE3::exp(&se3_tangent());
+        let xi = Vector6::new(0.1, 0.2, -0.1, 0.05, 0.02, -0.3);
+        let conjugated = pose * SE3::exp(&xi) * pose.inverse();
+        let expected = SE3::exp(&(pose.adjoint() * xi));
+        assert!((conjugated.matrix() - expected.matrix()).norm() < 1e-12);
+    }
+
+    #[test]
+    fn test_se3_interpolate() {
+        let a = SE3::exp(&se3_tangent());
+        let b = SE3::exp(&-se3_tangent());
+        assert!((a.interpolate(&b, 0.0).matrix() - a.matrix()).norm() < 1e-12);
+        assert!((a.interpolate(&b, 1.0).matrix() - b.matrix()).norm() < 1e-12);
+        // halfway between exp(xi) and exp(-xi) is the identity
+        assert!((a.interpolate(&b, 0.5).matrix() - Matrix4::identity()).norm() < 1e-12);
+    }
+
+    #[test]
+    fn test_sim3_exp_log() {
+        let xi = sim3_tangent();
+        let s = Sim3::exp(&xi);
+        assert!((s.log() - xi).norm() < 1e-12);
+        assert!((s.scale - 0.3f64.exp()).abs() < 1e-12);
+        let twice = Sim3::exp(&(xi * 2.0));
+        assert!(((s * s).matrix() - twice.matrix()).norm() < 1e-12);
+
+        // no rotation and no scale reduce to the simpler cases
+        let pure = Vector7::from_column_slice(&[0.3, -0.2, 1.1, 0.0, 0.0, 0.0, 0.0]);
+        assert!((Sim3::exp(&pure).translation - Vector3::new(0.3, -0.2, 1.1)).norm() < 1e-12);
+        let scaled = Vector7::from_column_slice(&[0.3, -0.2, 1.1, 0.0, 0.0, 0.0, 0.5]);
+        let s = Sim3::exp(&scaled);
+        assert!(((s * s).matrix() - Sim3::exp(&(scaled * 2.0)).matrix()).norm() < 1e-12);
+    }
+
+    #[test]
+    fn test_sim3_compose_inverse_adjoint() {
+        let a = Sim3::exp(&sim3_tangent());
+        let b = Sim3::from(SE3::exp(&se3_tangent()));
+        let p = Point3::new(0.5, -1.0, 2.0);
+
+        assert!(((a * b) * p - a * (b * p)).norm() < 1e-12);
+        assert!(((a * a.inverse()).matrix() - Matrix4::identity()).norm() < 1e-12);
+        assert!(((a * b).matrix() - a.matrix() * b.matrix()).norm() < 1e-12);
+
+        let xi = Vector7::from_column_slice(&[0.1, 0.2, -0.1, 0.05, 0.02, -0.3, 0.1]);
+        let conjugated = a * Sim3::exp(&xi) * a.inverse();
+        let expected = Sim3::exp(&(a.adjoint() * xi));
+        assert!((conjugated.matrix() - expected.matrix()).norm() < 1e-12);
+
+        let half = Sim3::identity().interpolate(&a, 0.5);
+        assert!(((half * half).matrix() - a.matrix()).norm() < 1e-12);
+    }
+
+    #[test]
+    fn test_sim3_to_se3() {
+        let pose = SE3::exp(&se3_tangent());
+        let s = Sim3::new(pose.rotation, pose.translation * 2.0, 2.0);
+        assert!((s.to_se3().matrix() - pose.matrix()).norm() < 1e-12);
+    }
+}
@@ -0,0 +1,584 @@
+//! Local mapping, the back end that grows and polishes the map around each new keyframe.
+//!
+//! For every keyframe handed over by tracking it
+//! 1. culls the recently created points that tracking keeps failing to find,
+//! 2. triangulates new points from the keyframe's unmatched features and those of its
+//!    covisible keyframes, and
+//! 3. runs bundle adjustment over the keyframe's covisibility neighbourhood.
+//!
+//! `LocalMapper` does this synchronously, `LocalMappingThread` runs it on its own thread next to
+//! tracking, sharing the map behind a mutex.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use crate::bundle_adjustment::{BundleAdjustment, BundleAdjustmentOptions};
+use crate::common::Descriptor;
+use crate::epipolar::sampson_distance;
+use crate::essential::essential_from_pose;
+use crate::map::{KeyFrameId, Map, MapError, MapPointId};
+use crate::matcher::match_unique;
+use crate::robust::{RobustKernel, MAX_REPROJECTION_ERROR};
+use crate::triangulation::{parallax, projection_matrix, triangulate};
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct LocalMappingOptions {
+    /// How many of the best covisible keyframes to triangulate new points with.
+    pub max_neighbours: usize,
+    pub max_hamming_distance: usize,
+    /// Skip neighbours whose baseline is below this fraction of their median scene depth.
+    pub min_baseline_ratio: f64,
+    /// The largest Sampson distance in pixels of a candidate match.
+    pub epipolar_threshold: f64,
+    /// The largest reprojection error in pixels of a new point, and of an observation after
+    /// bundle adjustment.
+    pub max_reprojection_error: f64,
+    /// The least parallax (radians) of a new point.
+    pub min_parallax: f64,
+    /// Cull recent points found in fewer than this fraction of the frames expected to see them.
+    pub min_found_ratio: f64,
+    /// Cull recent points that have fewer observations two keyframes after their creation.
+    pub min_observations: usize,
+    pub bundle_adjustment: BundleAdjustmentOptions,
+}
+
+impl Default for LocalMappingOptions {
+    fn default() -> Self {
+        Self {
+            max_neighbours: 10,
+            max_hamming_distance: 100,
+            min_baseline_ratio: 0.01,
+            epipolar_threshold: 2.0,
+            max_reprojection_error: MAX_REPROJECTION_ERROR,
+            min_parallax: 1f64.to_radians(),
+            min_found_ratio: 0.25,
+            min_observations: 3,
+            bundle_adjustment: BundleAdjustmentOptions {
+                max_iterations: 10,
+                kernel: RobustKernel::Huber(MAX_REPROJECTION_ERROR),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// What processing a keyframe changed.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct LocalMappingReport {
+    pub created_points: Vec<MapPointId>,
+    pub culled_points: usize,
+    /// Observations dropped as outliers by bundle adjustment.
+    pub removed_observations: usize,
+}
+
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct LocalMapper {
+    pub options: LocalMappingOptions,
+    // points created in the last few keyframes, still on probation
+    recent_points: Vec<MapPointId>,
+}
+
+impl LocalMapper {
+    pub fn new(options: LocalMappingOptions) -> Self {
+        Self {
+            options,
+            recent_points: Vec::new(),
+        }
+    }
+
+    /// Processes a keyframe tracking just inserted into `map`.
+    pub fn process_keyframe(
+        &mut self,
+        map: &mut Map,
+        keyframe: KeyFrameId,
+    ) -> Result<LocalMappingReport, MapError> {
+        if map.keyframe(keyframe).is_none() {
+            return Err(MapError::UnknownKeyFrame(keyframe));
+        }
+        let culled_points = self.cull_points(map, keyframe);
+        let created_points = self.create_points(map, keyframe)?;
+        self.recent_points.extend(&created_points);
+        let removed_observations = local_bundle_adjustment(
+            map,
+            keyframe,
+            &self.options.bundle_adjustment,
+            self.options.max_reprojection_error,
+        )?;
+
+        Ok(LocalMappingReport {
+            created_points,
+            culled_points,
+            removed_observations,
+        })
+    }
+
+    fn cull_points(&mut self, map: &mut Map, keyframe: KeyFrameId) -> usize {
+        let options = &self.options;
+        let mut culled = 0;
+        self.recent_points.retain(|&id| {
+            let (age, cull) = match map.map_point(id) {
+                None => return false,
+                Some(mp) => {
+                    let age = keyframe.0.saturating_sub(mp.first_keyframe().0);
+                    let cull = mp.found_ratio() < options.min_found_ratio
+                        || (age >= 2 && mp.num_observations() < options.min_observations);
+                    (age, cull)
+                }
+            };
+            if cull {
+                map.remove_map_point(id).unwrap();
+                culled += 1;
+                return false;
+            }
+            // points that survive three keyframes are trusted
+            age < 3
+        });
+        culled
+    }
+
+    fn create_points(
+        &mut self,
+        map: &mut Map,
+        keyframe: KeyFrameId,
+    ) -> Result<Vec<MapPointId>, MapError> {
+        let options = &self.options;
+        let kf = map.keyframe(keyframe).unwrap().clone();
+        let mut created = Vec::new();
+
+        for neighbour in map.best_covisible_keyframes(keyframe, options.max_neighbours) {
+            let other = map.keyframe(neighbour).unwrap().clone();
+            let baseline = (kf.center() - other.center()).norm();
+            match map.median_depth(neighbour) {
+                Some(depth) if baseline / depth >= options.min_baseline_ratio => {}
+                _ => continue,
+            }
+
+            // match the features neither keyframe has a point for yet
+            let free = |map: &Map, id: KeyFrameId| {
+                map.keyframe(id)
+                    .unwrap()
+                    .map_points()
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, point)| point.is_none())
+                    .map(|(i, _)| i)
+                    .collect::<Vec<_>>()
+            };
+            let free1 = free(map, keyframe);
+            let free2 = free(map, neighbour);
+            let descriptors = |indices: &[usize], all: &[Descriptor]| {
+                indices.iter().map(|&i| all[i].clone()).collect::<Vec<_>>()
+            };
+            let matches = match_unique(
+                &descriptors(&free1, kf.descriptors()),
+                &descriptors(&free2, other.descriptors()),
+                options.max_hamming_distance,
+            );
+
+            let relative = *other.pose() * kf.pose().inverse();
+            let essential = essential_from_pose(&relative);
+            let focal = (kf.camera().fx + kf.camera().fy) / 2.0;
+            let p1 = projection_matrix(kf.pose());
+            let p2 = projection_matrix(other.pose());
+
+            for (a, b, _) in matches {
+                let (i, j) = (free1[a], free2[b]);
+                let x1 = kf.key_points()[i].point();
+                let x2 = other.key_points()[j].point();
+                let n1 = kf.camera().normalize(&x1);
+                let n2 = other.camera().normalize(&x2);
+                if sampson_distance(&essential, &n1, &n2) * focal > options.epipolar_threshold {
+                    continue;
+                }
+
+                let point = match triangulate(&p1, &n1, &p2, &n2) {
+                    Some(point) => point,
+                    None => continue,
+                };
+                if parallax(&kf.center(), &other.center(), &point) < options.min_parallax {
+                    continue;
+                }
+                let in_front_and_close = [(&kf, x1), (&other, x2)].iter().all(|(k, x)| {
+                    k.camera()
+                        .project(&(*k.pose() * point))
+                        .is_some_and(|p| (p - x).norm() <= options.max_reprojection_error)
+                });
+                if !in_front_and_close {
+                    continue;
+                }
+
+                let id = map.insert_map_point(point, keyframe, i)?;
+                map.add_observation(id, neighbour, j)?;
+                created.push(id);
+            }
+        }
+
+        Ok(created)
+    }
+}
+
+/// Bundle adjustment of `keyframe`, its covisible keyframes and all the points they see. The
+/// other keyframes observing those points are held fixed, as is the first keyframe of the map,
+/// so the local solution stays attached to the rest of the map. Observations reprojecting
+/// further than `max_error` pixels afterwards are removed, and their count returned.
+pub fn local_bundle_adjustment(
+    map: &mut Map,
+    keyframe: KeyFrameId,
+    options: &BundleAdjustmentOptions,
+    max_error: f64,
+) -> Result<usize, MapError> {
+    let mut local = map
+        .covisible_keyframes(keyframe, 1)
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect::<BTreeSet<_>>();
+    local.insert(keyframe);
+    let points = local
+        .iter()
+        .flat_map(|&id| map.keyframe(id).unwrap().map_points().iter().flatten())
+        .copied()
+        .collect::<BTreeSet<_>>();
+    let mut keyframes = local.clone();
+    for point in &points {
+        keyframes.extend(map.map_point(*point).unwrap().observations().keys());
+    }
+
+    // pin the gauge, the first keyframe if it takes part or else the oldest local one
+    let first = map.keyframes().next().map(|kf| kf.id());
+    let has_fixed = keyframes.len() > local.len() || first.is_some_and(|f| local.contains(&f));
+    let oldest = *local.iter().next().unwrap();
+    let is_fixed =
+        |id: KeyFrameId| !local.contains(&id) || Some(id) == first || (!has_fixed && id == oldest);
+
+    let mut ba = BundleAdjustment::new();
+    let mut pose_index = BTreeMap::new();
+    for &id in &keyframes {
+        let kf = map.keyframe(id).unwrap();
+        pose_index.insert(id, ba.add_pose(*kf.camera(), *kf.pose(), is_fixed(id)));
+    }
+    let mut observations = Vec::new();
+    let mut point_index = BTreeMap::new();
+    for &point in &points {
+        let mp = map.map_point(point).unwrap();
+        let index = ba.add_point(*mp.position(), false);
+        point_index.insert(point, index);
+        for (&id, &key_point) in mp.observations() {
+            let pixel = map.keyframe(id).unwrap().key_points()[key_point].point();
+            ba.add_observation(pose_index[&id], index, pixel).unwrap();
+            observations.push((point, id));
+        }
+    }
+
+    let summary = ba.optimize(options);
+
+    for (&id, &index) in &pose_index {
+        if !is_fixed(id) {
+            map.set_keyframe_pose(id, ba.pose(index))?;
+        }
+    }
+    for (&point, &index) in &point_index {
+        map.set_map_point_position(point, ba.point(index))?;
+    }
+    let mut removed = 0;
+    for index in summary.outliers(max_error) {
+        let (point, id) = observations[index];
+        // the point may already be gone with its last observation
+        if map.map_point(point).is_some() {
+            map.remove_observation(point, id)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum LocalMappingError {
+    /// Processing a keyframe failed, e.g. because it was removed from the map meanwhile.
+    Map(KeyFrameId, MapError),
+    /// Another thread panicked holding the map, which may be inconsistent. The keyframes queued
+    /// since are skipped.
+    Poisoned,
+    /// The mapping thread panicked.
+    Panicked,
+}
+
+impl fmt::Display for LocalMappingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LocalMappingError::Map(keyframe, e) => {
+                write!(f, "processing keyframe {} failed: {}", keyframe.0, e)
+            }
+            LocalMappingError::Poisoned => write!(f, "the map lock is poisoned"),
+            LocalMappingError::Panicked => write!(f, "the local mapping thread panicked"),
+        }
+    }
+}
+
+impl std::error::Error for LocalMappingError {}
+
+/// A `LocalMapper` running on its own thread.
+///
+/// The map stays locked while a keyframe is processed, local bundle adjustment included, so
+/// tracking blocks on the map meanwhile.
+pub struct LocalMappingThread {
+    sender: Option<Sender<KeyFrameId>>,
+    errors: Receiver<LocalMappingError>,
+    pending: Arc<AtomicUsize>,
+    handle: Option<JoinHandle<Result<LocalMapper, LocalMappingError>>>,
+}
+
+impl LocalMappingThread {
+    pub fn spawn(mut mapper: LocalMapper, map: Arc<Mutex<Map>>) -> Self {
+        let (sender, receiver) = channel::<KeyFrameId>();
+        let (error_sender, errors) = channel();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let counter = pending.clone();
+        let handle = std::thread::spawn(move || {
+            let mut poisoned = false;
+            for keyframe in receiver {
+                if !poisoned {
+                    match map.lock() {
+                        Ok(mut map) => {
+                            if let Err(e) = mapper.process_keyframe(&mut map, keyframe) {
+                                let _ = error_sender.send(LocalMappingError::Map(keyframe, e));
+                            }
+                        }
+                        Err(_) => {
+                            poisoned = true;
+                            let _ = error_sender.send(LocalMappingError::Poisoned);
+                        }
+                    }
+                }
+                counter.fetch_sub(1, Ordering::SeqCst);
+            }
+            if poisoned {
+                return Err(LocalMappingError::Poisoned);
+            }
+            Ok(mapper)
+        });
+        Self {
+            sender: Some(sender),
+            errors,
+            pending,
+            handle: Some(handle),
+        }
+    }
+
+    /// Queues a keyframe for processing.
+    pub fn insert_keyframe(&self, keyframe: KeyFrameId) {
+        if let Some(sender) = &self.sender {
+            self.pending.fetch_add(1, Ordering::SeqCst);
+            if sender.send(keyframe).is_err() {
+                self.pending.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Whether all queued keyframes have been processed.
+    pub fn is_idle(&self) -> bool {
+        self.pending.load(Ordering::SeqCst) == 0
+    }
+
+    /// The errors since the last call, oldest first.
+    pub fn errors(&self) -> Vec<LocalMappingError> {
+        self.errors.try_iter().collect()
+    }
+
+    /// Finishes the queued keyframes and hands the mapper back. Errors not taken with `errors`
+    /// are dropped, but a poisoned map or a panic fails the stop.
+    pub fn stop(mut self) -> Result<LocalMapper, LocalMappingError> {
+        self.sender = None;
+        match self.handle.take().unwrap().join() {
+            Ok(result) => result,
+            Err(_) => Err(LocalMappingError::Panicked),
+        }
+    }
+}
+
+impl Drop for LocalMappingThread {
+    fn drop(&mut self) {
+        self.sender = None;
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::KeyPoint;
+    use crate::lie::SE3;
+    use crate::test_scene;
+    use nalgebra::{Point3, Vector6};
+
+    struct Scene {
+        map: Map,
+        points: Vec<Point3<f64>>,
+        poses: Vec<SE3>,
+        keyframes: Vec<KeyFrameId>,
+    }
+
+    // three keyframes seeing 60 points, the map only knows the first 30 and the last keyframe
+    // is inserted at `third_pose`
+    fn scene(third_pose: Option<SE3>) -> Scene {
+        let camera = test_scene::camera();
+        let points = test_scene::points(60);
+        let motion = test_scene::motion();
+        let poses = vec![SE3::identity(), motion, motion * motion];
+        let descriptors = test_scene::descriptors(points.len(), 1);
+
+        let mut map = Map::new();
+        let mut keyframes = vec![];
+        for (k, pose) in poses.iter().enumerate() {
+            let key_points = points
+                .iter()
+                .map(|p| test_scene::project(&camera, &(*pose * *p)))
+                .collect::<Vec<KeyPoint>>();
+            let inserted = if k == 2 {
+                third_pose.unwrap_or(*pose)
+            } else {
+                *pose
+            };
+            keyframes.push(
+                map.insert_keyframe(inserted, camera, key_points, descriptors.clone())
+                    .unwrap(),
+            );
+        }
+        for (i, p) in points.iter().take(30).enumerate() {
+            let id = map.insert_map_point(*p, keyframes[0], i).unwrap();
+            for kf in &keyframes[1..] {
+                map.add_observation(id, *kf, i).unwrap();
+            }
+        }
+
+        Scene {
+            map,
+            points,
+            poses,
+            keyframes,
+        }
+    }
+
+    #[test]
+    fn test_create_points() {
+        let mut scene = scene(None);
+        let mut mapper = LocalMapper::default();
+        let report = mapper
+            .process_keyframe(&mut scene.map, scene.keyframes[2])
+            .unwrap();
+
+        assert_eq!(report.created_points.len(), 30);
+        assert_eq!(report.removed_observations, 0);
+        assert_eq!(scene.map.num_map_points(), 60);
+        for (i, point) in scene.points.iter().enumerate().skip(30) {
+            let id = scene.map.keyframe(scene.keyframes[2]).unwrap().map_point(i);
+            let position = scene.map.map_point(id.unwrap()).unwrap().position();
+            assert!((position - point).norm() < 1e-2, "{}", i);
+        }
+    }
+
+    #[test]
+    fn test_local_bundle_adjustment() {
+        let truth = test_scene::motion() * test_scene::motion();
+        let noisy = SE3::exp(&Vector6::new(0.02, -0.01, 0.01, 0.005, 0.0, -0.005)) * truth;
+        let mut scene = scene(Some(noisy));
+
+        let mut mapper = LocalMapper::default();
+        mapper
+            .process_keyframe(&mut scene.map, scene.keyframes[2])
+            .unwrap();
+        // with a single fixed keyframe monocular BA recovers the poses up to scale
+        let pose = scene.map.keyframe(scene.keyframes[2]).unwrap().pose();
+        let truth = scene.poses[2];
+        assert!((pose.rotation.inverse() * truth.rotation).angle() < 1e-6);
+        let direction = pose
+            .translation
+            .normalize()
+            .dot(&truth.translation.normalize());
+        assert!(direction > 1.0 - 1e-8, "{}", direction);
+        // the first keyframe pins the gauge
+        let first = scene.map.keyframe(scene.keyframes[0]).unwrap().pose();
+        assert_eq!(*first, SE3::identity());
+    }
+
+    #[test]
+    fn test_cull_points() {
+        let mut scene = scene(None);
+        let mut mapper = LocalMapper::default();
+        let created = mapper
+            .process_keyframe(&mut scene.map, scene.keyframes[2])
+            .unwrap()
+            .created_points;
+
+        // tracking keeps missing one new point
+        let missed = created[0];
+        for _ in 0..5 {
+            scene.map.record_tracking(missed, false).unwrap();
+        }
+        let culled = mapper.cull_points(&mut scene.map, scene.keyframes[2]);
+        assert_eq!(culled, 1);
+        assert!(scene.map.map_point(missed).is_none());
+
+        // two keyframes later, points with only two observations go too
+        let culled = mapper.cull_points(&mut scene.map, KeyFrameId(scene.keyframes[2].0 + 2));
+        assert_eq!(culled, 29);
+    }
+
+    #[test]
+    fn test_local_mapping_thread() {
+        let scene = scene(None);
+        let keyframe = scene.keyframes[2];
+        let map = Arc::new(Mutex::new(scene.map));
+
+        let thread = LocalMappingThread::spawn(LocalMapper::default(), map.clone());
+        thread.insert_keyframe(keyframe);
+        let unknown = KeyFrameId(keyframe.0 + 100);
+        thread.insert_keyframe(unknown);
+        while !thread.is_idle() {
+            std::thread::yield_now();
+        }
+        assert_eq!(
+            thread.errors(),
+            [LocalMappingError::Map(
+                unknown,
+                MapError::UnknownKeyFrame(unknown)
+            )]
+        );
+        let mapper = thread.stop().unwrap();
+
+        assert_eq!(mapper.recent_points.len(), 30);
+        assert_eq!(map.lock().unwrap().num_map_points(), 60);
+    }
+
+    #[test]
+    fn test_local_mapping_thread_poisoned() {
+        let scene = scene(None);
+        let keyframe = scene.keyframes[2];
+        let map = Arc::new(Mutex::new(scene.map));
+        let holder = map.clone();
+        let _ = std::thread::spawn(move || {
+            let _map = holder.lock().unwrap();
+            panic!("poison the map");
+        })
+        .join();
+
+        let thread = LocalMappingThread::spawn(LocalMapper::default(), map.clone());
+        thread.insert_keyframe(keyframe);
+        thread.insert_keyframe(keyframe);
+        while !thread.is_idle() {
+            std::thread::yield_now();
+        }
+        assert_eq!(thread.errors(), [LocalMappingError::Poisoned]);
+        assert_eq!(thread.stop().err(), Some(LocalMappingError::Poisoned));
+        assert_eq!(map.lock().unwrap_err().into_inner().num_map_points(), 30);
+    }
+}
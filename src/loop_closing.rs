@@ -0,0 +1,728 @@
+//! Loop closing, recognizing a return to a mapped place and removing the drift accumulated since.
+//!
+//! For each new keyframe we
+//! 1. query the keyframe database for older keyframes that look alike but share no points with
+//!    it, and keep those detected over several consecutive keyframes,
+//! 2. match the map points of the keyframe and a candidate by descriptor and estimate the Sim(3)
+//!    between their cameras under RANSAC (monocular maps drift in scale too),
+//! 3. spread the correction over the map with a pose graph, move the points with their
+//!    reference keyframes, and fuse the duplicated points.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use nalgebra::{Matrix3, Point2, Point3, Vector3};
+
+use crate::camera::Camera;
+use crate::hamming::hamming_distance;
+use crate::keyframe_database::KeyFrameDatabase;
+use crate::lie::{Matrix7, Sim3, SO3};
+use crate::map::{KeyFrame, KeyFrameId, Map, MapError, MapPointId};
+use crate::matcher::match_unique;
+use crate::pose_graph::{EdgeKind, PoseGraph, PoseGraphOptions, PoseGraphSummary};
+use crate::rand::*;
+use crate::ransac::*;
+
+impl Model for Sim3 {
+    fn is_valid(&self) -> bool {
+        self.scale.is_finite() && self.scale > 0.0 && self.translation.iter().all(|v| v.is_finite())
+    }
+}
+
+/// The similarity `to = S * from` that best aligns two point sets in the least squares sense,
+/// needs at least 3 points not on a line.
+///
+/// Umeyama, "Least-squares estimation of transformation parameters between two point patterns",
+/// 1991.
+pub fn umeyama(pairs: &[(Point3<f64>, Point3<f64>)]) -> Option<Sim3> {
+    if pairs.len() < 3 {
+        return None;
+    }
+    let n = pairs.len() as f64;
+    let mean_from = pairs
+        .iter()
+        .fold(Vector3::zeros(), |sum, (a, _)| sum + a.coords)
+        / n;
+    let mean_to = pairs
+        .iter()
+        .fold(Vector3::zeros(), |sum, (_, b)| sum + b.coords)
+        / n;
+    let mut variance = 0.0;
+    let mut covariance = Matrix3::zeros();
+    for (a, b) in pairs {
+        let a = a.coords - mean_from;
+        let b = b.coords - mean_to;
+        variance += a.norm_squared() / n;
+        covariance += b * a.transpose() / n;
+    }
+    if variance <= f64::EPSILON {
+        return None;
+    }
+
+    let svd = covariance.svd(true, true);
+    let (u, v_t) = (svd.u?, svd.v_t?);
+    // a reflection is not a rotation, flip the weakest direction instead
+    let mut signs = Vector3::new(1.0, 1.0, 1.0);
+    if u.determinant() * v_t.determinant() < 0.0 {
+        signs[2] = -1.0;
+    }
+    let rotation = u * Matrix3::from_diagonal(&signs) * v_t;
+    let scale = svd.singular_values.dot(&signs) / variance;
+    if scale <= f64::EPSILON {
+        return None;
+    }
+    let translation = mean_to - scale * rotation * mean_from;
+    Some(Sim3::new(SO3::from_matrix(&rotation), translation, scale))
+}
+
+/// A map point seen by two keyframes, in each keyframe's camera coordinates, and the pixels it
+/// was seen at.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct Sim3Match {
+    pub point1: Point3<f64>,
+    pub point2: Point3<f64>,
+    pub pixel1: Point2<f64>,
+    pub pixel2: Point2<f64>,
+}
+
+/// Builds `X_2 = S * X_1` hypotheses with `umeyama`.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub struct Sim3Solver;
+
+impl MinimalSolver<Sim3Match> for Sim3Solver {
+    type Model = Sim3;
+
+    fn sample_size(&self) -> usize {
+        3
+    }
+
+    fn solve(&self, sample: &[Sim3Match]) -> Vec<Sim3> {
+        self.solve_nonminimal(sample)
+    }
+
+    fn solve_nonminimal(&self, data: &[Sim3Match]) -> Vec<Sim3> {
+        let pairs = data
+            .iter()
+            .map(|m| (m.point1, m.point2))
+            .collect::<Vec<_>>();
+        umeyama(&pairs).into_iter().collect()
+    }
+}
+
+/// The larger of the reprojection errors of each point into the other camera, in pixels. The
+/// error is measured in the images because the 3D distances depend on the drifting scale.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct Sim3ReprojectionError {
+    pub camera1: Camera,
+    pub camera2: Camera,
+}
+
+impl ErrorMetric<Sim3, Sim3Match> for Sim3ReprojectionError {
+    fn error(&self, sim3: &Sim3, m: &Sim3Match) -> f64 {
+        let in2 = self.camera2.project(&(*sim3 * m.point1));
+        let in1 = self.camera1.project(&(sim3.inverse() * m.point2));
+        match (in1, in2) {
+            (Some(in1), Some(in2)) => (in1 - m.pixel1).norm().max((in2 - m.pixel2).norm()),
+            _ => f64::INFINITY,
+        }
+    }
+}
+
+/// Estimates the similarity between two cameras, `options.inlier_threshold` is in pixels.
+pub fn estimate_sim3_ransac(
+    camera1: &Camera,
+    camera2: &Camera,
+    matches: &[Sim3Match],
+    options: &RansacOptions,
+    rnd: &mut Rand,
+) -> Option<RansacResult<Sim3>> {
+    let metric = Sim3ReprojectionError {
+        camera1: *camera1,
+        camera2: *camera2,
+    };
+    ransac(matches, &Sim3Solver, &metric, options, rnd)
+}
+
+/// The map point matches of two keyframes, possibly of different maps, that agree with the
+/// similarity between their cameras.
+#[derive(PartialEq, Debug, Clone)]
+pub struct KeyFrameSim3 {
+    /// Maps the first keyframe's camera coordinates to the second's, `X_2 = S * X_1`.
+    pub transform: Sim3,
+    /// The inliers, as (key point index, point) in the first keyframe and in the second.
+    pub matches: Vec<((usize, MapPointId), (usize, MapPointId))>,
+}
+
+/// Matches the map points of two keyframes by descriptor and estimates the similarity between
+/// their cameras under RANSAC. Fails with the number of inliers found, zero when there are fewer
+/// than `min_matches` matches or no model.
+#[allow(clippy::too_many_arguments)]
+pub fn estimate_keyframe_sim3(
+    map1: &Map,
+    keyframe1: KeyFrameId,
+    map2: &Map,
+    keyframe2: KeyFrameId,
+    max_hamming_distance: usize,
+    min_matches: usize,
+    min_inliers: usize,
+    options: &RansacOptions,
+    rnd: &mut Rand,
+) -> Result<KeyFrameSim3, usize> {
+    let kf1 = map1.keyframe(keyframe1).ok_or(0usize)?;
+    let kf2 = map2.keyframe(keyframe2).ok_or(0usize)?;
+    let points = |kf: &KeyFrame| {
+        kf.map_points()
+            .iter()
+            .enumerate()
+            .filter_map(|(i, point)| point.map(|point| (i, point)))
+            .collect::<Vec<_>>()
+    };
+    let descriptors = |map: &Map, points: &[(usize, MapPointId)]| {
+        points
+            .iter()
+            .map(|(_, id)| map.map_point(*id).unwrap().descriptor().clone())
+            .collect::<Vec<_>>()
+    };
+    let points1 = points(kf1);
+    let points2 = points(kf2);
+    let matches = match_unique(
+        &descriptors(map1, &points1),
+        &descriptors(map2, &points2),
+        max_hamming_distance,
+    );
+    if matches.len() < min_matches {
+        return Err(0);
+    }
+
+    let data = matches
+        .iter()
+        .map(|&(i, j, _)| {
+            let (index1, point1) = points1[i];
+            let (index2, point2) = points2[j];
+            Sim3Match {
+                point1: *kf1.pose() * *map1.map_point(point1).unwrap().position(),
+                point2: *kf2.pose() * *map2.map_point(point2).unwrap().position(),
+                pixel1: kf1.key_points()[index1].point(),
+                pixel2: kf2.key_points()[index2].point(),
+            }
+        })
+        .collect::<Vec<_>>();
+    let result =
+        estimate_sim3_ransac(kf1.camera(), kf2.camera(), &data, options, rnd).ok_or(0usize)?;
+    if result.num_inliers() < min_inliers {
+        return Err(result.num_inliers());
+    }
+    let matches = matches
+        .iter()
+        .zip(&result.inliers)
+        .filter(|(_, &inlier)| inlier)
+        .map(|(&(i, j, _), _)| (points1[i], points2[j]))
+        .collect();
+    Ok(KeyFrameSim3 {
+        transform: result.model,
+        matches,
+    })
+}
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct LoopClosingOptions {
+    /// How many keyframes proposed by the database to consider.
+    pub max_candidates: usize,
+    /// Ignore candidates less similar than this, whatever the keyframe's neighbours score.
+    pub min_similarity: f64,
+    /// A loop must be detected at this many consecutive keyframes.
+    pub min_consistency: usize,
+    pub max_hamming_distance: usize,
+    /// Skip candidates with fewer map point matches than this.
+    pub min_matches: usize,
+    /// `inlier_threshold` is a reprojection error in pixels.
+    pub ransac: RansacOptions,
+    pub min_inliers: usize,
+    /// The window searched around the projections of the loop points, pixels.
+    pub search_radius: f64,
+    /// Accept the loop with at least this many matches after the projection search.
+    pub min_loop_matches: usize,
+    /// Keyframes sharing at least this many points get a pose graph edge, besides the edges
+    /// between consecutive keyframes.
+    pub min_covisibility_weight: usize,
+    pub pose_graph: PoseGraphOptions,
+}
+
+impl Default for LoopClosingOptions {
+    fn default() -> Self {
+        Self {
+            max_candidates: 10,
+            min_similarity: 0.05,
+            min_consistency: 3,
+            max_hamming_distance: 100,
+            min_matches: 20,
+            ransac: RansacOptions::reprojection(),
+            min_inliers: 20,
+            search_radius: 10.0,
+            min_loop_matches: 40,
+            min_covisibility_weight: 100,
+            pose_graph: PoseGraphOptions::default(),
+        }
+    }
+}
+
+/// A closed loop.
+#[derive(PartialEq, Debug, Clone)]
+pub struct LoopClosure {
+    pub keyframe: KeyFrameId,
+    pub loop_keyframe: KeyFrameId,
+    /// Maps the loop keyframe's camera coordinates to the keyframe's, before the correction.
+    pub transform: Sim3,
+    pub matches: usize,
+    /// How many of the keyframe's points were merged into loop points, or newly observed.
+    pub fused_points: usize,
+    pub pose_graph: PoseGraphSummary,
+}
+
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct LoopCloser {
+    pub options: LoopClosingOptions,
+    // the candidate groups (a candidate and its covisible keyframes) of the previous keyframe,
+    // with how many consecutive keyframes detected them
+    groups: Vec<(BTreeSet<KeyFrameId>, usize)>,
+}
+
+impl LoopCloser {
+    pub fn new(options: LoopClosingOptions) -> Self {
+        Self {
+            options,
+            groups: Vec::new(),
+        }
+    }
+
+    /// Looks for a loop at a new keyframe and closes it when one is verified. The keyframe is
+    /// added to `database` either way.
+    pub fn process_keyframe(
+        &mut self,
+        map: &mut Map,
+        database: &mut KeyFrameDatabase,
+        keyframe: KeyFrameId,
+        rnd: &mut Rand,
+    ) -> Result<Option<LoopClosure>, MapError> {
+        let candidates = self.detect(map, database, keyframe)?;
+        database.add(map.keyframe(keyframe).unwrap());
+
+        for candidate in candidates {
+            let (transform, matches) = match self.compute_sim3(map, keyframe, candidate, rnd) {
+                Some(found) => found,
+                None => continue,
+            };
+            self.groups.clear();
+            let fused_points = fuse(map, keyframe, &matches)?;
+            let pose_graph = self.correct(map, keyframe, candidate, &transform)?;
+            return Ok(Some(LoopClosure {
+                keyframe,
+                loop_keyframe: candidate,
+                transform,
+                matches: matches.len(),
+                fused_points,
+                pose_graph,
+            }));
+        }
+        Ok(None)
+    }
+
+    // the database candidates that have been consistent for enough keyframes
+    fn detect(
+        &mut self,
+        map: &Map,
+        database: &KeyFrameDatabase,
+        keyframe: KeyFrameId,
+    ) -> Result<Vec<KeyFrameId>, MapError> {
+        let kf = map
+            .keyframe(keyframe)
+            .ok_or(MapError::UnknownKeyFrame(keyframe))?;
+        let mut connected = map
+            .covisible_keyframes(keyframe, 1)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect::<BTreeSet<_>>();
+
+        // a loop must look at least as similar as the keyframe's worst neighbour, scored with
+        // the keyframe's own words since it is only added to the database after detection
+        let min_score = connected
+            .iter()
+            .map(|&id| database.score_descriptors(kf.descriptors(), id))
+            .fold(f64::INFINITY, f64::min);
+        let min_score = match min_score.is_finite() {
+            true => min_score.max(self.options.min_similarity),
+            false => self.options.min_similarity,
+        };
+        connected.insert(keyframe);
+        let candidates = database
+            .query(kf.descriptors(), self.options.max_candidates)
+            .into_iter()
+            .filter(|&(id, score)| !connected.contains(&id) && score >= min_score)
+            .map(|(id, _)| id);
+
+        let mut groups = Vec::new();
+        let mut consistent = Vec::new();
+        for candidate in candidates {
+            let mut group = map
+                .covisible_keyframes(candidate, 1)
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect::<BTreeSet<_>>();
+            group.insert(candidate);
+            let count = 1 + self
+                .groups
+                .iter()
+                .filter(|(previous, _)| !previous.is_disjoint(&group))
+                .map(|&(_, count)| count)
+                .max()
+                .unwrap_or(0);
+            if count >= self.options.min_consistency {
+                consistent.push(candidate);
+            }
+            groups.push((group, count));
+        }
+        self.groups = groups;
+        Ok(consistent)
+    }
+
+    // the similarity from the candidate's camera to the keyframe's, and the keyframe's matches
+    // to loop points as (key point index, point)
+    fn compute_sim3(
+        &self,
+        map: &Map,
+        keyframe: KeyFrameId,
+        candidate: KeyFrameId,
+        rnd: &mut Rand,
+    ) -> Option<(Sim3, Vec<(usize, MapPointId)>)> {
+        let options = &self.options;
+        let kf = map.keyframe(keyframe)?;
+        let other = map.keyframe(candidate)?;
+
+        // X_keyframe = S * X_candidate
+        let KeyFrameSim3 { transform, matches } = estimate_keyframe_sim3(
+            map,
+            candidate,
+            map,
+            keyframe,
+            options.max_hamming_distance,
+            options.min_matches,
+            options.min_inliers,
+            &options.ransac,
+            rnd,
+        )
+        .ok()?;
+        let mut loop_matches = matches
+            .iter()
+            .map(|&((_, point), (i, _))| (i, point))
+            .collect::<BTreeMap<_, _>>();
+
+        let points = |id: KeyFrameId| {
+            map.keyframe(id)
+                .unwrap()
+                .map_points()
+                .iter()
+                .flatten()
+                .copied()
+                .collect::<Vec<_>>()
+        };
+        // project the points around the candidate for more matches
+        let mut loop_points = map
+            .covisible_keyframes(candidate, 1)
+            .into_iter()
+            .flat_map(|(id, _)| points(id))
+            .collect::<BTreeSet<_>>();
+        loop_points.extend(points(candidate));
+        let used = loop_matches.values().copied().collect::<BTreeSet<_>>();
+        let to_keyframe = transform * Sim3::from(*other.pose());
+        for point in loop_points.difference(&used) {
+            let mp = map.map_point(*point).unwrap();
+            let pixel = match kf.camera().project(&(to_keyframe * *mp.position())) {
+                Some(pixel) => pixel,
+                None => continue,
+            };
+            let best = kf
+                .key_points()
+                .iter()
+                .enumerate()
+                .filter(|(i, key_point)| {
+                    let offset = key_point.point() - pixel;
+                    !loop_matches.contains_key(i)
+                        && offset.x.abs() <= options.search_radius
+                        && offset.y.abs() <= options.search_radius
+                })
+                .map(|(i, _)| {
+                    (
+                        hamming_distance(&mp.descriptor().0, &kf.descriptors()[i].0),
+                        i,
+                    )
+                })
+                .min();
+            if let Some((distance, i)) = best {
+                if distance <= options.max_hamming_distance {
+                    loop_matches.insert(i, *point);
+                }
+            }
+        }
+
+        if loop_matches.len() < options.min_loop_matches {
+            return None;
+        }
+        Some((transform, loop_matches.into_iter().collect()))
+    }
+
+    // optimizes the essential graph with the loop edge and moves the keyframes and points
+    fn correct(
+        &self,
+        map: &mut Map,
+        keyframe: KeyFrameId,
+        loop_keyframe: KeyFrameId,
+        transform: &Sim3,
+    ) -> Result<PoseGraphSummary, MapError> {
+        let keyframes = map.keyframes().map(|kf| kf.id()).collect::<Vec<_>>();
+        let nodes = keyframes
+            .iter()
+            .enumerate()
+            .map(|(node, &id)| (id, node))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut graph = PoseGraph::new();
+        for (node, &id) in keyframes.iter().enumerate() {
+            let pose = Sim3::from(*map.keyframe(id).unwrap().pose());
+            graph.add_sim3_node(pose, node == 0);
+        }
+        let add_edge = |graph: &mut PoseGraph, from: usize, to: usize| {
+            let measurement = graph.pose(to) * graph.pose(from).inverse();
+            graph.add_sim3_edge(
+                from,
+                to,
+                measurement,
+                Matrix7::identity(),
+                EdgeKind::Odometry,
+            );
+        };
+        for node in 1..keyframes.len() {
+            add_edge(&mut graph, node - 1, node);
+        }
+        for (&id, &node) in &nodes {
+            for (other, _) in map.covisible_keyframes(id, self.options.min_covisibility_weight) {
+                // consecutive keyframes already have an edge
+                if nodes[&other] > node + 1 {
+                    add_edge(&mut graph, node, nodes[&other]);
+                }
+            }
+        }
+
+        // the loop edge measures the keyframe's pose relative to the loop keyframe
+        let summary = graph.close_loop(
+            nodes[&loop_keyframe],
+            nodes[&keyframe],
+            *transform,
+            Matrix7::identity(),
+            &self.options.pose_graph,
+        );
+
+        let points = map.map_points().map(|mp| mp.id()).collect::<Vec<_>>();
+        for point in points {
+            let mp = map.map_point(point).unwrap();
+            let node = nodes[&mp.reference_keyframe()];
+            let position = graph.correct_point(node, mp.position());
+            map.set_map_point_position(point, position)?;
+        }
+        for (&id, &node) in &nodes {
+            map.set_keyframe_pose(id, graph.se3_pose(node))?;
+        }
+        Ok(summary)
+    }
+}
+
+// replaces the keyframe's points by the loop points they matched, returns how many changed
+pub(crate) fn fuse(
+    map: &mut Map,
+    keyframe: KeyFrameId,
+    matches: &[(usize, MapPointId)],
+) -> Result<usize, MapError> {
+    let mut fused = 0;
+    for &(index, loop_point) in matches {
+        if map.map_point(loop_point).is_none() {
+            continue;
+        }
+        match map.keyframe(keyframe).unwrap().map_point(index) {
+            Some(point) if point == loop_point => continue,
+            Some(point) => map.replace_map_point(point, loop_point)?,
+            None => {
+                if map
+                    .map_point(loop_point)
+                    .unwrap()
+                    .observations()
+                    .contains_key(&keyframe)
+                {
+                    continue;
+                }
+                map.add_observation(loop_point, keyframe, index)?
+            }
+        }
+        fused += 1;
+    }
+    Ok(fused)
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::KeyPoint;
+    use crate::lie::SE3;
+    use crate::test_scene;
+
+    #[test]
+    fn test_umeyama() {
+        let sim3 = Sim3::new(
+            SO3::exp(&Vector3::new(0.1, -0.2, 0.3)),
+            Vector3::new(1.0, 2.0, -0.5),
+            1.7,
+        );
+        let pairs = test_scene::points(10)
+            .into_iter()
+            .map(|p| (p, sim3 * p))
+            .collect::<Vec<_>>();
+        let estimate = umeyama(&pairs).unwrap();
+        assert!((estimate.inverse() * sim3).log().norm() < 1e-10);
+        assert_eq!(umeyama(&pairs[..2]), None);
+    }
+
+    // a loop of keyframes around a scene, the map drifting further from the truth at each
+    // keyframe, and three last keyframes back at the start that share points duplicating the
+    // first keyframe's
+    fn drifting_map() -> (Map, Vec<KeyFrameId>, Vec<SE3>) {
+        let camera = test_scene::camera();
+        let truth = (0..8)
+            .map(|i| match i {
+                5.. => SE3::new(
+                    SO3::identity(),
+                    Vector3::new(-0.1 * (i - 3) as f64, 0.0, 0.0),
+                ),
+                _ => SE3::new(
+                    SO3::exp(&Vector3::new(0.0, 0.3 * i as f64, 0.0)),
+                    Vector3::new(-2.0 * i as f64, 0.0, 0.0),
+                ),
+            })
+            .collect::<Vec<_>>();
+
+        let mut map = Map::new();
+        let mut keyframes = vec![];
+        let mut returned = vec![];
+        for (i, pose) in truth.iter().enumerate() {
+            // the keyframes back at the start were tracked from each other, with the same drift
+            let drift = Sim3::new(
+                SO3::exp(&(Vector3::new(0.01, 0.02, -0.01) * i.min(5) as f64)),
+                Vector3::new(0.05, -0.03, 0.04) * i.min(5) as f64,
+                1.0 + 0.04 * i.min(5) as f64,
+            );
+            // the last keyframes see the points of the first
+            let seed = if i >= 5 { 0 } else { i as u64 };
+            let points = test_scene::points(60)
+                .into_iter()
+                .map(|p| if i >= 5 { p } else { pose.inverse() * p })
+                .collect::<Vec<_>>();
+            let key_points = points
+                .iter()
+                .map(|p| test_scene::project(&camera, &(*pose * *p)))
+                .collect::<Vec<KeyPoint>>();
+            let descriptors = test_scene::descriptors(points.len(), seed);
+            let stored = (Sim3::from(*pose) * drift.inverse()).to_se3();
+            let keyframe = map
+                .insert_keyframe(stored, camera, key_points, descriptors)
+                .unwrap();
+            for (index, p) in points.iter().enumerate() {
+                match i {
+                    6.. => map
+                        .add_observation(returned[index], keyframe, index)
+                        .unwrap(),
+                    _ => {
+                        let point = map.insert_map_point(drift * *p, keyframe, index).unwrap();
+                        if i == 5 {
+                            returned.push(point);
+                        }
+                    }
+                }
+            }
+            keyframes.push(keyframe);
+        }
+        (map, keyframes, truth)
+    }
+
+    #[test]
+    fn test_close_loop() {
+        let (mut map, keyframes, truth) = drifting_map();
+        let mut database = KeyFrameDatabase::new();
+        let mut closer = LoopCloser::default();
+        let mut rnd = Rand::new_with_seed(5);
+        for &keyframe in &keyframes[..5] {
+            let result = closer.process_keyframe(&mut map, &mut database, keyframe, &mut rnd);
+            assert_eq!(result, Ok(None));
+        }
+
+        let last = keyframes[7];
+        let error = |map: &Map| {
+            let start = map.keyframe(keyframes[0]).unwrap().center();
+            let end = map.keyframe(last).unwrap().center();
+            let true_offset = truth[7].center() - truth[0].center();
+            ((end - start) - true_offset).norm()
+        };
+        let before = error(&map);
+
+        // the loop has to be seen by consecutive keyframes before it is trusted
+        assert_eq!(closer.options.min_consistency, 3);
+        for &keyframe in &keyframes[5..7] {
+            let result = closer.process_keyframe(&mut map, &mut database, keyframe, &mut rnd);
+            assert_eq!(result, Ok(None));
+        }
+        let closure = closer
+            .process_keyframe(&mut map, &mut database, last, &mut rnd)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(closure.loop_keyframe, keyframes[0]);
+        assert_eq!(closure.matches, 60);
+        assert_eq!(closure.fused_points, 60);
+        assert_eq!(map.num_map_points(), 5 * 60);
+        assert_eq!(map.covisibility_weight(keyframes[0], last), 60);
+        assert!(error(&map) < 0.3 * before, "{} {}", error(&map), before);
+    }
+
+    #[test]
+    fn test_detect_needs_neighbour_similarity() {
+        let (mut map, keyframes, _) = drifting_map();
+        // a keyframe at the start seeing half of the first keyframe's descriptors
+        let mut descriptors = test_scene::descriptors(60, 0);
+        descriptors[30..].clone_from_slice(&test_scene::descriptors(30, 9));
+        let first = map.keyframe(keyframes[0]).unwrap();
+        let (pose, key_points) = (*first.pose(), first.key_points().to_vec());
+        let similar = map
+            .insert_keyframe(pose, test_scene::camera(), key_points, descriptors)
+            .unwrap();
+
+        let mut database = KeyFrameDatabase::new();
+        for &keyframe in keyframes[..7].iter().chain([&similar]) {
+            database.add(map.keyframe(keyframe).unwrap());
+        }
+        let last = keyframes[7];
+        let kf = map.keyframe(last).unwrap();
+        let score = database.score_descriptors(kf.descriptors(), similar);
+        assert!(score > 0.3 && score < 1.0, "{}", score);
+        // the neighbours, not yet scored against the keyframe itself, look exactly like it
+        assert!(!database.contains(last));
+        assert_eq!(
+            database.score_descriptors(kf.descriptors(), keyframes[6]),
+            1.0
+        );
+
+        let mut closer = LoopCloser::new(LoopClosingOptions {
+            min_consistency: 1,
+            ..Default::default()
+        });
+        assert_eq!(closer.detect(&map, &database, last), Ok(vec![keyframes[0]]));
+    }
+}
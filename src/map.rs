@@ -0,0 +1,801 @@
+//! The map shared by tracking, local mapping and loop closing: keyframes, the 3D points they
+//! observe, and the covisibility graph between keyframes.
+//!
+//! All the bookkeeping between keyframes and points goes through `Map`, so the two sides of an
+//! observation (the keyframe's keypoint slot and the point's observation list) and the
+//! covisibility weights can never disagree. Wrap it in a lock to share it between threads.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use nalgebra::{Point3, Vector3};
+
+use crate::camera::Camera;
+use crate::common::{Descriptor, KeyPoint};
+use crate::hamming::hamming_distance;
+use crate::lie::SE3;
+
+mod serialization;
+
+pub use serialization::{MapIoError, MAP_FORMAT_VERSION};
+
+/// Our detector works at a single scale, so a point is expected to be re-detected within this
+/// factor of the distance it was first seen at.
+pub const SCALE_TOLERANCE: f64 = 1.5;
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Copy, Clone)]
+pub struct KeyFrameId(pub usize);
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Copy, Clone)]
+pub struct MapPointId(pub usize);
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum MapError {
+    UnknownKeyFrame(KeyFrameId),
+    UnknownMapPoint(MapPointId),
+    /// A keyframe needs exactly one descriptor per keypoint.
+    DescriptorCountMismatch {
+        key_points: usize,
+        descriptors: usize,
+    },
+    KeyPointOutOfRange {
+        keyframe: KeyFrameId,
+        index: usize,
+    },
+    /// The keypoint is already the observation of another point.
+    KeyPointTaken {
+        keyframe: KeyFrameId,
+        index: usize,
+        point: MapPointId,
+    },
+    /// A point can only be observed once per keyframe.
+    AlreadyObserved {
+        keyframe: KeyFrameId,
+        point: MapPointId,
+    },
+    /// The keyframe exists but does not observe the point.
+    NotObserved {
+        keyframe: KeyFrameId,
+        point: MapPointId,
+    },
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapError::UnknownKeyFrame(id) => write!(f, "unknown keyframe {}", id.0),
+            MapError::UnknownMapPoint(id) => write!(f, "unknown map point {}", id.0),
+            MapError::DescriptorCountMismatch {
+                key_points,
+                descriptors,
+            } => write!(
+                f,
+                "{} keypoints but {} descriptors",
+                key_points, descriptors
+            ),
+            MapError::KeyPointOutOfRange { keyframe, index } => {
+                write!(f, "keyframe {} has no keypoint {}", keyframe.0, index)
+            }
+            MapError::KeyPointTaken {
+                keyframe,
+                index,
+                point,
+            } => write!(
+                f,
+                "keypoint {} of keyframe {} already observes point {}",
+                index, keyframe.0, point.0
+            ),
+            MapError::AlreadyObserved { keyframe, point } => {
+                write!(
+                    f,
+                    "keyframe {} already observes point {}",
+                    keyframe.0, point.0
+                )
+            }
+            MapError::NotObserved { keyframe, point } => {
+                write!(
+                    f,
+                    "keyframe {} does not observe point {}",
+                    keyframe.0, point.0
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for MapError {}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct KeyFrame {
+    id: KeyFrameId,
+    /// `X_camera = pose * X_world`.
+    pose: SE3,
+    camera: Camera,
+    key_points: Vec<KeyPoint>,
+    descriptors: Vec<Descriptor>,
+    // the point observed by each keypoint
+    map_points: Vec<Option<MapPointId>>,
+}
+
+impl KeyFrame {
+    pub fn id(&self) -> KeyFrameId {
+        self.id
+    }
+
+    pub fn pose(&self) -> &SE3 {
+        &self.pose
+    }
+
+    /// The camera centre in world coordinates.
+    pub fn center(&self) -> Point3<f64> {
+        self.pose.center()
+    }
+
+    pub fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    pub fn key_points(&self) -> &[KeyPoint] {
+        &self.key_points
+    }
+
+    pub fn descriptors(&self) -> &[Descriptor] {
+        &self.descriptors
+    }
+
+    /// The point observed by each keypoint, if any.
+    pub fn map_points(&self) -> &[Option<MapPointId>] {
+        &self.map_points
+    }
+
+    pub fn map_point(&self, index: usize) -> Option<MapPointId> {
+        self.map_points.get(index).copied().flatten()
+    }
+
+    pub fn num_map_points(&self) -> usize {
+        self.map_points.iter().flatten().count()
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct MapPoint {
+    id: MapPointId,
+    position: Point3<f64>,
+    // the keypoint index of each observation
+    observations: BTreeMap<KeyFrameId, usize>,
+    reference: KeyFrameId,
+    descriptor: Descriptor,
+    normal: Vector3<f64>,
+    min_distance: f64,
+    max_distance: f64,
+    first_keyframe: KeyFrameId,
+    // how often tracking expected to see the point, and how often it did
+    visible: usize,
+    found: usize,
+}
+
+impl MapPoint {
+    pub fn id(&self) -> MapPointId {
+        self.id
+    }
+
+    pub fn position(&self) -> &Point3<f64> {
+        &self.position
+    }
+
+    /// The keyframes observing the point and the index of the keypoint in each.
+    pub fn observations(&self) -> &BTreeMap<KeyFrameId, usize> {
+        &self.observations
+    }
+
+    pub fn num_observations(&self) -> usize {
+        self.observations.len()
+    }
+
+    /// The keyframe the point is anchored to, the first one to see it while it is alive.
+    pub fn reference_keyframe(&self) -> KeyFrameId {
+        self.reference
+    }
+
+    /// The observed descriptor closest to all the others, used when matching against the map.
+    pub fn descriptor(&self) -> &Descriptor {
+        &self.descriptor
+    }
+
+    /// The mean viewing direction, a unit vector from the cameras towards the point.
+    pub fn normal(&self) -> &Vector3<f64> {
+        &self.normal
+    }
+
+    /// The range of distances the point can be re-detected at.
+    pub fn scale_range(&self) -> (f64, f64) {
+        (self.min_distance, self.max_distance)
+    }
+
+    pub fn is_in_scale_range(&self, distance: f64) -> bool {
+        distance >= self.min_distance && distance <= self.max_distance
+    }
+
+    /// The keyframe that created the point, unlike the reference it never changes.
+    pub fn first_keyframe(&self) -> KeyFrameId {
+        self.first_keyframe
+    }
+
+    /// The fraction of the frames that should have seen the point that actually matched it.
+    /// The keyframe that created the point counts as the first sighting.
+    pub fn found_ratio(&self) -> f64 {
+        self.found as f64 / self.visible as f64
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Map {
+    keyframes: BTreeMap<KeyFrameId, KeyFrame>,
+    map_points: BTreeMap<MapPointId, MapPoint>,
+    // the number of points shared by each pair of keyframes, stored in both directions
+    covisibility: BTreeMap<KeyFrameId, BTreeMap<KeyFrameId, usize>>,
+    next_keyframe: usize,
+    next_map_point: usize,
+}
+
+impl Map {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn num_keyframes(&self) -> usize {
+        self.keyframes.len()
+    }
+
+    pub fn num_map_points(&self) -> usize {
+        self.map_points.len()
+    }
+
+    pub fn keyframe(&self, id: KeyFrameId) -> Option<&KeyFrame> {
+        self.keyframes.get(&id)
+    }
+
+    pub fn map_point(&self, id: MapPointId) -> Option<&MapPoint> {
+        self.map_points.get(&id)
+    }
+
+    /// All keyframes, oldest first.
+    pub fn keyframes(&self) -> impl Iterator<Item = &KeyFrame> {
+        self.keyframes.values()
+    }
+
+    pub fn map_points(&self) -> impl Iterator<Item = &MapPoint> {
+        self.map_points.values()
+    }
+
+    pub fn last_keyframe(&self) -> Option<&KeyFrame> {
+        self.keyframes.values().next_back()
+    }
+
+    /// Adds a keyframe without any map points yet.
+    pub fn insert_keyframe(
+        &mut self,
+        pose: SE3,
+        camera: Camera,
+        key_points: Vec<KeyPoint>,
+        descriptors: Vec<Descriptor>,
+    ) -> Result<KeyFrameId, MapError> {
+        if key_points.len() != descriptors.len() {
+            return Err(MapError::DescriptorCountMismatch {
+                key_points: key_points.len(),
+                descriptors: descriptors.len(),
+            });
+        }
+        let id = KeyFrameId(self.next_keyframe);
+        self.next_keyframe += 1;
+        self.keyframes.insert(
+            id,
+            KeyFrame {
+                id,
+                pose,
+                camera,
+                map_points: vec![None; key_points.len()],
+                key_points,
+                descriptors,
+            },
+        );
+        self.covisibility.insert(id, BTreeMap::new());
+        Ok(id)
+    }
+
+    /// Adds a point first observed by keypoint `index` of `keyframe`, its reference keyframe.
+    pub fn insert_map_point(
+        &mut self,
+        position: Point3<f64>,
+        keyframe: KeyFrameId,
+        index: usize,
+    ) -> Result<MapPointId, MapError> {
+        let descriptor = self.check_free_key_point(keyframe, index)?.clone();
+        let id = MapPointId(self.next_map_point);
+        self.next_map_point += 1;
+        self.map_points.insert(
+            id,
+            MapPoint {
+                id,
+                position,
+                observations: BTreeMap::new(),
+                reference: keyframe,
+                descriptor,
+                normal: Vector3::z(),
+                min_distance: 0.0,
+                max_distance: f64::INFINITY,
+                first_keyframe: keyframe,
+                visible: 1,
+                found: 1,
+            },
+        );
+        self.add_observation(id, keyframe, index)?;
+        Ok(id)
+    }
+
+    // the descriptor of a keypoint that is not yet associated with a point
+    fn check_free_key_point(
+        &self,
+        keyframe: KeyFrameId,
+        index: usize,
+    ) -> Result<&Descriptor, MapError> {
+        let kf = self
+            .keyframes
+            .get(&keyframe)
+            .ok_or(MapError::UnknownKeyFrame(keyframe))?;
+        match kf.map_points.get(index) {
+            None => Err(MapError::KeyPointOutOfRange { keyframe, index }),
+            Some(Some(point)) => Err(MapError::KeyPointTaken {
+                keyframe,
+                index,
+                point: *point,
+            }),
+            Some(None) => Ok(&kf.descriptors[index]),
+        }
+    }
+
+    /// Records that keypoint `index` of `keyframe` is an image of `point`.
+    pub fn add_observation(
+        &mut self,
+        point: MapPointId,
+        keyframe: KeyFrameId,
+        index: usize,
+    ) -> Result<(), MapError> {
+        self.check_free_key_point(keyframe, index)?;
+        let mp = self
+            .map_points
+            .get_mut(&point)
+            .ok_or(MapError::UnknownMapPoint(point))?;
+        if mp.observations.contains_key(&keyframe) {
+            return Err(MapError::AlreadyObserved { keyframe, point });
+        }
+
+        let others = mp.observations.keys().copied().collect::<Vec<_>>();
+        mp.observations.insert(keyframe, index);
+        self.keyframes.get_mut(&keyframe).unwrap().map_points[index] = Some(point);
+        for other in others {
+            self.change_covisibility(keyframe, other, 1);
+        }
+        self.update_point(point);
+        Ok(())
+    }
+
+    /// Forgets that `keyframe` observes `point`. A point left without observations is removed.
+    pub fn remove_observation(
+        &mut self,
+        point: MapPointId,
+        keyframe: KeyFrameId,
+    ) -> Result<(), MapError> {
+        if !self.keyframes.contains_key(&keyframe) {
+            return Err(MapError::UnknownKeyFrame(keyframe));
+        }
+        let mp = self
+            .map_points
+            .get_mut(&point)
+            .ok_or(MapError::UnknownMapPoint(point))?;
+        let index = mp
+            .observations
+            .remove(&keyframe)
+            .ok_or(MapError::NotObserved { keyframe, point })?;
+
+        let others = mp.observations.keys().copied().collect::<Vec<_>>();
+        if mp.reference == keyframe {
+            if let Some(&first) = others.first() {
+                mp.reference = first;
+            }
+        }
+        self.keyframes.get_mut(&keyframe).unwrap().map_points[index] = None;
+        for other in &others {
+            self.change_covisibility(keyframe, *other, -1);
+        }
+
+        if others.is_empty() {
+            self.map_points.remove(&point);
+        } else {
+            self.update_point(point);
+        }
+        Ok(())
+    }
+
+    /// Removes a point and all its observations.
+    pub fn remove_map_point(&mut self, point: MapPointId) -> Result<MapPoint, MapError> {
+        let mp = self
+            .map_points
+            .get(&point)
+            .ok_or(MapError::UnknownMapPoint(point))?
+            .clone();
+        let observers = mp.observations.iter().collect::<Vec<_>>();
+        for (i, (a, &index)) in observers.iter().enumerate() {
+            self.keyframes.get_mut(a).unwrap().map_points[index] = None;
+            for (b, _) in &observers[i + 1..] {
+                self.change_covisibility(**a, **b, -1);
+            }
+        }
+        self.map_points.remove(&point);
+        Ok(mp)
+    }
+
+    /// Removes a keyframe and its observations. Points it was the last observer of go too.
+    pub fn remove_keyframe(&mut self, keyframe: KeyFrameId) -> Result<KeyFrame, MapError> {
+        let points = self
+            .keyframes
+            .get(&keyframe)
+            .ok_or(MapError::UnknownKeyFrame(keyframe))?
+            .map_points
+            .iter()
+            .flatten()
+            .copied()
+            .collect::<Vec<_>>();
+        for point in points {
+            self.remove_observation(point, keyframe)?;
+        }
+        self.covisibility.remove(&keyframe);
+        Ok(self.keyframes.remove(&keyframe).unwrap())
+    }
+
+    /// Merges `old` into `new`, e.g. when loop closing finds they are the same physical point.
+    /// Observations of `old` by keyframes that already see `new` are dropped.
+    pub fn replace_map_point(&mut self, old: MapPointId, new: MapPointId) -> Result<(), MapError> {
+        if old == new {
+            return Ok(());
+        }
+        if !self.map_points.contains_key(&new) {
+            return Err(MapError::UnknownMapPoint(new));
+        }
+        let removed = self.remove_map_point(old)?;
+        for (keyframe, index) in removed.observations {
+            if !self.map_points[&new].observations.contains_key(&keyframe) {
+                self.add_observation(new, keyframe, index)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves a keyframe, e.g. after bundle adjustment.
+    pub fn set_keyframe_pose(&mut self, keyframe: KeyFrameId, pose: SE3) -> Result<(), MapError> {
+        let kf = self
+            .keyframes
+            .get_mut(&keyframe)
+            .ok_or(MapError::UnknownKeyFrame(keyframe))?;
+        kf.pose = pose;
+        let points = kf.map_points.iter().flatten().copied().collect::<Vec<_>>();
+        for point in points {
+            self.update_point(point);
+        }
+        Ok(())
+    }
+
+    /// Records that tracking expected to see `point` in a frame (visible) and whether it was
+    /// matched there (found).
+    pub fn record_tracking(&mut self, point: MapPointId, found: bool) -> Result<(), MapError> {
+        let mp = self
+            .map_points
+            .get_mut(&point)
+            .ok_or(MapError::UnknownMapPoint(point))?;
+        mp.visible += 1;
+        if found {
+            mp.found += 1;
+        }
+        Ok(())
+    }
+
+    /// The median depth of the points a keyframe observes, `None` if it observes none.
+    pub fn median_depth(&self, keyframe: KeyFrameId) -> Option<f64> {
+        let kf = self.keyframes.get(&keyframe)?;
+        let mut depths = kf
+            .map_points
+            .iter()
+            .flatten()
+            .map(|id| (kf.pose * self.map_points[id].position).z)
+            .collect::<Vec<_>>();
+        if depths.is_empty() {
+            return None;
+        }
+        depths.sort_by(f64::total_cmp);
+        Some(depths[depths.len() / 2])
+    }
+
+    /// Moves a point, e.g. after bundle adjustment.
+    pub fn set_map_point_position(
+        &mut self,
+        point: MapPointId,
+        position: Point3<f64>,
+    ) -> Result<(), MapError> {
+        self.map_points
+            .get_mut(&point)
+            .ok_or(MapError::UnknownMapPoint(point))?
+            .position = position;
+        self.update_point(point);
+        Ok(())
+    }
+
+    /// The number of points two keyframes share.
+    pub fn covisibility_weight(&self, a: KeyFrameId, b: KeyFrameId) -> usize {
+        self.covisibility
+            .get(&a)
+            .and_then(|neighbours| neighbours.get(&b))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// The keyframes sharing at least `min_weight` points with `keyframe`, most shared first.
+    pub fn covisible_keyframes(
+        &self,
+        keyframe: KeyFrameId,
+        min_weight: usize,
+    ) -> Vec<(KeyFrameId, usize)> {
+        let mut neighbours = self
+            .covisibility
+            .get(&keyframe)
+            .map(|neighbours| {
+                neighbours
+                    .iter()
+                    .filter(|(_, &weight)| weight >= min_weight)
+                    .map(|(&id, &weight)| (id, weight))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        neighbours.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        neighbours
+    }
+
+    /// The (up to) `n` keyframes sharing the most points with `keyframe`.
+    pub fn best_covisible_keyframes(&self, keyframe: KeyFrameId, n: usize) -> Vec<KeyFrameId> {
+        self.covisible_keyframes(keyframe, 1)
+            .into_iter()
+            .take(n)
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    fn change_covisibility(&mut self, a: KeyFrameId, b: KeyFrameId, change: isize) {
+        for (from, to) in [(a, b), (b, a)] {
+            let neighbours = self.covisibility.entry(from).or_default();
+            let weight = neighbours.entry(to).or_insert(0);
+            *weight = weight.saturating_add_signed(change);
+            if *weight == 0 {
+                neighbours.remove(&to);
+            }
+        }
+    }
+
+    // recomputes the viewing direction, scale range and representative descriptor
+    fn update_point(&mut self, point: MapPointId) {
+        let mp = &self.map_points[&point];
+        if mp.observations.is_empty() {
+            return;
+        }
+
+        let mut normal = Vector3::zeros();
+        let mut descriptors = Vec::with_capacity(mp.observations.len());
+        for (id, &index) in &mp.observations {
+            let keyframe = &self.keyframes[id];
+            let ray = mp.position - keyframe.center();
+            if ray.norm() > 0.0 {
+                normal += ray.normalize();
+            }
+            descriptors.push(&keyframe.descriptors[index]);
+        }
+        let normal = normal.try_normalize(f64::EPSILON).unwrap_or(Vector3::z());
+        let distance = (mp.position - self.keyframes[&mp.reference].center()).norm();
+
+        // the descriptor with the least median distance to the others
+        let descriptor = (0..descriptors.len())
+            .min_by_key(|&i| {
+                let mut distances = (0..descriptors.len())
+                    .filter(|&j| j != i)
+                    .map(|j| hamming_distance(&descriptors[i].0, &descriptors[j].0))
+                    .collect::<Vec<_>>();
+                distances.sort_unstable();
+                distances.get(distances.len() / 2).copied().unwrap_or(0)
+            })
+            .map(|i| descriptors[i].clone())
+            .unwrap();
+
+        let mp = self.map_points.get_mut(&point).unwrap();
+        mp.normal = normal;
+        mp.min_distance = distance / SCALE_TOLERANCE;
+        mp.max_distance = distance * SCALE_TOLERANCE;
+        mp.descriptor = descriptor;
+    }
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_scene;
+
+    fn descriptor(byte: u8) -> Descriptor {
+        Descriptor(vec![byte; 4])
+    }
+
+    // keyframes at the origin and at the test motion, each with `n` keypoints
+    fn map_with_keyframes(n: usize) -> (Map, KeyFrameId, KeyFrameId) {
+        let camera = test_scene::camera();
+        let mut map = Map::new();
+        let key_points = vec![KeyPoint::new(0.0, 0.0, 0.0); n];
+        let descriptors = (0..n).map(|i| descriptor(i as u8)).collect::<Vec<_>>();
+        let a = map
+            .insert_keyframe(
+                SE3::identity(),
+                camera,
+                key_points.clone(),
+                descriptors.clone(),
+            )
+            .unwrap();
+        let b = map
+            .insert_keyframe(test_scene::motion(), camera, key_points, descriptors)
+            .unwrap();
+        (map, a, b)
+    }
+
+    #[test]
+    fn test_observations_and_covisibility() {
+        let (mut map, a, b) = map_with_keyframes(5);
+        let points = test_scene::points(3);
+        for (i, position) in points.iter().enumerate() {
+            let point = map.insert_map_point(*position, a, i).unwrap();
+            map.add_observation(point, b, i).unwrap();
+        }
+
+        assert_eq!(map.num_map_points(), 3);
+        assert_eq!(map.covisibility_weight(a, b), 3);
+        assert_eq!(map.covisibility_weight(b, a), 3);
+        assert_eq!(map.covisible_keyframes(a, 1), vec![(b, 3)]);
+        assert!(map.covisible_keyframes(a, 4).is_empty());
+        assert_eq!(map.keyframe(a).unwrap().num_map_points(), 3);
+
+        let point = map.keyframe(b).unwrap().map_point(0).unwrap();
+        map.remove_map_point(point).unwrap();
+        assert_eq!(map.covisibility_weight(a, b), 2);
+        assert_eq!(map.keyframe(a).unwrap().map_point(0), None);
+        assert_eq!(map.keyframe(b).unwrap().map_point(0), None);
+    }
+
+    #[test]
+    fn test_invalid_operations() {
+        let (mut map, a, b) = map_with_keyframes(2);
+        let position = Point3::new(0.0, 0.0, 5.0);
+        let point = map.insert_map_point(position, a, 0).unwrap();
+
+        assert_eq!(
+            map.insert_map_point(position, a, 0),
+            Err(MapError::KeyPointTaken {
+                keyframe: a,
+                index: 0,
+                point
+            })
+        );
+        assert_eq!(
+            map.add_observation(point, a, 1),
+            Err(MapError::AlreadyObserved { keyframe: a, point })
+        );
+        assert_eq!(
+            map.add_observation(point, b, 7),
+            Err(MapError::KeyPointOutOfRange {
+                keyframe: b,
+                index: 7
+            })
+        );
+        assert_eq!(
+            map.add_observation(MapPointId(42), b, 0),
+            Err(MapError::UnknownMapPoint(MapPointId(42)))
+        );
+        assert_eq!(
+            map.remove_observation(point, b),
+            Err(MapError::NotObserved { keyframe: b, point })
+        );
+        assert_eq!(
+            map.remove_observation(point, KeyFrameId(42)),
+            Err(MapError::UnknownKeyFrame(KeyFrameId(42)))
+        );
+        assert!(map
+            .insert_keyframe(
+                SE3::identity(),
+                test_scene::camera(),
+                vec![],
+                vec![descriptor(0)]
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_remove_keyframe() {
+        let (mut map, a, b) = map_with_keyframes(3);
+        let shared = map
+            .insert_map_point(Point3::new(0.0, 0.0, 5.0), a, 0)
+            .unwrap();
+        map.add_observation(shared, b, 0).unwrap();
+        let only_a = map
+            .insert_map_point(Point3::new(1.0, 0.0, 5.0), a, 1)
+            .unwrap();
+
+        map.remove_keyframe(a).unwrap();
+        assert_eq!(map.num_keyframes(), 1);
+        // the point only a saw is gone, the shared one is re-anchored to b
+        assert!(map.map_point(only_a).is_none());
+        let shared = map.map_point(shared).unwrap();
+        assert_eq!(shared.reference_keyframe(), b);
+        assert_eq!(shared.num_observations(), 1);
+        assert!(map.covisible_keyframes(b, 1).is_empty());
+    }
+
+    #[test]
+    fn test_replace_map_point() {
+        let (mut map, a, b) = map_with_keyframes(3);
+        let first = map
+            .insert_map_point(Point3::new(0.0, 0.0, 5.0), a, 0)
+            .unwrap();
+        let second = map
+            .insert_map_point(Point3::new(0.0, 0.0, 5.1), b, 1)
+            .unwrap();
+
+        map.replace_map_point(second, first).unwrap();
+        assert!(map.map_point(second).is_none());
+        assert_eq!(map.map_point(first).unwrap().num_observations(), 2);
+        assert_eq!(map.keyframe(b).unwrap().map_point(1), Some(first));
+        assert_eq!(map.covisibility_weight(a, b), 1);
+    }
+
+    #[test]
+    fn test_point_statistics() {
+        let camera = test_scene::camera();
+        let mut map = Map::new();
+        let key_points = vec![KeyPoint::new(0.0, 0.0, 0.0)];
+        let mut ids = vec![];
+        // the middle descriptor is closest to the other two
+        for (x, byte) in [(0.0, 0b0000_0000), (1.0, 0b0000_0011), (2.0, 0b0000_1111)] {
+            let pose = SE3::new(Default::default(), Vector3::new(-x, 0.0, 0.0));
+            ids.push(
+                map.insert_keyframe(pose, camera, key_points.clone(), vec![descriptor(byte)])
+                    .unwrap(),
+            );
+        }
+        let point = map
+            .insert_map_point(Point3::new(1.0, 0.0, 4.0), ids[1], 0)
+            .unwrap();
+        map.add_observation(point, ids[0], 0).unwrap();
+        map.add_observation(point, ids[2], 0).unwrap();
+
+        let mp = map.map_point(point).unwrap();
+        assert_eq!(mp.descriptor(), &descriptor(0b0000_0011));
+        assert!((mp.normal() - Vector3::z()).norm() < 1e-12);
+        assert!(mp.is_in_scale_range(4.0));
+        assert!(!mp.is_in_scale_range(10.0));
+
+        map.set_map_point_position(point, Point3::new(1.0, 0.0, 8.0))
+            .unwrap();
+        assert!(map.map_point(point).unwrap().is_in_scale_range(10.0));
+        assert_eq!(map.median_depth(ids[0]), Some(8.0));
+
+        assert_eq!(map.map_point(point).unwrap().found_ratio(), 1.0);
+        for found in [false, false, true] {
+            map.record_tracking(point, found).unwrap();
+        }
+        assert_eq!(map.map_point(point).unwrap().found_ratio(), 0.5);
+    }
+}
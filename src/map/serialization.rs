@@ -0,0 +1,667 @@
+//! Saving and loading maps.
+//!
+//! The binary format is little endian and starts with a magic string and a format version, so
+//! maps written today still load once the format grows. Everything the map holds is stored,
+//! including the tracking statistics of the points, so a loaded map behaves exactly like the one
+//! saved. The JSON export is for inspection and plotting only, it cannot be loaded back.
+//!
+//! The header also holds the BRIEF settings the descriptors were made with, descriptors of other
+//! settings cannot be matched against the map.
+//!
+//! Version 1 layout, counts and ids as u64, `none` ids as `u64::MAX`:
+//!
+//! ```text
+//! magic "XDOFMAP\0", version u32, BRIEF seed u64, patch size, number of pairs,
+//! next keyframe id, next map point id
+//! keyframes: count, then per keyframe
+//!     id, rotation quaternion (i, j, k, w) f64, translation f64 x3, fx fy cx cy f64,
+//!     keypoint count, then per keypoint x y orientation f32, descriptor length u32 and bytes,
+//!     map point id or none
+//! map points: count, then per point
+//!     id, position f64 x3, reference, first keyframe, visible, found,
+//!     observation count, then per observation keyframe id and keypoint index,
+//!     normal f64 x3, min and max distance f64, descriptor length u32 and bytes
+//! covisibility: count, then per pair (first id < second id) the ids and the weight
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use nalgebra::{Point3, Quaternion, UnitQuaternion, Vector3};
+
+use super::{KeyFrame, KeyFrameId, Map, MapPoint, MapPointId};
+use crate::camera::Camera;
+use crate::common::{Descriptor, KeyPoint};
+use crate::extractor::BriefSettings;
+use crate::lie::{SE3, SO3};
+
+const MAGIC: &[u8; 8] = b"XDOFMAP\0";
+
+/// The binary format version written by `Map::write_binary`.
+pub const MAP_FORMAT_VERSION: u32 = 1;
+
+// longer descriptors are taken as a sign of a corrupt file rather than allocated
+const MAX_DESCRIPTOR_BYTES: usize = 1 << 16;
+const NONE: u64 = u64::MAX;
+
+#[derive(Debug)]
+pub enum MapIoError {
+    Io(io::Error),
+    /// The data does not start with the map magic string.
+    NotAMap,
+    /// Written by a newer version of the library, or not a valid version at all.
+    UnsupportedVersion(u32),
+    /// The data is inconsistent, e.g. an observation of a missing keyframe.
+    Corrupt(String),
+}
+
+impl fmt::Display for MapIoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapIoError::Io(e) => write!(f, "{}", e),
+            MapIoError::NotAMap => write!(f, "not a map file"),
+            MapIoError::UnsupportedVersion(version) => write!(
+                f,
+                "map format version {} is not one of the supported versions 1 to {}",
+                version, MAP_FORMAT_VERSION
+            ),
+            MapIoError::Corrupt(reason) => write!(f, "corrupt map: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for MapIoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MapIoError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MapIoError {
+    fn from(error: io::Error) -> Self {
+        MapIoError::Io(error)
+    }
+}
+
+fn corrupt<T>(reason: impl Into<String>) -> Result<T, MapIoError> {
+    Err(MapIoError::Corrupt(reason.into()))
+}
+
+struct Writer<W: Write>(W);
+
+impl<W: Write> Writer<W> {
+    fn u32(&mut self, v: u32) -> io::Result<()> {
+        self.0.write_all(&v.to_le_bytes())
+    }
+
+    fn u64(&mut self, v: u64) -> io::Result<()> {
+        self.0.write_all(&v.to_le_bytes())
+    }
+
+    fn usize(&mut self, v: usize) -> io::Result<()> {
+        self.u64(v as u64)
+    }
+
+    fn f32(&mut self, v: f32) -> io::Result<()> {
+        self.0.write_all(&v.to_le_bytes())
+    }
+
+    fn f64s(&mut self, values: &[f64]) -> io::Result<()> {
+        values
+            .iter()
+            .try_for_each(|v| self.0.write_all(&v.to_le_bytes()))
+    }
+
+    fn descriptor(&mut self, descriptor: &Descriptor) -> io::Result<()> {
+        self.u32(descriptor.0.len() as u32)?;
+        self.0.write_all(&descriptor.0)
+    }
+}
+
+struct Reader<R: Read>(R);
+
+impl<R: Read> Reader<R> {
+    fn bytes<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut bytes = [0; N];
+        self.0.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes()?))
+    }
+
+    fn usize(&mut self) -> Result<usize, MapIoError> {
+        match usize::try_from(self.u64()?) {
+            Ok(v) => Ok(v),
+            Err(_) => corrupt("count out of range"),
+        }
+    }
+
+    fn f32(&mut self) -> io::Result<f32> {
+        Ok(f32::from_le_bytes(self.bytes()?))
+    }
+
+    fn f64(&mut self) -> io::Result<f64> {
+        Ok(f64::from_le_bytes(self.bytes()?))
+    }
+
+    fn vector(&mut self) -> io::Result<Vector3<f64>> {
+        Ok(Vector3::new(self.f64()?, self.f64()?, self.f64()?))
+    }
+
+    fn descriptor(&mut self) -> Result<Descriptor, MapIoError> {
+        let len = self.u32()? as usize;
+        if len > MAX_DESCRIPTOR_BYTES {
+            return corrupt(format!("descriptor of {} bytes", len));
+        }
+        let mut bytes = vec![0; len];
+        self.0.read_exact(&mut bytes)?;
+        Ok(Descriptor(bytes))
+    }
+}
+
+impl Map {
+    /// Writes the map in the binary format, see the module docs. `brief` are the settings of
+    /// the descriptors in the map.
+    pub fn write_binary<W: Write>(
+        &self,
+        writer: W,
+        brief: &BriefSettings,
+    ) -> Result<(), MapIoError> {
+        let mut w = Writer(writer);
+        w.0.write_all(MAGIC)?;
+        w.u32(MAP_FORMAT_VERSION)?;
+        w.u64(brief.seed)?;
+        w.usize(brief.patch_size)?;
+        w.usize(brief.num_pairs)?;
+        w.usize(self.next_keyframe)?;
+        w.usize(self.next_map_point)?;
+
+        w.usize(self.keyframes.len())?;
+        for kf in self.keyframes.values() {
+            w.usize(kf.id.0)?;
+            w.f64s(kf.pose.rotation.quaternion().coords.as_slice())?;
+            w.f64s(kf.pose.translation.as_slice())?;
+            w.f64s(&[kf.camera.fx, kf.camera.fy, kf.camera.cx, kf.camera.cy])?;
+            w.usize(kf.key_points.len())?;
+            for ((key_point, descriptor), point) in kf
+                .key_points
+                .iter()
+                .zip(&kf.descriptors)
+                .zip(&kf.map_points)
+            {
+                w.f32(key_point.x)?;
+                w.f32(key_point.y)?;
+                w.f32(key_point.orientation)?;
+                w.descriptor(descriptor)?;
+                w.u64(point.map_or(NONE, |point| point.0 as u64))?;
+            }
+        }
+
+        w.usize(self.map_points.len())?;
+        for mp in self.map_points.values() {
+            w.usize(mp.id.0)?;
+            w.f64s(mp.position.coords.as_slice())?;
+            w.usize(mp.reference.0)?;
+            w.usize(mp.first_keyframe.0)?;
+            w.usize(mp.visible)?;
+            w.usize(mp.found)?;
+            w.usize(mp.observations.len())?;
+            for (keyframe, &index) in &mp.observations {
+                w.usize(keyframe.0)?;
+                w.usize(index)?;
+            }
+            w.f64s(mp.normal.as_slice())?;
+            w.f64s(&[mp.min_distance, mp.max_distance])?;
+            w.descriptor(&mp.descriptor)?;
+        }
+
+        let pairs = self.covisibility_pairs();
+        w.usize(pairs.len())?;
+        for ((a, b), weight) in pairs {
+            w.usize(a.0)?;
+            w.usize(b.0)?;
+            w.usize(weight)?;
+        }
+        w.0.flush()?;
+        Ok(())
+    }
+
+    /// Reads a map written by `write_binary`, by this or an older version of the library, and
+    /// the BRIEF settings of its descriptors.
+    pub fn read_binary<R: Read>(reader: R) -> Result<(Map, BriefSettings), MapIoError> {
+        let mut r = Reader(reader);
+        if &r.bytes::<8>()? != MAGIC {
+            return Err(MapIoError::NotAMap);
+        }
+        let version = r.u32()?;
+        if version == 0 || version > MAP_FORMAT_VERSION {
+            return Err(MapIoError::UnsupportedVersion(version));
+        }
+        let brief = BriefSettings {
+            seed: r.u64()?,
+            patch_size: r.usize()?,
+            num_pairs: r.usize()?,
+        };
+        if brief.patch_size == 0 || brief.num_pairs == 0 {
+            return corrupt("BRIEF settings");
+        }
+
+        let mut map = Map {
+            next_keyframe: r.usize()?,
+            next_map_point: r.usize()?,
+            ..Default::default()
+        };
+
+        for _ in 0..r.usize()? {
+            let id = KeyFrameId(r.usize()?);
+            let q = [r.f64()?, r.f64()?, r.f64()?, r.f64()?];
+            let q = Quaternion::new(q[3], q[0], q[1], q[2]);
+            if !q.coords.iter().all(|v| v.is_finite()) || (q.norm() - 1.0).abs() > 1e-6 {
+                return corrupt(format!("keyframe {} rotation", id.0));
+            }
+            let rotation = UnitQuaternion::new_unchecked(q);
+            let pose = SE3::new(SO3::from_quaternion(rotation), r.vector()?);
+            let camera = Camera::new(r.f64()?, r.f64()?, r.f64()?, r.f64()?);
+            if !(camera.fx.is_finite()
+                && camera.fx > 0.0
+                && camera.fy.is_finite()
+                && camera.fy > 0.0)
+            {
+                return corrupt(format!("keyframe {} focal length", id.0));
+            }
+            let mut kf = KeyFrame {
+                id,
+                pose,
+                camera,
+                key_points: Vec::new(),
+                descriptors: Vec::new(),
+                map_points: Vec::new(),
+            };
+            for _ in 0..r.usize()? {
+                kf.key_points
+                    .push(KeyPoint::new(r.f32()?, r.f32()?, r.f32()?));
+                kf.descriptors.push(r.descriptor()?);
+                let point = r.u64()?;
+                kf.map_points
+                    .push((point != NONE).then_some(MapPointId(point as usize)));
+            }
+            if id.0 >= map.next_keyframe || map.keyframes.insert(id, kf).is_some() {
+                return corrupt(format!("keyframe id {}", id.0));
+            }
+        }
+
+        for _ in 0..r.usize()? {
+            let id = MapPointId(r.usize()?);
+            let position = Point3::from(r.vector()?);
+            let reference = KeyFrameId(r.usize()?);
+            let first_keyframe = KeyFrameId(r.usize()?);
+            let visible = r.usize()?;
+            let found = r.usize()?;
+            let mut observations = BTreeMap::new();
+            for _ in 0..r.usize()? {
+                observations.insert(KeyFrameId(r.usize()?), r.usize()?);
+            }
+            let mp = MapPoint {
+                id,
+                position,
+                observations,
+                reference,
+                normal: r.vector()?,
+                min_distance: r.f64()?,
+                max_distance: r.f64()?,
+                descriptor: r.descriptor()?,
+                first_keyframe,
+                visible,
+                found,
+            };
+            if id.0 >= map.next_map_point || map.map_points.insert(id, mp).is_some() {
+                return corrupt(format!("map point id {}", id.0));
+            }
+        }
+
+        let mut pairs = BTreeMap::new();
+        for _ in 0..r.usize()? {
+            pairs.insert((KeyFrameId(r.usize()?), KeyFrameId(r.usize()?)), r.usize()?);
+        }
+
+        map.check_observations()?;
+        map.rebuild_covisibility();
+        if map.covisibility_pairs() != pairs {
+            return corrupt("covisibility does not match the observations");
+        }
+        Ok((map, brief))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>, brief: &BriefSettings) -> Result<(), MapIoError> {
+        self.write_binary(BufWriter::new(File::create(path)?), brief)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<(Map, BriefSettings), MapIoError> {
+        Map::read_binary(BufReader::new(File::open(path)?))
+    }
+
+    /// The map as JSON, for inspection. Poses are `X_camera = pose * X_world` with the rotation
+    /// as a quaternion `[i, j, k, w]`, descriptors are hex strings.
+    pub fn to_json(&self) -> String {
+        let keyframes = self
+            .keyframes
+            .values()
+            .map(|kf| {
+                let key_points = kf
+                    .key_points
+                    .iter()
+                    .map(|k| {
+                        format!(
+                            "[{},{},{}]",
+                            number(k.x),
+                            number(k.y),
+                            number(k.orientation)
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                let descriptors = kf.descriptors.iter().map(hex).collect::<Vec<_>>();
+                let map_points = kf
+                    .map_points
+                    .iter()
+                    .map(|p| p.map_or("null".to_string(), |p| p.0.to_string()))
+                    .collect::<Vec<_>>();
+                let c = &kf.camera;
+                format!(
+                    "{{\"id\":{},\"rotation\":{},\"translation\":{},\"camera\":{{\"fx\":{},\"fy\":{},\"cx\":{},\"cy\":{}}},\"key_points\":[{}],\"descriptors\":[{}],\"map_points\":[{}]}}",
+                    kf.id.0,
+                    numbers(kf.pose.rotation.quaternion().coords.as_slice()),
+                    numbers(kf.pose.translation.as_slice()),
+                    number(c.fx),
+                    number(c.fy),
+                    number(c.cx),
+                    number(c.cy),
+                    key_points.join(","),
+                    descriptors.join(","),
+                    map_points.join(","),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let map_points = self
+            .map_points
+            .values()
+            .map(|mp| {
+                let observations = mp
+                    .observations
+                    .iter()
+                    .map(|(k, i)| format!("[{},{}]", k.0, i))
+                    .collect::<Vec<_>>();
+                format!(
+                    "{{\"id\":{},\"position\":{},\"observations\":[{}],\"reference\":{},\"first_keyframe\":{},\"visible\":{},\"found\":{},\"normal\":{},\"distance_range\":{},\"descriptor\":{}}}",
+                    mp.id.0,
+                    numbers(mp.position.coords.as_slice()),
+                    observations.join(","),
+                    mp.reference.0,
+                    mp.first_keyframe.0,
+                    mp.visible,
+                    mp.found,
+                    numbers(mp.normal.as_slice()),
+                    numbers(&[mp.min_distance, mp.max_distance]),
+                    hex(&mp.descriptor),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let covisibility = self
+            .covisibility_pairs()
+            .into_iter()
+            .map(|((a, b), weight)| format!("[{},{},{}]", a.0, b.0, weight))
+            .collect::<Vec<_>>();
+
+        format!(
+            "{{\"version\":{},\"keyframes\":[\n{}\n],\"map_points\":[\n{}\n],\"covisibility\":[{}]}}\n",
+            MAP_FORMAT_VERSION,
+            keyframes.join(",\n"),
+            map_points.join(",\n"),
+            covisibility.join(","),
+        )
+    }
+
+    pub fn export_json(&self, path: impl AsRef<Path>) -> Result<(), MapIoError> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(self.to_json().as_bytes())?;
+        file.flush()?;
+        Ok(())
+    }
+
+    // each covisible pair once, the smaller id first
+    fn covisibility_pairs(&self) -> BTreeMap<(KeyFrameId, KeyFrameId), usize> {
+        self.covisibility
+            .iter()
+            .flat_map(|(&a, neighbours)| {
+                neighbours
+                    .iter()
+                    .filter(move |(&b, _)| a < b)
+                    .map(move |(&b, &weight)| ((a, b), weight))
+            })
+            .collect()
+    }
+
+    fn rebuild_covisibility(&mut self) {
+        self.covisibility = self
+            .keyframes
+            .keys()
+            .map(|&id| (id, BTreeMap::new()))
+            .collect();
+        let observers = self
+            .map_points
+            .values()
+            .map(|mp| mp.observations.keys().copied().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        for keyframes in observers {
+            for (i, &a) in keyframes.iter().enumerate() {
+                for &b in &keyframes[i + 1..] {
+                    self.change_covisibility(a, b, 1);
+                }
+            }
+        }
+    }
+
+    // both sides of every observation agree
+    fn check_observations(&self) -> Result<(), MapIoError> {
+        for kf in self.keyframes.values() {
+            if kf.key_points.len() != kf.descriptors.len() {
+                return corrupt(format!("keyframe {} descriptors", kf.id.0));
+            }
+            for (index, point) in kf.map_points.iter().enumerate() {
+                let observed = point.and_then(|point| {
+                    self.map_points
+                        .get(&point)?
+                        .observations
+                        .get(&kf.id)
+                        .filter(|&&i| i == index)
+                });
+                if point.is_some() && observed.is_none() {
+                    return corrupt(format!("keyframe {} keypoint {}", kf.id.0, index));
+                }
+            }
+        }
+        for mp in self.map_points.values() {
+            if !mp.observations.contains_key(&mp.reference) {
+                return corrupt(format!("map point {} reference", mp.id.0));
+            }
+            for (keyframe, &index) in &mp.observations {
+                let kf = match self.keyframes.get(keyframe) {
+                    Some(kf) => kf,
+                    None => return corrupt(format!("map point {} observations", mp.id.0)),
+                };
+                if kf.map_points.get(index) != Some(&Some(mp.id)) {
+                    return corrupt(format!("map point {} observations", mp.id.0));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn number<T: Into<f64> + ToString + Copy>(v: T) -> String {
+    // JSON has no infinity or NaN
+    match v.into().is_finite() {
+        true => v.to_string(),
+        false => "null".to_string(),
+    }
+}
+
+fn numbers(values: &[f64]) -> String {
+    let values = values.iter().map(|&v| number(v)).collect::<Vec<_>>();
+    format!("[{}]", values.join(","))
+}
+
+fn hex(descriptor: &Descriptor) -> String {
+    let digits = descriptor
+        .0
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    format!("\"{}\"", digits)
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_scene;
+
+    // two keyframes sharing points, some tracking statistics and a removed point
+    fn map() -> Map {
+        let camera = test_scene::camera();
+        let points = test_scene::points(20);
+        let descriptors = test_scene::descriptors(points.len(), 2);
+        let mut map = Map::new();
+        let keyframes = [SE3::identity(), test_scene::motion()]
+            .iter()
+            .map(|pose| {
+                let key_points = points
+                    .iter()
+                    .map(|p| test_scene::project(&camera, &(*pose * *p)))
+                    .collect();
+                map.insert_keyframe(*pose, camera, key_points, descriptors.clone())
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        for (i, p) in points.iter().enumerate() {
+            let id = map.insert_map_point(*p, keyframes[0], i).unwrap();
+            if i % 3 != 0 {
+                map.add_observation(id, keyframes[1], i).unwrap();
+            }
+            map.record_tracking(id, i % 2 == 0).unwrap();
+        }
+        map.remove_map_point(MapPointId(4)).unwrap();
+        map
+    }
+
+    fn brief() -> BriefSettings {
+        BriefSettings {
+            seed: 7,
+            patch_size: 48,
+            num_pairs: 256,
+        }
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let map = map();
+        let mut bytes = Vec::new();
+        map.write_binary(&mut bytes, &brief()).unwrap();
+        let (loaded, loaded_brief) = Map::read_binary(bytes.as_slice()).unwrap();
+        assert_eq!(loaded, map);
+        assert_eq!(loaded_brief, brief());
+
+        // ids keep counting where the saved map stopped
+        let mut loaded = loaded;
+        let keyframe = loaded
+            .insert_keyframe(SE3::identity(), test_scene::camera(), vec![], vec![])
+            .unwrap();
+        assert_eq!(keyframe, KeyFrameId(2));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let map = map();
+        let path = std::env::temp_dir().join(format!("xdof_map_{}.bin", std::process::id()));
+        map.save(&path, &brief()).unwrap();
+        let loaded = Map::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), (map, brief()));
+    }
+
+    #[test]
+    fn test_read_rejects_bad_data() {
+        let mut bytes = Vec::new();
+        map().write_binary(&mut bytes, &brief()).unwrap();
+
+        assert!(matches!(
+            Map::read_binary(&b"NOTAMAP\0\x01\0\0\0"[..]),
+            Err(MapIoError::NotAMap)
+        ));
+        for version in [0, MAP_FORMAT_VERSION + 1] {
+            let mut unsupported = bytes.clone();
+            unsupported[8..12].copy_from_slice(&version.to_le_bytes());
+            assert!(matches!(
+                Map::read_binary(unsupported.as_slice()),
+                Err(MapIoError::UnsupportedVersion(v)) if v == version
+            ));
+        }
+        // no BRIEF pairs
+        let mut tampered = bytes.clone();
+        tampered[28..36].copy_from_slice(&0u64.to_le_bytes());
+        assert!(matches!(
+            Map::read_binary(tampered.as_slice()),
+            Err(MapIoError::Corrupt(_))
+        ));
+        assert!(matches!(
+            Map::read_binary(&bytes[..bytes.len() - 3]),
+            Err(MapIoError::Io(_))
+        ));
+        // the first keyframe's quaternion w and focal length, after the header, count and id
+        for (offset, value) in [(92, 2.0), (92, f64::NAN), (124, 0.0), (124, f64::NAN)] {
+            let mut tampered = bytes.clone();
+            tampered[offset..offset + 8].copy_from_slice(&f64::to_le_bytes(value));
+            assert!(matches!(
+                Map::read_binary(tampered.as_slice()),
+                Err(MapIoError::Corrupt(_))
+            ));
+        }
+        // the last covisibility weight
+        let mut tampered = bytes.clone();
+        let last = tampered.len() - 8;
+        tampered[last] += 1;
+        assert!(matches!(
+            Map::read_binary(tampered.as_slice()),
+            Err(MapIoError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn test_json_export() {
+        let json = map().to_json();
+        assert!(json.starts_with("{\"version\":1,\"keyframes\":[\n{\"id\":0,"));
+        assert!(json.contains("\"covisibility\":[[0,1,"));
+        assert_eq!(json.matches("\"reference\":").count(), 19);
+        assert!(!json.contains("inf") && !json.contains("NaN"));
+
+        let mut map = map();
+        let keyframe = map.keyframes.values_mut().next().unwrap();
+        keyframe.key_points[0] = KeyPoint::new(f32::NAN, 1.5, f32::INFINITY);
+        assert!(map.to_json().contains("\"key_points\":[[null,1.5,null],"));
+    }
+}
@@ -0,0 +1,476 @@
+//! Merging the maps of separate sessions into one.
+//!
+//! The keyframes of the other map are looked up in a keyframe database of the map, and the
+//! Sim(3) between the cameras of the most similar pair is estimated under RANSAC from their
+//! matched points, as for loop closing. The other map is then moved into the map's frame, its
+//! keyframes and points copied over, the points seen by both sessions fused around the seam, and
+//! a global bundle adjustment makes the whole consistent.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::bundle_adjustment::{BundleAdjustment, BundleAdjustmentOptions};
+use crate::hamming::hamming_distance;
+use crate::keyframe_database::KeyFrameDatabase;
+use crate::lie::Sim3;
+use crate::loop_closing::{estimate_keyframe_sim3, fuse, KeyFrameSim3};
+use crate::map::{KeyFrameId, Map, MapError, MapPointId};
+use crate::rand::*;
+use crate::ransac::RansacOptions;
+use crate::robust::{RobustKernel, MAX_REPROJECTION_ERROR};
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct MapMergingOptions {
+    /// How many of the most similar keyframe pairs to verify.
+    pub max_candidates: usize,
+    pub max_hamming_distance: usize,
+    /// Skip pairs with fewer map point matches than this.
+    pub min_matches: usize,
+    /// `inlier_threshold` is a reprojection error in pixels.
+    pub ransac: RansacOptions,
+    pub min_inliers: usize,
+    /// How many covisible keyframes on each side of the seam take part in fusing points.
+    pub max_seam_keyframes: usize,
+    /// The window searched around the projections of the points across the seam, pixels.
+    pub search_radius: f64,
+    pub bundle_adjustment: BundleAdjustmentOptions,
+    /// Observations reprojecting further than this after the global bundle adjustment are
+    /// removed, pixels.
+    pub max_reprojection_error: f64,
+}
+
+impl Default for MapMergingOptions {
+    fn default() -> Self {
+        Self {
+            max_candidates: 10,
+            max_hamming_distance: 100,
+            min_matches: 20,
+            ransac: RansacOptions::reprojection(),
+            min_inliers: 20,
+            max_seam_keyframes: 10,
+            search_radius: 10.0,
+            bundle_adjustment: BundleAdjustmentOptions {
+                max_iterations: 20,
+                kernel: RobustKernel::Huber(MAX_REPROJECTION_ERROR),
+                ..Default::default()
+            },
+            max_reprojection_error: MAX_REPROJECTION_ERROR,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum MapMergeError {
+    /// No keyframe of one map resembles a keyframe of the other.
+    NoOverlap,
+    /// No similar pair was verified, the most inliers any had.
+    NotEnoughInliers(usize),
+    Map(MapError),
+}
+
+impl fmt::Display for MapMergeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapMergeError::NoOverlap => write!(f, "the maps do not overlap"),
+            MapMergeError::NotEnoughInliers(n) => {
+                write!(f, "the maps were aligned with at most {} inliers", n)
+            }
+            MapMergeError::Map(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for MapMergeError {}
+
+impl From<MapError> for MapMergeError {
+    fn from(error: MapError) -> Self {
+        MapMergeError::Map(error)
+    }
+}
+
+/// Where two maps overlap and how their frames relate.
+#[derive(PartialEq, Debug, Clone)]
+pub struct MapAlignment {
+    /// The keyframe of the map.
+    pub keyframe: KeyFrameId,
+    /// The keyframe of the other map seeing the same place.
+    pub other_keyframe: KeyFrameId,
+    /// Maps the other map's world coordinates to the map's, `X = transform * X_other`.
+    pub transform: Sim3,
+    /// Matches between the points of the two keyframes, as (point, other point).
+    pub matches: Vec<(MapPointId, MapPointId)>,
+}
+
+/// A merged map.
+#[derive(PartialEq, Debug, Clone)]
+pub struct MapMerge {
+    pub alignment: MapAlignment,
+    /// The id each keyframe of the other map got in the map.
+    pub keyframes: BTreeMap<KeyFrameId, KeyFrameId>,
+    /// How many copied points were merged into points of the map, or newly observed.
+    pub fused_points: usize,
+    pub removed_observations: usize,
+}
+
+/// Finds where `other` overlaps `map` and the similarity between their frames.
+pub fn align_maps(
+    map: &Map,
+    other: &Map,
+    options: &MapMergingOptions,
+    rnd: &mut Rand,
+) -> Result<MapAlignment, MapMergeError> {
+    let database = KeyFrameDatabase::from_map(map);
+    let mut pairs = other
+        .keyframes()
+        .filter_map(|kf| {
+            let (keyframe, score) = *database.query(kf.descriptors(), 1).first()?;
+            Some((score, keyframe, kf.id()))
+        })
+        .collect::<Vec<_>>();
+    if pairs.is_empty() {
+        return Err(MapMergeError::NoOverlap);
+    }
+    pairs.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    let mut best = 0;
+    for &(_, keyframe, other_keyframe) in pairs.iter().take(options.max_candidates) {
+        match align_keyframes(map, keyframe, other, other_keyframe, options, rnd) {
+            Ok(alignment) => return Ok(alignment),
+            Err(inliers) => best = best.max(inliers),
+        }
+    }
+    Err(MapMergeError::NotEnoughInliers(best))
+}
+
+// the alignment from the points two keyframes share, or the inliers found
+fn align_keyframes(
+    map: &Map,
+    keyframe: KeyFrameId,
+    other: &Map,
+    other_keyframe: KeyFrameId,
+    options: &MapMergingOptions,
+    rnd: &mut Rand,
+) -> Result<MapAlignment, usize> {
+    let KeyFrameSim3 {
+        transform: to_camera2,
+        matches,
+    } = estimate_keyframe_sim3(
+        map,
+        keyframe,
+        other,
+        other_keyframe,
+        options.max_hamming_distance,
+        options.min_matches,
+        options.min_inliers,
+        &options.ransac,
+        rnd,
+    )?;
+    let kf1 = map.keyframe(keyframe).unwrap();
+    let kf2 = other.keyframe(other_keyframe).unwrap();
+
+    // X_camera2 = S * X_camera1, so X = T1^-1 * S^-1 * T2 * X_other
+    let transform =
+        Sim3::from(*kf1.pose()).inverse() * to_camera2.inverse() * Sim3::from(*kf2.pose());
+    let matches = matches
+        .into_iter()
+        .map(|((_, point), (_, other_point))| (point, other_point))
+        .collect();
+    Ok(MapAlignment {
+        keyframe,
+        other_keyframe,
+        transform,
+        matches,
+    })
+}
+
+/// Merges `other` into `map`, keeping the frame of `map`. The maps must overlap. On an error
+/// `map` is left as it was.
+pub fn merge_maps(
+    map: &mut Map,
+    other: &Map,
+    options: &MapMergingOptions,
+    rnd: &mut Rand,
+) -> Result<MapMerge, MapMergeError> {
+    let alignment = align_maps(map, other, options, rnd)?;
+    let transform = alignment.transform;
+    let to_other = transform.inverse();
+
+    // merge into a copy, so an error part-way leaves the map whole
+    let mut merged = map.clone();
+
+    // copy the keyframes and points in the map's frame
+    let mut keyframes = BTreeMap::new();
+    for kf in other.keyframes() {
+        let pose = (Sim3::from(*kf.pose()) * to_other).to_se3();
+        let id = merged.insert_keyframe(
+            pose,
+            *kf.camera(),
+            kf.key_points().to_vec(),
+            kf.descriptors().to_vec(),
+        )?;
+        keyframes.insert(kf.id(), id);
+    }
+    let mut points = BTreeMap::new();
+    for mp in other.map_points() {
+        let reference = mp.reference_keyframe();
+        let index = mp.observations()[&reference];
+        let id =
+            merged.insert_map_point(transform * *mp.position(), keyframes[&reference], index)?;
+        for (keyframe, &index) in mp.observations() {
+            if *keyframe != reference {
+                merged.add_observation(id, keyframes[keyframe], index)?;
+            }
+        }
+        points.insert(mp.id(), id);
+    }
+
+    // the matched points first, then whatever projects onto the copied keyframes by the seam
+    let mut fused_points = 0;
+    for &(point, other_point) in &alignment.matches {
+        let copy = points[&other_point];
+        if merged.map_point(point).is_some() && merged.map_point(copy).is_some() {
+            merged.replace_map_point(copy, point)?;
+            fused_points += 1;
+        }
+    }
+    let mut seam = merged.best_covisible_keyframes(alignment.keyframe, options.max_seam_keyframes);
+    seam.push(alignment.keyframe);
+    let seam_points = seam
+        .iter()
+        .flat_map(|&id| merged.keyframe(id).unwrap().map_points().iter().flatten())
+        .copied()
+        .collect::<BTreeSet<_>>();
+    let mut copied = other
+        .best_covisible_keyframes(alignment.other_keyframe, options.max_seam_keyframes)
+        .into_iter()
+        .map(|id| keyframes[&id])
+        .collect::<Vec<_>>();
+    copied.push(keyframes[&alignment.other_keyframe]);
+    for keyframe in copied {
+        let matches = search_by_projection(&merged, keyframe, &seam_points, options);
+        fused_points += fuse(&mut merged, keyframe, &matches)?;
+    }
+
+    let removed_observations = global_bundle_adjustment(
+        &mut merged,
+        &options.bundle_adjustment,
+        options.max_reprojection_error,
+    )?;
+    *map = merged;
+    Ok(MapMerge {
+        alignment,
+        keyframes,
+        fused_points,
+        removed_observations,
+    })
+}
+
+// the best keypoint in the window around the projection of each point, as (key point index,
+// point), skipping keypoints that already see one of the points
+fn search_by_projection(
+    map: &Map,
+    keyframe: KeyFrameId,
+    points: &BTreeSet<MapPointId>,
+    options: &MapMergingOptions,
+) -> Vec<(usize, MapPointId)> {
+    let kf = map.keyframe(keyframe).unwrap();
+    let mut matches = BTreeMap::new();
+    for &point in points {
+        let mp = match map.map_point(point) {
+            Some(mp) if !mp.observations().contains_key(&keyframe) => mp,
+            _ => continue,
+        };
+        let pixel = match kf.camera().project(&(*kf.pose() * *mp.position())) {
+            Some(pixel) => pixel,
+            None => continue,
+        };
+        let best = kf
+            .key_points()
+            .iter()
+            .enumerate()
+            .filter(|(i, key_point)| {
+                let offset = key_point.point() - pixel;
+                !matches.contains_key(i)
+                    && kf.map_point(*i).is_none_or(|p| !points.contains(&p))
+                    && offset.x.abs() <= options.search_radius
+                    && offset.y.abs() <= options.search_radius
+            })
+            .map(|(i, _)| {
+                (
+                    hamming_distance(&mp.descriptor().0, &kf.descriptors()[i].0),
+                    i,
+                )
+            })
+            .min();
+        if let Some((distance, i)) = best {
+            if distance <= options.max_hamming_distance {
+                matches.insert(i, point);
+            }
+        }
+    }
+    matches.into_iter().collect()
+}
+
+/// Bundle adjustment of every keyframe and point, with the first keyframe fixed. Observations
+/// reprojecting further than `max_error` pixels afterwards are removed, returns how many.
+pub fn global_bundle_adjustment(
+    map: &mut Map,
+    options: &BundleAdjustmentOptions,
+    max_error: f64,
+) -> Result<usize, MapError> {
+    let first = match map.keyframes().next() {
+        Some(kf) => kf.id(),
+        None => return Ok(0),
+    };
+    let mut ba = BundleAdjustment::new();
+    let mut pose_index = BTreeMap::new();
+    for kf in map.keyframes() {
+        let index = ba.add_pose(*kf.camera(), *kf.pose(), kf.id() == first);
+        pose_index.insert(kf.id(), index);
+    }
+    let mut observations = Vec::new();
+    let mut point_index = BTreeMap::new();
+    for mp in map.map_points() {
+        let index = ba.add_point(*mp.position(), false);
+        point_index.insert(mp.id(), index);
+        for (&id, &key_point) in mp.observations() {
+            let pixel = map.keyframe(id).unwrap().key_points()[key_point].point();
+            ba.add_observation(pose_index[&id], index, pixel).unwrap();
+            observations.push((mp.id(), id));
+        }
+    }
+
+    let summary = ba.optimize(options);
+
+    for (&id, &index) in &pose_index {
+        if id != first {
+            map.set_keyframe_pose(id, ba.pose(index))?;
+        }
+    }
+    for (&point, &index) in &point_index {
+        map.set_map_point_position(point, ba.point(index))?;
+    }
+    let mut removed = 0;
+    for index in summary.outliers(max_error) {
+        let (point, id) = observations[index];
+        // the point may already be gone with its last observation
+        if map.map_point(point).is_some() {
+            map.remove_observation(point, id)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Descriptor;
+    use crate::lie::{SE3, SO3};
+    use crate::test_scene;
+    use nalgebra::{Point3, Vector3};
+
+    // keyframes at `poses` all seeing `points`, in the frame the poses and points are given in
+    fn session(poses: &[SE3], points: &[Point3<f64>], descriptors: &[Descriptor]) -> Map {
+        let camera = test_scene::camera();
+        let mut map = Map::new();
+        let mut ids = vec![];
+        for pose in poses {
+            let key_points = points
+                .iter()
+                .map(|p| test_scene::project(&camera, &(*pose * *p)))
+                .collect();
+            let keyframe = map
+                .insert_keyframe(*pose, camera, key_points, descriptors.to_vec())
+                .unwrap();
+            for (index, p) in points.iter().enumerate() {
+                match ids.get(index) {
+                    Some(&id) => map.add_observation(id, keyframe, index).unwrap(),
+                    None => ids.push(map.insert_map_point(*p, keyframe, index).unwrap()),
+                }
+            }
+        }
+        map
+    }
+
+    fn truth(x: f64) -> SE3 {
+        SE3::new(
+            SO3::exp(&Vector3::new(0.0, 0.02, 0.0)),
+            Vector3::new(-x, 0.0, 0.0),
+        )
+    }
+
+    #[test]
+    fn test_merge_maps() {
+        // both sessions see the same 80 points, the second also 40 of its own
+        let shared = test_scene::points(80);
+        let own = test_scene::points(40)
+            .into_iter()
+            .map(|p| p + Vector3::new(0.7, 0.3, 0.0))
+            .collect::<Vec<_>>();
+        let shared_descriptors = test_scene::descriptors(shared.len(), 1);
+        let own_descriptors = test_scene::descriptors(own.len(), 2);
+        let poses1 = [0.0, 0.3, 0.6].map(truth);
+        let mut map = session(&poses1, &shared, &shared_descriptors);
+
+        // the second session has its own frame, a similarity away from the first
+        let frame = Sim3::new(
+            SO3::exp(&Vector3::new(0.2, -0.1, 0.4)),
+            Vector3::new(3.0, -1.0, 2.0),
+            2.5,
+        );
+        let poses2 = [-0.2, 0.1].map(|x| (Sim3::from(truth(x)) * frame.inverse()).to_se3());
+        let points2 = shared
+            .iter()
+            .chain(&own)
+            .map(|p| frame * *p)
+            .collect::<Vec<_>>();
+        let descriptors2 = [shared_descriptors, own_descriptors].concat();
+        let other = session(&poses2, &points2, &descriptors2);
+
+        let mut rnd = Rand::new_with_seed(4);
+        let merge = merge_maps(&mut map, &other, &MapMergingOptions::default(), &mut rnd).unwrap();
+        assert!((merge.alignment.transform * frame).log().norm() < 1e-6);
+        assert_eq!(merge.removed_observations, 0);
+        assert_eq!(map.num_keyframes(), 5);
+        assert_eq!(map.num_map_points(), 120);
+
+        // the copied keyframes see the first session's points, at their true poses
+        let first = map.keyframes().next().unwrap().id();
+        for (x, id) in [-0.2, 0.1].iter().zip(merge.keyframes.values()) {
+            let kf = map.keyframe(*id).unwrap();
+            assert!((kf.pose().inverse() * truth(*x)).log().norm() < 1e-6);
+            assert_eq!(map.covisibility_weight(first, *id), 80);
+        }
+    }
+
+    #[test]
+    fn test_merge_unrelated_maps() {
+        let points = test_scene::points(80);
+        let poses = [0.0, 0.3].map(truth);
+        let mut map = session(&poses, &points, &test_scene::descriptors(80, 1));
+        let other = session(&poses, &points, &test_scene::descriptors(80, 2));
+        let before = map.clone();
+
+        let mut rnd = Rand::new_with_seed(4);
+        let result = merge_maps(&mut map, &other, &MapMergingOptions::default(), &mut rnd);
+        assert!(matches!(
+            result,
+            Err(MapMergeError::NoOverlap | MapMergeError::NotEnoughInliers(_))
+        ));
+        assert_eq!(map, before);
+        assert_eq!(
+            merge_maps(
+                &mut map,
+                &Map::new(),
+                &MapMergingOptions::default(),
+                &mut rnd
+            ),
+            Err(MapMergeError::NoOverlap)
+        );
+    }
+}
@@ -9,28 +9,77 @@ pub fn match_features(
     descriptors2: &[Descriptor],
     max_hamming_distance: usize,
 ) -> Vec<(KeyPoint, KeyPoint)> {
+    match_features_with_distance(
+        keypoints1,
+        descriptors1,
+        keypoints2,
+        descriptors2,
+        max_hamming_distance,
+    )
+    .into_iter()
+    .map(|(matched, _)| matched)
+    .collect()
+}
+
+/// Like `match_features`, but also returns the Hamming distance of each match so that callers
+/// can rank matches by quality (e.g. for PROSAC).
+pub fn match_features_with_distance(
+    keypoints1: &[KeyPoint],
+    descriptors1: &[Descriptor],
+    keypoints2: &[KeyPoint],
+    descriptors2: &[Descriptor],
+    max_hamming_distance: usize,
+) -> Vec<((KeyPoint, KeyPoint), usize)> {
+    match_indices(descriptors1, descriptors2, max_hamming_distance)
+        .into_iter()
+        .map(|(i, j, distance)| ((keypoints1[i], keypoints2[j]), distance))
+        .collect()
+}
+
+/// The brute force matches as `(index1, index2, distance)`, for callers that need to know which
+/// keypoints matched, e.g. to attach map points to them.
+pub fn match_indices(
+    descriptors1: &[Descriptor],
+    descriptors2: &[Descriptor],
+    max_hamming_distance: usize,
+) -> Vec<(usize, usize, usize)> {
     let mut matches = Vec::new();
 
-    for (keypoint1, descriptor1) in keypoints1.iter().zip(descriptors1) {
+    for (i, descriptor1) in descriptors1.iter().enumerate() {
         let mut best_distance = max_hamming_distance;
         let mut best_match = None;
 
-        for (keypoint2, descriptor2) in keypoints2.iter().zip(descriptors2) {
+        for (j, descriptor2) in descriptors2.iter().enumerate() {
             let distance = hamming_distance(&descriptor1.0, &descriptor2.0);
             if distance <= best_distance {
                 best_distance = distance;
-                best_match = Some(keypoint2);
+                best_match = Some(j);
             }
         }
 
-        if let Some(matched_keypoint) = best_match {
-            matches.push((*keypoint1, *matched_keypoint));
+        if let Some(j) = best_match {
+            matches.push((i, j, best_distance));
         }
     }
 
     matches
 }
 
+/// The matches of `match_indices` best first, keeping one per descriptor on either side: where
+/// several descriptors of the first set match the same one of the second, only the closest stays.
+/// Ties keep the lower index of the first set.
+pub fn match_unique(
+    descriptors1: &[Descriptor],
+    descriptors2: &[Descriptor],
+    max_hamming_distance: usize,
+) -> Vec<(usize, usize, usize)> {
+    let mut matches = match_indices(descriptors1, descriptors2, max_hamming_distance);
+    matches.sort_by_key(|&(_, _, distance)| distance);
+    let mut taken = vec![false; descriptors2.len()];
+    matches.retain(|&(_, j, _)| !std::mem::replace(&mut taken[j], true));
+    matches
+}
+
 /****************/
 /*  UNIT TESTS  */
 /****************/
@@ -59,7 +108,7 @@ mod tests {
         let descriptors2 = [
             Descriptor(vec![0b00000000, 0b00000000, 0b00000000, 0b00000000]),
             Descriptor(vec![0b00000000, 0b00000000, 0b00000000, 0b00000011]),
-            // odd girl out 
+            // odd girl out
             Descriptor(vec![0b01101000, 0b01000000, 0b00010000, 0b00000011]),
         ];
         let matches =
@@ -81,4 +130,45 @@ mod tests {
         assert_eq!(matches[2].0, keypoints1[2]);
         assert_eq!(matches[2].1, keypoints2[2]);
     }
+
+    #[test]
+    fn test_match_features_with_distance() {
+        let keypoints1 = [KeyPoint::new(0.0, 0.0, 0.0), KeyPoint::new(1.0, 1.0, 0.0)];
+        let descriptors1 = [
+            Descriptor(vec![0b00000000, 0b00000000]),
+            Descriptor(vec![0b00001111, 0b00000000]),
+        ];
+        let keypoints2 = [KeyPoint::new(5.0, 5.0, 0.0)];
+        let descriptors2 = [Descriptor(vec![0b00000001, 0b00000000])];
+        let matches = super::match_features_with_distance(
+            &keypoints1,
+            &descriptors1,
+            &keypoints2,
+            &descriptors2,
+            10,
+        );
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0], ((keypoints1[0], keypoints2[0]), 1));
+        assert_eq!(matches[1], ((keypoints1[1], keypoints2[0]), 3));
+        assert_eq!(
+            super::match_indices(&descriptors1, &descriptors2, 2),
+            vec![(0, 0, 1)]
+        );
+    }
+
+    #[test]
+    fn test_match_unique() {
+        let descriptors1 = [
+            Descriptor(vec![0b00000111]),
+            Descriptor(vec![0b00000001]),
+            Descriptor(vec![0b11110000]),
+            Descriptor(vec![0b00000011]),
+        ];
+        let descriptors2 = [Descriptor(vec![0b00000000]), Descriptor(vec![0b11110001])];
+        // the first, second and last all match the first descriptor, the second is closest
+        assert_eq!(
+            super::match_unique(&descriptors1, &descriptors2, 3),
+            vec![(1, 0, 1), (2, 1, 1)]
+        );
+    }
 }
@@ -0,0 +1,268 @@
+//! Camera pose from 2D-3D correspondences, the perspective-n-point problem.
+//!
+//! Hypotheses come from the direct linear transform over 6 correspondences in normalized
+//! coordinates, inside the generic RANSAC, and are polished by a motion-only bundle adjustment.
+//! The DLT needs the points not to be coplanar, which holds for the map points a keyframe sees in
+//! all but degenerate scenes.
+//!
+//! https://en.wikipedia.org/wiki/Perspective-n-Point
+
+use nalgebra::{DMatrix, Matrix3x4, Point2, Point3, Vector3};
+
+use crate::bundle_adjustment::{BundleAdjustment, BundleAdjustmentOptions};
+use crate::camera::Camera;
+use crate::lie::SE3;
+use crate::rand::*;
+use crate::ransac::*;
+
+/// A map point in world coordinates and the pixel it was matched to.
+pub type Correspondence = (Point3<f64>, Point2<f64>);
+
+impl Model for SE3 {
+    fn is_valid(&self) -> bool {
+        self.translation.iter().all(|v| v.is_finite())
+            && self
+                .rotation
+                .quaternion()
+                .coords
+                .iter()
+                .all(|v| v.is_finite())
+    }
+}
+
+/// The pose `X_camera = pose * X_world` by the normalized DLT, needs at least 6 correspondences.
+pub fn dlt(camera: &Camera, correspondences: &[Correspondence]) -> Option<SE3> {
+    let n = correspondences.len();
+    if n < 6 {
+        return None;
+    }
+
+    // move the points to their centroid and unit mean distance, for conditioning
+    let centroid = correspondences
+        .iter()
+        .fold(Vector3::zeros(), |sum, (p, _)| sum + p.coords)
+        / n as f64;
+    let spread = correspondences
+        .iter()
+        .map(|(p, _)| (p.coords - centroid).norm())
+        .sum::<f64>()
+        / n as f64;
+    if spread <= f64::EPSILON {
+        return None;
+    }
+
+    // two rows per correspondence from x cross (P * X) = 0, padded to at least 12 rows
+    let mut a = DMatrix::<f64>::zeros((2 * n).max(12), 12);
+    for (i, (point, pixel)) in correspondences.iter().enumerate() {
+        let p = (point.coords - centroid) / spread;
+        let x = camera.normalize(pixel);
+        let (u, v) = (x.x, x.y);
+        a.row_mut(2 * i).copy_from_slice(&[
+            p.x,
+            p.y,
+            p.z,
+            1.0,
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            -u * p.x,
+            -u * p.y,
+            -u * p.z,
+            -u,
+        ]);
+        a.row_mut(2 * i + 1).copy_from_slice(&[
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            p.x,
+            p.y,
+            p.z,
+            1.0,
+            -v * p.x,
+            -v * p.y,
+            -v * p.z,
+            -v,
+        ]);
+    }
+    let v_t = a.svd(false, true).v_t?;
+    let p = Matrix3x4::from_row_slice(v_t.row(11).transpose().as_slice());
+
+    // P = [s * R | s * t'] for the normalized points, fix the sign so the points are in front
+    let mut m = p.fixed_view::<3, 3>(0, 0).into_owned();
+    let mut last = p.column(3).into_owned();
+    if m.determinant() < 0.0 {
+        m = -m;
+        last = -last;
+    }
+    let svd = m.svd(true, true);
+    let rotation = svd.u? * svd.v_t?;
+    let scale = svd.singular_values.mean();
+    if scale <= f64::EPSILON {
+        return None;
+    }
+
+    // undo the normalization, X_camera = R * (X - c) / d + t' = (R * X + d * t' - R * c) / d
+    let translation = spread * last / scale - rotation * centroid;
+    Some(SE3::from_parts(&rotation, &translation))
+}
+
+/// Builds pose hypotheses with `dlt`.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct PnpSolver {
+    pub camera: Camera,
+}
+
+impl MinimalSolver<Correspondence> for PnpSolver {
+    type Model = SE3;
+
+    fn sample_size(&self) -> usize {
+        6
+    }
+
+    fn solve(&self, sample: &[Correspondence]) -> Vec<SE3> {
+        dlt(&self.camera, sample).into_iter().collect()
+    }
+
+    fn solve_nonminimal(&self, data: &[Correspondence]) -> Vec<SE3> {
+        dlt(&self.camera, data).into_iter().collect()
+    }
+}
+
+/// Scores correspondences by their reprojection error in pixels, infinite behind the camera.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct ReprojectionError {
+    pub camera: Camera,
+}
+
+impl ErrorMetric<SE3, Correspondence> for ReprojectionError {
+    fn error(&self, pose: &SE3, (point, pixel): &Correspondence) -> f64 {
+        self.camera
+            .project(&(*pose * *point))
+            .map_or(f64::INFINITY, |projected| (projected - pixel).norm())
+    }
+}
+
+/// Estimates the camera pose, `options.inlier_threshold` is a reprojection error in pixels.
+pub fn estimate_pose_ransac(
+    camera: &Camera,
+    correspondences: &[Correspondence],
+    options: &RansacOptions,
+    rnd: &mut Rand,
+) -> Option<RansacResult<SE3>> {
+    ransac(
+        correspondences,
+        &PnpSolver { camera: *camera },
+        &ReprojectionError { camera: *camera },
+        options,
+        rnd,
+    )
+}
+
+/// Motion-only bundle adjustment of `pose` against fixed points. Each of up to `rounds` rounds
+/// drops the correspondences reprojecting further than `max_error` pixels and optimizes again
+/// over the rest. Returns the pose and which correspondences are inliers.
+pub fn refine_pose(
+    camera: &Camera,
+    pose: &SE3,
+    correspondences: &[Correspondence],
+    rounds: usize,
+    max_error: f64,
+    options: &BundleAdjustmentOptions,
+) -> (SE3, Vec<bool>) {
+    let mut pose = *pose;
+    let mut inliers = vec![true; correspondences.len()];
+
+    for _ in 0..rounds {
+        let used = (0..correspondences.len())
+            .filter(|&i| inliers[i])
+            .collect::<Vec<_>>();
+        if used.len() < 3 {
+            break;
+        }
+        let mut ba = BundleAdjustment::new();
+        let camera_index = ba.add_pose(*camera, pose, false);
+        for &i in &used {
+            let (point, pixel) = correspondences[i];
+            let point = ba.add_point(point, true);
+            ba.add_observation(camera_index, point, pixel).unwrap();
+        }
+        ba.optimize(options);
+        pose = ba.pose(camera_index);
+
+        // every correspondence gets another chance against the refined pose
+        let metric = ReprojectionError { camera: *camera };
+        let refined = correspondences
+            .iter()
+            .map(|c| metric.error(&pose, c) <= max_error)
+            .collect::<Vec<_>>();
+        if refined == inliers {
+            break;
+        }
+        inliers = refined;
+    }
+    (pose, inliers)
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_scene;
+    use nalgebra::Vector6;
+
+    fn correspondences(pose: &SE3) -> Vec<Correspondence> {
+        let camera = test_scene::camera();
+        test_scene::points(60)
+            .into_iter()
+            .map(|p| (p, camera.project(&(*pose * p)).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_dlt() {
+        let pose = test_scene::motion();
+        let camera = test_scene::camera();
+        let correspondences = correspondences(&pose);
+
+        for sample in [&correspondences[..6], &correspondences[..]] {
+            let estimate = dlt(&camera, sample).unwrap();
+            assert!((estimate.inverse() * pose).log().norm() < 1e-8);
+        }
+        assert_eq!(dlt(&camera, &correspondences[..5]), None);
+    }
+
+    #[test]
+    fn test_estimate_pose_ransac_with_outliers() {
+        let pose = test_scene::motion();
+        let camera = test_scene::camera();
+        let mut correspondences = correspondences(&pose);
+        for (i, (_, pixel)) in correspondences.iter_mut().enumerate().step_by(4) {
+            pixel.x += 20.0 + i as f64;
+        }
+
+        let options = RansacOptions {
+            inlier_threshold: 2.0,
+            ..Default::default()
+        };
+        let mut rnd = Rand::new_with_seed(7);
+        let result = estimate_pose_ransac(&camera, &correspondences, &options, &mut rnd).unwrap();
+        assert_eq!(result.num_inliers(), 45);
+
+        let noisy = SE3::exp(&Vector6::new(0.01, 0.01, -0.02, 0.002, 0.001, 0.0)) * result.model;
+        let (refined, inliers) = refine_pose(
+            &camera,
+            &noisy,
+            &correspondences,
+            4,
+            2.0,
+            &BundleAdjustmentOptions::default(),
+        );
+        assert_eq!(inliers, result.inliers);
+        assert!((refined.inverse() * pose).log().norm() < 1e-8);
+    }
+}
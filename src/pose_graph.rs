@@ -0,0 +1,477 @@
+//! Pose graph optimization, the cheap way to spread a loop closure correction over a trajectory.
+//!
+//! The nodes are keyframe poses (world to camera, like everywhere else in the crate) and the
+//! edges relative pose measurements between them. Monocular maps drift in scale too, so the
+//! graph is optimized in Sim(3): SE(3) nodes simply keep their scale fixed at 1. Once optimized,
+//! `correct_point` moves the map points with the keyframe they hang off.
+//!
+//! https://en.wikipedia.org/wiki/Graph-based_SLAM
+
+use nalgebra::{DMatrix, DVector, Matrix6, Point3, SVector};
+
+use crate::lie::{Matrix7, Sim3, Vector7, SE3};
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+struct Node {
+    // the pose before the last optimization, to compute corrections
+    initial: Sim3,
+    pose: Sim3,
+    fixed: bool,
+    fixed_scale: bool,
+}
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum EdgeKind {
+    /// Consecutive keyframes or covisibility.
+    Odometry,
+    Loop,
+}
+
+/// A measurement of the relative pose `pose_to * pose_from^-1`, which maps camera `from`
+/// coordinates to camera `to` coordinates.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    pub measurement: Sim3,
+    /// The inverse covariance of the error, in the `(rho, phi, sigma)` tangent order.
+    pub information: Matrix7,
+    pub kind: EdgeKind,
+}
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct PoseGraphOptions {
+    pub max_iterations: usize,
+    /// Stop once an iteration reduces the cost by less than this fraction.
+    pub function_tolerance: f64,
+    /// The starting Levenberg-Marquardt damping.
+    pub initial_lambda: f64,
+}
+
+impl Default for PoseGraphOptions {
+    fn default() -> Self {
+        Self {
+            max_iterations: 20,
+            function_tolerance: 1e-10,
+            initial_lambda: 1e-6,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct PoseGraphSummary {
+    /// Half the summed squared Mahalanobis norm of the edge errors.
+    pub initial_cost: f64,
+    pub final_cost: f64,
+    pub iterations: usize,
+    /// False when we ran out of iterations before the cost settled.
+    pub converged: bool,
+}
+
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct PoseGraph {
+    nodes: Vec<Node>,
+    edges: Vec<Edge>,
+}
+
+/// Embeds a 6x6 SE(3) information matrix, SE(3) edges measure no change of scale.
+fn se3_information(information: &Matrix6<f64>) -> Matrix7 {
+    let mut info = Matrix7::identity();
+    info.fixed_view_mut::<6, 6>(0, 0).copy_from(information);
+    info
+}
+
+impl PoseGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a node whose scale stays fixed and returns its index. Fix at least one node, the
+    /// graph only constrains relative poses.
+    pub fn add_se3_node(&mut self, pose: SE3, fixed: bool) -> usize {
+        self.add_node(Sim3::from(pose), fixed, true)
+    }
+
+    /// Adds a node free to change scale as well as pose, and returns its index.
+    pub fn add_sim3_node(&mut self, pose: Sim3, fixed: bool) -> usize {
+        self.add_node(pose, fixed, false)
+    }
+
+    fn add_node(&mut self, pose: Sim3, fixed: bool, fixed_scale: bool) -> usize {
+        self.nodes.push(Node {
+            initial: pose,
+            pose,
+            fixed,
+            fixed_scale,
+        });
+        self.nodes.len() - 1
+    }
+
+    pub fn add_se3_edge(
+        &mut self,
+        from: usize,
+        to: usize,
+        measurement: SE3,
+        information: Matrix6<f64>,
+        kind: EdgeKind,
+    ) -> usize {
+        self.add_sim3_edge(
+            from,
+            to,
+            Sim3::from(measurement),
+            se3_information(&information),
+            kind,
+        )
+    }
+
+    pub fn add_sim3_edge(
+        &mut self,
+        from: usize,
+        to: usize,
+        measurement: Sim3,
+        information: Matrix7,
+        kind: EdgeKind,
+    ) -> usize {
+        assert!(from < self.nodes.len() && to < self.nodes.len());
+        self.edges.push(Edge {
+            from,
+            to,
+            measurement,
+            information,
+            kind,
+        });
+        self.edges.len() - 1
+    }
+
+    /// Adds a loop closure edge and optimizes the graph so the correction is spread over the
+    /// whole loop.
+    pub fn close_loop(
+        &mut self,
+        from: usize,
+        to: usize,
+        measurement: Sim3,
+        information: Matrix7,
+        options: &PoseGraphOptions,
+    ) -> PoseGraphSummary {
+        self.add_sim3_edge(from, to, measurement, information, EdgeKind::Loop);
+        self.optimize(options)
+    }
+
+    pub fn num_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn edges(&self) -> &[Edge] {
+        &self.edges
+    }
+
+    pub fn pose(&self, node: usize) -> Sim3 {
+        self.nodes[node].pose
+    }
+
+    /// The rigid camera pose of a node in the corrected (rescaled) world.
+    pub fn se3_pose(&self, node: usize) -> SE3 {
+        self.nodes[node].pose.to_se3()
+    }
+
+    /// Maps world points from before the last optimization to after it, for points anchored to
+    /// `node` (usually the keyframe that first observed them). They keep their position relative
+    /// to the keyframe.
+    pub fn correction(&self, node: usize) -> Sim3 {
+        let node = &self.nodes[node];
+        node.pose.inverse() * node.initial
+    }
+
+    pub fn correct_point(&self, node: usize, point: &Point3<f64>) -> Point3<f64> {
+        self.correction(node) * *point
+    }
+
+    fn error(&self, edge: &Edge, from: &Sim3, to: &Sim3) -> Vector7 {
+        (edge.measurement * *from * to.inverse()).log()
+    }
+
+    fn edge_cost(&self, edge: &Edge) -> f64 {
+        let e = self.error(edge, &self.nodes[edge.from].pose, &self.nodes[edge.to].pose);
+        0.5 * (e.transpose() * edge.information * e)[0]
+    }
+
+    fn cost(&self) -> f64 {
+        self.edges.iter().map(|e| self.edge_cost(e)).sum()
+    }
+
+    /// Runs Levenberg-Marquardt over the free nodes. The starting poses are remembered for
+    /// `correction`.
+    pub fn optimize(&mut self, options: &PoseGraphOptions) -> PoseGraphSummary {
+        for node in self.nodes.iter_mut() {
+            node.initial = node.pose;
+        }
+
+        // the offset and tangent dimension of each free node in the normal equations
+        let mut size = 0;
+        let blocks = self
+            .nodes
+            .iter()
+            .map(|node| {
+                if node.fixed {
+                    return None;
+                }
+                let dim = if node.fixed_scale { 6 } else { 7 };
+                size += dim;
+                Some((size - dim, dim))
+            })
+            .collect::<Vec<_>>();
+
+        let mut cost = self.cost();
+        let initial_cost = cost;
+        let mut lambda = options.initial_lambda;
+        let mut converged = size == 0;
+        let mut iterations = 0;
+
+        while iterations < options.max_iterations && !converged {
+            iterations += 1;
+
+            let mut h = DMatrix::<f64>::zeros(size, size);
+            let mut g = DVector::<f64>::zeros(size);
+            for edge in &self.edges {
+                let from = self.nodes[edge.from].pose;
+                let to = self.nodes[edge.to].pose;
+                let e = self.error(edge, &from, &to);
+
+                // numerical jacobians of the error for left perturbations of both nodes
+                let step = 1e-7;
+                let jacobian = |node: usize, dim: usize| {
+                    let mut j = DMatrix::<f64>::zeros(7, dim);
+                    for k in 0..dim {
+                        let mut delta = Vector7::zeros();
+                        delta[k] = step;
+                        let (plus, minus) = if node == edge.from {
+                            (
+                                self.error(edge, &(Sim3::exp(&delta) * from), &to),
+                                self.error(edge, &(Sim3::exp(&-delta) * from), &to),
+                            )
+                        } else {
+                            (
+                                self.error(edge, &from, &(Sim3::exp(&delta) * to)),
+                                self.error(edge, &from, &(Sim3::exp(&-delta) * to)),
+                            )
+                        };
+                        j.set_column(k, &((plus - minus) / (2.0 * step)));
+                    }
+                    j
+                };
+
+                let jacobians = [edge.from, edge.to]
+                    .iter()
+                    .filter_map(|&node| blocks[node].map(|b| (b, jacobian(node, b.1))))
+                    .collect::<Vec<_>>();
+                for ((offset_a, dim_a), j_a) in &jacobians {
+                    let j_a_t_info = j_a.transpose() * edge.information;
+                    let mut g_a = g.rows_mut(*offset_a, *dim_a);
+                    g_a += &j_a_t_info * e;
+                    for ((offset_b, dim_b), j_b) in &jacobians {
+                        let mut h_ab = h.view_mut((*offset_a, *offset_b), (*dim_a, *dim_b));
+                        h_ab += &j_a_t_info * j_b;
+                    }
+                }
+            }
+
+            // try increasingly damped steps until one lowers the cost
+            let mut improved = false;
+            while lambda < 1e12 {
+                let mut damped = h.clone();
+                for k in 0..size {
+                    damped[(k, k)] += lambda * h[(k, k)].max(1e-6);
+                }
+                let delta = match damped.cholesky() {
+                    Some(cholesky) => -cholesky.solve(&g),
+                    None => {
+                        lambda *= 10.0;
+                        continue;
+                    }
+                };
+
+                let previous = self.nodes.clone();
+                for (node, block) in self.nodes.iter_mut().zip(&blocks) {
+                    if let Some((offset, dim)) = block {
+                        let mut xi = Vector7::zeros();
+                        xi.rows_mut(0, *dim).copy_from(&delta.rows(*offset, *dim));
+                        node.pose = Sim3::exp(&xi) * node.pose;
+                    }
+                }
+                let new_cost = self.cost();
+
+                if new_cost < cost {
+                    converged = (cost - new_cost) <= options.function_tolerance * cost;
+                    cost = new_cost;
+                    lambda = (lambda / 10.0).max(1e-12);
+                    improved = true;
+                    break;
+                }
+
+                self.nodes = previous;
+                lambda *= 10.0;
+            }
+
+            if !improved {
+                // no step helps, we are at a minimum
+                converged = true;
+            }
+        }
+
+        PoseGraphSummary {
+            initial_cost,
+            final_cost: cost,
+            iterations,
+            converged,
+        }
+    }
+}
+
+/// The information matrix of independent errors with the given standard deviations, in the
+/// `(rho, phi, sigma)` tangent order.
+pub fn sim3_information(translation_sigma: f64, rotation_sigma: f64, scale_sigma: f64) -> Matrix7 {
+    let weights = SVector::<f64, 7>::from_column_slice(&[
+        translation_sigma.powi(-2),
+        translation_sigma.powi(-2),
+        translation_sigma.powi(-2),
+        rotation_sigma.powi(-2),
+        rotation_sigma.powi(-2),
+        rotation_sigma.powi(-2),
+        scale_sigma.powi(-2),
+    ]);
+    Matrix7::from_diagonal(&weights)
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lie::SO3;
+    use nalgebra::Vector3;
+
+    // keyframes on a circle, all looking along the direction of travel
+    fn trajectory(n: usize) -> Vec<SE3> {
+        (0..n)
+            .map(|k| {
+                let angle = k as f64 * std::f64::consts::TAU / n as f64;
+                let rotation = SO3::exp(&Vector3::new(0.0, angle, 0.0));
+                let center = Vector3::new(5.0 * angle.cos(), 0.0, 5.0 * angle.sin());
+                SE3::new(rotation, -(rotation * center))
+            })
+            .collect()
+    }
+
+    fn relative(from: &Sim3, to: &Sim3) -> Sim3 {
+        *to * from.inverse()
+    }
+
+    // distance between two poses on the group, in the tangent space
+    fn distance(a: &Sim3, b: &Sim3) -> f64 {
+        (a.inverse() * *b).log().norm()
+    }
+
+    #[test]
+    fn test_se3_loop_closure() {
+        let truth = trajectory(12);
+        let drift = SE3::exp(&nalgebra::Vector6::new(
+            0.02, 0.01, -0.03, 0.004, 0.006, -0.002,
+        ));
+
+        // dead reckoning with a biased odometry accumulates drift
+        let mut graph = PoseGraph::new();
+        let mut pose = truth[0];
+        graph.add_se3_node(pose, true);
+        for k in 1..truth.len() {
+            let measured = drift * (truth[k] * truth[k - 1].inverse());
+            pose = measured * pose;
+            graph.add_se3_node(pose, false);
+            graph.add_se3_edge(k - 1, k, measured, Matrix6::identity(), EdgeKind::Odometry);
+        }
+        let last = truth.len() - 1;
+        let before = distance(&graph.pose(last), &Sim3::from(truth[last]));
+
+        let loop_measurement = Sim3::from(truth[0] * truth[last].inverse());
+        let summary = graph.close_loop(
+            last,
+            0,
+            loop_measurement,
+            sim3_information(0.01, 0.01, 0.01),
+            &PoseGraphOptions::default(),
+        );
+
+        assert!(summary.final_cost < summary.initial_cost * 0.1);
+        let after = distance(&graph.pose(last), &Sim3::from(truth[last]));
+        assert!(after * 5.0 < before, "{} {}", before, after);
+        // SE(3) nodes keep their scale
+        assert!((graph.pose(last).scale - 1.0).abs() < 1e-12);
+        assert_eq!(graph.edges().last().unwrap().kind, EdgeKind::Loop);
+    }
+
+    #[test]
+    fn test_sim3_scale_drift() {
+        let truth = trajectory(10)
+            .into_iter()
+            .map(Sim3::from)
+            .collect::<Vec<_>>();
+
+        // monocular odometry that shrinks the map by 3% per keyframe
+        let shrink = Sim3::new(SO3::identity(), Vector3::zeros(), 0.97);
+        let mut graph = PoseGraph::new();
+        let mut pose = truth[0];
+        graph.add_sim3_node(pose, true);
+        for k in 1..truth.len() {
+            let measured = shrink * relative(&truth[k - 1], &truth[k]);
+            pose = measured * pose;
+            graph.add_sim3_node(pose, false);
+            graph.add_sim3_edge(k - 1, k, measured, Matrix7::identity(), EdgeKind::Odometry);
+        }
+        let last = truth.len() - 1;
+        let before = graph.pose(last).scale;
+
+        let summary = graph.close_loop(
+            last,
+            0,
+            relative(&truth[last], &truth[0]),
+            sim3_information(0.001, 0.001, 0.001),
+            &PoseGraphOptions::default(),
+        );
+
+        assert!(summary.converged);
+        assert!(summary.final_cost < summary.initial_cost);
+        // the loop pulls the accumulated scale back towards 1
+        let after = graph.pose(last).scale;
+        assert!(
+            (after - 1.0).abs() < (before - 1.0).abs() * 0.5,
+            "{} {}",
+            before,
+            after
+        );
+    }
+
+    #[test]
+    fn test_correct_point() {
+        let mut graph = PoseGraph::new();
+        graph.add_se3_node(SE3::identity(), true);
+        let drifted = SE3::new(SO3::identity(), Vector3::new(0.5, 0.0, 0.0));
+        let node = graph.add_se3_node(drifted, false);
+        graph.add_se3_edge(
+            0,
+            node,
+            SE3::identity(),
+            Matrix6::identity(),
+            EdgeKind::Loop,
+        );
+
+        // a point 2 units in front of the drifted keyframe
+        let point = drifted.inverse() * Point3::new(0.0, 0.0, 2.0);
+        graph.optimize(&PoseGraphOptions::default());
+
+        assert!(distance(&graph.pose(node), &Sim3::identity()) < 1e-6);
+        let corrected = graph.correct_point(node, &point);
+        assert!((corrected - Point3::new(0.0, 0.0, 2.0)).norm() < 1e-6);
+        assert!((graph.se3_pose(node).translation).norm() < 1e-6);
+    }
+}
@@ -1,35 +1,109 @@
+//! A small, seedable xoshiro256** generator. The same seed always gives the same sequence, on
+//! every platform, so RANSAC and the BRIEF sampling pattern are reproducible.
+//!
+//! https://prng.di.unimi.it/
+
 use std::ops::RangeInclusive;
 
-pub struct Rand(u64);
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Rand([u64; 4]);
+
+// expands the seed into the state, the seeding xoshiro's authors recommend
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E3779B97F4A7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
 
 impl Rand {
     pub fn new_with_seed(seed: u64) -> Self {
-        Self(seed)
+        let mut state = seed;
+        // four outputs of a bijection on distinct inputs are distinct, so never all zero
+        Self([(); 4].map(|_| splitmix64(&mut state)))
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> u64 {
-        self.0 = self.0.wrapping_mul(0x6C078965).wrapping_add(1);
-        self.0
+        let s = &mut self.0;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        result
     }
 
+    /// A uniform integer in `0..max_to_stay_below`, without modulo bias (Lemire's method).
     pub fn next_max(&mut self, max_to_stay_below: usize) -> usize {
+        assert!(max_to_stay_below > 0, "empty range");
         let max = max_to_stay_below as u64;
-        let rand = self.next() % max;
-        rand as usize
+        let mut product = self.next() as u128 * max as u128;
+        if (product as u64) < max {
+            // reject the values of the partial last bucket
+            let threshold = max.wrapping_neg() % max;
+            while (product as u64) < threshold {
+                product = self.next() as u128 * max as u128;
+            }
+        }
+        (product >> 64) as usize
+    }
+
+    /// A uniform float in `[0, 1)`, with all 53 bits of precision.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next() >> 11) as f64 * (1.0 / (1u64 << 53) as f64)
+    }
+
+    /// A uniform float in `[0, 1)`, with all 24 bits of precision.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next() >> 40) as f32 * (1.0 / (1u32 << 24) as f32)
     }
 
     pub fn gen_range(&mut self, range: RangeInclusive<f32>) -> f32 {
-        let min = *range.start();
-        let max = *range.end();
-        let range = max - min;
-        let rand = self.next() as f32 / u64::MAX as f32;
-        min + rand * range
+        let (min, max) = (*range.start() as f64, *range.end() as f64);
+        (min + self.next_f64() * (max - min)) as f32
     }
-}
 
-// implement choose_multiple for Vec
+    pub fn gen_range_f64(&mut self, range: RangeInclusive<f64>) -> f64 {
+        let (min, max) = (*range.start(), *range.end());
+        min + self.next_f64() * (max - min)
+    }
+
+    /// A normally distributed float (Marsaglia's polar method).
+    pub fn gaussian(&mut self, mean: f64, std_dev: f64) -> f64 {
+        loop {
+            let u = 2.0 * self.next_f64() - 1.0;
+            let v = 2.0 * self.next_f64() - 1.0;
+            let s = u * u + v * v;
+            if s > 0.0 && s < 1.0 {
+                return mean + std_dev * u * (-2.0 * s.ln() / s).sqrt();
+            }
+        }
+    }
+
+    /// `k` distinct indices in `0..n`, in no particular order (Floyd's algorithm). Takes k
+    /// draws whatever `n`, and O(k²) comparisons, which suits the small samples of RANSAC.
+    pub fn sample_indices(&mut self, n: usize, k: usize) -> Vec<usize> {
+        assert!(k <= n, "cannot sample {} of {}", k, n);
+        let mut sample = Vec::with_capacity(k);
+        for j in n - k..n {
+            let index = self.next_max(j + 1);
+            if sample.contains(&index) {
+                sample.push(j);
+            } else {
+                sample.push(index);
+            }
+        }
+        sample
+    }
+}
 
 pub trait ChooseMultiple {
+    /// `n` distinct elements, in no particular order.
     fn choose_multiple(&self, rng: &mut Rand, n: usize) -> Self
     where
         Self: Sized;
@@ -40,14 +114,96 @@ where
     T: Clone,
 {
     fn choose_multiple(&self, rng: &mut Rand, n: usize) -> Self {
-        // choose multiple and don't repeat
-        let mut chosen = Vec::with_capacity(n);
-        let mut indices = (0..self.len()).collect::<Vec<_>>();
-        for _ in 0..n {
-            let index = rng.next_max(indices.len());
-            let index = indices.remove(index);
-            chosen.push(self[index].clone());
+        rng.sample_indices(self.len(), n)
+            .into_iter()
+            .map(|index| self[index].clone())
+            .collect()
+    }
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded_sequence() {
+        // the reference output of xoshiro256** for the state 1, 2, 3, 4
+        let mut rng = Rand([1, 2, 3, 4]);
+        let first = (0..3).map(|_| rng.next()).collect::<Vec<_>>();
+        assert_eq!(first, [11520, 0, 1509978240]);
+
+        let draws = |seed| {
+            let mut rng = Rand::new_with_seed(seed);
+            (0..8).map(|_| rng.next()).collect::<Vec<_>>()
+        };
+        assert_eq!(draws(0), draws(0));
+        assert_ne!(draws(0), draws(1));
+        assert!(draws(0).iter().all(|&v| v != 0));
+    }
+
+    #[test]
+    fn test_uniform() {
+        let mut rng = Rand::new_with_seed(7);
+        let mut counts = [0usize; 3];
+        for _ in 0..30000 {
+            counts[rng.next_max(3)] += 1;
         }
-        chosen
+        assert!(
+            counts.iter().all(|&c| (9500..10500).contains(&c)),
+            "{:?}",
+            counts
+        );
+
+        let floats = (0..10000).map(|_| rng.next_f64()).collect::<Vec<_>>();
+        assert!(floats.iter().all(|&f| (0.0..1.0).contains(&f)));
+        let mean = floats.iter().sum::<f64>() / floats.len() as f64;
+        assert!((mean - 0.5).abs() < 0.01);
+        assert!((0..1000).all(|_| (-2.0..=3.0).contains(&rng.gen_range(-2.0..=3.0))));
+    }
+
+    #[test]
+    fn test_gaussian() {
+        let mut rng = Rand::new_with_seed(11);
+        let samples = (0..20000)
+            .map(|_| rng.gaussian(1.0, 2.0))
+            .collect::<Vec<_>>();
+        let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+        let variance =
+            samples.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / samples.len() as f64;
+        assert!((mean - 1.0).abs() < 0.05);
+        assert!((variance.sqrt() - 2.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_sample_indices() {
+        let mut rng = Rand::new_with_seed(3);
+        let mut counts = [0usize; 10];
+        for _ in 0..10000 {
+            let mut sample = rng.sample_indices(10, 4);
+            sample.sort();
+            sample.dedup();
+            assert_eq!(sample.len(), 4);
+            for i in sample {
+                counts[i] += 1;
+            }
+        }
+        // each index is in 4 of 10 samples
+        assert!(
+            counts.iter().all(|&c| (3700..4300).contains(&c)),
+            "{:?}",
+            counts
+        );
+
+        let mut all = rng.sample_indices(5, 5);
+        all.sort();
+        assert_eq!(all, [0, 1, 2, 3, 4]);
+        assert!(rng.sample_indices(5, 0).is_empty());
+
+        let chosen = vec!["a", "b", "c"].choose_multiple(&mut rng, 2);
+        assert!(chosen.len() == 2 && chosen[0] != chosen[1]);
     }
 }
@@ -0,0 +1,738 @@
+//! A small, generic RANSAC estimator.
+//!
+//! The estimator is split into three pieces so that the same loop can drive essential,
+//! fundamental, homography or PnP estimation:
+//!
+//! * a [`Model`] is the thing we are trying to find (e.g. a 3x3 matrix),
+//! * a [`MinimalSolver`] builds candidate models from a small random sample of the data,
+//! * an [`ErrorMetric`] tells us how well a single datum agrees with a model.
+//!
+//! Besides plain RANSAC, hypotheses can be scored with MSAC or with MAGSAC++, which needs no
+//! inlier threshold, only an upper bound on the noise level.
+//!
+//! https://en.wikipedia.org/wiki/Random_sample_consensus
+
+use nalgebra::Matrix3;
+
+use crate::rand::*;
+use crate::robust::MAX_REPROJECTION_ERROR;
+
+/// A model that can be hypothesised and scored by RANSAC.
+pub trait Model: Clone {
+    /// Returns false for hypotheses that should be thrown away before scoring,
+    /// e.g. matrices containing NaNs from a degenerate sample.
+    fn is_valid(&self) -> bool {
+        true
+    }
+}
+
+impl Model for Matrix3<f64> {
+    fn is_valid(&self) -> bool {
+        self.iter().all(|v| v.is_finite())
+    }
+}
+
+/// Builds model hypotheses from a minimal sample of data.
+pub trait MinimalSolver<D> {
+    type Model: Model;
+
+    /// The number of data points needed to compute a hypothesis.
+    fn sample_size(&self) -> usize;
+
+    /// Computes zero or more hypotheses from `sample` (some solvers, like the 7 point
+    /// algorithm, have several solutions).
+    fn solve(&self, sample: &[D]) -> Vec<Self::Model>;
+
+    /// Fits a model to more than `sample_size` data, e.g. by least squares. This is used by
+    /// local optimization; solvers that can only handle minimal samples return nothing, which
+    /// disables it.
+    fn solve_nonminimal(&self, _data: &[D]) -> Vec<Self::Model> {
+        Vec::new()
+    }
+
+    /// Fits a model to all of `data`, each datum weighted by `weights`, e.g. by weighted least
+    /// squares. This is the final fit of `Scoring::Magsac`. The default fits the data with a
+    /// non zero weight with `solve_nonminimal`.
+    fn solve_weighted(&self, data: &[D], weights: &[f64]) -> Vec<Self::Model>
+    where
+        D: Clone,
+    {
+        let data = data
+            .iter()
+            .zip(weights)
+            .filter(|(_, &weight)| weight > 0.0)
+            .map(|(datum, _)| datum.clone())
+            .collect::<Vec<_>>();
+        self.solve_nonminimal(&data)
+    }
+}
+
+/// Measures how well a single datum agrees with a model.
+pub trait ErrorMetric<M, D> {
+    /// A non negative error, in the same units as the inlier threshold.
+    fn error(&self, model: &M, datum: &D) -> f64;
+}
+
+/// How a hypothesis is scored against the data.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub enum Scoring {
+    /// Classic RANSAC, count the inliers.
+    #[default]
+    Ransac,
+    /// MSAC, a truncated quadratic: inliers contribute `t^2 - e^2`, so a hypothesis that
+    /// fits its inliers tightly beats one that barely holds on to the same number.
+    Msac,
+    /// MAGSAC++ (Barath et al., 2020), a quality marginalized over noise levels `sigma` uniform
+    /// in `(0, sigma_max]`, so there is no inlier threshold. The best hypothesis is refined by
+    /// sigma-consensus, a weighted fit with the same marginalized weights (see
+    /// [`magsac_weight`]). Data beyond `MAGSAC_K * sigma_max` count as outliers.
+    Magsac { sigma_max: f64 },
+}
+
+/// How minimal samples are drawn.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub enum Sampling {
+    /// Every datum is equally likely to be chosen.
+    #[default]
+    Uniform,
+    /// PROSAC, progressively grows the sampling pool from the best data. The data must be
+    /// sorted best first (e.g. matches by ascending Hamming distance).
+    Prosac,
+}
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct RansacOptions {
+    /// Upper bound on the number of hypotheses drawn.
+    pub max_iterations: usize,
+    /// Data with an error below this are counted as inliers. Ignored by `Scoring::Magsac`.
+    pub inlier_threshold: f64,
+    /// Probability (0..1) that at least one all-inlier sample has been drawn when we stop.
+    pub confidence: f64,
+    pub scoring: Scoring,
+    pub sampling: Sampling,
+    /// LO-RANSAC, the number of inner non-minimal samples drawn from the inliers each time a
+    /// new best hypothesis is found. 0 disables local optimization.
+    pub local_optimization_iterations: usize,
+}
+
+impl Default for RansacOptions {
+    fn default() -> Self {
+        Self {
+            max_iterations: 1000,
+            inlier_threshold: 1.0,
+            confidence: 0.99,
+            scoring: Scoring::Ransac,
+            sampling: Sampling::Uniform,
+            local_optimization_iterations: 0,
+        }
+    }
+}
+
+impl RansacOptions {
+    /// For matches scored by reprojection error and sorted best first: MSAC scoring, PROSAC
+    /// sampling and local optimization, with `MAX_REPROJECTION_ERROR` as inlier threshold.
+    pub fn reprojection() -> Self {
+        Self {
+            max_iterations: 300,
+            inlier_threshold: MAX_REPROJECTION_ERROR,
+            confidence: 0.99,
+            scoring: Scoring::Msac,
+            sampling: Sampling::Prosac,
+            local_optimization_iterations: 10,
+        }
+    }
+
+    /// The same options with the error bounds (`inlier_threshold` and the `sigma_max` of
+    /// `Scoring::Magsac`) multiplied by `scale`, e.g. to go from pixels to normalized image
+    /// coordinates.
+    pub fn scaled(&self, scale: f64) -> Self {
+        let scoring = match self.scoring {
+            Scoring::Magsac { sigma_max } => Scoring::Magsac {
+                sigma_max: sigma_max * scale,
+            },
+            scoring => scoring,
+        };
+        Self {
+            inlier_threshold: self.inlier_threshold * scale,
+            scoring,
+            ..*self
+        }
+    }
+
+    // the error below which a datum counts as an inlier
+    fn inlier_bound(&self) -> f64 {
+        match self.scoring {
+            Scoring::Magsac { sigma_max } => MAGSAC_K * sigma_max,
+            _ => self.inlier_threshold,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct RansacResult<M> {
+    pub model: M,
+    /// `inliers[i]` is true when `data[i]` agrees with `model`.
+    pub inliers: Vec<bool>,
+    /// Higher is better. For plain RANSAC this is the number of inliers.
+    pub score: f64,
+    /// The number of hypotheses drawn before stopping.
+    pub iterations: usize,
+}
+
+impl<M> RansacResult<M> {
+    pub fn num_inliers(&self) -> usize {
+        self.inliers.iter().filter(|&&inlier| inlier).count()
+    }
+
+    /// Returns the inlying subset of `data`.
+    pub fn select_inliers<D: Clone>(&self, data: &[D]) -> Vec<D> {
+        data.iter()
+            .zip(&self.inliers)
+            .filter(|(_, &inlier)| inlier)
+            .map(|(datum, _)| datum.clone())
+            .collect()
+    }
+}
+
+/// The number of iterations needed to draw at least one all-inlier sample of
+/// `sample_size` with probability `confidence`, given the current inlier ratio.
+pub fn adaptive_iterations(
+    inlier_ratio: f64,
+    sample_size: usize,
+    confidence: f64,
+    max_iterations: usize,
+) -> usize {
+    let all_inliers = inlier_ratio.powi(sample_size as i32);
+    if all_inliers <= f64::EPSILON {
+        return max_iterations;
+    }
+    if all_inliers >= 1.0 - f64::EPSILON {
+        return 1;
+    }
+    let n = (1.0 - confidence).ln() / (1.0 - all_inliers).ln();
+    if n.is_finite() {
+        (n.ceil() as usize).clamp(1, max_iterations)
+    } else {
+        max_iterations
+    }
+}
+
+/// The 0.99 quantile of the chi distribution with 2 degrees of freedom (the square root of the
+/// chi-square quantile 9.21). `Scoring::Magsac` treats errors beyond `MAGSAC_K * sigma` as
+/// outliers at noise level `sigma`.
+pub const MAGSAC_K: f64 = 3.0348542587702925;
+
+// The number of reweighted refits of sigma-consensus.
+const SIGMA_CONSENSUS_ITERATIONS: usize = 5;
+
+// Abramowitz & Stegun 7.1.26, the absolute error is below 1.5e-7.
+fn erf(x: f64) -> f64 {
+    let t = 1.0 / (1.0 + 0.3275911 * x.abs());
+    let poly = t
+        * (0.254829592
+            + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    let y = 1.0 - poly * (-x * x).exp();
+    y.copysign(x)
+}
+
+/// The MAGSAC++ weight of a datum with error `error`: the probability of it being an inlier,
+/// marginalized over noise levels uniform in `(0, sigma_max]`. 1 for a perfect fit, falling
+/// smoothly to 0 at `MAGSAC_K * sigma_max`.
+pub fn magsac_weight(error: f64, sigma_max: f64) -> f64 {
+    if error.is_nan() || error >= MAGSAC_K * sigma_max {
+        return 0.0;
+    }
+    let total = erf(MAGSAC_K / std::f64::consts::SQRT_2);
+    (total - erf(error / (std::f64::consts::SQRT_2 * sigma_max))) / total
+}
+
+// The MAGSAC++ loss, the integral of `e * magsac_weight(e)` from 0 to `error`.
+fn magsac_loss(error: f64, sigma_max: f64) -> f64 {
+    let error = error.min(MAGSAC_K * sigma_max);
+    let a = std::f64::consts::SQRT_2 * sigma_max;
+    let total = erf(MAGSAC_K / std::f64::consts::SQRT_2);
+    // an antiderivative of e * erf(e / a)
+    let integral = (error * error / 2.0 - a * a / 4.0) * erf(error / a)
+        + a * error * (-error * error / (a * a)).exp() / (2.0 * std::f64::consts::PI.sqrt());
+    (total * error * error / 2.0 - integral) / total
+}
+
+/// Scores a set of errors, higher is better. `Scoring::Magsac` ignores `threshold`, each datum
+/// scores between 1 (a perfect fit) and 0 (an outlier).
+pub fn score_errors(errors: &[f64], threshold: f64, scoring: Scoring) -> f64 {
+    let threshold_sq = threshold * threshold;
+    match scoring {
+        Scoring::Ransac => errors.iter().filter(|&&e| e < threshold).count() as f64,
+        Scoring::Msac => errors
+            .iter()
+            .map(|&e| (threshold_sq - e * e).max(0.0))
+            .sum::<f64>(),
+        Scoring::Magsac { sigma_max } => {
+            let outlier_loss = magsac_loss(f64::INFINITY, sigma_max);
+            errors
+                .iter()
+                .map(|&e| 1.0 - magsac_loss(e, sigma_max) / outlier_loss)
+                .sum::<f64>()
+        }
+    }
+}
+
+/// PROSAC sampler (Chum & Matas, 2005). Samples are drawn from the top `n` data, where `n`
+/// grows on a schedule that, once all data are in play, matches uniform sampling.
+pub struct ProsacSampler {
+    num_data: usize,
+    sample_size: usize,
+    // the size of the current sampling pool
+    n: usize,
+    // the iteration at which the pool grows
+    t_n_prime: f64,
+    // the expected number of samples drawn from the top n data in uniform sampling
+    t_n: f64,
+    iteration: usize,
+}
+
+impl ProsacSampler {
+    pub fn new(num_data: usize, sample_size: usize, max_iterations: usize) -> Self {
+        // T_n for n = sample_size, scaled so that T_N is max_iterations
+        let mut t_n = max_iterations as f64;
+        for i in 0..sample_size {
+            t_n *= (sample_size - i) as f64 / (num_data - i) as f64;
+        }
+        Self {
+            num_data,
+            sample_size,
+            n: sample_size,
+            t_n_prime: 1.0,
+            t_n,
+            iteration: 0,
+        }
+    }
+
+    /// Draws the indices of the next sample.
+    pub fn sample(&mut self, rng: &mut Rand) -> Vec<usize> {
+        self.iteration += 1;
+
+        if self.iteration as f64 >= self.t_n_prime && self.n < self.num_data {
+            let t_n_next = self.t_n * (self.n + 1) as f64 / (self.n + 1 - self.sample_size) as f64;
+            self.t_n_prime += (t_n_next - self.t_n).ceil();
+            self.t_n = t_n_next;
+            self.n += 1;
+        }
+
+        if self.t_n_prime < self.iteration as f64 || self.n == self.sample_size {
+            // the pool has caught up, sample uniformly from the top n
+            rng.sample_indices(self.n, self.sample_size)
+        } else {
+            // always include the newest datum, the rest come from the better n - 1
+            let mut sample = rng.sample_indices(self.n - 1, self.sample_size - 1);
+            sample.push(self.n - 1);
+            sample
+        }
+    }
+}
+
+// a hypothesis scored against the data, `iterations` is filled in when the search stops
+fn evaluate<D, M, E>(model: M, data: &[D], metric: &E, options: &RansacOptions) -> RansacResult<M>
+where
+    E: ErrorMetric<M, D>,
+{
+    let errors = data
+        .iter()
+        .map(|datum| metric.error(&model, datum))
+        .collect::<Vec<_>>();
+    let inlier_bound = options.inlier_bound();
+    let inliers = errors.iter().map(|&e| e < inlier_bound).collect();
+    let score = score_errors(&errors, options.inlier_threshold, options.scoring);
+    RansacResult {
+        model,
+        inliers,
+        score,
+        iterations: 0,
+    }
+}
+
+// LO-RANSAC: repeatedly refit non-minimal samples of the current inliers and keep
+// whatever scores best, finishing with a refit on all the inliers.
+fn local_optimization<D, S, E>(
+    best: RansacResult<S::Model>,
+    data: &[D],
+    solver: &S,
+    metric: &E,
+    options: &RansacOptions,
+    rng: &mut Rand,
+) -> RansacResult<S::Model>
+where
+    D: Clone,
+    S: MinimalSolver<D>,
+    E: ErrorMetric<S::Model, D>,
+{
+    let mut best = best;
+    let inlier_indices = (0..data.len())
+        .filter(|&i| best.inliers[i])
+        .collect::<Vec<_>>();
+    let sample_size = (solver.sample_size() * 2).min(inlier_indices.len());
+    if sample_size <= solver.sample_size() {
+        return best;
+    }
+
+    for _ in 0..options.local_optimization_iterations {
+        let sample = inlier_indices
+            .choose_multiple(rng, sample_size)
+            .iter()
+            .map(|&i| data[i].clone())
+            .collect::<Vec<_>>();
+        for model in solver.solve_nonminimal(&sample) {
+            if !model.is_valid() {
+                continue;
+            }
+            let candidate = evaluate(model, data, metric, options);
+            if candidate.score > best.score {
+                best = candidate;
+            }
+        }
+    }
+
+    let inliers = best.select_inliers(data);
+    for model in solver.solve_nonminimal(&inliers) {
+        if !model.is_valid() {
+            continue;
+        }
+        let candidate = evaluate(model, data, metric, options);
+        if candidate.score > best.score {
+            best = candidate;
+        }
+    }
+
+    best
+}
+
+// MAGSAC++ sigma-consensus: reweighted fits to all of the data with the marginalized weights
+// of the current best model, for as long as the quality improves.
+fn sigma_consensus<D, S, E>(
+    best: RansacResult<S::Model>,
+    data: &[D],
+    solver: &S,
+    metric: &E,
+    options: &RansacOptions,
+) -> RansacResult<S::Model>
+where
+    D: Clone,
+    S: MinimalSolver<D>,
+    E: ErrorMetric<S::Model, D>,
+{
+    let Scoring::Magsac { sigma_max } = options.scoring else {
+        return best;
+    };
+    let mut best = best;
+    for _ in 0..SIGMA_CONSENSUS_ITERATIONS {
+        let weights = data
+            .iter()
+            .map(|datum| magsac_weight(metric.error(&best.model, datum), sigma_max))
+            .collect::<Vec<_>>();
+        let mut improved = false;
+        for model in solver.solve_weighted(data, &weights) {
+            if !model.is_valid() {
+                continue;
+            }
+            let candidate = evaluate(model, data, metric, options);
+            if candidate.score > best.score {
+                best = candidate;
+                improved = true;
+            }
+        }
+        if !improved {
+            break;
+        }
+    }
+    best
+}
+
+/// Robustly fits a model to `data`. Returns `None` when there are fewer data than the solver
+/// needs, or no hypothesis explained any of the data.
+pub fn ransac<D, S, E>(
+    data: &[D],
+    solver: &S,
+    metric: &E,
+    options: &RansacOptions,
+    rng: &mut Rand,
+) -> Option<RansacResult<S::Model>>
+where
+    D: Clone,
+    S: MinimalSolver<D>,
+    E: ErrorMetric<S::Model, D>,
+{
+    let sample_size = solver.sample_size();
+    if data.len() < sample_size {
+        return None;
+    }
+
+    let mut prosac = match options.sampling {
+        Sampling::Prosac => Some(ProsacSampler::new(
+            data.len(),
+            sample_size,
+            options.max_iterations,
+        )),
+        Sampling::Uniform => None,
+    };
+    let mut best: Option<RansacResult<S::Model>> = None;
+    let mut required_iterations = options.max_iterations;
+    let mut iteration = 0;
+
+    while iteration < required_iterations {
+        iteration += 1;
+
+        let sample_indices = match prosac.as_mut() {
+            Some(prosac) => prosac.sample(rng),
+            None => rng.sample_indices(data.len(), sample_size),
+        };
+        let sample = sample_indices
+            .iter()
+            .map(|&i| data[i].clone())
+            .collect::<Vec<_>>();
+
+        for model in solver.solve(&sample) {
+            if !model.is_valid() {
+                continue;
+            }
+
+            let mut hypothesis = evaluate(model, data, metric, options);
+            if hypothesis.num_inliers() == 0
+                || best.as_ref().is_some_and(|b| hypothesis.score <= b.score)
+            {
+                continue;
+            }
+
+            if options.local_optimization_iterations > 0 {
+                hypothesis = local_optimization(hypothesis, data, solver, metric, options, rng);
+            }
+
+            required_iterations = adaptive_iterations(
+                hypothesis.num_inliers() as f64 / data.len() as f64,
+                sample_size,
+                options.confidence,
+                options.max_iterations,
+            );
+            best = Some(hypothesis);
+        }
+    }
+
+    best.map(|best| RansacResult {
+        iterations: iteration,
+        ..sigma_consensus(best, data, solver, metric, options)
+    })
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a 2D line a*x + b*y + c = 0 with a^2 + b^2 = 1
+    #[derive(Debug, Clone)]
+    struct Line(f64, f64, f64);
+
+    impl Model for Line {}
+
+    struct LineSolver;
+
+    impl MinimalSolver<(f64, f64)> for LineSolver {
+        type Model = Line;
+
+        fn sample_size(&self) -> usize {
+            2
+        }
+
+        fn solve(&self, sample: &[(f64, f64)]) -> Vec<Line> {
+            let (x1, y1) = sample[0];
+            let (x2, y2) = sample[1];
+            let (a, b) = (y1 - y2, x2 - x1);
+            let norm = (a * a + b * b).sqrt();
+            if norm == 0.0 {
+                return vec![];
+            }
+            vec![Line(a / norm, b / norm, -(a * x1 + b * y1) / norm)]
+        }
+
+        // weighted total least squares
+        fn solve_weighted(&self, data: &[(f64, f64)], weights: &[f64]) -> Vec<Line> {
+            let total = weights.iter().sum::<f64>();
+            if total <= 0.0 {
+                return vec![];
+            }
+            let mean = |f: &dyn Fn(f64, f64) -> f64| {
+                data.iter()
+                    .zip(weights)
+                    .map(|(&(x, y), w)| w * f(x, y))
+                    .sum::<f64>()
+                    / total
+            };
+            let (mx, my) = (mean(&|x, _| x), mean(&|_, y| y));
+            let sxx = mean(&|x, _| (x - mx) * (x - mx));
+            let sxy = mean(&|x, y| (x - mx) * (y - my));
+            let syy = mean(&|_, y| (y - my) * (y - my));
+            // the normal is the eigenvector of the smaller eigenvalue of the scatter matrix
+            let angle = 0.5 * (2.0 * sxy).atan2(sxx - syy) + std::f64::consts::FRAC_PI_2;
+            let (a, b) = (angle.cos(), angle.sin());
+            vec![Line(a, b, -(a * mx + b * my))]
+        }
+    }
+
+    struct PointLineDistance;
+
+    impl ErrorMetric<Line, (f64, f64)> for PointLineDistance {
+        fn error(&self, line: &Line, &(x, y): &(f64, f64)) -> f64 {
+            (line.0 * x + line.1 * y + line.2).abs()
+        }
+    }
+
+    #[test]
+    fn test_ransac_line_with_outliers() {
+        // y = 2x + 1 with a few wild outliers at the end
+        let mut data = (0..20)
+            .map(|i| (i as f64, 2.0 * i as f64 + 1.0))
+            .collect::<Vec<_>>();
+        data.extend([(3.0, 40.0), (10.0, -5.0), (15.0, 2.0), (-4.0, 30.0)]);
+
+        let options = RansacOptions {
+            max_iterations: 200,
+            inlier_threshold: 0.1,
+            confidence: 0.999,
+            ..Default::default()
+        };
+        let mut rng = Rand::new_with_seed(42);
+        let result = ransac(&data, &LineSolver, &PointLineDistance, &options, &mut rng).unwrap();
+
+        assert_eq!(result.num_inliers(), 20);
+        assert!(result.inliers[..20].iter().all(|&i| i));
+        assert!(result.inliers[20..].iter().all(|&i| !i));
+        assert_eq!(result.score, 20.0);
+        // with ~83% inliers we should stop long before the iteration cap
+        assert!(result.iterations < options.max_iterations);
+        assert_eq!(result.select_inliers(&data), data[..20].to_vec());
+    }
+
+    #[test]
+    fn test_ransac_variants_agree_on_clean_line() {
+        let mut data = (0..30)
+            .map(|i| (i as f64, 0.5 * i as f64 - 3.0 + 0.01 * (i % 3) as f64))
+            .collect::<Vec<_>>();
+        data.extend([(3.0, 40.0), (10.0, -25.0), (15.0, 2.0), (-4.0, 30.0)]);
+
+        let magsac = Scoring::Magsac { sigma_max: 0.05 };
+        for scoring in [Scoring::Ransac, Scoring::Msac, magsac] {
+            for sampling in [Sampling::Uniform, Sampling::Prosac] {
+                let options = RansacOptions {
+                    max_iterations: 500,
+                    inlier_threshold: 0.1,
+                    confidence: 0.999,
+                    scoring,
+                    sampling,
+                    local_optimization_iterations: 5,
+                };
+                let mut rng = Rand::new_with_seed(7);
+                let result =
+                    ransac(&data, &LineSolver, &PointLineDistance, &options, &mut rng).unwrap();
+                assert_eq!(result.num_inliers(), 30, "{:?} {:?}", scoring, sampling);
+            }
+        }
+    }
+
+    #[test]
+    fn test_score_errors() {
+        let errors = [0.0, 0.5, 1.0, 2.0];
+        assert_eq!(score_errors(&errors, 1.0, Scoring::Ransac), 2.0);
+        // 1 + 0.75 + 0 + 0
+        assert_eq!(score_errors(&errors, 1.0, Scoring::Msac), 1.75);
+        // a perfect fit scores 1, errors beyond MAGSAC_K * sigma_max score 0, whatever the
+        // threshold
+        let magsac = Scoring::Magsac { sigma_max: 1.0 };
+        assert!((score_errors(&[0.0], 1.0, magsac) - 1.0).abs() < 1e-6);
+        assert_eq!(score_errors(&[MAGSAC_K, 10.0], 1.0, magsac), 0.0);
+        let score = score_errors(&errors, 1.0, magsac);
+        assert!(score > 2.0 && score < 3.0, "{}", score);
+        assert_eq!(score, score_errors(&errors, 100.0, magsac));
+    }
+
+    #[test]
+    fn test_magsac_weight() {
+        assert!((magsac_weight(0.0, 2.0) - 1.0).abs() < 1e-6);
+        let weights = (0..=10)
+            .map(|i| magsac_weight(i as f64 * 0.5, 1.0))
+            .collect::<Vec<_>>();
+        assert!(weights.windows(2).all(|w| w[1] < w[0] || w[1] == 0.0));
+        assert_eq!(magsac_weight(MAGSAC_K, 1.0), 0.0);
+        assert!(magsac_weight(MAGSAC_K - 1e-3, 1.0) < 1e-3);
+    }
+
+    #[test]
+    fn test_magsac_needs_no_threshold() {
+        // y = 0.5x - 3 with small alternating noise, and outliers
+        let noise = [0.02, -0.015, 0.01, -0.02, 0.005];
+        let mut data = (0..30)
+            .map(|i| (i as f64, 0.5 * i as f64 - 3.0 + noise[i % 5]))
+            .collect::<Vec<_>>();
+        data.extend([(3.0, 40.0), (10.0, -25.0), (15.0, 2.0), (-4.0, 30.0)]);
+
+        for inlier_threshold in [1e-9, 1e9] {
+            let options = RansacOptions {
+                max_iterations: 500,
+                inlier_threshold,
+                confidence: 0.999,
+                scoring: Scoring::Magsac { sigma_max: 0.1 },
+                ..Default::default()
+            };
+            let mut rng = Rand::new_with_seed(7);
+            let result =
+                ransac(&data, &LineSolver, &PointLineDistance, &options, &mut rng).unwrap();
+            assert_eq!(result.num_inliers(), 30);
+            assert!(result.inliers[30..].iter().all(|&i| !i));
+
+            // the weighted final fit is close to the true line, not just to two samples
+            let Line(a, b, c) = result.model;
+            let (slope, intercept) = (-a / b, -c / b);
+            assert!((slope - 0.5).abs() < 1e-3, "{}", slope);
+            assert!((intercept + 3.0).abs() < 1e-2, "{}", intercept);
+        }
+    }
+
+    #[test]
+    fn test_prosac_starts_with_the_best_data() {
+        let mut rng = Rand::new_with_seed(3);
+        let mut prosac = ProsacSampler::new(100, 4, 1000);
+        for _ in 0..10 {
+            let sample = prosac.sample(&mut rng);
+            assert_eq!(sample.len(), 4);
+            assert!(sample.iter().all(|&i| i < 20), "{:?}", sample);
+        }
+        // eventually every datum is in play
+        let seen = (0..5000)
+            .flat_map(|_| prosac.sample(&mut rng))
+            .any(|i| i > 90);
+        assert!(seen);
+    }
+
+    #[test]
+    fn test_ransac_not_enough_data() {
+        let mut rng = Rand::new_with_seed(42);
+        let result = ransac(
+            &[(0.0, 0.0)],
+            &LineSolver,
+            &PointLineDistance,
+            &RansacOptions::default(),
+            &mut rng,
+        );
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn test_adaptive_iterations() {
+        assert_eq!(adaptive_iterations(1.0, 8, 0.99, 1000), 1);
+        assert_eq!(adaptive_iterations(0.0, 8, 0.99, 1000), 1000);
+        // classic table value: 50% inliers, sample of 4, 99% confidence -> 72 iterations
+        assert_eq!(adaptive_iterations(0.5, 4, 0.99, 1000), 72);
+        assert_eq!(adaptive_iterations(0.1, 8, 0.99, 1000), 1000);
+    }
+}
@@ -0,0 +1,271 @@
+//! Nonlinear refinement of the relative pose between two calibrated views.
+//!
+//! The pose from `essential::recover_pose` comes from a linear solve on a small sample. Here we
+//! polish it over all the inliers with Levenberg-Marquardt, minimizing the (robustified) Sampson
+//! distance. Only 5 of the 6 degrees of freedom are observable from two views, so the rotation
+//! lives on SO(3) and the translation direction on the unit sphere S^2.
+//!
+//! https://en.wikipedia.org/wiki/Levenberg%E2%80%93Marquardt_algorithm
+
+use nalgebra::{SMatrix, SVector, Vector3};
+
+use crate::common::PointMatch;
+use crate::epipolar::sampson_residual;
+use crate::essential::essential_from_pose;
+use crate::lie::{SE3, SO3};
+use crate::robust::RobustKernel;
+
+type Matrix5 = SMatrix<f64, 5, 5>;
+type Vector5 = SVector<f64, 5>;
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct RefinementOptions {
+    pub max_iterations: usize,
+    /// Applied to the Sampson distances, which are in normalized image units (pixels divided by
+    /// the focal length).
+    pub kernel: RobustKernel,
+    /// Stop once an iteration reduces the cost by less than this fraction.
+    pub function_tolerance: f64,
+    /// The starting Levenberg-Marquardt damping.
+    pub initial_lambda: f64,
+}
+
+impl Default for RefinementOptions {
+    fn default() -> Self {
+        Self {
+            max_iterations: 50,
+            kernel: RobustKernel::Quadratic,
+            function_tolerance: 1e-10,
+            initial_lambda: 1e-3,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct RelativePoseRefinement {
+    /// `X2 = pose * X1`, with a unit translation.
+    pub pose: SE3,
+    /// The summed kernel cost before and after refinement.
+    pub initial_cost: f64,
+    pub final_cost: f64,
+    pub iterations: usize,
+    /// False when we ran out of iterations before the cost settled.
+    pub converged: bool,
+}
+
+/// Two unit vectors that complete `t` to an orthonormal basis, spanning the tangent plane of
+/// the sphere at `t`.
+fn tangent_basis(t: &Vector3<f64>) -> (Vector3<f64>, Vector3<f64>) {
+    // cross with the axis least aligned with t, to stay well conditioned
+    let axis = if t.x.abs() < t.y.abs() && t.x.abs() < t.z.abs() {
+        Vector3::x()
+    } else if t.y.abs() < t.z.abs() {
+        Vector3::y()
+    } else {
+        Vector3::z()
+    };
+    let b1 = t.cross(&axis).normalize();
+    let b2 = t.cross(&b1);
+    (b1, b2)
+}
+
+/// Moves the pose along the 5 dimensional tangent step `delta`, the first three components
+/// rotate (axis-angle, applied on the left) and the last two move t along the sphere.
+fn retract(pose: &SE3, delta: &Vector5) -> SE3 {
+    let omega = Vector3::new(delta[0], delta[1], delta[2]);
+    let rotation = SO3::exp(&omega) * pose.rotation;
+
+    let translation = &pose.translation;
+    let (b1, b2) = tangent_basis(translation);
+    let v = b1 * delta[3] + b2 * delta[4];
+    let angle = v.norm();
+    let translation = if angle > f64::EPSILON {
+        translation * angle.cos() + v * (angle.sin() / angle)
+    } else {
+        *translation
+    };
+
+    SE3::new(rotation, translation.normalize())
+}
+
+fn residuals(pose: &SE3, points: &[PointMatch]) -> Vec<f64> {
+    let essential = essential_from_pose(pose);
+    points
+        .iter()
+        .map(|(x1, x2)| sampson_residual(&essential, x1, x2))
+        .collect()
+}
+
+fn total_cost(residuals: &[f64], kernel: &RobustKernel) -> f64 {
+    residuals.iter().map(|&r| kernel.cost(r)).sum()
+}
+
+/// Refines `pose` over the normalized inlier matches `points`.
+pub fn refine_relative_pose(
+    pose: &SE3,
+    points: &[PointMatch],
+    options: &RefinementOptions,
+) -> RelativePoseRefinement {
+    let mut pose = SE3::new(pose.rotation, pose.translation.normalize());
+    let mut current = residuals(&pose, points);
+    let mut cost = total_cost(&current, &options.kernel);
+    let initial_cost = cost;
+    let mut lambda = options.initial_lambda;
+    let mut converged = false;
+    let mut iterations = 0;
+
+    while iterations < options.max_iterations && !converged {
+        iterations += 1;
+
+        // numerical jacobian of the residuals with respect to the tangent step
+        let step = 1e-7;
+        let mut columns = Vec::with_capacity(5);
+        for k in 0..5 {
+            let mut delta = Vector5::zeros();
+            delta[k] = step;
+            let plus = residuals(&retract(&pose, &delta), points);
+            let minus = residuals(&retract(&pose, &-delta), points);
+            columns.push(
+                plus.iter()
+                    .zip(&minus)
+                    .map(|(p, m)| (p - m) / (2.0 * step))
+                    .collect::<Vec<_>>(),
+            );
+        }
+
+        // the weighted normal equations, H = J^T W J and g = J^T W r
+        let mut h = Matrix5::zeros();
+        let mut g = Vector5::zeros();
+        for (i, &r) in current.iter().enumerate() {
+            let weight = options.kernel.weight(r);
+            let j = Vector5::from_fn(|k, _| columns[k][i]);
+            h += j * j.transpose() * weight;
+            g += j * (weight * r);
+        }
+
+        // try increasingly damped steps until one lowers the cost
+        let mut improved = false;
+        while lambda < 1e12 {
+            let mut damped = h;
+            for k in 0..5 {
+                damped[(k, k)] += lambda * h[(k, k)].max(1e-12);
+            }
+            let delta = match damped.cholesky() {
+                Some(cholesky) => -cholesky.solve(&g),
+                None => {
+                    lambda *= 10.0;
+                    continue;
+                }
+            };
+
+            let new_pose = retract(&pose, &delta);
+            let new_residuals = residuals(&new_pose, points);
+            let new_cost = total_cost(&new_residuals, &options.kernel);
+
+            if new_cost < cost {
+                converged = (cost - new_cost) <= options.function_tolerance * cost;
+                pose = new_pose;
+                current = new_residuals;
+                cost = new_cost;
+                lambda = (lambda / 10.0).max(1e-12);
+                improved = true;
+                break;
+            }
+            lambda *= 10.0;
+        }
+
+        if !improved {
+            // no step helps, we are at a minimum
+            converged = true;
+        }
+    }
+
+    RelativePoseRefinement {
+        pose,
+        initial_cost,
+        final_cost: cost,
+        iterations,
+        converged,
+    }
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::KeyPoint;
+    use crate::test_scene;
+
+    fn rotation_error(a: &SE3, b: &SE3) -> f64 {
+        (a.rotation.inverse() * b.rotation).angle()
+    }
+
+    fn unit(pose: SE3) -> SE3 {
+        SE3::new(pose.rotation, pose.translation.normalize())
+    }
+
+    #[test]
+    fn test_retract_stays_on_manifold() {
+        let delta = Vector5::new(0.1, -0.2, 0.3, 0.5, -0.4);
+        let pose = unit(test_scene::motion());
+        let moved = retract(&pose, &delta);
+        assert!((moved.translation.norm() - 1.0).abs() < 1e-12);
+        assert!((rotation_error(&pose, &moved) - 0.1f64.hypot(0.2).hypot(0.3)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_refine_relative_pose() {
+        let camera = test_scene::camera();
+        let pose = test_scene::motion();
+        let matches = test_scene::matches(&camera, &test_scene::points(50), &pose);
+        let points = camera.normalize_matches(&matches);
+
+        // start from a perturbed pose
+        let pose = unit(pose);
+        let start = retract(&pose, &Vector5::new(0.02, -0.01, 0.015, 0.05, 0.03));
+        let refined = refine_relative_pose(&start, &points, &RefinementOptions::default());
+
+        assert!(refined.converged);
+        assert!(refined.iterations > 1);
+        assert!(refined.final_cost < refined.initial_cost * 1e-6);
+        assert!(rotation_error(&refined.pose, &pose) < 1e-5);
+        assert!((refined.pose.translation - pose.translation).norm() < 1e-4);
+    }
+
+    #[test]
+    fn test_robust_kernels_ignore_outliers() {
+        let camera = test_scene::camera();
+        let pose = test_scene::motion();
+        let mut matches = test_scene::matches(&camera, &test_scene::points(50), &pose);
+        for i in 0..5 {
+            let (p1, p2) = matches[i * 10];
+            matches[i * 10] = (p1, KeyPoint::new(p2.x + 15.0, p2.y - 25.0, 0.0));
+        }
+        let points = camera.normalize_matches(&matches);
+        let start = retract(&unit(pose), &Vector5::new(0.01, 0.01, -0.01, 0.02, 0.0));
+
+        // one pixel in normalized units
+        let scale = 1.0 / camera.fx;
+        let quadratic = refine_relative_pose(&start, &points, &RefinementOptions::default());
+        for kernel in [
+            RobustKernel::Huber(scale),
+            RobustKernel::Cauchy(scale),
+            RobustKernel::Tukey(3.0 * scale),
+        ] {
+            // Huber still gives the outliers a linear pull, the redescending kernels do not
+            let options = RefinementOptions {
+                kernel,
+                ..Default::default()
+            };
+            let robust = refine_relative_pose(&start, &points, &options);
+            assert!(
+                rotation_error(&robust.pose, &pose) * 10.0 < rotation_error(&quadratic.pose, &pose),
+                "{:?}",
+                kernel
+            );
+        }
+    }
+}
@@ -0,0 +1,313 @@
+//! Relocalization, recovering the camera pose against the existing map once tracking is lost.
+//!
+//! The keyframe database proposes the keyframes that look most like the frame. The frame's
+//! descriptors are matched to each candidate's map points, the pose is solved by PnP under
+//! RANSAC and refined, and the first candidate with enough inliers wins. Tracking can then
+//! `reset` from the recovered pose without touching the map.
+
+use std::fmt;
+
+use crate::bundle_adjustment::BundleAdjustmentOptions;
+use crate::camera::Camera;
+use crate::common::{Descriptor, KeyPoint};
+use crate::keyframe_database::KeyFrameDatabase;
+use crate::lie::SE3;
+use crate::map::{KeyFrameId, Map, MapPointId};
+use crate::matcher::match_unique;
+use crate::pnp::{estimate_pose_ransac, refine_pose};
+use crate::rand::*;
+use crate::ransac::RansacOptions;
+use crate::robust::{RobustKernel, MAX_REPROJECTION_ERROR};
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct RelocalizationOptions {
+    /// How many keyframes proposed by the database to try.
+    pub max_candidates: usize,
+    pub max_hamming_distance: usize,
+    /// Skip candidates with fewer descriptor matches than this.
+    pub min_matches: usize,
+    /// `inlier_threshold` is a reprojection error in pixels.
+    pub ransac: RansacOptions,
+    /// The largest reprojection error of an inlier after refinement, pixels.
+    pub max_reprojection_error: f64,
+    pub optimization_rounds: usize,
+    pub pose_optimization: BundleAdjustmentOptions,
+    /// Accept a pose with at least this many inliers after refinement.
+    pub min_inliers: usize,
+}
+
+impl Default for RelocalizationOptions {
+    fn default() -> Self {
+        Self {
+            max_candidates: 5,
+            max_hamming_distance: 100,
+            min_matches: 15,
+            ransac: RansacOptions::reprojection(),
+            max_reprojection_error: MAX_REPROJECTION_ERROR,
+            optimization_rounds: 4,
+            pose_optimization: BundleAdjustmentOptions {
+                max_iterations: 10,
+                kernel: RobustKernel::Huber(MAX_REPROJECTION_ERROR),
+                ..Default::default()
+            },
+            min_inliers: 30,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum RelocalizationError {
+    /// No keyframe in the database looks like the frame.
+    NoCandidates,
+    /// No candidate gave enough inliers, the most any did.
+    NotEnoughInliers(usize),
+}
+
+impl fmt::Display for RelocalizationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RelocalizationError::NoCandidates => write!(f, "no keyframe resembles the frame"),
+            RelocalizationError::NotEnoughInliers(n) => {
+                write!(f, "relocalization found at most {} inliers", n)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RelocalizationError {}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Relocalization {
+    /// `X_camera = pose * X_world`.
+    pub pose: SE3,
+    /// The candidate the frame was localized against, a reference keyframe for tracking.
+    pub keyframe: KeyFrameId,
+    /// The map point matched to each keypoint of the frame, if any. Only inliers are kept.
+    pub map_points: Vec<Option<MapPointId>>,
+    /// The descriptor matches to the candidate's map points.
+    pub matches: usize,
+    pub inliers: usize,
+}
+
+/// Localizes a frame against `map`, with `database` indexing its keyframes.
+pub fn relocalize(
+    map: &Map,
+    database: &KeyFrameDatabase,
+    camera: &Camera,
+    key_points: &[KeyPoint],
+    descriptors: &[Descriptor],
+    options: &RelocalizationOptions,
+    rnd: &mut Rand,
+) -> Result<Relocalization, RelocalizationError> {
+    let candidates = database.query(descriptors, options.max_candidates);
+    if candidates.is_empty() {
+        return Err(RelocalizationError::NoCandidates);
+    }
+
+    let mut best = 0;
+    for (keyframe, _) in candidates {
+        let kf = match map.keyframe(keyframe) {
+            Some(kf) => kf,
+            None => continue,
+        };
+        let points = kf
+            .map_points()
+            .iter()
+            .flatten()
+            .copied()
+            .collect::<Vec<_>>();
+        let point_descriptors = points
+            .iter()
+            .map(|id| map.map_point(*id).unwrap().descriptor().clone())
+            .collect::<Vec<_>>();
+
+        // best matches first for PROSAC, each map point used once
+        let matches = match_unique(
+            descriptors,
+            &point_descriptors,
+            options.max_hamming_distance,
+        );
+        if matches.len() < options.min_matches {
+            continue;
+        }
+
+        let correspondences = matches
+            .iter()
+            .map(|&(i, j, _)| {
+                (
+                    *map.map_point(points[j]).unwrap().position(),
+                    key_points[i].point(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let result = match estimate_pose_ransac(camera, &correspondences, &options.ransac, rnd) {
+            Some(result) => result,
+            None => continue,
+        };
+        let (pose, inliers) = refine_pose(
+            camera,
+            &result.model,
+            &correspondences,
+            options.optimization_rounds,
+            options.max_reprojection_error,
+            &options.pose_optimization,
+        );
+
+        let count = inliers.iter().filter(|&&inlier| inlier).count();
+        if count < options.min_inliers {
+            best = best.max(count);
+            continue;
+        }
+        let mut map_points = vec![None; key_points.len()];
+        for (&(i, j, _), inlier) in matches.iter().zip(inliers) {
+            if inlier {
+                map_points[i] = Some(points[j]);
+            }
+        }
+        return Ok(Relocalization {
+            pose,
+            keyframe,
+            map_points,
+            matches: matches.len(),
+            inliers: count,
+        });
+    }
+
+    Err(RelocalizationError::NotEnoughInliers(best))
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lie::SO3;
+    use crate::test_scene;
+    use crate::tracking::{Tracker, TrackingOptions};
+    use nalgebra::{Point3, Vector3, Vector6};
+
+    struct Scene {
+        map: Map,
+        places: Vec<KeyFrameId>,
+        points: Vec<Vec<Point3<f64>>>,
+        descriptors: Vec<Vec<Descriptor>>,
+    }
+
+    fn observe(pose: &SE3, points: &[Point3<f64>]) -> Vec<KeyPoint> {
+        let camera = test_scene::camera();
+        points
+            .iter()
+            .map(|p| test_scene::project(&camera, &(*pose * *p)))
+            .collect()
+    }
+
+    // two keyframes at the origin of two far apart places, each seeing its own points
+    fn scene() -> Scene {
+        let camera = test_scene::camera();
+        let mut scene = Scene {
+            map: Map::new(),
+            places: vec![],
+            points: vec![],
+            descriptors: vec![],
+        };
+        for place in 0..2 {
+            let offset = Vector3::new(100.0 * place as f64, 0.0, 0.0);
+            let pose = SE3::new(SO3::identity(), -offset);
+            let points = test_scene::points(80)
+                .into_iter()
+                .map(|p| p + offset)
+                .collect::<Vec<_>>();
+            let descriptors = test_scene::descriptors(points.len(), 10 + place);
+            let keyframe = scene
+                .map
+                .insert_keyframe(pose, camera, observe(&pose, &points), descriptors.clone())
+                .unwrap();
+            for (i, p) in points.iter().enumerate() {
+                scene.map.insert_map_point(*p, keyframe, i).unwrap();
+            }
+            scene.places.push(keyframe);
+            scene.points.push(points);
+            scene.descriptors.push(descriptors);
+        }
+        scene
+    }
+
+    #[test]
+    fn test_relocalize_and_resume_tracking() {
+        let mut scene = scene();
+        let database = KeyFrameDatabase::from_map(&scene.map);
+        let camera = test_scene::camera();
+        let mut rnd = Rand::new_with_seed(3);
+
+        // near the second place, with some features matching the wrong points
+        let lost = SE3::exp(&Vector6::new(0.3, -0.1, 0.2, 0.02, -0.05, 0.01))
+            * *scene.map.keyframe(scene.places[1]).unwrap().pose();
+        let mut key_points = observe(&lost, &scene.points[1]);
+        for key_point in key_points.iter_mut().step_by(5) {
+            key_point.x = 640.0 - key_point.x;
+        }
+        let descriptors = &scene.descriptors[1];
+        let options = RelocalizationOptions::default();
+        let found = relocalize(
+            &scene.map,
+            &database,
+            &camera,
+            &key_points,
+            descriptors,
+            &options,
+            &mut rnd,
+        )
+        .unwrap();
+        assert_eq!(found.keyframe, scene.places[1]);
+        assert_eq!(found.inliers, 64);
+        assert!((found.pose.inverse() * lost).log().norm() < 1e-4);
+
+        // tracking picks up from the recovered pose
+        let mut tracker = Tracker::new(camera, 640, 480, TrackingOptions::default());
+        tracker.reset(found.pose, found.keyframe);
+        let next = SE3::exp(&Vector6::new(0.02, 0.0, 0.0, 0.0, 0.0, 0.0)) * lost;
+        let key_points = observe(&next, &scene.points[1]);
+        let tracked = tracker
+            .track(&mut scene.map, &key_points, descriptors)
+            .unwrap();
+        assert!((tracked.pose.inverse() * next).log().norm() < 1e-4);
+    }
+
+    #[test]
+    fn test_relocalize_unknown_place() {
+        let scene = scene();
+        let database = KeyFrameDatabase::from_map(&scene.map);
+        let camera = test_scene::camera();
+        let mut rnd = Rand::new_with_seed(3);
+        let key_points = observe(&SE3::identity(), &scene.points[0]);
+        let descriptors = test_scene::descriptors(80, 99);
+
+        let result = relocalize(
+            &scene.map,
+            &database,
+            &camera,
+            &key_points,
+            &descriptors,
+            &RelocalizationOptions::default(),
+            &mut rnd,
+        );
+        assert!(matches!(
+            result,
+            Err(RelocalizationError::NoCandidates | RelocalizationError::NotEnoughInliers(_))
+        ));
+        assert_eq!(
+            relocalize(
+                &scene.map,
+                &KeyFrameDatabase::new(),
+                &camera,
+                &key_points,
+                &descriptors,
+                &RelocalizationOptions::default(),
+                &mut rnd,
+            ),
+            Err(RelocalizationError::NoCandidates)
+        );
+    }
+}
@@ -0,0 +1,147 @@
+//! Robust loss functions (M-estimators) for least squares.
+//!
+//! Plain least squares lets a single bad match drag the solution around, because its cost grows
+//! with the square of its residual. A robust kernel grows more slowly for large residuals.
+//! Optimizers use `weight` to turn this into iteratively reweighted least squares.
+//!
+//! https://en.wikipedia.org/wiki/M-estimator
+
+/// The reprojection error, pixels, within which 95% of correct matches fall under one pixel of
+/// Gaussian noise: the square root of 5.991, the 95% chi-square quantile for 2 degrees of freedom.
+/// The inlier threshold and kernel width used against reprojection errors.
+pub const MAX_REPROJECTION_ERROR: f64 = 2.4476519360399265;
+
+/// A robust kernel, the parameter is the residual (in the units of the problem) beyond which
+/// the kernel stops behaving quadratically.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub enum RobustKernel {
+    /// Ordinary least squares.
+    #[default]
+    Quadratic,
+    /// Quadratic near zero, linear beyond `delta`.
+    Huber(f64),
+    /// Logarithmic beyond `c`, large residuals keep a small but non zero influence.
+    Cauchy(f64),
+    /// Tukey's biweight, residuals beyond `c` are ignored entirely.
+    Tukey(f64),
+}
+
+impl RobustKernel {
+    /// The cost of a residual `r`. All kernels match `r^2 / 2` for small residuals.
+    pub fn cost(&self, r: f64) -> f64 {
+        let r_abs = r.abs();
+        match *self {
+            RobustKernel::Quadratic => 0.5 * r * r,
+            RobustKernel::Huber(delta) => {
+                if r_abs <= delta {
+                    0.5 * r * r
+                } else {
+                    delta * (r_abs - 0.5 * delta)
+                }
+            }
+            RobustKernel::Cauchy(c) => 0.5 * c * c * (1.0 + (r / c).powi(2)).ln(),
+            RobustKernel::Tukey(c) => {
+                if r_abs <= c {
+                    c * c / 6.0 * (1.0 - (1.0 - (r / c).powi(2)).powi(3))
+                } else {
+                    c * c / 6.0
+                }
+            }
+        }
+    }
+
+    /// The IRLS weight `cost'(r) / r`, how much a residual counts relative to least squares.
+    pub fn weight(&self, r: f64) -> f64 {
+        let r_abs = r.abs();
+        match *self {
+            RobustKernel::Quadratic => 1.0,
+            RobustKernel::Huber(delta) => {
+                if r_abs <= delta {
+                    1.0
+                } else {
+                    delta / r_abs
+                }
+            }
+            RobustKernel::Cauchy(c) => 1.0 / (1.0 + (r / c).powi(2)),
+            RobustKernel::Tukey(c) => {
+                if r_abs <= c {
+                    (1.0 - (r / c).powi(2)).powi(2)
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+
+    /// The same kernel with its parameter multiplied by `factor`, e.g. to convert a pixel
+    /// threshold to normalized image units.
+    pub fn scaled(&self, factor: f64) -> Self {
+        match *self {
+            RobustKernel::Quadratic => RobustKernel::Quadratic,
+            RobustKernel::Huber(delta) => RobustKernel::Huber(delta * factor),
+            RobustKernel::Cauchy(c) => RobustKernel::Cauchy(c * factor),
+            RobustKernel::Tukey(c) => RobustKernel::Tukey(c * factor),
+        }
+    }
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KERNELS: [RobustKernel; 4] = [
+        RobustKernel::Quadratic,
+        RobustKernel::Huber(1.0),
+        RobustKernel::Cauchy(1.0),
+        RobustKernel::Tukey(1.0),
+    ];
+
+    #[test]
+    fn test_max_reprojection_error() {
+        assert_eq!(MAX_REPROJECTION_ERROR, 5.991f64.sqrt());
+    }
+
+    #[test]
+    fn test_kernels_are_quadratic_near_zero() {
+        for kernel in KERNELS {
+            assert_eq!(kernel.cost(0.0), 0.0);
+            assert!(
+                (kernel.cost(0.01) - 0.5 * 0.01 * 0.01).abs() < 1e-8,
+                "{:?}",
+                kernel
+            );
+            assert!((kernel.weight(0.01) - 1.0).abs() < 1e-3, "{:?}", kernel);
+        }
+    }
+
+    #[test]
+    fn test_weight_is_derivative_over_residual() {
+        for kernel in KERNELS {
+            for r in [0.3, 0.9, 2.0, -5.0] {
+                let h = 1e-6;
+                let derivative = (kernel.cost(r + h) - kernel.cost(r - h)) / (2.0 * h);
+                assert!(
+                    (derivative / r - kernel.weight(r)).abs() < 1e-5,
+                    "{:?}",
+                    kernel
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_large_residuals() {
+        assert_eq!(RobustKernel::Huber(1.0).cost(10.0), 9.5);
+        assert_eq!(RobustKernel::Tukey(1.0).cost(10.0), 1.0 / 6.0);
+        assert_eq!(RobustKernel::Tukey(1.0).weight(10.0), 0.0);
+        assert!(RobustKernel::Cauchy(1.0).weight(10.0) > 0.0);
+        assert_eq!(
+            RobustKernel::Huber(2.0).scaled(0.5),
+            RobustKernel::Huber(1.0)
+        );
+    }
+}
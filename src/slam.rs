@@ -1,143 +1,987 @@
-use nalgebra::Matrix3;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use nalgebra::Vector3;
 
+use crate::camera::Camera;
 use crate::common::*;
-use crate::descriptors;
+use crate::config::{ConfigError, SlamConfig};
+use crate::error::XdofError;
 use crate::essential;
-use crate::essential::decompose_essential_matrix;
-use crate::fast_detect;
-use crate::image_impl;
+use crate::extractor::{BriefSettings, ExtractionPipeline, FeatureExtractor};
+use crate::frame::Frame;
+use crate::initializer::{initialize, InitializationError};
+use crate::keyframe_database::KeyFrameDatabase;
+use crate::keyframe_policy::{DefaultKeyFramePolicy, KeyFrameContext, KeyFramePolicy};
+use crate::lie::{SE3, SO3};
+use crate::local_mapping::LocalMapper;
+use crate::loop_closing::LoopCloser;
+use crate::map::{KeyFrameId, Map, MapError, MapPointId};
 use crate::matcher;
 use crate::rand::*;
+use crate::relative_pose::{refine_relative_pose, RefinementOptions};
+use crate::relocalization::relocalize;
+use crate::tracking::{Tracker, TrackingError};
+use crate::triangulation::parallax;
+
+/// The fewest keypoints and matches the eight point algorithm works with.
+const MIN_MATCHES: usize = 8;
+
+/// The pose of camera B relative to camera A, `X_b = pose() * X_a`, with a unit translation.
+#[derive(PartialEq, Debug, Clone)]
+pub struct PoseEstimate {
+    pub rotation: SO3,
+    /// Of unit length, monocular images do not give the baseline.
+    pub translation: Vector3<f64>,
+    /// The matches consistent with the pose, best first.
+    pub inliers: Vec<(KeyPoint, KeyPoint)>,
+    /// The features of the frames, shared with them rather than copied.
+    pub features_a: Arc<Features>,
+    pub features_b: Arc<Features>,
+    pub timing: PoseTiming,
+    pub quality: PoseQuality,
+}
 
+impl PoseEstimate {
+    pub fn pose(&self) -> SE3 {
+        SE3::new(self.rotation, self.translation)
+    }
+}
+
+/// Where the time of `calculate_pose` went.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub struct PoseTiming {
+    /// Blur, detection, orientation and descriptors of both images.
+    pub features: Duration,
+    /// The features were extracted by an earlier `calculate_pose`, `features` is then about zero.
+    pub features_cached: bool,
+    pub matching: Duration,
+    /// RANSAC, decomposition and refinement.
+    pub estimation: Duration,
+}
+
+impl PoseTiming {
+    pub fn total(&self) -> Duration {
+        self.features + self.matching + self.estimation
+    }
+}
+
+/// How well the pose is supported.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct PoseQuality {
+    pub matches: usize,
+    pub inliers: usize,
+    /// `inliers / matches`.
+    pub inlier_ratio: f64,
+    /// The inliers triangulating in front of both cameras.
+    pub points_in_front: usize,
+    /// The robust cost of the inliers before and after refinement, in normalized coordinates.
+    pub initial_cost: f64,
+    pub final_cost: f64,
+    /// Whether the refinement converged before running out of iterations.
+    pub converged: bool,
+}
+
+/// What `SlamSequence::process_frame` may change.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum SlamMode {
+    /// Build the map: initialize, insert keyframes, create points and close loops.
+    Mapping,
+    /// Only track and relocalize against the map as it is, which is never modified.
+    Localization,
+}
+
+/// How a frame was localized.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum FrameState {
+    /// Waiting for a second frame with enough parallax to initialize the map.
+    Initializing,
+    /// The frame initialized the map with the frame before it.
+    Initialized,
+    /// Tracked from the previous frame.
+    Tracking,
+    /// Recovered against the keyframes after tracking was lost or had not started.
+    Relocalized,
+    Lost,
+}
+
+/// The outcome of `SlamSequence::process_frame`.
+#[derive(PartialEq, Debug, Clone)]
+pub struct FrameResult {
+    pub timestamp: f64,
+    pub state: FrameState,
+    /// `X_camera = pose * X_world`, in map units.
+    pub pose: Option<SE3>,
+    /// Between 0 and 1: the fraction of the matched map points that are inliers, scaled down
+    /// when fewer than `confident_inliers` support the pose. Zero without a pose.
+    pub confidence: f64,
+    pub inliers: usize,
+    /// The keyframe made from this frame, in mapping mode.
+    pub keyframe: Option<KeyFrameId>,
+}
+
+/// Estimates the motion between two images with `calculate_pose`, or between any two frames
+/// with `estimate_pose`. For a sequence of frames see `SlamSequence`.
 pub struct Slam {
     image_a: Image,
     image_b: Image,
+    // the frames of the two images, extracted on the first `calculate_pose`
+    frames: Option<(Frame, Frame)>,
+    camera: Camera,
     random: Rand,
-    patch_size: usize,
-    num_pairs: usize,
-    max_hamming_distance: usize,
-    blur_radius: f32,
-    essential_num_iterations: usize,
-    essential_threshold: f32,
+    config: SlamConfig,
+    extractor: Box<dyn FeatureExtractor>,
 }
 
 impl Slam {
     pub fn new(image_a: Image, image_b: Image) -> Slam {
-        let seed = 2523523;
-        let random = Rand::new_with_seed(seed);
+        let config = SlamConfig::default();
+        let random = Rand::new_with_seed(config.seed);
+        let camera = Camera::from_image_size(image_a.width, image_a.height);
+        let extractor = ExtractionPipeline::from(&config.extractor());
         Slam {
             image_a,
             image_b,
+            frames: None,
+            camera,
             random,
-            patch_size: 100,
-            num_pairs: 500,
-            max_hamming_distance: 300,
-            blur_radius: 3.0,
-            essential_num_iterations: 1000,
-            essential_threshold: 10.0,
+            config,
+            extractor: Box::new(extractor),
+        }
+    }
+
+    /// Replaces the default parameters, restarting the random generator from the new seed and
+    /// going back to the default extractor with the new features parameters.
+    pub fn with_config(mut self, config: SlamConfig) -> Result<Self, ConfigError> {
+        config.validate()?;
+        self.random = Rand::new_with_seed(config.seed);
+        self.extractor = Box::new(ExtractionPipeline::from(&config.extractor()));
+        self.frames = None;
+        if let Some(camera) = config.camera {
+            self.camera = camera;
         }
+        self.config = config;
+        Ok(self)
+    }
+
+    pub fn config(&self) -> &SlamConfig {
+        &self.config
+    }
+
+    /// Uses calibrated intrinsics instead of the guess made from the image size.
+    pub fn with_camera(mut self, camera: Camera) -> Self {
+        self.camera = camera;
+        self
     }
 
-    pub fn calculate_pose(
+    /// Extracts the features of `extract` and `calculate_pose` with `extractor` instead of the
+    /// pipeline made from the config.
+    pub fn with_extractor(mut self, extractor: impl FeatureExtractor + 'static) -> Self {
+        self.extractor = Box::new(extractor);
+        self.frames = None;
+        self
+    }
+
+    /// Extracts the features of an image with the configured extractor.
+    pub fn extract(&self, image: &Image) -> Result<Frame, XdofError> {
+        Frame::new(image, self.extractor.as_ref())
+    }
+
+    /// Estimates the motion between image A and image B. Their features are extracted on the
+    /// first call and reused after.
+    pub fn calculate_pose(&mut self) -> Result<PoseEstimate, XdofError> {
+        let (a, b) = (&self.image_a, &self.image_b);
+        if (a.width, a.height) != (b.width, b.height) {
+            return Err(XdofError::ImageSizeMismatch {
+                a: (a.width, a.height),
+                b: (b.width, b.height),
+            });
+        }
+        let start = Instant::now();
+        let features_cached = self.frames.is_some();
+        let (frame_a, frame_b) = match self.frames.take() {
+            Some(frames) => frames,
+            None => (self.extract(&self.image_a)?, self.extract(&self.image_b)?),
+        };
+        let features = start.elapsed();
+        let estimate = self.estimate_pose(&frame_a, &frame_b);
+        self.frames = Some((frame_a, frame_b));
+        let mut estimate = estimate?;
+        estimate.timing.features = features;
+        estimate.timing.features_cached = features_cached;
+        Ok(estimate)
+    }
+
+    /// Estimates the motion between two frames of the same size, `X_b = pose() * X_a`.
+    pub fn estimate_pose(&mut self, a: &Frame, b: &Frame) -> Result<PoseEstimate, XdofError> {
+        if (a.width(), a.height()) != (b.width(), b.height()) {
+            return Err(XdofError::ImageSizeMismatch {
+                a: (a.width(), a.height()),
+                b: (b.width(), b.height()),
+            });
+        }
+        for (image, frame) in [a, b].iter().enumerate() {
+            if frame.key_points().len() < MIN_MATCHES {
+                return Err(XdofError::TooFewKeyPoints {
+                    image,
+                    found: frame.key_points().len(),
+                    required: MIN_MATCHES,
+                });
+            }
+        }
+        let start = Instant::now();
+
+        // PHASE 4  -  Match features between the two images
+
+        let mut scored_matches = matcher::match_features_with_distance(
+            a.key_points(),
+            a.descriptors(),
+            b.key_points(),
+            b.descriptors(),
+            self.config.max_hamming_distance,
+        );
+
+        // best matches first, PROSAC relies on this ordering
+        scored_matches.sort_by_key(|&(_, distance)| distance);
+        let matched_keypoints = scored_matches
+            .into_iter()
+            .map(|(matched, _)| matched)
+            .collect::<Vec<_>>();
+        if matched_keypoints.len() < MIN_MATCHES {
+            return Err(XdofError::TooFewMatches {
+                found: matched_keypoints.len(),
+                required: MIN_MATCHES,
+            });
+        }
+        let matching = start.elapsed();
+
+        // PHASE 5  -  RANSAC to find the best rotation and translation using 8 point algorithm
+        let result = essential::estimate_essential_ransac(
+            &matched_keypoints,
+            &self.camera,
+            &self.config.essential,
+            &mut self.random,
+        )
+        .ok_or(XdofError::DegenerateGeometry)?;
+
+        // PHASE 6  -  Decompose the essential matrix to find the rotation and translation, and
+        // polish them over all the inliers
+        let inliers = result.select_inliers(&matched_keypoints);
+        let normalized = self.camera.normalize_matches(&inliers);
+        let recovered = essential::recover_pose(&result.model, &normalized)
+            .ok_or(XdofError::DegenerateGeometry)?;
+        let options = RefinementOptions {
+            max_iterations: self.config.refinement_iterations,
+            // the kernel is in pixels, the Sampson distances are normalized
+            kernel: self
+                .config
+                .refinement_kernel
+                .scaled(2.0 / (self.camera.fx + self.camera.fy)),
+            ..Default::default()
+        };
+        let refined = refine_relative_pose(&recovered.pose, &normalized, &options);
+        let estimation = start.elapsed() - matching;
+
+        Ok(PoseEstimate {
+            rotation: refined.pose.rotation,
+            translation: refined.pose.translation,
+            quality: PoseQuality {
+                matches: matched_keypoints.len(),
+                inliers: inliers.len(),
+                inlier_ratio: inliers.len() as f64 / matched_keypoints.len() as f64,
+                points_in_front: recovered.num_points(),
+                initial_cost: refined.initial_cost,
+                final_cost: refined.final_cost,
+                converged: refined.converged,
+            },
+            inliers,
+            features_a: a.shared_features(),
+            features_b: b.shared_features(),
+            timing: PoseTiming {
+                features: Duration::ZERO,
+                features_cached: false,
+                matching,
+                estimation,
+            },
+        })
+    }
+}
+
+/// Localizes a sequence of frames fed to `process_frame`, building a map of them in mapping
+/// mode.
+pub struct SlamSequence {
+    width: usize,
+    height: usize,
+    camera: Camera,
+    random: Rand,
+    config: SlamConfig,
+    mode: SlamMode,
+    map: Map,
+    database: KeyFrameDatabase,
+    tracker: Tracker,
+    policy: Box<dyn KeyFramePolicy>,
+    local_mapper: LocalMapper,
+    loop_closer: LoopCloser,
+    extractor: Box<dyn FeatureExtractor>,
+    // the first frame of the initialization pair
+    pending: Option<Frame>,
+    frames_since_keyframe: usize,
+    keyframe_timestamp: f64,
+}
+
+impl SlamSequence {
+    /// A sequence of `width` x `height` frames taken with `camera`.
+    pub fn new(camera: Camera, width: usize, height: usize) -> SlamSequence {
+        let config = SlamConfig::default();
+        let random = Rand::new_with_seed(config.seed);
+        let extractor = ExtractionPipeline::from(&config.extractor());
+        SlamSequence {
+            width,
+            height,
+            camera,
+            random,
+            mode: SlamMode::Mapping,
+            map: Map::new(),
+            database: KeyFrameDatabase::new(),
+            tracker: Tracker::new(camera, width, height, config.tracking),
+            policy: Box::new(DefaultKeyFramePolicy::default()),
+            local_mapper: LocalMapper::new(config.local_mapping),
+            loop_closer: LoopCloser::new(config.loop_closing),
+            extractor: Box::new(extractor),
+            config,
+            pending: None,
+            frames_since_keyframe: 0,
+            keyframe_timestamp: 0.0,
+        }
+    }
+
+    /// Replaces the default parameters, restarting the random generator from the new seed,
+    /// going back to the default extractor with the new features parameters and starting
+    /// tracking, local mapping and loop closing over with their new options.
+    pub fn with_config(mut self, config: SlamConfig) -> Result<Self, ConfigError> {
+        config.validate()?;
+        self.random = Rand::new_with_seed(config.seed);
+        self.extractor = Box::new(ExtractionPipeline::from(&config.extractor()));
+        self.tracker = Tracker::new(self.camera, self.width, self.height, config.tracking);
+        self.local_mapper = LocalMapper::new(config.local_mapping);
+        self.loop_closer = LoopCloser::new(config.loop_closing);
+        let camera = config.camera;
+        self.config = config;
+        Ok(match camera {
+            Some(camera) => self.with_camera(camera),
+            None => self,
+        })
+    }
+
+    pub fn config(&self) -> &SlamConfig {
+        &self.config
+    }
+
+    /// Replaces the intrinsics given to `new`.
+    pub fn with_camera(mut self, camera: Camera) -> Self {
+        self.camera = camera;
+        self.tracker = Tracker::new(camera, self.width, self.height, self.tracker.options);
+        self
+    }
+
+    /// Continues from an existing map and the BRIEF settings of its descriptors, e.g. as loaded
+    /// with `Map::load`. The first frame is relocalized against it. What was learned from the
+    /// previous map is dropped, the options are kept. Fails when the configured extractor makes
+    /// descriptors with other settings.
+    pub fn with_map(mut self, map: Map, brief: BriefSettings) -> Result<Self, XdofError> {
+        let extractor = self.config.extractor().brief();
+        if brief != extractor {
+            return Err(XdofError::BriefMismatch {
+                map: brief,
+                extractor,
+            });
+        }
+        self.database = KeyFrameDatabase::from_map(&map);
+        self.map = map;
+        self.tracker = Tracker::new(self.camera, self.width, self.height, self.config.tracking);
+        self.local_mapper = LocalMapper::new(self.config.local_mapping);
+        self.loop_closer = LoopCloser::new(self.config.loop_closing);
+        self.pending = None;
+        self.frames_since_keyframe = 0;
+        self.keyframe_timestamp = 0.0;
+        Ok(self)
+    }
+
+    pub fn with_mode(mut self, mode: SlamMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Decides when mapping inserts keyframes.
+    pub fn with_keyframe_policy(mut self, policy: impl KeyFramePolicy + 'static) -> Self {
+        self.policy = Box::new(policy);
+        self
+    }
+
+    /// Extracts the features of `extract` with `extractor` instead of the pipeline made from
+    /// the config. Maps only match frames of the extractor they were made with.
+    pub fn with_extractor(mut self, extractor: impl FeatureExtractor + 'static) -> Self {
+        self.extractor = Box::new(extractor);
+        self
+    }
+
+    pub fn mode(&self) -> SlamMode {
+        self.mode
+    }
+
+    pub fn map(&self) -> &Map {
+        &self.map
+    }
+
+    pub fn into_map(self) -> Map {
+        self.map
+    }
+
+    /// Extracts the features of an image with the configured extractor.
+    pub fn extract(&self, image: &Image) -> Result<Frame, XdofError> {
+        Frame::new(image, self.extractor.as_ref())
+    }
+
+    /// Localizes the next frame of the sequence, taken at `timestamp` seconds, and extends the
+    /// map with it in mapping mode. A frame that can be neither tracked nor relocalized is
+    /// `FrameState::Lost`, errors are for an inconsistent map.
+    pub fn process_frame(
         &mut self,
-    ) -> (
-        Option<(Matrix3<f64>, Vector3<f64>)>,
-        Vec<(KeyPoint, KeyPoint)>,
-        (Vec<KeyPoint>, Vec<Descriptor>),
-        (Vec<KeyPoint>, Vec<Descriptor>),
-    ) {
-        let (key_points_with_orientation_a, blurred_image_a) = {
-            let width = self.image_a.width;
-            let height = self.image_a.height;
-
-            // PHASE 1  -  Convert RGB image to greyscale and blur it with a Gaussian filter
-            //let greyscale = image_impl::rgb_to_grayscale(self.image_a.data, width, height);
-            let greyscale = self.image_a.data.clone();
-            let blurred_img =
-                image_impl::greyscale_gaussian_blur(&greyscale, width, height, self.blur_radius);
-            let threshold: u8 = 30;
-
-            // PHASE 2  -  Detect FAST keypoints and compute their orientations
-            let keypoints = fast_detect::fast_keypoints(&blurred_img, width, height, threshold);
-            let key_points_with_orientation =
-                fast_detect::compute_orientations(&blurred_img, width, &keypoints);
-
-            (key_points_with_orientation, blurred_img)
-        };
-
-        let (key_points_with_orientation_b, blurred_image_b) = {
-            let width = self.image_b.width;
-            let height = self.image_b.height;
-
-            // PHASE 1  -  Convert RGB image to greyscale and blur it with a Gaussian filter
-            // let greyscale = image_impl::rgb_to_grayscale(self.image_b.data, width, height);
-            let greyscale = self.image_b.data.clone();
-            let blurred_img =
-                image_impl::greyscale_gaussian_blur(&greyscale, width, height, self.blur_radius);
-            let threshold: u8 = 30;
-
-            // PHASE 2  -  Detect FAST keypoints and compute their orientations
-            let keypoints = fast_detect::fast_keypoints(&blurred_img, width, height, threshold);
-            let key_points_with_orientation =
-                fast_detect::compute_orientations(&blurred_img, width, &keypoints);
-
-            (key_points_with_orientation, blurred_img)
-        };
-
-        // PHASE 3  -  Compute BRIEF descriptors for each keypoint so we can visually match them
-        let sampling_pattern = descriptors::generate_sampling_pattern(
+        frame: Frame,
+        timestamp: f64,
+    ) -> Result<FrameResult, XdofError> {
+        let mut result = FrameResult {
+            timestamp,
+            state: FrameState::Lost,
+            pose: None,
+            confidence: 0.0,
+            inliers: 0,
+            keyframe: None,
+        };
+        if self.mode == SlamMode::Mapping && self.map.num_keyframes() == 0 {
+            self.initialize(frame, &mut result)?;
+            return Ok(result);
+        }
+        self.frames_since_keyframe += 1;
+
+        let tracked = match self.tracker.last_pose() {
+            None => None,
+            Some(_) => {
+                let tracked = match self.mode {
+                    SlamMode::Mapping => {
+                        self.tracker
+                            .track(&mut self.map, frame.key_points(), frame.descriptors())
+                    }
+                    SlamMode::Localization => {
+                        self.tracker
+                            .track_fixed(&self.map, frame.key_points(), frame.descriptors())
+                    }
+                };
+                match tracked {
+                    Ok(tracked) => Some(tracked),
+                    // lost, relocalize below
+                    Err(TrackingError::NotInitialized | TrackingError::NotEnoughInliers(_)) => None,
+                    Err(TrackingError::DescriptorCountMismatch {
+                        key_points,
+                        descriptors,
+                    }) => {
+                        return Err(XdofError::DescriptorCountMismatch {
+                            key_points,
+                            descriptors,
+                        })
+                    }
+                    Err(TrackingError::Map(error)) => return Err(error.into()),
+                }
+            }
+        };
+        let tracked = match tracked {
+            Some(tracked) => tracked,
+            None => {
+                if let Ok(found) = relocalize(
+                    &self.map,
+                    &self.database,
+                    &self.camera,
+                    frame.key_points(),
+                    frame.descriptors(),
+                    &self.config.relocalization,
+                    &mut self.random,
+                ) {
+                    self.tracker.reset(found.pose, found.keyframe);
+                    result.state = FrameState::Relocalized;
+                    result.pose = Some(found.pose);
+                    result.inliers = found.inliers;
+                    result.confidence = self.confidence(found.inliers, found.matches);
+                }
+                return Ok(result);
+            }
+        };
+        result.state = FrameState::Tracking;
+        result.pose = Some(tracked.pose);
+        result.inliers = tracked.inliers;
+        result.confidence = self.confidence(tracked.inliers, tracked.matches);
+        if self.mode == SlamMode::Localization {
+            return Ok(result);
+        }
+
+        let last = match self.map.last_keyframe() {
+            Some(last) => last,
+            None => return Ok(result),
+        };
+        let center = tracked.pose.center();
+        let mut parallaxes = tracked
+            .map_points
+            .iter()
+            .flatten()
+            .map(|id| {
+                let point = self.map.map_point(*id).unwrap().position();
+                parallax(&last.center(), &center, point)
+            })
+            // a point on either camera centre has no parallax
+            .filter(|parallax| parallax.is_finite())
+            .collect::<Vec<_>>();
+        parallaxes.sort_by(f64::total_cmp);
+        let context = KeyFrameContext {
+            frames_since_keyframe: self.frames_since_keyframe,
+            seconds_since_keyframe: timestamp - self.keyframe_timestamp,
+            tracked_points: tracked.inliers,
+            keyframe_points: last.num_map_points(),
+            motion: tracked.pose * last.pose().inverse(),
+            median_parallax: parallaxes.get(parallaxes.len() / 2).copied().unwrap_or(0.0),
+            // local mapping runs to completion on every keyframe
+            local_mapping_idle: true,
+        };
+        if self.policy.should_insert(&context) {
+            let keyframe =
+                self.insert_keyframe(&tracked.pose, frame.into_features(), &tracked.map_points)?;
+            self.frames_since_keyframe = 0;
+            self.keyframe_timestamp = timestamp;
+            result.keyframe = Some(keyframe);
+        }
+        Ok(result)
+    }
+
+    // the first frame is kept until a later one initializes the map with it
+    fn initialize(&mut self, frame: Frame, result: &mut FrameResult) -> Result<(), MapError> {
+        result.state = FrameState::Initializing;
+        let first = match self.pending.take() {
+            Some(first) => first,
+            None => {
+                self.pending = Some(frame);
+                return Ok(());
+            }
+        };
+        let initialization = initialize(
+            &mut self.map,
+            &self.camera,
+            (first.key_points(), first.descriptors()),
+            (frame.key_points(), frame.descriptors()),
+            &self.config.initializer,
+            &mut self.random,
+        );
+        match initialization {
+            Ok(initialization) => {
+                for keyframe in [initialization.first, initialization.second] {
+                    self.database.add(self.map.keyframe(keyframe).unwrap());
+                }
+                self.tracker
+                    .reset(initialization.pose, initialization.second);
+                self.frames_since_keyframe = 0;
+                self.keyframe_timestamp = result.timestamp;
+                result.state = FrameState::Initialized;
+                result.pose = Some(initialization.pose);
+                result.inliers = initialization.map_points.len();
+                result.confidence = self.confidence(result.inliers, result.inliers);
+                result.keyframe = Some(initialization.second);
+            }
+            Err(InitializationError::Map(error)) => return Err(error),
+            // too few matches means the view changed too much, start over from this frame
+            Err(InitializationError::NotEnoughMatches(_)) => {
+                self.pending = Some(frame);
+            }
+            Err(_) => self.pending = Some(first),
+        }
+        Ok(())
+    }
+
+    // adds the tracked frame to the map and runs local mapping and loop closing on it
+    fn insert_keyframe(
+        &mut self,
+        pose: &SE3,
+        features: Features,
+        map_points: &[Option<MapPointId>],
+    ) -> Result<KeyFrameId, MapError> {
+        let keyframe = self.map.insert_keyframe(
+            *pose,
+            self.camera,
+            features.key_points,
+            features.descriptors,
+        )?;
+        for (index, point) in map_points.iter().enumerate() {
+            if let Some(point) = point {
+                self.map.add_observation(*point, keyframe, index)?;
+            }
+        }
+        self.local_mapper
+            .process_keyframe(&mut self.map, keyframe)?;
+        let closure = self.loop_closer.process_keyframe(
+            &mut self.map,
+            &mut self.database,
+            keyframe,
             &mut self.random,
-            self.patch_size,
-            self.num_pairs,
+        )?;
+        match closure {
+            // the keyframe moved with the correction, so tracking starts over from it
+            Some(_) => {
+                let pose = *self.map.keyframe(keyframe).unwrap().pose();
+                self.tracker.reset(pose, keyframe);
+            }
+            None => self.tracker.set_reference_keyframe(keyframe),
+        }
+        Ok(keyframe)
+    }
+
+    fn confidence(&self, inliers: usize, matches: usize) -> f64 {
+        if matches == 0 {
+            return 0.0;
+        }
+        let support = (inliers as f64 / self.config.confident_inliers as f64).min(1.0);
+        inliers as f64 / matches as f64 * support
+    }
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_view::ImageView;
+    use crate::lie::SO3;
+    use crate::local_mapping::LocalMappingOptions;
+    use crate::loop_closing::LoopClosingOptions;
+    use crate::test_scene;
+    use crate::tracking::TrackingOptions;
+    use nalgebra::{Point3, Vector3, Vector6};
+
+    fn observe(pose: &SE3, points: &[Point3<f64>]) -> Vec<KeyPoint> {
+        let camera = test_scene::camera();
+        points
+            .iter()
+            .map(|p| test_scene::project(&camera, &(*pose * *p)))
+            .collect()
+    }
+
+    fn features(key_points: Vec<KeyPoint>, descriptors: Vec<Descriptor>) -> Frame {
+        Frame::from_features(
+            640,
+            480,
+            Features {
+                key_points,
+                descriptors,
+            },
+        )
+    }
+
+    // sideways from the origin, far enough at the second frame to initialize
+    fn trajectory(frame: usize) -> SE3 {
+        SE3::exp(&(Vector6::new(-0.25, 0.02, 0.0, 0.0, -0.01, 0.0) * frame as f64))
+    }
+
+    fn sequence() -> SlamSequence {
+        SlamSequence::new(test_scene::camera(), 640, 480)
+    }
+
+    // an image pair for `estimate_pose`, which does not look at the images
+    fn pair() -> Slam {
+        let image = Image {
+            width: 640,
+            height: 480,
+            data: vec![0; 640 * 480],
+        };
+        Slam::new(image.clone(), image).with_camera(test_scene::camera())
+    }
+
+    #[test]
+    fn test_mapping_sequence() {
+        let points = test_scene::points(120);
+        let descriptors = test_scene::descriptors(points.len(), 5);
+        let mut slam = sequence();
+
+        let mut states = vec![];
+        let mut keyframes = 0;
+        for frame in 0..12 {
+            let key_points = observe(&trajectory(frame), &points);
+            let result = slam
+                .process_frame(
+                    features(key_points, descriptors.clone()),
+                    frame as f64 / 30.0,
+                )
+                .unwrap();
+            states.push(result.state);
+            keyframes += result.keyframe.is_some() as usize;
+            if frame > 0 {
+                assert!(
+                    result.pose.is_some() && result.confidence > 0.9,
+                    "{:?}",
+                    result
+                );
+            }
+        }
+        assert_eq!(
+            states[..2],
+            [FrameState::Initializing, FrameState::Initialized]
         );
+        assert!(states[2..].iter().all(|&s| s == FrameState::Tracking));
+        assert_eq!(slam.map().num_keyframes(), keyframes + 1);
+        assert!(keyframes > 2);
+    }
 
-        let descriptors_a = descriptors::compute_brief_descriptors(
-            &blurred_image_a,
-            self.image_a.width as u32,
-            self.image_a.height as u32,
-            &key_points_with_orientation_b,
-            &sampling_pattern,
+    #[test]
+    fn test_calculate_pose_errors() {
+        let image = |width: usize, height: usize| Image {
+            width,
+            height,
+            data: vec![128; width * height],
+        };
+        let mut slam = Slam::new(image(64, 48), image(48, 64));
+        assert_eq!(
+            slam.calculate_pose(),
+            Err(XdofError::ImageSizeMismatch {
+                a: (64, 48),
+                b: (48, 64)
+            })
         );
 
-        let descriptors_b = descriptors::compute_brief_descriptors(
-            &blurred_image_b,
-            self.image_b.width as u32,
-            self.image_b.height as u32,
-            &key_points_with_orientation_b,
-            &sampling_pattern,
+        // a flat image has no corners
+        let mut slam = Slam::new(image(64, 48), image(64, 48));
+        assert_eq!(
+            slam.calculate_pose(),
+            Err(XdofError::TooFewKeyPoints {
+                image: 0,
+                found: 0,
+                required: 8
+            })
         );
+    }
 
-        // PHASE 4  -  Match features between the two images
+    #[test]
+    fn test_estimate_pose() {
+        let points = test_scene::points(100);
+        let descriptors = test_scene::descriptors(points.len(), 8);
+        let truth = trajectory(2);
+        let a = features(observe(&SE3::identity(), &points), descriptors.clone());
+        let b = features(observe(&truth, &points), descriptors);
+
+        let mut slam = pair();
+        let estimate = slam.estimate_pose(&a, &b).unwrap();
+        assert_eq!(estimate.quality.inliers, points.len());
+        assert!(Arc::ptr_eq(&estimate.features_a, &a.shared_features()));
+        let expected = truth.translation.normalize();
+        assert!((estimate.translation - expected).norm() < 1e-3);
+        assert!((estimate.rotation.inverse() * truth.rotation).log().norm() < 1e-3);
+    }
+
+    #[test]
+    fn test_calculate_pose_caches_features() {
+        let points = test_scene::points(100);
+        let descriptors = test_scene::descriptors(points.len(), 8);
+        let a = features(observe(&SE3::identity(), &points), descriptors.clone());
+        let b = features(observe(&trajectory(2), &points), descriptors);
+
+        // image A is black and image B grey, the extractor tells them apart by that
+        let image = |value: u8| Image {
+            width: 640,
+            height: 480,
+            data: vec![value; 640 * 480],
+        };
+        let (a, b) = (a.into_features(), b.into_features());
+        let mut slam = Slam::new(image(0), image(1))
+            .with_camera(test_scene::camera())
+            .with_extractor(move |image: ImageView<u8>| match image.get(0, 0) {
+                0 => a.clone(),
+                _ => b.clone(),
+            });
+        let first = slam.calculate_pose().unwrap();
+        assert!(!first.timing.features_cached);
+        let second = slam.calculate_pose().unwrap();
+        assert!(second.timing.features_cached);
+        assert!(Arc::ptr_eq(&first.features_b, &second.features_b));
+    }
+
+    #[test]
+    fn test_with_config() {
+        let camera = Camera::new(400.0, 400.0, 320.0, 240.0);
+        let config = SlamConfig::builder()
+            .camera(camera)
+            .fast_threshold(12)
+            .build()
+            .unwrap();
+        let slam = sequence().with_config(config.clone()).unwrap();
+        assert_eq!(slam.config(), &config);
+        assert_eq!(slam.camera, camera);
+
+        // the options of the sequence modules come from the config too
+        let tracking = TrackingOptions {
+            min_inliers: 12,
+            ..Default::default()
+        };
+        let local_mapping = LocalMappingOptions {
+            max_neighbours: 4,
+            ..Default::default()
+        };
+        let loop_closing = LoopClosingOptions {
+            min_consistency: 1,
+            ..Default::default()
+        };
+        let sequence_config = SlamConfig::builder()
+            .tracking(tracking)
+            .local_mapping(local_mapping)
+            .loop_closing(loop_closing)
+            .confident_inliers(10)
+            .build()
+            .unwrap();
+        let slam = sequence().with_config(sequence_config).unwrap();
+        assert_eq!(slam.tracker.options, tracking);
+        assert_eq!(slam.local_mapper.options, local_mapping);
+        assert_eq!(slam.loop_closer.options, loop_closing);
+        assert_eq!(slam.confidence(10, 20), 0.5);
+        assert_eq!(slam.confidence(5, 10), 0.25);
+        let slam = pair().with_config(config.clone()).unwrap();
+        assert_eq!(slam.config(), &config);
+        assert_eq!(slam.camera, camera);
+
+        let invalid = SlamConfig {
+            patch_size: 0,
+            ..SlamConfig::default()
+        };
+        assert!(sequence().with_config(invalid.clone()).is_err());
+        assert!(pair().with_config(invalid).is_err());
+    }
 
-        let matched_keypoints = matcher::match_features(
-            &key_points_with_orientation_a,
-            &descriptors_a,
-            &key_points_with_orientation_b,
-            &descriptors_b,
-            self.max_hamming_distance,
+    #[test]
+    fn test_with_extractor() {
+        let points = test_scene::points(60);
+        let descriptors = test_scene::descriptors(points.len(), 9);
+        let features = Features {
+            key_points: observe(&SE3::identity(), &points),
+            descriptors,
+        };
+        let extractor = {
+            let features = features.clone();
+            move |_: ImageView<u8>| features.clone()
+        };
+        let slam = sequence().with_extractor(extractor);
+        let image = Image {
+            width: 640,
+            height: 480,
+            data: vec![0; 640 * 480],
+        };
+        assert_eq!(slam.extract(&image).unwrap().into_features(), features);
+    }
+
+    #[test]
+    fn test_with_map_resets_mapping() {
+        let points = test_scene::points(120);
+        let descriptors = test_scene::descriptors(points.len(), 5);
+        let mut slam = sequence();
+        for frame in 0..4 {
+            let key_points = observe(&trajectory(frame), &points);
+            slam.process_frame(features(key_points, descriptors.clone()), frame as f64)
+                .unwrap();
+        }
+        assert_ne!(
+            slam.local_mapper,
+            LocalMapper::new(slam.local_mapper.options)
         );
 
-        // PHASE 5  -  RANSAC to find the best rotation and translation using 8 point algorithm
-        let essential_matrix = essential::estimate_essential_ransac(
-            &matched_keypoints,
-            *&self.essential_num_iterations,
-            *&self.essential_threshold as f64,
-            &mut self.random,
+        let brief = slam.config().extractor().brief();
+        let slam = slam.with_map(Map::new(), brief).unwrap();
+        assert_eq!(
+            slam.local_mapper,
+            LocalMapper::new(slam.local_mapper.options)
         );
+        assert_eq!(slam.loop_closer, LoopCloser::new(slam.loop_closer.options));
+        assert_eq!(slam.frames_since_keyframe, 0);
+        assert_eq!(slam.keyframe_timestamp, 0.0);
+    }
 
-        // PHASE 6  -  Decompose the essential matrix to find the rotation and translation
-        let decomposed_essential = if let Some(essential) = essential_matrix {
-            Some(decompose_essential_matrix(essential))
-        } else {
-            None
+    #[test]
+    fn test_with_map_rejects_other_brief_settings() {
+        let extractor = SlamConfig::default().extractor().brief();
+        let brief = BriefSettings {
+            num_pairs: 256,
+            ..extractor
         };
+        assert_eq!(
+            sequence().with_map(Map::new(), brief).err(),
+            Some(XdofError::BriefMismatch {
+                map: brief,
+                extractor
+            })
+        );
+    }
 
-        (
-            decomposed_essential,
-            matched_keypoints,
-            (key_points_with_orientation_a, descriptors_a),
-            (key_points_with_orientation_b, descriptors_b),
-        )
+    #[test]
+    fn test_localization_leaves_map_unchanged() {
+        let camera = test_scene::camera();
+        let points = test_scene::points(80);
+        let descriptors = test_scene::descriptors(points.len(), 6);
+        let mut map = Map::new();
+        let keyframe = map
+            .insert_keyframe(
+                SE3::identity(),
+                camera,
+                observe(&SE3::identity(), &points),
+                descriptors.clone(),
+            )
+            .unwrap();
+        for (i, p) in points.iter().enumerate() {
+            map.insert_map_point(*p, keyframe, i).unwrap();
+        }
+
+        let mut slam = sequence()
+            .with_map(map.clone(), SlamConfig::default().extractor().brief())
+            .unwrap()
+            .with_mode(SlamMode::Localization);
+        let mut states = vec![];
+        for frame in 0..5 {
+            let truth = SE3::new(
+                SO3::identity(),
+                Vector3::new(-0.05 * frame as f64, 0.0, 0.0),
+            );
+            let result = slam
+                .process_frame(
+                    features(observe(&truth, &points), descriptors.clone()),
+                    frame as f64,
+                )
+                .unwrap();
+            assert!((result.pose.unwrap().inverse() * truth).log().norm() < 1e-4);
+            assert!(result.confidence > 0.9);
+            assert_eq!(result.keyframe, None);
+            states.push(result.state);
+        }
+        assert_eq!(states[0], FrameState::Relocalized);
+        assert!(states[1..].iter().all(|&s| s == FrameState::Tracking));
+
+        // an unknown place is reported lost with no confidence
+        let unknown = test_scene::descriptors(points.len(), 7);
+        let result = slam
+            .process_frame(features(observe(&SE3::identity(), &points), unknown), 5.0)
+            .unwrap();
+        assert_eq!(result.state, FrameState::Lost);
+        assert_eq!((result.pose, result.confidence), (None, 0.0));
+
+        // a map missing the reference keyframe is an error, not a lost frame
+        slam.tracker.reset(SE3::identity(), KeyFrameId(99));
+        let result = slam.process_frame(
+            features(observe(&SE3::identity(), &points), descriptors.clone()),
+            6.0,
+        );
+        assert_eq!(
+            result,
+            Err(XdofError::Map(MapError::UnknownKeyFrame(KeyFrameId(99))))
+        );
+        assert_eq!(slam.into_map(), map);
     }
 }
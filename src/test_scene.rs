@@ -0,0 +1,61 @@
+//! Synthetic scenes shared by the geometry unit tests.
+
+use nalgebra::{Point3, Rotation3, Vector3};
+
+use crate::camera::Camera;
+use crate::common::{Descriptor, KeyPoint};
+use crate::lie::SE3;
+use crate::rand::Rand;
+
+pub fn camera() -> Camera {
+    Camera::new(500.0, 500.0, 320.0, 240.0)
+}
+
+/// Deterministic, well spread points in front of the first camera.
+pub fn points(n: usize) -> Vec<Point3<f64>> {
+    (0..n)
+        .map(|i| {
+            let i = i as f64;
+            Point3::new(
+                ((i * 0.731).sin() * 2.0).clamp(-2.0, 2.0),
+                ((i * 1.377).cos() * 1.5).clamp(-1.5, 1.5),
+                6.0 + (i * 2.113).sin() * 2.0,
+            )
+        })
+        .collect()
+}
+
+/// Points on the plane z = 5 in front of the first camera.
+pub fn planar_points(n: usize) -> Vec<Point3<f64>> {
+    points(n)
+        .into_iter()
+        .map(|p| Point3::new(p.x, p.y, 5.0))
+        .collect()
+}
+
+/// A moderate rotation and a sideways translation, maps camera 1 coordinates to camera 2.
+pub fn motion() -> SE3 {
+    let rotation = Rotation3::from_euler_angles(0.05, -0.1, 0.02).into_inner();
+    SE3::from_parts(&rotation, &Vector3::new(-1.0, 0.1, 0.05))
+}
+
+pub fn project(camera: &Camera, point: &Point3<f64>) -> KeyPoint {
+    let pixel = camera.project(point).unwrap();
+    KeyPoint::new(pixel.x as f32, pixel.y as f32, 0.0)
+}
+
+/// Matches between camera 1 at the origin and camera 2 at `X2 = pose * X1`.
+pub fn matches(camera: &Camera, points: &[Point3<f64>], pose: &SE3) -> Vec<(KeyPoint, KeyPoint)> {
+    points
+        .iter()
+        .map(|p| (project(camera, p), project(camera, &(*pose * *p))))
+        .collect()
+}
+
+/// Random 256 bit descriptors, far apart in Hamming distance.
+pub fn descriptors(n: usize, seed: u64) -> Vec<Descriptor> {
+    let mut rnd = Rand::new_with_seed(seed);
+    (0..n)
+        .map(|_| Descriptor((0..32).map(|_| (rnd.next() >> 32) as u8).collect()))
+        .collect()
+}
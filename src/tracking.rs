@@ -0,0 +1,575 @@
+//! Frame to map tracking, the steady state front end once the map is initialized.
+//!
+//! The pose of a new frame is predicted with a constant velocity motion model, the points of
+//! the local map (the reference keyframe and its covisible keyframes) are projected with it, and
+//! each is matched by descriptor to the keypoints in a window around its projection. Only the
+//! pose is then optimized against the fixed points, so every frame is expressed in the scale of
+//! the map rather than up to an unknown scale as with pairwise essential matrices.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use nalgebra::Point2;
+
+use crate::bundle_adjustment::BundleAdjustmentOptions;
+use crate::camera::Camera;
+use crate::common::{Descriptor, KeyPoint};
+use crate::hamming::hamming_distance;
+use crate::lie::SE3;
+use crate::map::{KeyFrameId, Map, MapError, MapPointId};
+use crate::pnp::refine_pose;
+use crate::robust::{RobustKernel, MAX_REPROJECTION_ERROR};
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct TrackingOptions {
+    /// Half the side of the square window searched around each projected point, pixels.
+    pub search_radius: f64,
+    /// How much to widen the window when the first search finds too few matches.
+    pub wide_search_factor: f64,
+    pub max_hamming_distance: usize,
+    /// A match must beat the second best keypoint in its window by this ratio.
+    pub ratio: f64,
+    /// Skip points seen at a larger angle than this from their mean viewing direction (cosine).
+    pub min_view_cosine: f64,
+    /// How many covisible keyframes of the reference keyframe make up the local map.
+    pub max_local_keyframes: usize,
+    /// The largest reprojection error of an inlier after pose optimization, pixels.
+    pub max_reprojection_error: f64,
+    /// Rounds of pose optimization, each dropping the outliers of the previous one.
+    pub optimization_rounds: usize,
+    pub pose_optimization: BundleAdjustmentOptions,
+    /// Tracking fails with fewer inliers than this.
+    pub min_inliers: usize,
+}
+
+impl Default for TrackingOptions {
+    fn default() -> Self {
+        Self {
+            search_radius: 15.0,
+            wide_search_factor: 2.0,
+            max_hamming_distance: 100,
+            ratio: 0.8,
+            min_view_cosine: 0.5,
+            max_local_keyframes: 20,
+            max_reprojection_error: MAX_REPROJECTION_ERROR,
+            optimization_rounds: 4,
+            pose_optimization: BundleAdjustmentOptions {
+                max_iterations: 10,
+                kernel: RobustKernel::Huber(MAX_REPROJECTION_ERROR),
+                ..Default::default()
+            },
+            min_inliers: 20,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum TrackingError {
+    /// `reset` has not been called since the tracker was created or lost.
+    NotInitialized,
+    /// Too few map points matched after pose optimization, tracking is lost.
+    NotEnoughInliers(usize),
+    /// The frame has a different number of keypoints and descriptors.
+    DescriptorCountMismatch {
+        key_points: usize,
+        descriptors: usize,
+    },
+    Map(MapError),
+}
+
+impl fmt::Display for TrackingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrackingError::NotInitialized => write!(f, "tracking is not initialized"),
+            TrackingError::NotEnoughInliers(n) => {
+                write!(f, "only {} map points were tracked, tracking is lost", n)
+            }
+            TrackingError::DescriptorCountMismatch {
+                key_points,
+                descriptors,
+            } => write!(
+                f,
+                "the frame has {} keypoints but {} descriptors",
+                key_points, descriptors
+            ),
+            TrackingError::Map(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for TrackingError {}
+
+impl From<MapError> for TrackingError {
+    fn from(error: MapError) -> Self {
+        TrackingError::Map(error)
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct TrackedFrame {
+    /// `X_camera = pose * X_world`.
+    pub pose: SE3,
+    /// The map point matched to each keypoint of the frame, if any. Only inliers are kept.
+    pub map_points: Vec<Option<MapPointId>>,
+    /// The map points matched by descriptor before pose optimization.
+    pub matches: usize,
+    pub inliers: usize,
+    /// The keyframe sharing the most points with the frame.
+    pub reference_keyframe: KeyFrameId,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Tracker {
+    pub options: TrackingOptions,
+    camera: Camera,
+    width: usize,
+    height: usize,
+    last_pose: Option<SE3>,
+    // the motion between the last two frames, `X_last = velocity * X_previous`
+    velocity: Option<SE3>,
+    reference_keyframe: Option<KeyFrameId>,
+}
+
+impl Tracker {
+    pub fn new(camera: Camera, width: usize, height: usize, options: TrackingOptions) -> Self {
+        Self {
+            options,
+            camera,
+            width,
+            height,
+            last_pose: None,
+            velocity: None,
+            reference_keyframe: None,
+        }
+    }
+
+    /// Restarts tracking from a known pose, e.g. after initialization or relocalization.
+    pub fn reset(&mut self, pose: SE3, reference_keyframe: KeyFrameId) {
+        self.last_pose = Some(pose);
+        self.velocity = None;
+        self.reference_keyframe = Some(reference_keyframe);
+    }
+
+    /// Tells the tracker about a new keyframe made from the last tracked frame.
+    pub fn set_reference_keyframe(&mut self, keyframe: KeyFrameId) {
+        self.reference_keyframe = Some(keyframe);
+    }
+
+    pub fn reference_keyframe(&self) -> Option<KeyFrameId> {
+        self.reference_keyframe
+    }
+
+    pub fn last_pose(&self) -> Option<SE3> {
+        self.last_pose
+    }
+
+    /// The pose expected for the next frame, assuming it moves as much as the last one did.
+    pub fn predicted_pose(&self) -> Option<SE3> {
+        let last = self.last_pose?;
+        Some(self.velocity.map_or(last, |velocity| velocity * last))
+    }
+
+    /// Tracks a new frame against the map. The found/visible statistics of the local map points
+    /// are updated; on failure the tracker is lost until the next `reset`, except for a frame
+    /// with a different number of keypoints and descriptors, which is rejected untouched.
+    pub fn track(
+        &mut self,
+        map: &mut Map,
+        key_points: &[KeyPoint],
+        descriptors: &[Descriptor],
+    ) -> Result<TrackedFrame, TrackingError> {
+        check_counts(key_points, descriptors)?;
+        let mut visibility = Vec::new();
+        let mut result = self.track_local_map(map, key_points, descriptors, &mut visibility);
+        for (point, found) in visibility {
+            if let Err(error) = map.record_tracking(point, found) {
+                result = Err(error.into());
+                break;
+            }
+        }
+        self.update(&result);
+        result
+    }
+
+    /// Like `track`, but leaves the map untouched, for localizing against a fixed map.
+    pub fn track_fixed(
+        &mut self,
+        map: &Map,
+        key_points: &[KeyPoint],
+        descriptors: &[Descriptor],
+    ) -> Result<TrackedFrame, TrackingError> {
+        check_counts(key_points, descriptors)?;
+        let result = self.track_local_map(map, key_points, descriptors, &mut Vec::new());
+        self.update(&result);
+        result
+    }
+
+    fn update(&mut self, result: &Result<TrackedFrame, TrackingError>) {
+        match result {
+            Ok(frame) => {
+                self.velocity = self.last_pose.map(|last| frame.pose * last.inverse());
+                self.last_pose = Some(frame.pose);
+                self.reference_keyframe = Some(frame.reference_keyframe);
+            }
+            Err(_) => {
+                self.last_pose = None;
+                self.velocity = None;
+            }
+        }
+    }
+
+    // `visibility` receives the local map points expected in the frame and whether each was
+    // tracked, the statistics `track` records
+    fn track_local_map(
+        &self,
+        map: &Map,
+        key_points: &[KeyPoint],
+        descriptors: &[Descriptor],
+        visibility: &mut Vec<(MapPointId, bool)>,
+    ) -> Result<TrackedFrame, TrackingError> {
+        let predicted = self.predicted_pose().ok_or(TrackingError::NotInitialized)?;
+        let reference = self
+            .reference_keyframe
+            .ok_or(TrackingError::NotInitialized)?;
+        if map.keyframe(reference).is_none() {
+            return Err(MapError::UnknownKeyFrame(reference).into());
+        }
+
+        let points = self.local_points(map, reference);
+        let visible = self.visible_points(map, &points, &predicted);
+        let grid = Grid::new(key_points, self.options.search_radius);
+
+        let mut matches = self.search(map, &visible, &grid, key_points, descriptors, 1.0);
+        if matches.len() < self.options.min_inliers {
+            let factor = self.options.wide_search_factor;
+            matches = self.search(map, &visible, &grid, key_points, descriptors, factor);
+        }
+
+        let num_matches = matches.len();
+        let (pose, inliers) = self.optimize_pose(map, predicted, key_points, matches);
+
+        visibility.extend(
+            visible
+                .iter()
+                .map(|(id, _)| (*id, inliers.values().any(|point| point == id))),
+        );
+        if inliers.len() < self.options.min_inliers {
+            return Err(TrackingError::NotEnoughInliers(inliers.len()));
+        }
+
+        // the keyframe observing the most tracked points becomes the reference
+        let mut counts = BTreeMap::<KeyFrameId, usize>::new();
+        for point in inliers.values() {
+            for keyframe in map.map_point(*point).unwrap().observations().keys() {
+                *counts.entry(*keyframe).or_default() += 1;
+            }
+        }
+        let reference_keyframe = counts
+            .iter()
+            .max_by_key(|(_, &count)| count)
+            .map_or(reference, |(&keyframe, _)| keyframe);
+
+        let mut map_points = vec![None; key_points.len()];
+        for (&index, &point) in &inliers {
+            map_points[index] = Some(point);
+        }
+        Ok(TrackedFrame {
+            pose,
+            map_points,
+            matches: num_matches,
+            inliers: inliers.len(),
+            reference_keyframe,
+        })
+    }
+
+    // the points of the reference keyframe and its best covisible keyframes
+    fn local_points(&self, map: &Map, reference: KeyFrameId) -> BTreeSet<MapPointId> {
+        let mut keyframes =
+            map.best_covisible_keyframes(reference, self.options.max_local_keyframes);
+        keyframes.push(reference);
+        keyframes
+            .iter()
+            .flat_map(|&id| map.keyframe(id).unwrap().map_points().iter().flatten())
+            .copied()
+            .collect()
+    }
+
+    // the points expected in the image, with their projections
+    fn visible_points(
+        &self,
+        map: &Map,
+        points: &BTreeSet<MapPointId>,
+        pose: &SE3,
+    ) -> Vec<(MapPointId, Point2<f64>)> {
+        let center = pose.center();
+        points
+            .iter()
+            .filter_map(|&id| {
+                let mp = map.map_point(id).unwrap();
+                let pixel = self.camera.project(&(*pose * *mp.position()))?;
+                let inside = pixel.x >= 0.0
+                    && pixel.y >= 0.0
+                    && pixel.x < self.width as f64
+                    && pixel.y < self.height as f64;
+                let ray = mp.position() - center;
+                let distance = ray.norm();
+                let facing = ray.dot(mp.normal()) >= self.options.min_view_cosine * distance;
+                (inside && facing && mp.is_in_scale_range(distance)).then_some((id, pixel))
+            })
+            .collect()
+    }
+
+    // the best keypoint in the window around each projection, as keypoint index -> map point
+    fn search(
+        &self,
+        map: &Map,
+        visible: &[(MapPointId, Point2<f64>)],
+        grid: &Grid,
+        key_points: &[KeyPoint],
+        descriptors: &[Descriptor],
+        factor: f64,
+    ) -> BTreeMap<usize, (MapPointId, usize)> {
+        let radius = self.options.search_radius * factor;
+        let mut matches = BTreeMap::<usize, (MapPointId, usize)>::new();
+
+        for (id, pixel) in visible {
+            let descriptor = map.map_point(*id).unwrap().descriptor();
+            let mut best = (usize::MAX, usize::MAX);
+            let mut second = usize::MAX;
+            for index in grid.query(pixel, radius) {
+                let offset = key_points[index].point() - pixel;
+                if offset.x.abs() > radius || offset.y.abs() > radius {
+                    continue;
+                }
+                let distance = hamming_distance(&descriptor.0, &descriptors[index].0);
+                if distance < best.0 {
+                    second = best.0;
+                    best = (distance, index);
+                } else if distance < second {
+                    second = distance;
+                }
+            }
+
+            let (distance, index) = best;
+            if distance > self.options.max_hamming_distance
+                || (second != usize::MAX && distance as f64 > self.options.ratio * second as f64)
+            {
+                continue;
+            }
+            // a keypoint goes to the closest descriptor
+            if matches.get(&index).is_none_or(|&(_, d)| distance < d) {
+                matches.insert(index, (*id, distance));
+            }
+        }
+        matches
+    }
+
+    // motion-only bundle adjustment, returns the pose and the inlier matches
+    fn optimize_pose(
+        &self,
+        map: &Map,
+        pose: SE3,
+        key_points: &[KeyPoint],
+        matches: BTreeMap<usize, (MapPointId, usize)>,
+    ) -> (SE3, BTreeMap<usize, MapPointId>) {
+        let correspondences = matches
+            .iter()
+            .map(|(&index, (point, _))| {
+                let position = *map.map_point(*point).unwrap().position();
+                (position, key_points[index].point())
+            })
+            .collect::<Vec<_>>();
+        let (pose, inliers) = refine_pose(
+            &self.camera,
+            &pose,
+            &correspondences,
+            self.options.optimization_rounds,
+            self.options.max_reprojection_error,
+            &self.options.pose_optimization,
+        );
+        let inliers = matches
+            .into_iter()
+            .zip(inliers)
+            .filter(|(_, inlier)| *inlier)
+            .map(|((index, (point, _)), _)| (index, point))
+            .collect();
+        (pose, inliers)
+    }
+}
+
+// one descriptor per keypoint, as matching indexes both by the same position
+fn check_counts(key_points: &[KeyPoint], descriptors: &[Descriptor]) -> Result<(), TrackingError> {
+    if key_points.len() != descriptors.len() {
+        return Err(TrackingError::DescriptorCountMismatch {
+            key_points: key_points.len(),
+            descriptors: descriptors.len(),
+        });
+    }
+    Ok(())
+}
+
+// buckets keypoints by position so window searches don't scan the whole frame
+struct Grid {
+    cell: f64,
+    cells: BTreeMap<(i64, i64), Vec<usize>>,
+}
+
+impl Grid {
+    fn new(key_points: &[KeyPoint], cell: f64) -> Self {
+        let mut cells = BTreeMap::<(i64, i64), Vec<usize>>::new();
+        for (i, key_point) in key_points.iter().enumerate() {
+            let p = key_point.point();
+            cells
+                .entry(((p.x / cell).floor() as i64, (p.y / cell).floor() as i64))
+                .or_default()
+                .push(i);
+        }
+        Self { cell, cells }
+    }
+
+    // the keypoints in the cells overlapping the window, a superset of the window
+    fn query(&self, center: &Point2<f64>, radius: f64) -> Vec<usize> {
+        let cell = |v: f64| (v / self.cell).floor() as i64;
+        let mut indices = Vec::new();
+        for x in cell(center.x - radius)..=cell(center.x + radius) {
+            for y in cell(center.y - radius)..=cell(center.y + radius) {
+                if let Some(bucket) = self.cells.get(&(x, y)) {
+                    indices.extend(bucket);
+                }
+            }
+        }
+        indices
+    }
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_scene;
+    use nalgebra::{Point3, Vector6};
+
+    // a keyframe at the origin seeing 80 points, and a camera drifting away from it
+    fn scene() -> (Map, KeyFrameId, Vec<Point3<f64>>, Vec<Descriptor>) {
+        let camera = test_scene::camera();
+        let points = test_scene::points(80);
+        let descriptors = test_scene::descriptors(points.len(), 3);
+        let key_points = points
+            .iter()
+            .map(|p| test_scene::project(&camera, p))
+            .collect();
+
+        let mut map = Map::new();
+        let keyframe = map
+            .insert_keyframe(SE3::identity(), camera, key_points, descriptors.clone())
+            .unwrap();
+        for (i, p) in points.iter().enumerate() {
+            map.insert_map_point(*p, keyframe, i).unwrap();
+        }
+        (map, keyframe, points, descriptors)
+    }
+
+    fn trajectory(frame: usize) -> SE3 {
+        SE3::exp(&(Vector6::new(-0.04, 0.01, 0.0, 0.0, -0.004, 0.001) * frame as f64))
+    }
+
+    fn observe(pose: &SE3, points: &[Point3<f64>]) -> Vec<KeyPoint> {
+        let camera = test_scene::camera();
+        points
+            .iter()
+            .map(|p| test_scene::project(&camera, &(*pose * *p)))
+            .collect()
+    }
+
+    fn tracker() -> Tracker {
+        Tracker::new(test_scene::camera(), 640, 480, TrackingOptions::default())
+    }
+
+    #[test]
+    fn test_track_sequence() {
+        let (mut map, keyframe, points, descriptors) = scene();
+        let mut tracker = tracker();
+        tracker.reset(SE3::identity(), keyframe);
+
+        for frame in 1..6 {
+            let truth = trajectory(frame);
+            let tracked = tracker
+                .track(&mut map, &observe(&truth, &points), &descriptors)
+                .unwrap();
+            assert_eq!(tracked.inliers, points.len());
+            assert_eq!(tracked.reference_keyframe, keyframe);
+            assert!((tracked.pose.inverse() * truth).log().norm() < 1e-4);
+            assert!(tracked.map_points.iter().all(|p| p.is_some()));
+        }
+
+        // constant velocity predicts the next frame exactly on this trajectory
+        let predicted = tracker.predicted_pose().unwrap();
+        assert!((predicted.inverse() * trajectory(6)).log().norm() < 1e-4);
+        let point = map.map_point(MapPointId(0)).unwrap();
+        assert_eq!(point.found_ratio(), 1.0);
+    }
+
+    #[test]
+    fn test_track_rejects_outliers() {
+        let (mut map, keyframe, points, descriptors) = scene();
+        let mut tracker = tracker();
+        tracker.reset(SE3::identity(), keyframe);
+
+        // a few keypoints sit inside the search window but far from their true position
+        let truth = trajectory(1);
+        let mut key_points = observe(&truth, &points);
+        for key_point in key_points.iter_mut().take(5) {
+            key_point.x += 8.0;
+        }
+        let tracked = tracker.track(&mut map, &key_points, &descriptors).unwrap();
+
+        assert_eq!(tracked.inliers, points.len() - 5);
+        assert!(tracked.map_points[..5].iter().all(|p| p.is_none()));
+        assert!((tracked.pose.inverse() * truth).log().norm() < 1e-4);
+        let missed = map.map_point(MapPointId(0)).unwrap();
+        assert_eq!(missed.found_ratio(), 0.5);
+    }
+
+    #[test]
+    fn test_tracking_lost() {
+        let (mut map, keyframe, points, _) = scene();
+        let mut tracker = tracker();
+        assert_eq!(
+            tracker.track(&mut map, &[], &[]),
+            Err(TrackingError::NotInitialized)
+        );
+
+        // unrelated descriptors match nothing
+        tracker.reset(SE3::identity(), keyframe);
+        let key_points = observe(&trajectory(1), &points);
+        let descriptors = test_scene::descriptors(points.len(), 4);
+        assert_eq!(
+            tracker.track(&mut map, &key_points, &descriptors),
+            Err(TrackingError::NotEnoughInliers(0))
+        );
+        assert_eq!(tracker.predicted_pose(), None);
+    }
+
+    #[test]
+    fn test_track_checks_descriptor_count() {
+        let (mut map, keyframe, points, descriptors) = scene();
+        let mut tracker = tracker();
+        tracker.reset(SE3::identity(), keyframe);
+
+        let key_points = observe(&trajectory(1), &points);
+        let mismatch = Err(TrackingError::DescriptorCountMismatch {
+            key_points: points.len(),
+            descriptors: points.len() - 1,
+        });
+        let fewer = &descriptors[1..];
+        assert_eq!(tracker.track(&mut map, &key_points, fewer), mismatch);
+        assert_eq!(tracker.track_fixed(&map, &key_points, fewer), mismatch);
+
+        // the tracker is not lost by a malformed frame
+        assert_eq!(tracker.last_pose(), Some(SE3::identity()));
+        assert!(tracker.track(&mut map, &key_points, &descriptors).is_ok());
+    }
+}
@@ -0,0 +1,85 @@
+//! Recovering 3D points from their images in two views.
+//!
+//! https://en.wikipedia.org/wiki/Triangulation_(computer_vision)
+
+use nalgebra::{Matrix3x4, Matrix4, Point2, Point3};
+
+use crate::lie::SE3;
+
+/// The normalized projection matrix `[R | t]` of a camera with the pose
+/// `X_camera = pose * X_world`.
+pub fn projection_matrix(pose: &SE3) -> Matrix3x4<f64> {
+    pose.matrix().fixed_view::<3, 4>(0, 0).into_owned()
+}
+
+/// Linear (DLT) triangulation of the normalized image points `x1` and `x2` seen by cameras with
+/// the normalized projection matrices `p1` and `p2`. Returns `None` for points at infinity.
+pub fn triangulate(
+    p1: &Matrix3x4<f64>,
+    x1: &Point2<f64>,
+    p2: &Matrix3x4<f64>,
+    x2: &Point2<f64>,
+) -> Option<Point3<f64>> {
+    // each view gives two equations, x * P.row(2) - P.row(0) and y * P.row(2) - P.row(1)
+    let mut a = Matrix4::zeros();
+    a.set_row(0, &(p1.row(2) * x1.x - p1.row(0)));
+    a.set_row(1, &(p1.row(2) * x1.y - p1.row(1)));
+    a.set_row(2, &(p2.row(2) * x2.x - p2.row(0)));
+    a.set_row(3, &(p2.row(2) * x2.y - p2.row(1)));
+
+    let v_t = a.svd(false, true).v_t?;
+    let x = v_t.row(3);
+    if x[3].abs() <= f64::EPSILON {
+        return None;
+    }
+    let point = Point3::new(x[0] / x[3], x[1] / x[3], x[2] / x[3]);
+    point.coords.iter().all(|v| v.is_finite()).then_some(point)
+}
+
+/// The depth of a world point in a camera with the normalized projection matrix `p`.
+pub fn depth(p: &Matrix3x4<f64>, point: &Point3<f64>) -> f64 {
+    (p.row(2) * point.to_homogeneous())[0]
+}
+
+/// The angle (radians) between the rays from two camera centres to a point. Small parallax
+/// means the depth of the point is poorly constrained.
+pub fn parallax(center1: &Point3<f64>, center2: &Point3<f64>, point: &Point3<f64>) -> f64 {
+    let ray1 = point - center1;
+    let ray2 = point - center2;
+    let cos = ray1.dot(&ray2) / (ray1.norm() * ray2.norm());
+    cos.clamp(-1.0, 1.0).acos()
+}
+
+/****************/
+/*  UNIT TESTS  */
+/****************/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_scene;
+
+    #[test]
+    fn test_triangulate() {
+        let camera = test_scene::camera();
+        let pose = test_scene::motion();
+        let points = test_scene::points(10);
+        let matches = camera.normalize_matches(&test_scene::matches(&camera, &points, &pose));
+
+        let p1 = projection_matrix(&SE3::identity());
+        let p2 = projection_matrix(&pose);
+        for (point, (x1, x2)) in points.iter().zip(&matches) {
+            let triangulated = triangulate(&p1, x1, &p2, x2).unwrap();
+            assert!((triangulated - point).norm() < 1e-3);
+            assert!(depth(&p1, &triangulated) > 0.0);
+            assert!(depth(&p2, &triangulated) > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_parallax() {
+        let point = Point3::new(1.0, 0.0, 1.0);
+        let angle = parallax(&Point3::origin(), &Point3::new(2.0, 0.0, 0.0), &point);
+        assert!((angle - std::f64::consts::FRAC_PI_2).abs() < 1e-12);
+    }
+}